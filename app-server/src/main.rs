@@ -1,12 +1,7 @@
-mod config;
-mod server;
-mod state;
-
 use server_common::prelude::*;
-use state::{get_state, AppState};
-
-use crate::config::Config;
-use crate::server::get_router;
+use app_server::config::Config;
+use app_server::server::get_router;
+use app_server::state::{get_state, AppState};
 
 server_args!("cfg/app-server.toml");
 
@@ -1,32 +1,45 @@
 use std::str::FromStr;
 
-use axum::body::Body;
+use axum::body::{Body, StreamBody};
 use axum::extract::State;
 use axum::http::header::{
-    ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, AUTHORIZATION, CONNECTION, CONTENT_TYPE,
+    ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, AUTHORIZATION, CONNECTION, CONTENT_TYPE, ETAG,
+    IF_MATCH,
 };
 use axum::http::uri::Authority;
-use axum::http::{Method, Request, StatusCode, Uri};
+use axum::http::{HeaderMap, HeaderName, Method, Request, StatusCode, Uri};
 use axum::response::{IntoResponse, Response};
-use axum::routing::{delete, get, put};
+use axum::routing::{delete, get, post, put};
 use axum::Router;
 use tower_http::cors::CorsLayer;
 use tower_http::services::ServeDir;
 use tracing::error;
 
 use crate::state::AppState;
-use server_common::ORIGIN;
+use server_common::allowed_origin;
+use server_common::auth::{AuthError, Claims};
+use server_common::revocation;
 
 macro_rules! proxy {
     ($name: ident, $method: ident, $service_config_entry: ident) => {
         async fn $name(State(state): State<AppState>, request: Request<Body>) -> Response {
+            if let Err(err) = reject_revoked_token(request.headers()).await {
+                return err.into_response();
+            }
+
             let uri = match Uri::builder()
                 .scheme("https")
                 .authority(
                     Authority::from_str(&state.config.$service_config_entry.authority())
                         .expect("SocketAddr.to_string() should be a valid authority"),
                 )
-                .path_and_query(request.uri().path())
+                .path_and_query(
+                    request
+                        .uri()
+                        .path_and_query()
+                        .map(|path_and_query| path_and_query.as_str())
+                        .unwrap_or(request.uri().path()),
+                )
                 .build()
             {
                 Ok(uri) => uri,
@@ -47,13 +60,7 @@ macro_rules! proxy {
                 Ok(response) => {
                     let status = response.status();
                     let headers = response.headers().to_owned();
-                    match response.bytes().await {
-                        Ok(body) => (status, headers, body).into_response(),
-                        Err(err) => {
-                            error!(?err, "Error in response");
-                            StatusCode::INTERNAL_SERVER_ERROR.into_response()
-                        }
-                    }
+                    (status, headers, StreamBody::new(response.bytes_stream())).into_response()
                 }
                 Err(err) => {
                     error!(?err, "Error sending request");
@@ -64,26 +71,61 @@ macro_rules! proxy {
     };
 }
 
+/// Turns away requests made with a revoked token before they reach a service.
+async fn reject_revoked_token(headers: &HeaderMap) -> Result<(), AuthError> {
+    let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return Ok(());
+    };
+
+    // Tokens that aren't valid are left for the service to reject.
+    match Claims::from_encoded(token).await {
+        Ok(claims) => revocation::check(&claims).await,
+        Err(_) => Ok(()),
+    }
+}
+
 proxy!(filestore_get, get, filestore_server);
 proxy!(filestore_put, put, filestore_server);
+proxy!(filestore_delete, delete, filestore_server);
 proxy!(fileshare_get, get, fileshare_server);
+proxy!(fileshare_post, post, fileshare_server);
 proxy!(fileshare_put, put, fileshare_server);
 proxy!(fileshare_delete, delete, fileshare_server);
 
 pub fn get_router() -> Router<AppState> {
     Router::new()
-        .route("/config", get(config))
         .route("/files", get(filestore_get))
         .route("/files/:file", get(filestore_get))
         .route("/files/:file", put(filestore_put))
+        .route("/files/:file", delete(filestore_delete))
+        .route("/files/:file/meta", get(filestore_get))
+        .route("/files/:file/key", get(filestore_get))
+        .route("/files/:file/keys/:user", put(filestore_put))
+        .route("/files/:file/acl", get(filestore_get))
+        .route("/files/:file/acl", put(filestore_put))
         .route("/links", get(fileshare_get))
         .route("/link", put(fileshare_put))
         .route("/link/:code", get(fileshare_get))
+        .route("/link/:code", post(fileshare_post))
         .route("/link/:code", delete(fileshare_delete))
+        .route("/grants", get(fileshare_get))
+        .route("/grants/received", get(fileshare_get))
+        .route("/grant", put(fileshare_put))
+        .route("/grant", delete(fileshare_delete))
         .fallback_service(ServeDir::new("www"))
         .layer(
             CorsLayer::new()
-                .allow_methods([Method::GET, Method::PUT, Method::DELETE, Method::OPTIONS])
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::PUT,
+                    Method::DELETE,
+                    Method::OPTIONS,
+                ])
                 .allow_headers([
                     ACCEPT,
                     ACCEPT_ENCODING,
@@ -91,12 +133,152 @@ pub fn get_router() -> Router<AppState> {
                     AUTHORIZATION,
                     CONNECTION,
                     CONTENT_TYPE,
+                    IF_MATCH,
+                    HeaderName::from_static("file-key"),
                 ])
-                .allow_origin(ORIGIN),
+                .expose_headers([ETAG])
+                .allow_origin(allowed_origin()),
         )
 }
 
-#[tracing::instrument]
-async fn config(State(state): State<AppState>) -> String {
-    format!("{:#?}", state.config)
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Bytes;
+    use axum::extract::Path;
+    use axum::http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN};
+    use axum_server::tls_rustls::RustlsConfig;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+
+    use super::*;
+    use crate::config::Config;
+
+    type Files = Arc<Mutex<HashMap<String, Bytes>>>;
+
+    /// A stand-in for the filestore service that keeps files in memory.
+    fn filestore() -> Router {
+        Router::new()
+            .route(
+                "/files/:file",
+                put(
+                    |State(files): State<Files>, Path(file): Path<String>, body: Bytes| async move {
+                        files.lock().unwrap().insert(file, body);
+                        StatusCode::CREATED
+                    },
+                )
+                .get(
+                    |State(files): State<Files>, Path(file): Path<String>| async move {
+                        files
+                            .lock()
+                            .unwrap()
+                            .get(&file)
+                            .cloned()
+                            .ok_or(StatusCode::NOT_FOUND)
+                    },
+                ),
+            )
+            .with_state(Files::default())
+    }
+
+    /// Serves `upstream` over TLS as the filestore and fileshare services, and the proxy in front
+    /// of it over plain HTTP, returning the proxy's address.
+    async fn start_proxy(upstream: Router) -> SocketAddr {
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let cert =
+            Certificate::from_params(CertificateParams::new(vec!["localhost".to_owned()])).unwrap();
+        let tls = RustlsConfig::from_pem(
+            cert.serialize_pem_with_signer(&ca).unwrap().into_bytes(),
+            cert.serialize_private_key_pem().into_bytes(),
+        )
+        .await
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_port = listener.local_addr().unwrap().port();
+        tokio::spawn(
+            axum_server::from_tcp_rustls(listener, tls).serve(upstream.into_make_service()),
+        );
+
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [general]
+            port = 0
+            [auth-server]
+            host = "localhost"
+            port = 1
+            [filestore-server]
+            host = "localhost"
+            port = {upstream_port}
+            [fileshare-server]
+            host = "localhost"
+            port = {upstream_port}
+            "#
+        ))
+        .unwrap();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(ca.serialize_pem().unwrap().as_bytes()).unwrap(),
+            )
+            .build()
+            .unwrap();
+
+        let proxy = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            get_router()
+                .with_state(AppState { client, config })
+                .into_make_service(),
+        );
+        let address = proxy.local_addr();
+        tokio::spawn(proxy);
+        address
+    }
+
+    #[tokio::test]
+    async fn binary_files_round_trip() {
+        let proxy = start_proxy(filestore()).await;
+        let client = reqwest::Client::new();
+        // Every byte value, including sequences that aren't valid UTF-8
+        let content: Vec<u8> = (0..=255).chain([0xc3, 0x28, 0xff, 0xfe, 0x00]).collect();
+
+        let response = client
+            .put(format!("http://{proxy}/files/image.png"))
+            .body(content.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = client
+            .get(format!("http://{proxy}/files/image.png"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn only_the_configured_origin_is_allowed() {
+        let proxy = start_proxy(filestore()).await;
+        let client = reqwest::Client::new();
+
+        for (origin, allowed) in [
+            (server_common::DEFAULT_ORIGIN, true),
+            ("https://attacker.example", false),
+        ] {
+            let response = client
+                .request(Method::OPTIONS, format!("http://{proxy}/files/image.png"))
+                .header(ORIGIN, origin)
+                .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+                .send()
+                .await
+                .unwrap();
+            let allowed_origin = response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN);
+            assert_eq!(allowed_origin.is_some_and(|value| value == origin), allowed);
+        }
+    }
 }
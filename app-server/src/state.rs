@@ -1,5 +1,7 @@
 use crate::config::Config;
+use server_common::auth::{AuthClient, AUTH_CLIENT};
 use server_common::util::new_reqwest_client_from_certificates;
+use server_common::ServerConfig;
 
 #[derive(Clone, Debug)]
 pub struct State {
@@ -10,6 +12,14 @@ pub struct State {
 pub type AppState = State;
 
 pub fn get_state(config: Config) -> anyhow::Result<AppState> {
+    AUTH_CLIENT
+        .set(AuthClient::new(
+            Config::name(),
+            config.auth_server.authority(),
+        )?)
+        .ok()
+        .expect("this should only get called once");
+
     Ok(State {
         client: new_reqwest_client_from_certificates("app-server")?,
         config,
@@ -0,0 +1,310 @@
+//! Checks that files are streamed on their way through the services rather than held in memory,
+//! by counting what's allocated while they pass through.
+//!
+//! This is a test binary of its own, without the test harness, so that the checks run one at a
+//! time and nothing else allocates while they measure.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use axum::body::{Bytes, StreamBody};
+use axum::extract::BodyStream;
+use axum::http::StatusCode;
+use axum::routing::put;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use futures_util::{stream, Stream, StreamExt};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use serde_json::json;
+
+use app_server::server::get_router;
+use app_server::state::State as AppState;
+use server_common::user::Username;
+use service_filestore::config::{EncryptionConfig, LocalStorageConfig, StorageConfig};
+use service_filestore::storage::{Storage, StoredFile};
+
+/// Keeps track of how much memory is allocated, and the most that was at once.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+            PEAK_ALLOCATED.fetch_max(allocated + layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Files are encrypted and decrypted on their way through the filestore, which is slow without
+// optimizations, so they are smaller than those that only pass through the proxy.
+const PROXIED_FILE_SIZE: usize = 256 * 1024 * 1024;
+const STORED_FILE_SIZE: usize = 64 * 1024 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Well below the size of the files, but above what buffering a few chunks along the way takes.
+const MAX_BUFFERED: usize = 16 * 1024 * 1024;
+
+/// `size` bytes, produced a chunk at a time.
+fn large_content(size: usize) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    let chunk = Bytes::from(vec![0xa5; CHUNK_SIZE]);
+    stream::repeat(chunk).take(size / CHUNK_SIZE).map(Ok)
+}
+
+async fn count_bytes(mut body: impl Stream<Item = Result<Bytes, reqwest::Error>> + Unpin) -> usize {
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        size += chunk.unwrap().len();
+    }
+    size
+}
+
+/// Runs `check`, failing if more than `MAX_BUFFERED` bytes were allocated at once on top of what
+/// already was.
+async fn assert_streamed(name: &str, check: impl Future<Output = ()>) {
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK_ALLOCATED.store(baseline, Ordering::Relaxed);
+    check.await;
+
+    let peak = PEAK_ALLOCATED.load(Ordering::Relaxed) - baseline;
+    assert!(
+        peak < MAX_BUFFERED,
+        "{name}: {peak} bytes were allocated at once"
+    );
+    println!("{name}: at most {peak} bytes were allocated at once");
+}
+
+/// A certificate for `localhost`, and a client that trusts it.
+async fn tls() -> (RustlsConfig, reqwest::Client) {
+    let mut ca_params = CertificateParams::new(Vec::new());
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params).unwrap();
+    let cert =
+        Certificate::from_params(CertificateParams::new(vec!["localhost".to_owned()])).unwrap();
+    let config = RustlsConfig::from_pem(
+        cert.serialize_pem_with_signer(&ca).unwrap().into_bytes(),
+        cert.serialize_private_key_pem().into_bytes(),
+    )
+    .await
+    .unwrap();
+    let client = reqwest::Client::builder()
+        .add_root_certificate(
+            reqwest::Certificate::from_pem(ca.serialize_pem().unwrap().as_bytes()).unwrap(),
+        )
+        .build()
+        .unwrap();
+    (config, client)
+}
+
+/// Serves `router` over TLS, as the services are, returning its port.
+fn serve_tls(router: Router, tls: RustlsConfig) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(
+        axum_server::from_tcp_rustls(listener, tls)
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>()),
+    );
+    port
+}
+
+/// Serves the app server in front of the filestore and fileshare services, returning its address.
+fn start_proxy(client: reqwest::Client, filestore_port: u16, fileshare_port: u16) -> SocketAddr {
+    let config = serde_json::from_value(json!({
+        "general": {"port": 0},
+        "auth-server": {"host": "localhost", "port": 1},
+        "filestore-server": {"host": "localhost", "port": filestore_port},
+        "fileshare-server": {"host": "localhost", "port": fileshare_port},
+    }))
+    .unwrap();
+    let proxy = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+        get_router()
+            .with_state(AppState { client, config })
+            .into_make_service(),
+    );
+    let address = proxy.local_addr();
+    tokio::spawn(proxy);
+    address
+}
+
+async fn proxied_files_are_streamed() {
+    // Counts what's uploaded and serves generated content, so that only the proxy could hold a
+    // whole file in memory.
+    let upstream = Router::new().route(
+        "/files/:file",
+        put(|mut body: BodyStream| async move {
+            let mut size = 0;
+            while let Some(chunk) = body.next().await {
+                size += chunk.unwrap().len();
+            }
+            size.to_string()
+        })
+        .get(|| async { StreamBody::new(large_content(PROXIED_FILE_SIZE)) }),
+    );
+    let (tls, upstream_client) = tls().await;
+    let port = serve_tls(upstream, tls);
+    let proxy = start_proxy(upstream_client, port, port);
+    let client = reqwest::Client::new();
+
+    assert_streamed("upload through the proxy", async {
+        let response = client
+            .put(format!("http://{proxy}/files/backup.tar"))
+            .body(reqwest::Body::wrap_stream(large_content(PROXIED_FILE_SIZE)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            PROXIED_FILE_SIZE.to_string()
+        );
+    })
+    .await;
+
+    assert_streamed("download through the proxy", async {
+        let response = client
+            .get(format!("http://{proxy}/files/backup.tar"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            count_bytes(response.bytes_stream()).await,
+            PROXIED_FILE_SIZE
+        );
+    })
+    .await;
+}
+
+/// Serves the filestore with files kept encrypted under `dir`, returning its port.
+fn start_filestore(dir: &Path, tls: RustlsConfig) -> u16 {
+    use service_filestore::state::{Database, State, STORAGE};
+    use service_filestore::store::SqliteStore;
+
+    let storage = StorageConfig::Local(LocalStorageConfig {
+        path: dir.join("files"),
+        legacy_owner: None,
+    });
+    let encryption = EncryptionConfig {
+        master_key: dir.join("master.key"),
+        retired_master_keys: Vec::new(),
+    };
+    assert!(STORAGE
+        .set(Storage::from_config(&storage, &encryption, false).unwrap())
+        .is_ok());
+
+    let config = serde_json::from_value(json!({
+        "general": {"port": 0},
+        "auth-server": {"host": "localhost", "port": 1},
+        "file-store": {"known-services": ["127.0.0.1"]},
+        "fileshare-server": {"host": "localhost", "port": 1},
+    }))
+    .unwrap();
+    let store = SqliteStore::open(&dir.join("service-filestore.db")).unwrap();
+    let state = Arc::new(RwLock::new(State {
+        config,
+        db: Database::new(Box::new(store)),
+    }));
+    serve_tls(
+        service_filestore::server::get_router().with_state(state),
+        tls,
+    )
+}
+
+/// Serves the fileshare service with a link to `file`, returning its port and the link's code.
+fn start_fileshare(
+    dir: &Path,
+    tls: RustlsConfig,
+    client: reqwest::Client,
+    filestore_port: u16,
+    file: &StoredFile,
+) -> (u16, String) {
+    use service_fileshare::state::{Database, State, CLIENT};
+    use service_fileshare::store::SqliteStore;
+
+    CLIENT.set(client).unwrap();
+    let config = serde_json::from_value(json!({
+        "general": {"port": 0},
+        "auth-server": {"host": "localhost", "port": 1},
+        "file-share": {"known-services": []},
+        "filestore-server": {"host": "localhost", "port": filestore_port},
+    }))
+    .unwrap();
+    let store = SqliteStore::open(&dir.join("service-fileshare.db")).unwrap();
+    let mut db = Database::new(Box::new(store));
+    let code = db
+        .add_link(
+            file.owner().clone(),
+            file.name().to_owned(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+    let state = Arc::new(RwLock::new(State {
+        config,
+        db,
+        password_failures: Default::default(),
+    }));
+    let port = serve_tls(
+        service_fileshare::server::get_router().with_state(state),
+        tls,
+    );
+    (port, code.as_ref().to_owned())
+}
+
+async fn shared_files_are_streamed() {
+    let dir = tempfile::tempdir().unwrap();
+    let (tls, client) = tls().await;
+    let filestore_port = start_filestore(dir.path(), tls.clone());
+    let owner: Username = serde_json::from_str("\"alice\"").unwrap();
+    let file = StoredFile::new(owner, "backup.tar".to_owned()).unwrap();
+    let storage = service_filestore::state::STORAGE.get().unwrap();
+
+    assert_streamed("encryption of an upload", async {
+        let lock = storage.lock(&file).await;
+        let content = large_content(STORED_FILE_SIZE).boxed();
+        assert!(storage.create(&lock, content).await.unwrap().is_some());
+    })
+    .await;
+    assert!(storage.is_encrypted(&file).await.unwrap());
+
+    let (fileshare_port, code) =
+        start_fileshare(dir.path(), tls, client.clone(), filestore_port, &file);
+    let proxy = start_proxy(client, filestore_port, fileshare_port);
+
+    assert_streamed("download of a shared link", async {
+        let response = reqwest::get(format!("http://{proxy}/link/{code}"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(count_bytes(response.bytes_stream()).await, STORED_FILE_SIZE);
+    })
+    .await;
+}
+
+fn main() {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            proxied_files_are_streamed().await;
+            shared_files_are_streamed().await;
+        });
+}
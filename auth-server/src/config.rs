@@ -1,26 +1,76 @@
-use std::collections::HashSet;
+// `prae::define!` expands validators into code that trips this lint.
+#![allow(clippy::question_mark)]
+
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use time::Duration;
 
-use server_common::auth::ADMIN_ROLE;
+use server_common::auth::{
+    ADMIN_ROLE, FILES_ADMIN_PERMISSION, LINKS_ADMIN_PERMISSION, USERS_MANAGE_PERMISSION,
+};
 use server_common::server_config;
-use server_common::user::Role;
+use server_common::user::{Permission, Role};
+
+use crate::user::UserRecord;
 
 server_config! {
     "auth-server",
     #[derive(Clone, Debug, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
     pub struct Config {
         pub authenticator: AuthConfig,
+        pub filestore_server: ServiceClientConfig,
+        pub fileshare_server: ServiceClientConfig,
     }
 }
 
+/// Where a service that keeps data of users is, so that it can be deleted along with them.
 #[derive(Clone, Debug, Deserialize)]
+pub struct ServiceClientConfig {
+    host: String,
+    port: u16,
+}
+
+impl ServiceClientConfig {
+    pub fn authority(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct AuthConfig {
-    allowed_roles: AllowedRoleSet,
+    /// The roles users can be given, with the permissions each allows.
+    roles: RoleDefinitions,
     default_roles: HashSet<Role>,
     known_services: HashSet<IpAddr>,
+    /// How many days a signing key is used before being replaced.
+    #[serde(default = "default_key_rotation_days")]
+    key_rotation_days: u32,
+    /// Whether users with admin permissions must set up two-factor authentication before they can
+    /// use them.
+    #[serde(default)]
+    require_admin_two_factor: bool,
+    /// How many logins to an account can fail in a row before it's locked.
+    #[serde(default = "default_max_login_failures")]
+    max_login_failures: u32,
+    /// How many minutes an account stays locked for.
+    #[serde(default = "default_lockout_minutes")]
+    lockout_minutes: u32,
+}
+
+fn default_key_rotation_days() -> u32 {
+    30
+}
+
+fn default_max_login_failures() -> u32 {
+    10
+}
+
+fn default_lockout_minutes() -> u32 {
+    15
 }
 
 impl AuthConfig {
@@ -32,23 +82,71 @@ impl AuthConfig {
         &self.default_roles
     }
 
+    pub fn key_rotation_interval(&self) -> Duration {
+        Duration::days(self.key_rotation_days.into())
+    }
+
+    pub fn max_login_failures(&self) -> u32 {
+        self.max_login_failures
+    }
+
+    pub fn lockout_duration(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(u64::from(self.lockout_minutes) * 60)
+    }
+
+    /// Checks whether a user must set up two-factor authentication to use all of their
+    /// permissions.
+    pub fn two_factor_required(&self, user: &UserRecord) -> bool {
+        self.require_admin_two_factor
+            && admin_permissions().any(|permission| self.roles_grant(user, permission))
+    }
+
+    /// Checks whether any of a user's roles allows them `permission`, regardless of whether they
+    /// set up two-factor authentication.
+    pub fn roles_grant(&self, user: &UserRecord, permission: &Permission) -> bool {
+        user.roles()
+            .iter()
+            .filter_map(|role| self.roles.permissions(role))
+            .any(|permissions| permissions.contains(permission))
+    }
+
+    /// Checks whether a user can use a permission, which users that must set up two-factor
+    /// authentication can't do for admin permissions until they have.
+    pub fn user_has_permission(&self, user: &UserRecord, permission: &Permission) -> bool {
+        self.roles_grant(user, permission)
+            && !(admin_permissions().any(|admin_permission| admin_permission == permission)
+                && self.two_factor_required(user)
+                && !user.two_factor_enabled())
+    }
+
     pub fn role_is_allowed(&self, role: &Role) -> bool {
-        self.allowed_roles.contains(role)
+        self.roles.permissions(role).is_some()
     }
 }
 
+/// Permissions over other users and their data.
+fn admin_permissions() -> impl Iterator<Item = &'static Permission> {
+    [
+        &*USERS_MANAGE_PERMISSION,
+        &*FILES_ADMIN_PERMISSION,
+        &*LINKS_ADMIN_PERMISSION,
+    ]
+    .into_iter()
+}
+
 prae::define! {
     #[derive(Clone, Debug)]
-    AllowedRoleSet: HashSet<Role>;
-    validate(&'static str) |set|
-        set.contains(ADMIN_ROLE.as_ref())
+    RoleDefinitions: HashMap<Role, HashSet<Permission>>;
+    validate(&'static str) |roles|
+        roles.get(&*ADMIN_ROLE)
+            .is_some_and(|permissions| permissions.contains(&*USERS_MANAGE_PERMISSION))
             .then_some(())
-            .ok_or("`allowed-roles` must contain the `admin` role");
+            .ok_or("`roles` must define the `admin` role with the `users.manage` permission");
     plugins: [prae::impl_serde];
 }
 
-impl AllowedRoleSet {
-    fn contains(&self, role: &Role) -> bool {
-        self.0.contains(role.as_ref())
+impl RoleDefinitions {
+    fn permissions(&self, role: &Role) -> Option<&HashSet<Permission>> {
+        self.0.get(role)
     }
 }
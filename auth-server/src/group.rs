@@ -0,0 +1,13 @@
+use serde::Serialize;
+use server_common::user::{GroupName, Username};
+use time::OffsetDateTime;
+
+/// A named set of users, such as a department, that files can be shared with.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Group {
+    pub name: GroupName,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    /// Ordered by name.
+    pub members: Vec<Username>,
+}
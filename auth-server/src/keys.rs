@@ -0,0 +1,198 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, Jwk, JwkSet, KeyAlgorithm, PublicKeyUse,
+    RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::EncodingKey;
+use rsa::pkcs1::EncodeRsaPrivateKey;
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+use tracing::info;
+
+const KEYS_DIR: &str = "cfg/auth-server-keys";
+const LEGACY_PRIVATE_KEY_PATH: &str = "cfg/auth-server-private.pem";
+const KEY_SIZE: usize = 2048;
+/// How long a key keeps being published after it was replaced, which is well beyond the lifetime
+/// of the tokens it signed.
+const RETIRED_KEY_LIFETIME: Duration = Duration::days(1);
+
+/// A key that JWTs are signed with.
+pub struct Key {
+    id: String,
+    created_at: OffsetDateTime,
+    key: RsaPrivateKey,
+    jwt_key: EncodingKey,
+}
+
+impl Key {
+    fn new(key: RsaPrivateKey, created_at: OffsetDateTime) -> anyhow::Result<Self> {
+        let pkcs1_der = key
+            .to_pkcs1_der()
+            .context("Failed to convert key to DER format")?;
+        Ok(Self {
+            id: thumbprint(&key),
+            created_at,
+            jwt_key: EncodingKey::from_rsa_der(pkcs1_der.as_bytes()),
+            key,
+        })
+    }
+
+    pub fn generate() -> anyhow::Result<Self> {
+        let mut rng = rand::thread_rng();
+        let key = RsaPrivateKey::new(&mut rng, KEY_SIZE)?;
+        Self::new(key, OffsetDateTime::now_utc())
+    }
+
+    /// Loads a key from a file named after the Unix timestamp of when it was created.
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let created_at = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+            .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok())
+            .with_context(|| format!("Invalid signing key file name '{}'", path.display()))?;
+        let key = RsaPrivateKey::read_pkcs8_pem_file(path)
+            .with_context(|| format!("Failed to load signing key '{}'", path.display()))?;
+        Self::new(key, created_at)
+    }
+
+    fn path(&self) -> PathBuf {
+        Path::new(KEYS_DIR).join(format!("{}.pem", self.created_at.unix_timestamp()))
+    }
+
+    /// Gets the ID that tokens signed with this key name it by.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn jwt_key(&self) -> &EncodingKey {
+        &self.jwt_key
+    }
+
+    fn jwk(&self) -> Jwk {
+        Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::RS384),
+                key_id: Some(self.id.clone()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: general_purpose::URL_SAFE_NO_PAD.encode(self.key.n().to_bytes_be()),
+                e: general_purpose::URL_SAFE_NO_PAD.encode(self.key.e().to_bytes_be()),
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("id", &self.id)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+/// The JWK thumbprint of a key, as defined by RFC 7638.
+fn thumbprint(key: &RsaPrivateKey) -> String {
+    let members = json!({
+        "e": general_purpose::URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+        "kty": "RSA",
+        "n": general_purpose::URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+    });
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(members.to_string()))
+}
+
+/// The keys that JWTs are signed with. The newest one signs new tokens, while the ones it replaced
+/// keep being published until the tokens they signed have expired.
+#[derive(Debug)]
+pub struct KeyRing {
+    /// Oldest first.
+    keys: Vec<Key>,
+}
+
+impl KeyRing {
+    /// Loads the keys, generating the first one if there are none yet.
+    pub fn load() -> anyhow::Result<Self> {
+        fs::create_dir_all(KEYS_DIR).context("Failed to create signing key directory")?;
+
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(KEYS_DIR).context("Failed to read signing key directory")? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "pem") {
+                keys.push(Key::load(&path)?);
+            }
+        }
+        keys.sort_by_key(|key| key.created_at);
+
+        let mut ring = Self { keys };
+        if ring.keys.is_empty() {
+            // The single key that used to be kept keeps signing, so that issued tokens stay valid.
+            let legacy_path = Path::new(LEGACY_PRIVATE_KEY_PATH);
+            let key = if legacy_path.is_file() {
+                info!(
+                    "Moving signing key '{}' to '{}'",
+                    LEGACY_PRIVATE_KEY_PATH, KEYS_DIR
+                );
+                let key = RsaPrivateKey::read_pkcs8_pem_file(legacy_path)?;
+                Key::new(key, OffsetDateTime::now_utc())?
+            } else {
+                info!("Generating new signing key");
+                Key::generate()?
+            };
+            ring.add(key)?;
+            if legacy_path.is_file() {
+                fs::remove_file(legacy_path).context("Failed to remove legacy signing key")?;
+            }
+        }
+        Ok(ring)
+    }
+
+    /// Gets the key that new tokens are signed with.
+    pub fn current(&self) -> &Key {
+        self.keys.last().expect("key ring should never be empty")
+    }
+
+    pub fn key_set(&self) -> JwkSet {
+        JwkSet {
+            keys: self.keys.iter().map(Key::jwk).collect(),
+        }
+    }
+
+    /// Checks whether the current key is older than `rotation_interval`.
+    pub fn rotation_due(&self, rotation_interval: Duration) -> bool {
+        self.current().created_at + rotation_interval <= OffsetDateTime::now_utc()
+    }
+
+    /// Makes `key` the one new tokens are signed with, saving it first.
+    pub fn add(&mut self, key: Key) -> anyhow::Result<()> {
+        key.key
+            .write_pkcs8_pem_file(key.path(), LineEnding::LF)
+            .context("Failed to save signing key")?;
+        info!(key_id = key.id(), "Added signing key");
+        self.keys.push(key);
+        Ok(())
+    }
+
+    /// Removes the keys that were replaced long enough ago for their tokens to have expired.
+    pub fn remove_retired(&mut self) -> anyhow::Result<()> {
+        let now = OffsetDateTime::now_utc();
+        while self.keys.len() > 1 && self.keys[1].created_at + RETIRED_KEY_LIFETIME <= now {
+            let key = self.keys.remove(0);
+            fs::remove_file(key.path()).context("Failed to remove retired signing key")?;
+            info!(key_id = key.id(), "Removed retired signing key");
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,12 @@
 mod config;
+mod group;
+mod keys;
 mod server;
+mod session;
 mod state;
+mod store;
+mod throttle;
+mod totp;
 mod user;
 
 use server_common::prelude::*;
@@ -1,37 +1,82 @@
-use std::net::SocketAddr;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
 
-use axum::extract::{ConnectInfo, Path, State};
-use axum::http::header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE};
-use axum::http::{Method, StatusCode};
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER, USER_AGENT};
+use axum::http::{HeaderMap, Method, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{delete, get, post, put};
 use axum::{Json, Router};
-use jsonwebtoken::EncodingKey;
-use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+use jsonwebtoken::jwk::JwkSet;
+use rsa::pkcs8::{DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::traits::PublicKeyParts;
 use rsa::{RsaPrivateKey, RsaPublicKey};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use time::Duration;
-use tower_http::cors::{Any, CorsLayer};
-use tracing::{error, info};
+use time::OffsetDateTime;
+use tower_http::cors::CorsLayer;
+use tracing::{error, info, warn};
 use zxcvbn::{zxcvbn, ZxcvbnError};
 
-use crate::state::AppState;
-use crate::user::UserRecord;
-use server_common::auth::{Claims, ADMIN_ROLE, VIEWER_ROLE, UPLOADER_ROLE, SHARER_ROLE};
-use server_common::user::{Role, Username};
-use server_common::{unwrap_result_and_500_on_error, ORIGIN};
+use crate::keys::Key;
+use crate::session::{Refresh, Session};
+use crate::state::{self, AppState, TwoFactorError, CLIENT};
+use crate::totp;
+use crate::user::{self, EncryptionKeys, UserRecord};
+use server_common::auth::{Claims, ADMIN_ROLE, USERS_MANAGE_PERMISSION};
+use server_common::signature::SIGNATURE_HEADERS;
+use server_common::user::{GroupName, Permission, Role, Username};
+use server_common::util::service_url;
+use server_common::{allowed_origin, unwrap_result_and_500_on_error};
 
-const AUTH_TOKEN_DURATION: Duration = Duration::hours(1);
+// Longest user agent kept with a session, so that clients can't fill the database.
+const MAX_USER_AGENT_LENGTH: usize = 256;
 const KEY_SIZE: usize = 2048;
+// Smallest end-to-end encryption key accepted from clients, in bits.
+const MIN_ENCRYPTION_KEY_SIZE: usize = 2048;
+// Generous for a password-encrypted PKCS#8 key, but keeps the database from being filled.
+const MAX_ENCRYPTED_PRIVATE_KEY_SIZE: usize = 16 * 1024;
+const DEFAULT_USERS_PER_PAGE: usize = 50;
+const MAX_USERS_PER_PAGE: usize = 200;
 
 pub fn get_router() -> Router<AppState> {
     Router::new()
         .route("/config", get(config))
-        .route("/db", get(db))
+        .route("/users", get(list_users))
+        .route("/.well-known/jwks.json", get(key_set))
+        .route("/revoked-tokens", get(revoked_tokens))
+        .route("/lockouts", get(lockouts))
+        .route("/groups", get(list_groups))
+        .route("/groups", post(create_group))
+        .route("/groups/:group", delete(delete_group))
+        .route("/groups/:group/exists", get(group_exists))
+        .route("/groups/:group/members/:user", put(add_group_member))
+        .route("/groups/:group/members/:user", delete(remove_group_member))
         .route("/user/login", post(login))
+        .route("/user/login/two-factor", post(login_two_factor))
         .route("/user/register", post(register))
-        .route("/user/:user/is/:role", get(user_in_role))
+        .route("/user/password", post(change_password))
+        .route("/user/password/reset", post(reset_password))
+        .route("/user/refresh", post(refresh))
+        .route("/user/logout", post(logout))
+        .route("/user/sessions", get(sessions))
+        .route("/user/sessions/:id", delete(delete_session))
+        .route("/user/two-factor", get(two_factor_status))
+        .route("/user/two-factor", post(start_two_factor_enrollment))
+        .route("/user/two-factor", put(enable_two_factor))
+        .route("/user/two-factor", delete(disable_two_factor))
+        .route("/user/keys", get(get_encryption_keys))
+        .route("/user/keys", put(set_encryption_keys))
+        .route("/user/groups", get(own_groups))
+        .route("/user/:user", delete(delete_user))
+        .route("/user/:user/password-reset", post(start_password_reset))
+        .route("/user/:user/disabled", put(disable_user))
+        .route("/user/:user/disabled", delete(enable_user))
+        .route("/user/:user/sessions", delete(end_user_sessions))
+        .route("/user/:user/public-key", get(public_key))
+        .route("/user/:user/can/:permission", get(user_has_permission))
+        .route("/user/:user/groups", get(user_groups))
+        .route("/user/:user/exists", get(user_exists))
         .route("/user/:user/is/:role", put(add_role_to_user))
         .route("/user/:user/is/:role", delete(remove_role_from_user))
         .layer(
@@ -43,17 +88,116 @@ pub fn get_router() -> Router<AppState> {
                     Method::DELETE,
                     Method::OPTIONS,
                 ])
-                .allow_headers([ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE])
-                .allow_origin(ORIGIN),
+                .allow_headers(
+                    [ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE]
+                        .into_iter()
+                        .chain(SIGNATURE_HEADERS)
+                        .collect::<Vec<_>>(),
+                )
+                .allow_origin(allowed_origin()),
         )
 }
 
-async fn config(State(state): State<AppState>) -> String {
-    format!("{:#?}", state.read().expect("poisoned lock"))
+/// Checks that the signed in user can manage users, returning the response rejecting the request
+/// if they can't.
+fn check_admin(state: &state::State, claims: &Claims) -> Option<Response> {
+    match state.db.get_user(claims.username()) {
+        Ok(Some(user))
+            if state
+                .config
+                .user_has_permission(&user, &USERS_MANAGE_PERMISSION) =>
+        {
+            None
+        }
+        Ok(Some(_)) => Some(StatusCode::UNAUTHORIZED.into_response()),
+        Ok(None) => Some(StatusCode::BAD_REQUEST.into_response()),
+        Err(err) => {
+            error!(?err, "failed to read database");
+            Some(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+/// Gets the authenticator settings, for admins.
+#[tracing::instrument(skip(state), ret)]
+async fn config(State(state): State<AppState>, claims: Claims) -> Response {
+    let state = state.read().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+
+    Json(&state.config).into_response()
+}
+
+/// A user as listed to admins.
+#[derive(Serialize)]
+struct UserSummary<'a> {
+    username: &'a Username,
+    roles: &'a HashSet<Role>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    created_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_login_at: Option<OffsetDateTime>,
+    disabled: bool,
+    two_factor_enabled: bool,
+}
+
+impl<'a> From<&'a UserRecord> for UserSummary<'a> {
+    fn from(user: &'a UserRecord) -> Self {
+        Self {
+            username: user.name(),
+            roles: user.roles(),
+            created_at: user.created_at(),
+            last_login_at: user.last_login_at(),
+            disabled: user.is_disabled(),
+            two_factor_enabled: user.two_factor_enabled(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UsersQuery {
+    /// Starts at 1.
+    page: Option<usize>,
+    per_page: Option<usize>,
 }
 
-async fn db(State(state): State<AppState>) -> String {
-    format!("{:#?}", state.read().expect("poisoned lock").db)
+/// Lists users by name a page at a time, for admins.
+#[tracing::instrument(skip(state), ret)]
+async fn list_users(
+    State(state): State<AppState>,
+    claims: Claims,
+    Query(query): Query<UsersQuery>,
+) -> Response {
+    let state = state.read().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+
+    let page = query.page.unwrap_or(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_USERS_PER_PAGE);
+    if page == 0 || per_page == 0 || per_page > MAX_USERS_PER_PAGE {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "invalid page"})),
+        )
+            .into_response();
+    }
+
+    let users = unwrap_result_and_500_on_error!(
+        state
+            .db
+            .users_page((page - 1).saturating_mul(per_page), per_page),
+        "failed to read database"
+    );
+    let total = unwrap_result_and_500_on_error!(state.db.user_count(), "failed to read database");
+    Json(json!({
+        "users": users.iter().map(UserSummary::from).collect::<Vec<_>>(),
+        "page": page,
+        "per_page": per_page,
+        "total": total,
+    }))
+    .into_response()
 }
 
 #[derive(Deserialize)]
@@ -62,65 +206,261 @@ struct LoginRequest {
     password: String,
 }
 
-#[tracing::instrument(skip(state, request), ret)]
-async fn login(State(state): State<AppState>, Json(request): Json<LoginRequest>) -> Response {
-    let state = state.read().expect("poisoned lock");
-    let user = match state
+#[tracing::instrument(skip(state, headers, request), ret)]
+async fn login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<LoginRequest>,
+) -> Response {
+    if let Some(response) = start_login(
+        &mut state.write().expect("poisoned lock"),
+        &request.username,
+        addr.ip(),
+    ) {
+        return response;
+    }
+
+    let user = state
+        .read()
+        .expect("poisoned lock")
         .db
-        .get_user_from_credentials(&request.username, &request.password)
-    {
-        Some(user) => user,
-        None => {
+        .get_user_from_credentials(&request.username, &request.password);
+    let mut state_guard = state.write().expect("poisoned lock");
+    let user = match user {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            record_login_failure(&mut state_guard, &request.username, addr.ip());
             return (
                 StatusCode::BAD_REQUEST,
                 Json(json!({"error": "invalid credentials"})),
             )
-                .into_response()
+                .into_response();
+        }
+        Err(err) => {
+            state_guard
+                .login_throttle
+                .abandon(&request.username, addr.ip());
+            error!(?err, "failed to read database");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
     };
 
-    create_jwt_response(user.name().clone(), &state.signing_key.jwt_key)
+    if user.is_disabled() {
+        state_guard.login_throttle.abandon(user.name(), addr.ip());
+        info!(username = %user.name(), "Refused login to disabled account");
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "account disabled"})),
+        )
+            .into_response();
+    }
+
+    if user.two_factor_enabled() {
+        // Failures aren't forgotten until the second factor is given too
+        state_guard.login_throttle.abandon(user.name(), addr.ip());
+        let challenge = state_guard.login_challenges.create(user.name().clone());
+        return Json(json!({"two_factor_required": true, "challenge": challenge})).into_response();
+    }
+
+    state_guard
+        .login_throttle
+        .record_success(user.name(), addr.ip());
+    let enrollment_required = state_guard.config.two_factor_required(&user);
+    drop(state_guard);
+    create_session_response(&state, user.name().clone(), enrollment_required, &headers)
 }
 
-#[tracing::instrument(skip(state, request), ret)]
-async fn register(State(state): State<AppState>, Json(request): Json<LoginRequest>) -> Response {
-    let roles = {
-        let state = state.read().expect("poisoned lock");
-        let mut roles = state.config.default_roles().to_owned();
+#[derive(Deserialize)]
+struct TwoFactorLoginRequest {
+    challenge: String,
+    code: String,
+}
 
-        // Make the first account into an admin account and make the account suitable for all roles.
-        if state.db.len() == 0 {
-            roles.insert(ADMIN_ROLE.clone());
-            roles.insert(VIEWER_ROLE.clone());
-            roles.insert(UPLOADER_ROLE.clone());
-            roles.insert(SHARER_ROLE.clone());
-        }
+/// Finishes a login that was given the right password with a code from the user's authenticator
+/// app or one of their recovery codes.
+#[tracing::instrument(skip(state, headers, request), ret)]
+async fn login_two_factor(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<TwoFactorLoginRequest>,
+) -> Response {
+    let invalid_challenge = || {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "invalid or expired login"})),
+        )
+            .into_response()
+    };
 
-        roles
+    let mut state_guard = state.write().expect("poisoned lock");
+    let Some(username) = state_guard
+        .login_challenges
+        .username(&request.challenge)
+        .cloned()
+    else {
+        return invalid_challenge();
     };
+    // The user may have been disabled since giving their password
+    if unwrap_result_and_500_on_error!(
+        state_guard.db.get_user(&username),
+        "failed to read database"
+    )
+    .is_some_and(|user| user.is_disabled())
+    {
+        state_guard.login_challenges.complete(&request.challenge);
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "account disabled"})),
+        )
+            .into_response();
+    }
+    if let Some(response) = start_login(&mut state_guard, &username, addr.ip()) {
+        return response;
+    }
 
-    match zxcvbn(&request.password, &[request.username.as_ref()]) {
-        Ok(entropy) => {
-            if entropy.score() < 3 {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({"error": "password too weak"})),
-                )
-                    .into_response();
-            }
+    match state_guard.db.verify_two_factor(&username, &request.code) {
+        Ok(()) => {
+            state_guard.login_challenges.complete(&request.challenge);
+            state_guard
+                .login_throttle
+                .record_success(&username, addr.ip());
+        }
+        Err(TwoFactorError::Store(err)) => {
+            state_guard.login_throttle.abandon(&username, addr.ip());
+            error!(?err, "failed to save database");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
-        Err(ZxcvbnError::BlankPassword) => {
+        Err(TwoFactorError::InvalidCode) => {
+            warn!(%username, "Invalid two-factor code");
+            state_guard
+                .login_challenges
+                .record_failure(&request.challenge);
+            record_login_failure(&mut state_guard, &username, addr.ip());
             return (
                 StatusCode::BAD_REQUEST,
-                Json(json!({"error": "blank password"})),
+                Json(json!({"error": "invalid code"})),
+            )
+                .into_response();
+        }
+        // The user was deleted or disabled two-factor authentication since giving their password.
+        Err(_) => {
+            state_guard.login_throttle.abandon(&username, addr.ip());
+            state_guard.login_challenges.complete(&request.challenge);
+            return invalid_challenge();
+        }
+    }
+    drop(state_guard);
+
+    create_session_response(&state, username, false, &headers)
+}
+
+/// Checks the password of a signed in user who is confirming a change to their account, which is
+/// throttled like logins. Returns the response rejecting the change if it's wrong.
+fn confirm_password(
+    state: &AppState,
+    username: &Username,
+    password: &str,
+    address: IpAddr,
+) -> Option<Response> {
+    if let Some(response) = start_login(
+        &mut state.write().expect("poisoned lock"),
+        username,
+        address,
+    ) {
+        return Some(response);
+    }
+
+    let user = state
+        .read()
+        .expect("poisoned lock")
+        .db
+        .get_user_from_credentials(username, password);
+    let mut state = state.write().expect("poisoned lock");
+    match user {
+        Ok(Some(_)) => {
+            state.login_throttle.record_success(username, address);
+            None
+        }
+        Ok(None) => {
+            record_login_failure(&mut state, username, address);
+            Some(
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "invalid credentials"})),
+                )
+                    .into_response(),
             )
-                .into_response()
         }
         Err(err) => {
-            error!(?err, "Error evaluating password");
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            state.login_throttle.abandon(username, address);
+            error!(?err, "failed to read database");
+            Some(StatusCode::INTERNAL_SERVER_ERROR.into_response())
         }
     }
+}
+
+/// Starts a login to `username`, unless too many logins to it or from `address` failed recently,
+/// in which case it returns the response telling the client how long to wait.
+fn start_login(state: &mut state::State, username: &Username, address: IpAddr) -> Option<Response> {
+    let retry_after = state.login_throttle.start(username, address).err()?;
+    warn!(%username, %address, ?retry_after, "Throttled login");
+
+    // Rounded up, so that retrying after that long works
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    Some(
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, seconds.to_string())],
+            Json(json!({
+                "error": format!("too many failed logins, try again in {} seconds", seconds)
+            })),
+        )
+            .into_response(),
+    )
+}
+
+/// Finishes a login that failed, recording the lockout if that locked the account.
+fn record_login_failure(state: &mut state::State, username: &Username, address: IpAddr) {
+    let lockout = state.login_throttle.record_failure(
+        username,
+        address,
+        state.config.max_login_failures(),
+        state.config.lockout_duration(),
+    );
+    if let Some(lockout) = lockout {
+        warn!(%username, %address, "Locked account after too many failed logins");
+        if let Err(err) = state.db.record_lockout(&lockout) {
+            error!(?err, "failed to save database");
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, headers, request), ret)]
+async fn register(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<LoginRequest>,
+) -> Response {
+    let roles = {
+        let state = state.read().expect("poisoned lock");
+        let mut roles = state.config.default_roles().to_owned();
+
+        // Make the first account into an admin account, whose role allows everything it's
+        // configured to.
+        let user_count =
+            unwrap_result_and_500_on_error!(state.db.user_count(), "failed to read database");
+        if user_count == 0 {
+            roles.insert(ADMIN_ROLE.clone());
+        }
+
+        roles
+    };
+
+    if let Some(response) = check_password_strength(&request.username, &request.password) {
+        return response;
+    }
 
     let user = unwrap_result_and_500_on_error!(
         UserRecord::new(request.username, request.password, roles),
@@ -128,9 +468,14 @@ async fn register(State(state): State<AppState>, Json(request): Json<LoginReques
     );
 
     let username = user.name().clone();
-    let mut state = state.write().expect("poisoned lock");
-    match state.db.add_user(user) {
-        Ok(true) => create_jwt_response(username, &state.signing_key.jwt_key),
+    let enrollment_required = state
+        .read()
+        .expect("poisoned lock")
+        .config
+        .two_factor_required(&user);
+    let added = state.write().expect("poisoned lock").db.add_user(user);
+    match added {
+        Ok(true) => create_session_response(&state, username, enrollment_required, &headers),
         Ok(false) => (
             StatusCode::CONFLICT,
             Json(json!({"error": "username already taken"})),
@@ -143,7 +488,47 @@ async fn register(State(state): State<AppState>, Json(request): Json<LoginReques
     }
 }
 
-fn create_jwt_response(username: Username, key: &EncodingKey) -> Response {
+/// Checks a new password against the password policy, returning the response rejecting it if it's
+/// too weak.
+fn check_password_strength(username: &Username, password: &str) -> Option<Response> {
+    match zxcvbn(password, &[username.as_ref()]) {
+        Ok(entropy) => {
+            if entropy.score() < 3 {
+                return Some(
+                    (
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({"error": "password too weak"})),
+                    )
+                        .into_response(),
+                );
+            }
+            None
+        }
+        Err(ZxcvbnError::BlankPassword) => Some(
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "blank password"})),
+            )
+                .into_response(),
+        ),
+        Err(err) => {
+            error!(?err, "Error evaluating password");
+            Some(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+/// Starts a session for a user who just logged in, handing out the key pair to sign their requests
+/// with along with the session's tokens.
+///
+/// `two_factor_enrollment_required` tells the client that the user has to set up two-factor
+/// authentication before they can act as an admin.
+fn create_session_response(
+    state: &AppState,
+    username: Username,
+    two_factor_enrollment_required: bool,
+    headers: &HeaderMap,
+) -> Response {
     let mut rng = rand::thread_rng();
     let private_key = unwrap_result_and_500_on_error!(
         RsaPrivateKey::new(&mut rng, KEY_SIZE),
@@ -160,17 +545,133 @@ fn create_jwt_response(username: Username, key: &EncodingKey) -> Response {
         "failed to convert key to PEM"
     );
 
-    let claims = Claims::create(username, AUTH_TOKEN_DURATION, public_key_pem);
-    let jwt = unwrap_result_and_500_on_error!(claims.encode(key), "failed to encode JWT");
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect());
+
+    let mut state = state.write().expect("poisoned lock");
+    let (session, refresh_token, claims) = unwrap_result_and_500_on_error!(
+        state
+            .db
+            .create_session(username, public_key_pem, user_agent),
+        "failed to save database"
+    );
+    info!(username = %session.username(), session = session.id(), "Started session");
+
+    let Some(mut body) =
+        create_jwt_response(&session, &claims, refresh_token, state.keys.current())
+    else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    body["private_key"] = json!(*private_key_pem);
+    body["two_factor_enrollment_required"] = json!(two_factor_enrollment_required);
+    Json(body).into_response()
+}
+
+/// Creates the body of a response handing out a new JWT for a session and its refresh token.
+fn create_jwt_response(
+    session: &Session,
+    claims: &Claims,
+    refresh_token: String,
+    key: &Key,
+) -> Option<serde_json::Value> {
+    match claims.encode(key.id(), key.jwt_key()) {
+        Ok(jwt) => Some(json!({
+            "token": jwt,
+            "refresh_token": refresh_token,
+            "session_id": session.id(),
+        })),
+        Err(err) => {
+            error!(?err, "failed to encode JWT");
+            None
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// Exchanges a refresh token for a new JWT and refresh token. Presenting a refresh token that was
+/// already exchanged ends the session, since either it or its replacement must have been stolen.
+#[tracing::instrument(skip(state, request), ret)]
+async fn refresh(State(state): State<AppState>, Json(request): Json<RefreshRequest>) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+    let refreshed = state.db.refresh_session(&request.refresh_token);
+    let (session, refresh_token, claims) = match refreshed {
+        Ok(Some((
+            session,
+            Refresh::Rotated {
+                refresh_token,
+                claims,
+            },
+        ))) => (session, refresh_token, claims),
+        Ok(Some((session, Refresh::Reused))) => {
+            warn!(
+                username = %session.username(),
+                session = session.id(),
+                "Refresh token reused, ended session"
+            );
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "session ended"})),
+            )
+                .into_response();
+        }
+        Ok(Some((_, Refresh::Expired))) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "session expired"})),
+            )
+                .into_response()
+        }
+        Ok(None | Some((_, Refresh::Invalid))) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "invalid refresh token"})),
+            )
+                .into_response()
+        }
+        Err(err) => {
+            error!(?err, "Failed to save database");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
 
-    Json(json!({"token": jwt, "private_key": *private_key_pem })).into_response()
+    match create_jwt_response(&session, &claims, refresh_token, state.keys.current()) {
+        Some(body) => Json(body).into_response(),
+        None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
 }
 
+/// Ends the caller's session and revokes its tokens, so that they can't be used anywhere anymore.
 #[tracing::instrument(skip(state), ret)]
-async fn user_in_role(
+async fn logout(State(state): State<AppState>, claims: Claims) -> StatusCode {
+    match state.write().expect("poisoned lock").db.logout(&claims) {
+        Ok(()) => {
+            info!(username = %claims.username(), "Logged out");
+            StatusCode::OK
+        }
+        Err(err) => {
+            error!(?err, "Failed to save database");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Publishes the keys that tokens are signed with, including the ones recently replaced.
+#[tracing::instrument(skip(state))]
+async fn key_set(State(state): State<AppState>) -> Json<JwkSet> {
+    Json(state.read().expect("poisoned lock").keys.key_set())
+}
+
+/// Lists the tokens that were revoked before they expired, for services to turn them away.
+#[tracing::instrument(skip(state))]
+async fn revoked_tokens(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Path((username, role)): Path<(Username, Role)>,
 ) -> Response {
     let state = state.read().expect("poisoned lock");
 
@@ -178,42 +679,71 @@ async fn user_in_role(
         return StatusCode::FORBIDDEN.into_response();
     }
 
-    match state.db.get_user(&username) {
-        Some(user) => Json(user.roles().contains(role.as_ref())).into_response(),
-        None => StatusCode::NOT_FOUND.into_response(),
+    Json(unwrap_result_and_500_on_error!(
+        state.db.revoked_tokens(),
+        "failed to read database"
+    ))
+    .into_response()
+}
+
+#[derive(Serialize)]
+struct SessionSummary<'a> {
+    id: &'a str,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    refreshed_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+    user_agent: Option<&'a str>,
+}
+
+impl<'a> From<&'a Session> for SessionSummary<'a> {
+    fn from(session: &'a Session) -> Self {
+        Self {
+            id: session.id(),
+            created_at: session.created_at(),
+            refreshed_at: session.refreshed_at(),
+            expires_at: session.expires_at(),
+            user_agent: session.user_agent(),
+        }
     }
 }
 
+/// Lists the caller's sessions, so that they can end the ones they don't recognise.
 #[tracing::instrument(skip(state), ret)]
-async fn add_role_to_user(
+async fn sessions(State(state): State<AppState>, claims: Claims) -> Response {
+    let sessions = unwrap_result_and_500_on_error!(
+        state
+            .read()
+            .expect("poisoned lock")
+            .db
+            .sessions_of_user(claims.username()),
+        "failed to read database"
+    );
+    Json(
+        sessions
+            .iter()
+            .map(SessionSummary::from)
+            .collect::<Vec<_>>(),
+    )
+    .into_response()
+}
+
+#[tracing::instrument(skip(state), ret)]
+async fn delete_session(
     State(state): State<AppState>,
     claims: Claims,
-    Path((username, role)): Path<(Username, Role)>,
+    Path(id): Path<String>,
 ) -> StatusCode {
-    {
-        let state = state.read().expect("poisoned lock");
-        match state.db.get_user(&claims.username()) {
-            Some(user) => {
-                if !user.roles().contains(ADMIN_ROLE.as_ref()) {
-                    return StatusCode::UNAUTHORIZED;
-                }
-            }
-            None => return StatusCode::BAD_REQUEST,
-        };
-
-        if !state.config.role_is_allowed(&role) {
-            return StatusCode::BAD_REQUEST;
-        }
-    }
-
     match state
         .write()
         .expect("poisoned lock")
         .db
-        .add_role_to_user(&username, role.clone())
+        .delete_session(claims.username(), &id)
     {
         Ok(true) => {
-            info!(%username, %role, "Added role to user");
+            info!(username = %claims.username(), session = id, "Ended session");
             StatusCode::OK
         }
         Ok(false) => StatusCode::NOT_FOUND,
@@ -224,40 +754,942 @@ async fn add_role_to_user(
     }
 }
 
+/// Gets the caller's end-to-end encryption keys, so that the client can recover its private key.
 #[tracing::instrument(skip(state), ret)]
-async fn remove_role_from_user(
+async fn get_encryption_keys(State(state): State<AppState>, claims: Claims) -> Response {
+    let user = unwrap_result_and_500_on_error!(
+        state
+            .read()
+            .expect("poisoned lock")
+            .db
+            .get_user(claims.username()),
+        "failed to read database"
+    );
+    match user.as_ref().map(|user| user.encryption_keys()) {
+        Some(Some(keys)) => Json(keys).into_response(),
+        Some(None) => StatusCode::NOT_FOUND.into_response(),
+        None => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
+
+#[tracing::instrument(skip(state, keys), ret)]
+async fn set_encryption_keys(
     State(state): State<AppState>,
     claims: Claims,
-    Path((username, role)): Path<(Username, Role)>,
-) -> StatusCode {
-    match state
-        .read()
-        .expect("poisoned lock")
-        .db
-        .get_user(&claims.username())
-    {
-        Some(user) => {
-            if !(user.roles().contains(ADMIN_ROLE.as_ref()) || user.name() == &username) {
-                return StatusCode::UNAUTHORIZED;
-            }
+    Json(keys): Json<EncryptionKeys>,
+) -> Response {
+    match RsaPublicKey::from_public_key_pem(&keys.public_key) {
+        Ok(public_key) if public_key.size() * 8 >= MIN_ENCRYPTION_KEY_SIZE => {}
+        Ok(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "public key too small"})),
+            )
+                .into_response()
         }
-        None => return StatusCode::BAD_REQUEST,
-    };
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "invalid public key"})),
+            )
+                .into_response()
+        }
+    }
+    if keys.encrypted_private_key.len() > MAX_ENCRYPTED_PRIVATE_KEY_SIZE {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "encrypted private key too large"})),
+        )
+            .into_response();
+    }
 
     match state
         .write()
         .expect("poisoned lock")
         .db
-        .remove_role_from_user(&username, &role)
+        .set_encryption_keys(claims.username(), keys)
     {
-        Ok(true) => {
-            info!(%username, %role, "Removed role from user");
-            StatusCode::OK
+        Ok(Some(true)) => {
+            info!(username = %claims.username(), "Set encryption keys of user");
+            StatusCode::OK.into_response()
         }
-        Ok(false) => StatusCode::NOT_FOUND,
+        Ok(Some(false)) => (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "encryption keys already set"})),
+        )
+            .into_response(),
+        Ok(None) => StatusCode::BAD_REQUEST.into_response(),
         Err(err) => {
             error!(?err, "Failed to save database");
-            StatusCode::INTERNAL_SERVER_ERROR
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
+
+#[tracing::instrument(skip(state), ret)]
+async fn two_factor_status(State(state): State<AppState>, claims: Claims) -> Response {
+    let state = state.read().expect("poisoned lock");
+    match unwrap_result_and_500_on_error!(
+        state.db.get_user(claims.username()),
+        "failed to read database"
+    ) {
+        Some(user) => Json(json!({
+            "enabled": user.two_factor_enabled(),
+            "required": state.config.two_factor_required(&user),
+            "recovery_codes_left": user.two_factor().map(|two_factor| two_factor.recovery_codes_left()),
+        }))
+        .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Generates a secret for the user to set up their authenticator app with. Nothing is saved until
+/// they confirm it with a code, so abandoning the setup leaves the account as it was.
+#[tracing::instrument(skip(state), ret)]
+async fn start_two_factor_enrollment(State(state): State<AppState>, claims: Claims) -> Response {
+    let user = unwrap_result_and_500_on_error!(
+        state
+            .read()
+            .expect("poisoned lock")
+            .db
+            .get_user(claims.username()),
+        "failed to read database"
+    );
+    match user {
+        Some(user) if user.two_factor_enabled() => (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "two-factor authentication is already enabled"})),
+        )
+            .into_response(),
+        Some(user) => {
+            let secret = totp::generate_secret();
+            Json(json!({
+                "provisioning_uri": totp::provisioning_uri(user.name(), &secret),
+                "secret": secret,
+            }))
+            .into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct EnableTwoFactorRequest {
+    secret: String,
+    code: String,
+}
+
+#[tracing::instrument(skip(state, request), ret)]
+async fn enable_two_factor(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(request): Json<EnableTwoFactorRequest>,
+) -> Response {
+    let enabled = state.write().expect("poisoned lock").db.enable_two_factor(
+        claims.username(),
+        request.secret,
+        &request.code,
+    );
+    match enabled {
+        Ok(recovery_codes) => {
+            info!(username = %claims.username(), "Enabled two-factor authentication");
+            Json(json!({"recovery_codes": recovery_codes})).into_response()
+        }
+        Err(err) => two_factor_error_response(err),
+    }
+}
+
+#[derive(Deserialize)]
+struct DisableTwoFactorRequest {
+    code: String,
+}
+
+/// Disables two-factor authentication, which needs a current code so that a stolen session can't
+/// be used to remove it.
+#[tracing::instrument(skip(state, request), ret)]
+async fn disable_two_factor(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(request): Json<DisableTwoFactorRequest>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+    let user = unwrap_result_and_500_on_error!(
+        state.db.get_user(claims.username()),
+        "failed to read database"
+    );
+    match user {
+        Some(user) if state.config.two_factor_required(&user) => {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({"error": "two-factor authentication is required for admins"})),
+            )
+                .into_response()
+        }
+        Some(_) => {}
+        None => return StatusCode::NOT_FOUND.into_response(),
+    }
+
+    match state
+        .db
+        .disable_two_factor(claims.username(), &request.code)
+    {
+        Ok(()) => {
+            info!(username = %claims.username(), "Disabled two-factor authentication");
+            StatusCode::OK.into_response()
+        }
+        Err(err) => two_factor_error_response(err),
+    }
+}
+
+fn two_factor_error_response(err: TwoFactorError) -> Response {
+    match err {
+        TwoFactorError::NotFound => StatusCode::NOT_FOUND.into_response(),
+        TwoFactorError::AlreadyEnabled | TwoFactorError::NotEnabled => (
+            StatusCode::CONFLICT,
+            Json(json!({"error": err.to_string()})),
+        )
+            .into_response(),
+        TwoFactorError::InvalidCode => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": err.to_string()})),
+        )
+            .into_response(),
+        TwoFactorError::Store(err) => {
+            error!(?err, "failed to save database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Lists the accounts that were recently locked after too many failed logins, for admins.
+#[tracing::instrument(skip(state), ret)]
+async fn lockouts(State(state): State<AppState>, claims: Claims) -> Response {
+    let state = state.read().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+
+    Json(unwrap_result_and_500_on_error!(
+        state.db.lockouts(),
+        "failed to read database"
+    ))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+    /// The user's private key encrypted with the new password, which is needed if they have
+    /// encryption keys.
+    #[serde(default)]
+    encrypted_private_key: Option<String>,
+}
+
+/// Changes the password of the signed in user, ending their other sessions.
+#[tracing::instrument(skip(state, request), ret)]
+async fn change_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    claims: Claims,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Response {
+    let username = claims.username();
+    if let Some(response) = check_password_strength(username, &request.new_password) {
+        return response;
+    }
+    if request
+        .encrypted_private_key
+        .as_ref()
+        .is_some_and(|key| key.len() > MAX_ENCRYPTED_PRIVATE_KEY_SIZE)
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "encrypted private key too large"})),
+        )
+            .into_response();
+    }
+    if let Some(response) = confirm_password(&state, username, &request.current_password, addr.ip())
+    {
+        return response;
+    }
+
+    let user = unwrap_result_and_500_on_error!(
+        state.read().expect("poisoned lock").db.get_user(username),
+        "failed to read database"
+    );
+    let has_encryption_keys = user.is_some_and(|user| user.encryption_keys().is_some());
+    if has_encryption_keys && request.encrypted_private_key.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "private key must be encrypted with the new password"})),
+        )
+            .into_response();
+    }
+
+    let password_hash = unwrap_result_and_500_on_error!(
+        user::hash_password(&request.new_password),
+        "failed to hash password"
+    );
+
+    let mut state = state.write().expect("poisoned lock");
+    match state
+        .db
+        .change_password(username, password_hash, request.encrypted_private_key)
+    {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "failed to save database");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    // Whoever knew the old password may have logged in with it
+    unwrap_result_and_500_on_error!(
+        state.db.end_sessions(username, Some(&claims)),
+        "failed to save database"
+    );
+    info!(%username, "Changed password");
+    StatusCode::OK.into_response()
+}
+
+#[derive(Serialize)]
+struct PasswordResetResponse {
+    reset_token: String,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+}
+
+/// Issues a single-use token for a user who forgot their password to set a new one with, which
+/// only admins can do.
+#[tracing::instrument(skip(state), ret)]
+async fn start_password_reset(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(username): Path<Username>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+
+    match unwrap_result_and_500_on_error!(
+        state.db.start_password_reset(&username),
+        "failed to save database"
+    ) {
+        Some((reset_token, expires_at)) => {
+            info!(admin = %claims.username(), %username, "Issued password reset token");
+            Json(PasswordResetResponse {
+                reset_token,
+                expires_at,
+            })
+            .into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordRequest {
+    username: Username,
+    reset_token: String,
+    new_password: String,
+}
+
+/// Sets a new password with a token issued by an admin. Reset tokens are throttled like logins.
+#[tracing::instrument(skip(state, request), ret)]
+async fn reset_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(request): Json<ResetPasswordRequest>,
+) -> Response {
+    if let Some(response) = check_password_strength(&request.username, &request.new_password) {
+        return response;
+    }
+    let password_hash = unwrap_result_and_500_on_error!(
+        user::hash_password(&request.new_password),
+        "failed to hash password"
+    );
+
+    let mut state = state.write().expect("poisoned lock");
+    if let Some(response) = start_login(&mut state, &request.username, addr.ip()) {
+        return response;
+    }
+    let reset = state
+        .db
+        .reset_password(&request.username, &request.reset_token, password_hash);
+    match reset {
+        Ok(true) => {
+            state
+                .login_throttle
+                .record_success(&request.username, addr.ip());
+            info!(username = %request.username, "Reset password");
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => {
+            record_login_failure(&mut state, &request.username, addr.ip());
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "invalid or expired reset token"})),
+            )
+                .into_response()
+        }
+        Err(err) => {
+            state.login_throttle.abandon(&request.username, addr.ip());
+            error!(?err, "failed to save database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeleteUserRequest {
+    password: String,
+}
+
+/// Deletes an account along with the user's files and share links. Users can delete their own
+/// account by confirming their password, and admins can delete anyone's.
+#[tracing::instrument(skip(state, request), ret)]
+async fn delete_user(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    claims: Claims,
+    Path(username): Path<Username>,
+    request: Option<Json<DeleteUserRequest>>,
+) -> Response {
+    if claims.username() == &username {
+        let Some(Json(request)) = request else {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "password required"})),
+            )
+                .into_response();
+        };
+        if let Some(response) = confirm_password(&state, &username, &request.password, addr.ip()) {
+            return response;
+        }
+    }
+
+    {
+        let state = state.read().expect("poisoned lock");
+        if claims.username() != &username {
+            if let Some(response) = check_admin(&state, &claims) {
+                return response;
+            }
+        }
+
+        let users = unwrap_result_and_500_on_error!(state.db.users(), "failed to read database");
+        let is_admin = |user: &UserRecord| state.config.roles_grant(user, &USERS_MANAGE_PERMISSION);
+        match users.iter().find(|user| user.name() == &username) {
+            Some(user)
+                if is_admin(user) && users.iter().filter(|user| is_admin(user)).count() == 1 =>
+            {
+                return (
+                    StatusCode::CONFLICT,
+                    Json(json!({"error": "the last admin can't be deleted"})),
+                )
+                    .into_response()
+            }
+            Some(_) => {}
+            None => return StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    // The account is only removed once its data is, so that deleting it can be retried if a
+    // service fails, and whoever registers the name next doesn't get the data.
+    if let Err(err) = delete_user_data(&state, &username).await {
+        error!(?err, %username, "Failed to delete data of user");
+        return (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({"error": "failed to delete the user's files and links"})),
+        )
+            .into_response();
+    }
+
+    match state
+        .write()
+        .expect("poisoned lock")
+        .db
+        .delete_user(&username)
+    {
+        Ok(true) => {
+            info!(%username, by = %claims.username(), "Deleted user");
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "failed to save database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Deletes the share links and files of a user from the services that keep them.
+async fn delete_user_data(state: &AppState, username: &Username) -> Result<(), reqwest::Error> {
+    let urls = {
+        let state = state.read().expect("poisoned lock");
+        [
+            service_url(
+                &state.fileshare_server.authority(),
+                &["user-links", username.as_ref()],
+            ),
+            service_url(
+                &state.filestore_server.authority(),
+                &["user-files", username.as_ref()],
+            ),
+        ]
+    };
+
+    let client = CLIENT.get().unwrap();
+    for url in urls {
+        client
+            .delete(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())?;
+    }
+    Ok(())
+}
+
+/// Disables a user's account, which logs them out and keeps them from logging in again until it's
+/// enabled. Only admins can do this, and not to themselves.
+#[tracing::instrument(skip(state), ret)]
+async fn disable_user(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(username): Path<Username>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+    if claims.username() == &username {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "admins can't disable their own account"})),
+        )
+            .into_response();
+    }
+
+    match state.db.set_user_disabled(&username, true) {
+        Ok(true) => {
+            info!(%username, admin = %claims.username(), "Disabled user");
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "failed to save database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Enables a user's account that was disabled, which only admins can do.
+#[tracing::instrument(skip(state), ret)]
+async fn enable_user(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(username): Path<Username>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+
+    match state.db.set_user_disabled(&username, false) {
+        Ok(true) => {
+            info!(%username, admin = %claims.username(), "Enabled user");
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "failed to save database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Logs a user out of every session, which only admins can do.
+#[tracing::instrument(skip(state), ret)]
+async fn end_user_sessions(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(username): Path<Username>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+    if unwrap_result_and_500_on_error!(state.db.get_user(&username), "failed to read database")
+        .is_none()
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    unwrap_result_and_500_on_error!(
+        state.db.end_sessions(&username, None),
+        "failed to save database"
+    );
+    info!(%username, admin = %claims.username(), "Logged user out of every session");
+    StatusCode::OK.into_response()
+}
+
+/// Gets a user's end-to-end encryption public key, so that file keys can be wrapped for them.
+#[tracing::instrument(skip(state), ret)]
+async fn public_key(
+    State(state): State<AppState>,
+    _claims: Claims,
+    Path(username): Path<Username>,
+) -> Response {
+    let user = unwrap_result_and_500_on_error!(
+        state.read().expect("poisoned lock").db.get_user(&username),
+        "failed to read database"
+    );
+    match user.as_ref().and_then(|user| user.encryption_keys()) {
+        Some(keys) => Json(json!({"public_key": keys.public_key})).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Tells a service whether a user may do something, which depends on the permissions of their
+/// roles.
+#[tracing::instrument(skip(state), ret)]
+async fn user_has_permission(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((username, permission)): Path<(Username, Permission)>,
+) -> Response {
+    let state = state.read().expect("poisoned lock");
+
+    if !state.config.address_is_service(&addr.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    match unwrap_result_and_500_on_error!(state.db.get_user(&username), "failed to read database") {
+        Some(user) => Json(state.config.user_has_permission(&user, &permission)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[tracing::instrument(skip(state), ret)]
+async fn add_role_to_user(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((username, role)): Path<(Username, Role)>,
+) -> StatusCode {
+    {
+        let state = state.read().expect("poisoned lock");
+        match state.db.get_user(claims.username()) {
+            Ok(Some(user)) => {
+                if !state
+                    .config
+                    .user_has_permission(&user, &USERS_MANAGE_PERMISSION)
+                {
+                    return StatusCode::UNAUTHORIZED;
+                }
+            }
+            Ok(None) => return StatusCode::BAD_REQUEST,
+            Err(err) => {
+                error!(?err, "Failed to read database");
+                return StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        };
+
+        if !state.config.role_is_allowed(&role) {
+            return StatusCode::BAD_REQUEST;
+        }
+    }
+
+    match state
+        .write()
+        .expect("poisoned lock")
+        .db
+        .add_role_to_user(&username, role.clone())
+    {
+        Ok(true) => {
+            info!(%username, %role, "Added role to user");
+            StatusCode::OK
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(err) => {
+            error!(?err, "Failed to save database");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[tracing::instrument(skip(state), ret)]
+async fn remove_role_from_user(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((username, role)): Path<(Username, Role)>,
+) -> StatusCode {
+    {
+        let state = state.read().expect("poisoned lock");
+        match state.db.get_user(claims.username()) {
+            Ok(Some(user)) => {
+                if !(state
+                    .config
+                    .user_has_permission(&user, &USERS_MANAGE_PERMISSION)
+                    || user.name() == &username)
+                {
+                    return StatusCode::UNAUTHORIZED;
+                }
+            }
+            Ok(None) => return StatusCode::BAD_REQUEST,
+            Err(err) => {
+                error!(?err, "Failed to read database");
+                return StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        };
+    }
+
+    match state
+        .write()
+        .expect("poisoned lock")
+        .db
+        .remove_role_from_user(&username, &role)
+    {
+        Ok(true) => {
+            info!(%username, %role, "Removed role from user");
+            StatusCode::OK
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(err) => {
+            error!(?err, "Failed to save database");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[tracing::instrument(skip(state), ret)]
+async fn list_groups(State(state): State<AppState>, claims: Claims) -> Response {
+    let state = state.read().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+
+    Json(unwrap_result_and_500_on_error!(
+        state.db.groups(),
+        "failed to read database"
+    ))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateGroupRequest {
+    name: GroupName,
+}
+
+#[tracing::instrument(skip(state), ret)]
+async fn create_group(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(request): Json<CreateGroupRequest>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+
+    if unwrap_result_and_500_on_error!(
+        state.db.create_group(&request.name),
+        "failed to save database"
+    ) {
+        info!(group = %request.name, admin = %claims.username(), "Created group");
+        StatusCode::CREATED.into_response()
+    } else {
+        (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "group already exists"})),
+        )
+            .into_response()
+    }
+}
+
+/// Deletes a group, along with the ACL entries naming it, so that a group created later with the
+/// same name doesn't get access to the files shared with this one.
+#[tracing::instrument(skip(state), ret)]
+async fn delete_group(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(name): Path<GroupName>,
+) -> Response {
+    {
+        let state = state.read().expect("poisoned lock");
+        if let Some(response) = check_admin(&state, &claims) {
+            return response;
+        }
+        if !unwrap_result_and_500_on_error!(state.db.group_exists(&name), "failed to read database")
+        {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    }
+
+    // Like accounts, the group is only removed once access through it is, so that deleting it can
+    // be retried if a service fails.
+    if let Err(err) = delete_group_access(&state, &name).await {
+        error!(?err, group = %name, "Failed to remove access given to group");
+        return (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({"error": "failed to remove the access given to the group"})),
+        )
+            .into_response();
+    }
+
+    let mut state = state.write().expect("poisoned lock");
+    if unwrap_result_and_500_on_error!(state.db.delete_group(&name), "failed to save database") {
+        info!(group = %name, admin = %claims.username(), "Deleted group");
+        StatusCode::OK.into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// Removes the access given to a group from the services that keep track of it.
+async fn delete_group_access(state: &AppState, name: &GroupName) -> Result<(), reqwest::Error> {
+    let urls = {
+        let state = state.read().expect("poisoned lock");
+        [
+            service_url(
+                &state.fileshare_server.authority(),
+                &["group-grants", name.as_ref()],
+            ),
+            service_url(
+                &state.filestore_server.authority(),
+                &["group-acl", name.as_ref()],
+            ),
+        ]
+    };
+
+    let client = CLIENT.get().unwrap();
+    for url in urls {
+        client
+            .delete(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())?;
+    }
+    Ok(())
+}
+
+/// Tells a service whether a group exists, before files are shared with it.
+#[tracing::instrument(skip(state), ret)]
+async fn group_exists(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(name): Path<GroupName>,
+) -> Response {
+    let state = state.read().expect("poisoned lock");
+
+    if !state.config.address_is_service(&addr.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    Json(unwrap_result_and_500_on_error!(
+        state.db.group_exists(&name),
+        "failed to read database"
+    ))
+    .into_response()
+}
+
+#[tracing::instrument(skip(state), ret)]
+async fn add_group_member(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((name, username)): Path<(GroupName, Username)>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+
+    match unwrap_result_and_500_on_error!(
+        state.db.add_group_member(&name, &username),
+        "failed to save database"
+    ) {
+        Some(added) => {
+            if added {
+                info!(group = %name, %username, admin = %claims.username(), "Added user to group");
+            }
+            StatusCode::OK.into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[tracing::instrument(skip(state), ret)]
+async fn remove_group_member(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((name, username)): Path<(GroupName, Username)>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+    if let Some(response) = check_admin(&state, &claims) {
+        return response;
+    }
+
+    if unwrap_result_and_500_on_error!(
+        state.db.remove_group_member(&name, &username),
+        "failed to save database"
+    ) {
+        info!(group = %name, %username, admin = %claims.username(), "Removed user from group");
+        StatusCode::OK.into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// Gets the groups the signed in user is a member of, so that they can share files with them.
+#[tracing::instrument(skip(state), ret)]
+async fn own_groups(State(state): State<AppState>, claims: Claims) -> Response {
+    Json(unwrap_result_and_500_on_error!(
+        state
+            .read()
+            .expect("poisoned lock")
+            .db
+            .groups_of_user(claims.username()),
+        "failed to read database"
+    ))
+    .into_response()
+}
+
+/// Tells a service which groups a user is a member of, which files may be shared with.
+#[tracing::instrument(skip(state), ret)]
+async fn user_groups(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<Username>,
+) -> Response {
+    let state = state.read().expect("poisoned lock");
+
+    if !state.config.address_is_service(&addr.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    Json(unwrap_result_and_500_on_error!(
+        state.db.groups_of_user(&username),
+        "failed to read database"
+    ))
+    .into_response()
+}
+
+/// Tells a service whether a user exists, before files are shared with them.
+#[tracing::instrument(skip(state), ret)]
+async fn user_exists(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(username): Path<Username>,
+) -> Response {
+    let state = state.read().expect("poisoned lock");
+
+    if !state.config.address_is_service(&addr.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    Json(
+        unwrap_result_and_500_on_error!(state.db.get_user(&username), "failed to read database")
+            .is_some(),
+    )
+    .into_response()
+}
@@ -0,0 +1,273 @@
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use server_common::auth::Claims;
+use server_common::revocation::TokenId;
+use server_common::user::Username;
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+
+const SESSION_ID_SIZE: usize = 16;
+const REFRESH_SECRET_SIZE: usize = 32;
+// Kept short, since clients refresh JWTs with their session's refresh token.
+pub const AUTH_TOKEN_DURATION: Duration = Duration::minutes(15);
+/// How long a session lasts without being refreshed.
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::days(14);
+// How long a replaced refresh token keeps working, for clients such as two tabs of the dashboard
+// that refresh with the same token at the same time.
+const REFRESH_GRACE_PERIOD: Duration = Duration::seconds(30);
+// Enough for a day of refreshes. Older tokens are only rejected, without ending the session.
+const MAX_REPLACED_REFRESH_TOKENS: usize = 100;
+// Clients refreshing concurrently within the grace period, besides the one that got there first.
+const MAX_CONCURRENT_REFRESH_TOKENS: usize = 4;
+
+/// A login on one client, which lasts for as long as the client keeps refreshing its token.
+///
+/// The public key of the request signing key pair handed out on login is kept, so that refreshed
+/// JWTs keep working with it, along with the IDs of the JWTs that haven't expired so that they can
+/// be revoked when the session ends. Only hashes of refresh tokens are kept. Each refresh replaces
+/// the current token, so an older token being presented after a short grace period means it was
+/// stolen, and the session is ended.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Session {
+    id: String,
+    username: Username,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    refreshed_at: OffsetDateTime,
+    #[serde(default)]
+    user_agent: Option<String>,
+    public_key: String,
+    #[serde(default)]
+    tokens: Vec<TokenId>,
+    refresh_token_hash: String,
+    /// Tokens issued to clients that refreshed with a token that was replaced within the grace
+    /// period, which are as valid as the current one.
+    #[serde(default)]
+    concurrent_refresh_token_hashes: Vec<String>,
+    /// Oldest first.
+    #[serde(default)]
+    replaced_refresh_tokens: Vec<ReplacedRefreshToken>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+struct ReplacedRefreshToken {
+    hash: String,
+    #[serde(with = "time::serde::rfc3339")]
+    replaced_at: OffsetDateTime,
+}
+
+/// What happened when a refresh token was presented.
+#[derive(Debug)]
+pub enum Refresh {
+    /// The token was current, and has been replaced by the returned one. A new JWT was issued
+    /// along with it.
+    Rotated {
+        refresh_token: String,
+        claims: Claims,
+    },
+    /// The token was already replaced, so the session has been ended.
+    Reused,
+    /// The token was never issued for the session. Since session IDs aren't secret, this doesn't
+    /// end the session.
+    Invalid,
+    Expired,
+}
+
+fn random_string(size: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(size)
+        .map(char::from)
+        .collect()
+}
+
+fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret))
+}
+
+/// Splits a refresh token into the ID of its session and its secret.
+pub fn parse_refresh_token(token: &str) -> Option<(&str, &str)> {
+    token
+        .split_once('.')
+        .filter(|(id, secret)| id.len() == SESSION_ID_SIZE && secret.len() == REFRESH_SECRET_SIZE)
+}
+
+impl Session {
+    /// Starts a new session, returning it along with its first refresh token.
+    pub fn new(
+        username: Username,
+        public_key: String,
+        user_agent: Option<String>,
+    ) -> (Self, String) {
+        let now = OffsetDateTime::now_utc();
+        let id = random_string(SESSION_ID_SIZE);
+        let secret = random_string(REFRESH_SECRET_SIZE);
+
+        let session = Self {
+            refresh_token_hash: hash_secret(&secret),
+            concurrent_refresh_token_hashes: Vec::new(),
+            replaced_refresh_tokens: Vec::new(),
+            id: id.clone(),
+            username,
+            created_at: now,
+            refreshed_at: now,
+            user_agent,
+            public_key,
+            tokens: Vec::new(),
+        };
+        (session, format!("{}.{}", id, secret))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn username(&self) -> &Username {
+        &self.username
+    }
+
+    pub fn created_at(&self) -> OffsetDateTime {
+        self.created_at
+    }
+
+    pub fn refreshed_at(&self) -> OffsetDateTime {
+        self.refreshed_at
+    }
+
+    pub fn expires_at(&self) -> OffsetDateTime {
+        self.refreshed_at + SESSION_IDLE_TIMEOUT
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    /// Gets the IDs of the JWTs issued for this session, some of which may have expired.
+    pub fn tokens(&self) -> &[TokenId] {
+        &self.tokens
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at() <= OffsetDateTime::now_utc()
+    }
+
+    /// Issues a new JWT for this session.
+    pub fn issue_claims(&mut self) -> Claims {
+        let claims = Claims::create(
+            self.username.clone(),
+            AUTH_TOKEN_DURATION,
+            self.public_key.clone(),
+        );
+        self.tokens.retain(|token| !token.is_expired());
+        self.tokens.push(claims.token_id());
+        claims
+    }
+
+    /// Checks the secret of a refresh token for this session, replacing it and issuing a new JWT
+    /// if it's current.
+    ///
+    /// A token that was replaced less than `REFRESH_GRACE_PERIOD` ago is also accepted, but the
+    /// tokens that replaced it stay valid, since another client may have stored them.
+    pub fn refresh(&mut self, secret: &str) -> Refresh {
+        if self.is_expired() {
+            return Refresh::Expired;
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let hash = hash_secret(secret);
+        let secret = random_string(REFRESH_SECRET_SIZE);
+        if hash == self.refresh_token_hash || self.concurrent_refresh_token_hashes.contains(&hash) {
+            let replaced = std::mem::replace(&mut self.refresh_token_hash, hash_secret(&secret));
+            self.replaced_refresh_tokens.extend(
+                std::iter::once(replaced)
+                    .chain(self.concurrent_refresh_token_hashes.drain(..))
+                    .map(|hash| ReplacedRefreshToken {
+                        hash,
+                        replaced_at: now,
+                    }),
+            );
+            let excess = self
+                .replaced_refresh_tokens
+                .len()
+                .saturating_sub(MAX_REPLACED_REFRESH_TOKENS);
+            self.replaced_refresh_tokens.drain(..excess);
+        } else {
+            match self
+                .replaced_refresh_tokens
+                .iter()
+                .find(|replaced| replaced.hash == hash)
+            {
+                Some(replaced)
+                    if now - replaced.replaced_at < REFRESH_GRACE_PERIOD
+                        && self.concurrent_refresh_token_hashes.len()
+                            < MAX_CONCURRENT_REFRESH_TOKENS =>
+                {
+                    self.concurrent_refresh_token_hashes
+                        .push(hash_secret(&secret));
+                }
+                Some(_) => return Refresh::Reused,
+                None => return Refresh::Invalid,
+            }
+        }
+
+        self.refreshed_at = now;
+        Refresh::Rotated {
+            refresh_token: format!("{}.{}", self.id, secret),
+            claims: self.issue_claims(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_session() -> (Session, String) {
+        let username = serde_json::from_str("\"alice\"").unwrap();
+        let (session, token) = Session::new(username, "public key".to_owned(), None);
+        (session, secret_of(&token))
+    }
+
+    fn secret_of(token: &str) -> String {
+        parse_refresh_token(token).unwrap().1.to_owned()
+    }
+
+    fn rotate(session: &mut Session, secret: &str) -> String {
+        match session.refresh(secret) {
+            Refresh::Rotated { refresh_token, .. } => secret_of(&refresh_token),
+            refresh => panic!("expected the token to be rotated, got {refresh:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_secret_is_invalid() {
+        let (mut session, secret) = new_session();
+        let guess = "x".repeat(REFRESH_SECRET_SIZE);
+        assert!(matches!(session.refresh(&guess), Refresh::Invalid));
+        rotate(&mut session, &secret);
+    }
+
+    #[test]
+    fn replaced_secret_is_reused_after_grace_period() {
+        let (mut session, first) = new_session();
+        let second = rotate(&mut session, &first);
+        for replaced in &mut session.replaced_refresh_tokens {
+            replaced.replaced_at -= REFRESH_GRACE_PERIOD;
+        }
+        assert!(matches!(session.refresh(&first), Refresh::Reused));
+        assert!(matches!(session.refresh(&second), Refresh::Rotated { .. }));
+    }
+
+    #[test]
+    fn concurrent_refreshes_keep_both_tokens_valid() {
+        let (mut session, first) = new_session();
+        let second = rotate(&mut session, &first);
+        let concurrent = rotate(&mut session, &first);
+
+        let third = rotate(&mut session, &concurrent);
+        // Still within the grace period of being replaced
+        rotate(&mut session, &second);
+        rotate(&mut session, &third);
+    }
+}
@@ -1,177 +1,746 @@
 use std::collections::HashMap;
-use std::fmt;
 use std::fs::{self, File};
 use std::io;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use jsonwebtoken::EncodingKey;
-use rsa::pkcs1::EncodeRsaPrivateKey;
-use rsa::pkcs8::{
-    DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding,
-};
-use rsa::{RsaPrivateKey, RsaPublicKey};
-use serde::{Deserialize, Serialize};
-use server_common::user::{Role, Username};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use server_common::auth::Claims;
+use server_common::jwks;
+use server_common::revocation::{self, TokenId};
+use server_common::user::{GroupName, Role, Username};
+use server_common::util::new_reqwest_client_from_certificates;
+use server_common::ServerConfig;
 use thiserror::Error;
-use tracing::info;
+use time::OffsetDateTime;
+use tracing::{error, info};
 
-use crate::config::{AuthConfig, Config};
-use crate::user::UserRecord;
+use crate::config::{AuthConfig, Config, ServiceClientConfig};
+use crate::group::Group;
+use crate::keys::{Key, KeyRing};
+use crate::session::{self, Refresh, Session, SESSION_IDLE_TIMEOUT};
+use crate::store::{SessionStore, SqliteStore, Store, StoreError, UserStore};
+use crate::throttle::{Lockout, LoginThrottle};
+use crate::totp::TwoFactor;
+use crate::user::{EncryptionKeys, UserRecord};
 
-const DB_PATH: &str = "data/auth-server/db.json";
-const KEY_SIZE: usize = 2048;
-const PRIVATE_KEY_PATH: &str = "cfg/auth-server-private.pem";
-const PUBLIC_KEY_PATH: &str = "cfg/auth-server.pem";
+const DB_PATH: &str = "data/auth-server/db.sqlite";
+const LEGACY_DB_PATH: &str = "data/auth-server/db.json";
+const MIGRATED_DB_PATH: &str = "data/auth-server/db.json.migrated";
+/// How often to check whether the signing key is due to be rotated.
+const KEY_ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const LOGIN_CHALLENGE_SIZE: usize = 32;
+/// How long a user has to give their second factor after giving their password.
+const LOGIN_CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+const MAX_LOGIN_CHALLENGE_FAILURES: u32 = 5;
+/// How long lockouts are kept for admins to review.
+const LOCKOUT_RETENTION: time::Duration = time::Duration::days(90);
+/// How many lockouts admins are shown.
+const MAX_LISTED_LOCKOUTS: usize = 100;
 
 #[derive(Debug)]
 pub struct State {
     pub config: AuthConfig,
     pub db: Database,
-    pub signing_key: Key,
+    pub keys: KeyRing,
+    pub login_challenges: LoginChallenges,
+    pub login_throttle: LoginThrottle,
+    /// Services that keep data of users, which is deleted along with them.
+    pub filestore_server: ServiceClientConfig,
+    pub fileshare_server: ServiceClientConfig,
 }
 
 pub type AppState = Arc<RwLock<State>>;
 
-pub struct Key {
-    pub key: RsaPrivateKey,
-    pub jwt_key: EncodingKey,
+pub static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Logins waiting for the user's second factor, after they gave the right password.
+#[derive(Debug, Default)]
+pub struct LoginChallenges {
+    challenges: HashMap<String, LoginChallenge>,
 }
 
-impl From<RsaPrivateKey> for Key {
-    fn from(key: RsaPrivateKey) -> Key {
-        let pkcs1_der = key
-            .to_pkcs1_der()
-            .expect("Failed to convert key to DER format");
-        Key {
-            key,
-            jwt_key: EncodingKey::from_rsa_der(pkcs1_der.as_bytes()),
+#[derive(Debug)]
+struct LoginChallenge {
+    username: Username,
+    created_at: Instant,
+    failures: u32,
+}
+
+impl LoginChallenges {
+    /// Starts waiting for the second factor of a user, returning the challenge that identifies
+    /// their login.
+    pub fn create(&mut self, username: Username) -> String {
+        self.purge_stale();
+
+        let challenge: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(LOGIN_CHALLENGE_SIZE)
+            .map(char::from)
+            .collect();
+        self.challenges.insert(
+            challenge.clone(),
+            LoginChallenge {
+                username,
+                created_at: Instant::now(),
+                failures: 0,
+            },
+        );
+        challenge
+    }
+
+    /// Gets the user whose login a challenge is for, unless it expired.
+    pub fn username(&self, challenge: &str) -> Option<&Username> {
+        self.challenges
+            .get(challenge)
+            .filter(|login| login.created_at.elapsed() < LOGIN_CHALLENGE_LIFETIME)
+            .map(|login| &login.username)
+    }
+
+    /// Records a wrong second factor, ending the login after too many.
+    pub fn record_failure(&mut self, challenge: &str) {
+        if let Some(login) = self.challenges.get_mut(challenge) {
+            login.failures += 1;
+            if login.failures >= MAX_LOGIN_CHALLENGE_FAILURES {
+                self.challenges.remove(challenge);
+            }
         }
     }
-}
 
-impl fmt::Debug for Key {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Key")
+    pub fn complete(&mut self, challenge: &str) {
+        self.challenges.remove(challenge);
+    }
+
+    fn purge_stale(&mut self) {
+        self.challenges
+            .retain(|_, login| login.created_at.elapsed() < LOGIN_CHALLENGE_LIFETIME);
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Error)]
+pub enum TwoFactorError {
+    #[error("user doesn't exist")]
+    NotFound,
+    #[error("two-factor authentication is already enabled")]
+    AlreadyEnabled,
+    #[error("two-factor authentication isn't enabled")]
+    NotEnabled,
+    #[error("invalid code")]
+    InvalidCode,
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[derive(Debug)]
 pub struct Database {
-    users: HashMap<Username, UserRecord>,
+    store: Box<dyn Store>,
 }
 
 impl Database {
-    pub fn get_user(&self, username: &Username) -> Option<&UserRecord> {
-        self.users.get(username.as_ref())
+    pub fn get_user(&self, username: &Username) -> Result<Option<UserRecord>, StoreError> {
+        self.store.get(username)
     }
 
     pub fn get_user_from_credentials(
         &self,
         username: &Username,
         password: &str,
-    ) -> Option<&UserRecord> {
-        self.users
-            .get(username.as_ref())
-            .and_then(|record| record.check_password(password).then_some(record))
+    ) -> Result<Option<UserRecord>, StoreError> {
+        match self.store.get(username)? {
+            Some(record) => Ok(Some(record).filter(|record| record.check_password(password))),
+            None => {
+                // Takes as long as checking the password of an existing user, so that timing
+                // doesn't tell which users exist.
+                UserRecord::check_dummy_password(password);
+                Ok(None)
+            }
+        }
     }
 
-    pub fn len(&self) -> usize {
-        self.users.len()
+    pub fn users(&self) -> Result<Vec<UserRecord>, StoreError> {
+        self.store.list()
     }
 
-    pub fn add_user(&mut self, user: UserRecord) -> Result<bool, SaveError> {
-        if self.users.contains_key(user.name().as_ref()) {
+    /// Lists up to `limit` users ordered by name, skipping the first `offset`.
+    pub fn users_page(&self, offset: usize, limit: usize) -> Result<Vec<UserRecord>, StoreError> {
+        self.store.list_page(offset, limit)
+    }
+
+    pub fn user_count(&self) -> Result<usize, StoreError> {
+        self.store.count()
+    }
+
+    pub fn add_user(&mut self, user: UserRecord) -> Result<bool, StoreError> {
+        self.store.insert(&user)
+    }
+
+    /// Replaces a user's password with one hashed by `user::hash_password`, along with their
+    /// encrypted private key, which has to be encrypted again with the new password.
+    pub fn change_password(
+        &mut self,
+        username: &Username,
+        password_hash: String,
+        encrypted_private_key: Option<String>,
+    ) -> Result<bool, StoreError> {
+        let updated = self.store.update(username, &mut |user| {
+            user.set_password_hash(password_hash.clone());
+            if let Some(encrypted_private_key) = &encrypted_private_key {
+                user.set_encrypted_private_key(encrypted_private_key.clone());
+            }
+            true
+        })?;
+        Ok(updated.is_some())
+    }
+
+    /// Issues a token for a user to reset their password with, returning it along with when it
+    /// expires.
+    pub fn start_password_reset(
+        &mut self,
+        username: &Username,
+    ) -> Result<Option<(String, OffsetDateTime)>, StoreError> {
+        let mut reset = None;
+        self.store.update(username, &mut |user| {
+            reset = Some(user.start_password_reset());
+            true
+        })?;
+        Ok(reset)
+    }
+
+    /// Sets a new password with a reset token, ending every session of the user.
+    ///
+    /// The user's encryption keys are dropped, since the private key can't be decrypted without
+    /// the old password. New ones are created on their next login.
+    pub fn reset_password(
+        &mut self,
+        username: &Username,
+        token: &str,
+        password_hash: String,
+    ) -> Result<bool, StoreError> {
+        let updated = self.store.update(username, &mut |user| {
+            if !user.take_password_reset(token) {
+                return false;
+            }
+            user.set_password_hash(password_hash.clone());
+            user.clear_encryption_keys();
+            true
+        })?;
+        if updated != Some(true) {
             return Ok(false);
         }
 
-        if self.users.insert(user.name().clone(), user).is_some() {
-            panic!("user shouldn't exist");
-        }
-        self.save()?;
+        self.end_sessions(username, None)?;
         Ok(true)
     }
 
-    pub fn add_role_to_user(&mut self, username: &Username, role: Role) -> Result<bool, SaveError> {
-        match self.users.get_mut(username.as_ref()) {
-            Some(user) => {
-                user.roles_mut().insert(role);
-                self.save().and(Ok(true))
+    /// Removes a user, ending all of their sessions.
+    pub fn delete_user(&mut self, username: &Username) -> Result<bool, StoreError> {
+        match self.store.delete(username)? {
+            Some(tokens) => {
+                revocation::revoke(tokens);
+                Ok(true)
             }
             None => Ok(false),
         }
     }
 
+    /// Disables or enables a user. Disabling them also ends all of their sessions.
+    ///
+    /// Returns `Ok(false)` if the user doesn't exist.
+    pub fn set_user_disabled(
+        &mut self,
+        username: &Username,
+        disabled: bool,
+    ) -> Result<bool, StoreError> {
+        let updated = self.store.update(username, &mut |user| {
+            user.set_disabled(disabled);
+            true
+        })?;
+        if updated.is_none() {
+            return Ok(false);
+        }
+
+        if disabled {
+            self.end_sessions(username, None)?;
+        }
+        Ok(true)
+    }
+
+    pub fn add_role_to_user(
+        &mut self,
+        username: &Username,
+        role: Role,
+    ) -> Result<bool, StoreError> {
+        let updated = self.store.update(username, &mut |user| {
+            user.roles_mut().insert(role.clone());
+            true
+        })?;
+        Ok(updated.is_some())
+    }
+
     pub fn remove_role_from_user(
         &mut self,
         username: &Username,
         role: &Role,
-    ) -> Result<bool, SaveError> {
-        match self.users.get_mut(username.as_ref()) {
-            Some(user) => {
-                let removed = Ok(user.roles_mut().remove(role.as_ref()));
-                self.save().and(removed)
+    ) -> Result<bool, StoreError> {
+        let removed = self
+            .store
+            .update(username, &mut |user| user.roles_mut().remove(role.as_ref()))?;
+        Ok(removed.unwrap_or(false))
+    }
+
+    /// Sets a user's end-to-end encryption keys, unless they already have some, since replacing
+    /// them would leave every file key wrapped with the old public key unusable.
+    ///
+    /// Returns `Ok(None)` if the user doesn't exist and `Ok(Some(false))` if they already have
+    /// keys.
+    pub fn set_encryption_keys(
+        &mut self,
+        username: &Username,
+        keys: EncryptionKeys,
+    ) -> Result<Option<bool>, StoreError> {
+        self.store.update(username, &mut |user| {
+            if user.encryption_keys().is_some() {
+                return false;
             }
-            None => Ok(false),
+            user.set_encryption_keys(keys.clone());
+            true
+        })
+    }
+
+    /// Enables two-factor authentication for a user, given a code generated from `secret` to show
+    /// that their authenticator app is set up with it.
+    ///
+    /// Returns the recovery codes, which the user needs to keep.
+    pub fn enable_two_factor(
+        &mut self,
+        username: &Username,
+        secret: String,
+        code: &str,
+    ) -> Result<Vec<String>, TwoFactorError> {
+        let mut outcome = Err(TwoFactorError::NotFound);
+        self.store.update(username, &mut |user| {
+            if user.two_factor_enabled() {
+                outcome = Err(TwoFactorError::AlreadyEnabled);
+                return false;
+            }
+            match TwoFactor::enroll(secret.clone(), code) {
+                Some((two_factor, recovery_codes)) => {
+                    user.set_two_factor(Some(two_factor));
+                    outcome = Ok(recovery_codes);
+                    true
+                }
+                None => {
+                    outcome = Err(TwoFactorError::InvalidCode);
+                    false
+                }
+            }
+        })?;
+        outcome
+    }
+
+    /// Checks a user's second factor, using up the code given.
+    pub fn verify_two_factor(
+        &mut self,
+        username: &Username,
+        code: &str,
+    ) -> Result<(), TwoFactorError> {
+        let mut outcome = Err(TwoFactorError::NotFound);
+        self.store.update(username, &mut |user| {
+            outcome = match user
+                .two_factor_mut()
+                .map(|two_factor| two_factor.verify(code))
+            {
+                Some(true) => Ok(()),
+                Some(false) => Err(TwoFactorError::InvalidCode),
+                None => Err(TwoFactorError::NotEnabled),
+            };
+            outcome.is_ok()
+        })?;
+        outcome
+    }
+
+    /// Disables two-factor authentication for a user, given a code from their second factor.
+    pub fn disable_two_factor(
+        &mut self,
+        username: &Username,
+        code: &str,
+    ) -> Result<(), TwoFactorError> {
+        let mut outcome = Err(TwoFactorError::NotFound);
+        self.store.update(username, &mut |user| {
+            outcome = match user
+                .two_factor_mut()
+                .map(|two_factor| two_factor.verify(code))
+            {
+                Some(true) => Ok(()),
+                Some(false) => Err(TwoFactorError::InvalidCode),
+                None => Err(TwoFactorError::NotEnabled),
+            };
+            if outcome.is_ok() {
+                user.set_two_factor(None);
+            }
+            outcome.is_ok()
+        })?;
+        outcome
+    }
+
+    /// Starts a session for a user who just logged in and records when they did, returning it
+    /// along with its refresh token and first JWT.
+    pub fn create_session(
+        &mut self,
+        username: Username,
+        public_key: String,
+        user_agent: Option<String>,
+    ) -> Result<(Session, String, Claims), StoreError> {
+        self.store
+            .delete_idle_sessions(&username, OffsetDateTime::now_utc() - SESSION_IDLE_TIMEOUT)?;
+        self.store.update(&username, &mut |user| {
+            user.record_login();
+            true
+        })?;
+
+        let (mut session, refresh_token) = Session::new(username, public_key, user_agent);
+        let claims = session.issue_claims();
+        self.store.insert_session(&session)?;
+        Ok((session, refresh_token, claims))
+    }
+
+    /// Exchanges a refresh token for a new one. A session whose token is reused or that expired
+    /// is removed.
+    ///
+    /// Returns `Ok(None)` if the token doesn't belong to any session.
+    pub fn refresh_session(
+        &mut self,
+        refresh_token: &str,
+    ) -> Result<Option<(Session, Refresh)>, StoreError> {
+        let Some((id, secret)) = session::parse_refresh_token(refresh_token) else {
+            return Ok(None);
+        };
+
+        let mut outcome = None;
+        self.store.update_session(id, &mut |session| {
+            let refresh = session.refresh(secret);
+            let rotated = matches!(refresh, Refresh::Rotated { .. });
+            outcome = Some((session.clone(), refresh));
+            rotated
+        })?;
+
+        if let Some((session, Refresh::Reused | Refresh::Expired)) = &outcome {
+            self.end_session(session)?;
+        }
+        Ok(outcome)
+    }
+
+    /// Gets the sessions of a user that haven't expired, most recently refreshed first.
+    pub fn sessions_of_user(&self, username: &Username) -> Result<Vec<Session>, StoreError> {
+        let mut sessions = self.store.list_sessions(username)?;
+        sessions.retain(|session| !session.is_expired());
+        Ok(sessions)
+    }
+
+    /// Ends one of a user's sessions.
+    ///
+    /// Returns `Ok(false)` if the user has no session with that ID.
+    pub fn delete_session(&mut self, username: &Username, id: &str) -> Result<bool, StoreError> {
+        match self.store.get_session(id)? {
+            Some(session) if session.username() == username => self.end_session(&session),
+            _ => Ok(false),
         }
     }
 
-    pub fn save(&self) -> Result<(), SaveError> {
-        fs::create_dir_all(Path::new(DB_PATH).parent().unwrap())?;
+    /// Ends the session a JWT was issued for, revoking the JWT even if the session already ended.
+    pub fn logout(&mut self, claims: &Claims) -> Result<(), StoreError> {
+        let session = self
+            .store
+            .list_sessions(claims.username())?
+            .into_iter()
+            .find(|session| session.tokens().iter().any(|token| token.id == claims.id()));
+        match session {
+            Some(session) => {
+                self.end_session(&session)?;
+            }
+            None => {
+                self.store.revoke_tokens(&[claims.token_id()])?;
+                revocation::revoke([claims.token_id()]);
+            }
+        }
+        Ok(())
+    }
 
-        let mut file = File::create(DB_PATH)?;
-        serde_json::to_writer_pretty(&mut file, self)?;
+    /// Ends every session of a user, except for the one that `current` was issued for if given.
+    pub fn end_sessions(
+        &mut self,
+        username: &Username,
+        current: Option<&Claims>,
+    ) -> Result<(), StoreError> {
+        for session in self.store.list_sessions(username)? {
+            let is_current = current
+                .is_some_and(|claims| session.tokens().iter().any(|token| token.id == claims.id()));
+            if !is_current {
+                self.end_session(&session)?;
+            }
+        }
         Ok(())
     }
+
+    /// Removes a session and revokes the JWTs issued for it, so that it can't be used anymore.
+    fn end_session(&mut self, session: &Session) -> Result<bool, StoreError> {
+        let ended = self.store.delete_session(session.id())?;
+        revocation::revoke(session.tokens().iter().cloned());
+        Ok(ended)
+    }
+
+    pub fn revoked_tokens(&self) -> Result<Vec<TokenId>, StoreError> {
+        self.store.revoked_tokens()
+    }
+
+    pub fn record_lockout(&mut self, lockout: &Lockout) -> Result<(), StoreError> {
+        self.store
+            .insert_lockout(lockout, OffsetDateTime::now_utc() - LOCKOUT_RETENTION)
+    }
+
+    /// Gets the most recent lockouts, newest first.
+    pub fn lockouts(&self) -> Result<Vec<Lockout>, StoreError> {
+        self.store.list_lockouts(MAX_LISTED_LOCKOUTS)
+    }
+
+    pub fn groups(&self) -> Result<Vec<Group>, StoreError> {
+        self.store.list_groups()
+    }
+
+    /// Creates a group without members, returning `false` if it already exists.
+    pub fn create_group(&mut self, name: &GroupName) -> Result<bool, StoreError> {
+        self.store.insert_group(name, OffsetDateTime::now_utc())
+    }
+
+    pub fn group_exists(&self, name: &GroupName) -> Result<bool, StoreError> {
+        self.store.group_exists(name)
+    }
+
+    pub fn delete_group(&mut self, name: &GroupName) -> Result<bool, StoreError> {
+        self.store.delete_group(name)
+    }
+
+    /// Returns `Ok(None)` if the group or the user doesn't exist.
+    pub fn add_group_member(
+        &mut self,
+        name: &GroupName,
+        username: &Username,
+    ) -> Result<Option<bool>, StoreError> {
+        self.store.add_group_member(name, username)
+    }
+
+    pub fn remove_group_member(
+        &mut self,
+        name: &GroupName,
+        username: &Username,
+    ) -> Result<bool, StoreError> {
+        self.store.remove_group_member(name, username)
+    }
+
+    pub fn groups_of_user(&self, username: &Username) -> Result<Vec<GroupName>, StoreError> {
+        self.store.groups_of_user(username)
+    }
 }
 
-fn get_signing_key() -> anyhow::Result<RsaPrivateKey> {
-    let private_key_path = Path::new(PRIVATE_KEY_PATH);
-    let private_key = if private_key_path.is_file() {
-        info!("Loading signing key from '{}'", PRIVATE_KEY_PATH);
-        RsaPrivateKey::read_pkcs8_pem_file(private_key_path)?
-    } else {
-        info!("Generating new signing key");
-        let mut rng = rand::thread_rng();
-        let key = RsaPrivateKey::new(&mut rng, KEY_SIZE)?;
-        key.write_pkcs8_pem_file(private_key_path, LineEnding::LF)?;
-        key
+/// Imports the users of the JSON file the database used to be kept in, then moves the file aside
+/// so that it's only imported once.
+///
+/// Users that already exist in the store are skipped, so the import is safe to repeat if the
+/// server stops before the file is moved.
+fn migrate_legacy_db(
+    store: &dyn UserStore,
+    legacy_path: &Path,
+    migrated_path: &Path,
+) -> anyhow::Result<()> {
+    #[derive(Deserialize)]
+    struct LegacyDatabase {
+        users: HashMap<Username, UserRecord>,
+    }
+
+    let file = match File::open(legacy_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("Failed to open legacy db file"),
     };
+    let legacy: LegacyDatabase =
+        serde_json::from_reader(file).context("Failed to deserialize legacy db file")?;
 
-    let public_key = RsaPublicKey::from(&private_key);
+    info!(
+        users = legacy.users.len(),
+        "Importing users from '{}'",
+        legacy_path.display()
+    );
+    store
+        .import(&legacy.users.into_values().collect::<Vec<_>>())
+        .context("Failed to import legacy db file")?;
+    fs::rename(legacy_path, migrated_path).context("Failed to move legacy db file")?;
+    Ok(())
+}
 
-    let public_key_path = Path::new(PUBLIC_KEY_PATH);
-    if !public_key_path.is_file()
-        || RsaPublicKey::read_public_key_pem_file(public_key_path)? != public_key
-    {
-        info!("Writing public key to '{}'", PUBLIC_KEY_PATH);
-        public_key.write_public_key_pem_file(public_key_path, LineEnding::LF)?;
+/// Replaces the signing key once it's due, and removes the ones that were replaced long enough
+/// ago.
+fn rotate_keys(state: &AppState) -> anyhow::Result<()> {
+    let due = {
+        let state = state.read().expect("poisoned lock");
+        state
+            .keys
+            .rotation_due(state.config.key_rotation_interval())
+    };
+    // Generating a key takes a while, so it's done without holding the lock.
+    let key = if due { Some(Key::generate()?) } else { None };
+
+    let mut state = state.write().expect("poisoned lock");
+    if let Some(key) = key {
+        state.keys.add(key)?;
     }
+    state.keys.remove_retired()?;
+    jwks::set_key_set(&state.keys.key_set());
+    Ok(())
+}
 
-    Ok(private_key)
+fn spawn_key_rotator(state: AppState) {
+    thread::spawn(move || loop {
+        thread::sleep(KEY_ROTATION_CHECK_INTERVAL);
+        if let Err(err) = rotate_keys(&state) {
+            error!(?err, "Failed to rotate signing keys");
+        }
+    });
 }
 
 pub fn get_state(config: Config) -> anyhow::Result<AppState> {
-    let signing_key = get_signing_key()?.into();
+    let keys = KeyRing::load()?;
 
-    let db = match File::open(DB_PATH) {
-        Ok(file) => serde_json::from_reader(file).context("Failed to deserialize db file")?,
-        Err(err) if err.kind() == io::ErrorKind::NotFound => Default::default(),
-        Err(err) => return Err(err).context("Failed to open db file"),
+    fs::create_dir_all(Path::new(DB_PATH).parent().unwrap())?;
+    let store = SqliteStore::open(Path::new(DB_PATH)).context("Failed to open db")?;
+    migrate_legacy_db(
+        &store,
+        Path::new(LEGACY_DB_PATH),
+        Path::new(MIGRATED_DB_PATH),
+    )?;
+    revocation::revoke(
+        store
+            .revoked_tokens()
+            .context("Failed to read revoked tokens")?,
+    );
+    let db = Database {
+        store: Box::new(store),
     };
 
-    Ok(Arc::new(RwLock::new(State {
+    CLIENT
+        .set(new_reqwest_client_from_certificates(Config::name())?)
+        .expect("this should only get called once");
+
+    let state = Arc::new(RwLock::new(State {
         config: config.authenticator,
         db,
-        signing_key,
-    })))
+        keys,
+        login_challenges: Default::default(),
+        login_throttle: Default::default(),
+        filestore_server: config.filestore_server,
+        fileshare_server: config.fileshare_server,
+    }));
+    rotate_keys(&state)?;
+    spawn_key_rotator(Arc::clone(&state));
+    Ok(state)
 }
 
-#[derive(Debug, Error)]
-pub enum SaveError {
-    #[error("IO error saving database: {0}")]
-    Io(#[from] io::Error),
-    #[error("serialization error saving database: {0}")]
-    Json(#[from] serde_json::Error),
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn user(name: &str) -> UserRecord {
+        UserRecord::new(
+            serde_json::from_str(&format!("\"{name}\"")).unwrap(),
+            format!("password of {name}"),
+            HashSet::new(),
+        )
+        .unwrap()
+    }
+
+    fn names(store: &SqliteStore) -> Vec<String> {
+        let mut names: Vec<_> = store
+            .list()
+            .unwrap()
+            .iter()
+            .map(|user| user.name().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn legacy_db_is_imported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (legacy_path, migrated_path) = (
+            dir.path().join("db.json"),
+            dir.path().join("db.json.migrated"),
+        );
+        let legacy = serde_json::json!({
+            "users": {"alice": user("alice"), "bob": user("bob")},
+        });
+        fs::write(&legacy_path, legacy.to_string()).unwrap();
+
+        let store = SqliteStore::open(&dir.path().join("auth-server.db")).unwrap();
+        migrate_legacy_db(&store, &legacy_path, &migrated_path).unwrap();
+        assert!(!legacy_path.exists());
+        assert!(migrated_path.exists());
+        assert_eq!(names(&store), ["alice", "bob"]);
+        let alice = serde_json::from_str("\"alice\"").unwrap();
+        assert!(store
+            .get(&alice)
+            .unwrap()
+            .unwrap()
+            .check_password("password of alice"));
+
+        // Nothing is left to import on the next start.
+        migrate_legacy_db(&store, &legacy_path, &migrated_path).unwrap();
+        assert_eq!(names(&store), ["alice", "bob"]);
+    }
+
+    #[test]
+    fn interrupted_import_is_repeated_without_undoing_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (legacy_path, migrated_path) = (
+            dir.path().join("db.json"),
+            dir.path().join("db.json.migrated"),
+        );
+        let legacy = serde_json::json!({
+            "users": {"alice": user("alice"), "bob": user("bob")},
+        });
+        fs::write(&legacy_path, legacy.to_string()).unwrap();
+
+        // As if the server stopped after importing some of the users but before the file was
+        // moved, and a user was changed since.
+        let store = SqliteStore::open(&dir.path().join("auth-server.db")).unwrap();
+        let mut alice = user("alice");
+        alice.set_disabled(true);
+        store.import(&[alice.clone()]).unwrap();
+        migrate_legacy_db(&store, &legacy_path, &migrated_path).unwrap();
+
+        assert!(!legacy_path.exists());
+        assert_eq!(names(&store), ["alice", "bob"]);
+        assert!(store.get(alice.name()).unwrap().unwrap().is_disabled());
+    }
+
+    #[test]
+    fn unreadable_legacy_db_is_left_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let (legacy_path, migrated_path) = (
+            dir.path().join("db.json"),
+            dir.path().join("db.json.migrated"),
+        );
+        fs::write(&legacy_path, "{\"users\": {\"alice\": ").unwrap();
+
+        let store = SqliteStore::open(&dir.path().join("auth-server.db")).unwrap();
+        assert!(migrate_legacy_db(&store, &legacy_path, &migrated_path).is_err());
+        assert!(legacy_path.exists());
+        assert!(!migrated_path.exists());
+        assert!(names(&store).is_empty());
+    }
 }
@@ -0,0 +1,145 @@
+use std::fmt;
+
+use server_common::revocation::TokenId;
+use server_common::user::{GroupName, Username};
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use crate::group::Group;
+use crate::session::Session;
+use crate::throttle::Lockout;
+use crate::user::UserRecord;
+
+mod sqlite;
+
+pub use sqlite::SqliteStore;
+
+/// Persistent storage of user accounts. Every change is applied atomically, so that a crash never
+/// leaves a record half written.
+pub trait UserStore: fmt::Debug + Send + Sync {
+    fn get(&self, username: &Username) -> Result<Option<UserRecord>, StoreError>;
+
+    fn list(&self) -> Result<Vec<UserRecord>, StoreError>;
+
+    /// Lists up to `limit` users ordered by name, skipping the first `offset`.
+    fn list_page(&self, offset: usize, limit: usize) -> Result<Vec<UserRecord>, StoreError>;
+
+    fn count(&self) -> Result<usize, StoreError>;
+
+    /// Adds a user, unless one with the same name already exists.
+    fn insert(&self, user: &UserRecord) -> Result<bool, StoreError>;
+
+    /// Adds all the users that don't exist yet in a single transaction.
+    fn import(&self, users: &[UserRecord]) -> Result<(), StoreError>;
+
+    /// Applies `update` to a user within a transaction, saving the record if it returns `true`.
+    ///
+    /// Returns `Ok(None)` if the user doesn't exist, or what `update` returned otherwise.
+    fn update(
+        &self,
+        username: &Username,
+        update: &mut dyn FnMut(&mut UserRecord) -> bool,
+    ) -> Result<Option<bool>, StoreError>;
+
+    /// Removes a user along with their sessions and group memberships, revoking the JWTs issued
+    /// for those in the same transaction.
+    ///
+    /// Returns the revoked JWTs, or `Ok(None)` if the user doesn't exist.
+    fn delete(&self, username: &Username) -> Result<Option<Vec<TokenId>>, StoreError>;
+}
+
+/// Persistent storage of the sessions users are logged in with.
+pub trait SessionStore: fmt::Debug + Send + Sync {
+    fn get_session(&self, id: &str) -> Result<Option<Session>, StoreError>;
+
+    fn list_sessions(&self, username: &Username) -> Result<Vec<Session>, StoreError>;
+
+    fn insert_session(&self, session: &Session) -> Result<(), StoreError>;
+
+    /// Applies `update` to a session within a transaction, saving it if `update` returns `true`.
+    ///
+    /// Returns `Ok(None)` if the session doesn't exist, or what `update` returned otherwise.
+    fn update_session(
+        &self,
+        id: &str,
+        update: &mut dyn FnMut(&mut Session) -> bool,
+    ) -> Result<Option<bool>, StoreError>;
+
+    /// Removes a session, revoking the JWTs issued for it in the same transaction.
+    fn delete_session(&self, id: &str) -> Result<bool, StoreError>;
+
+    /// Removes every session of `username` that was last refreshed before `before`.
+    fn delete_idle_sessions(
+        &self,
+        username: &Username,
+        before: OffsetDateTime,
+    ) -> Result<usize, StoreError>;
+
+    fn revoke_tokens(&self, tokens: &[TokenId]) -> Result<(), StoreError>;
+
+    /// Gets the revoked tokens that haven't expired yet.
+    fn revoked_tokens(&self) -> Result<Vec<TokenId>, StoreError>;
+}
+
+/// Persistent record of accounts being locked after failed logins, for admins to review.
+pub trait LockoutStore: fmt::Debug + Send + Sync {
+    /// Records a lockout, dropping the ones recorded before `retain_since`.
+    fn insert_lockout(
+        &self,
+        lockout: &Lockout,
+        retain_since: OffsetDateTime,
+    ) -> Result<(), StoreError>;
+
+    /// Gets the `limit` most recent lockouts, newest first.
+    fn list_lockouts(&self, limit: usize) -> Result<Vec<Lockout>, StoreError>;
+}
+
+/// Persistent storage of groups and their members.
+pub trait GroupStore: fmt::Debug + Send + Sync {
+    /// Adds a group without members, unless one with the same name already exists.
+    fn insert_group(
+        &self,
+        name: &GroupName,
+        created_at: OffsetDateTime,
+    ) -> Result<bool, StoreError>;
+
+    /// Removes a group along with its memberships.
+    fn delete_group(&self, name: &GroupName) -> Result<bool, StoreError>;
+
+    fn group_exists(&self, name: &GroupName) -> Result<bool, StoreError>;
+
+    /// Lists every group with its members, ordered by name.
+    fn list_groups(&self) -> Result<Vec<Group>, StoreError>;
+
+    /// Adds a user to a group. Returns `Ok(None)` if either of them doesn't exist, or whether the
+    /// user wasn't a member yet otherwise.
+    fn add_group_member(
+        &self,
+        name: &GroupName,
+        username: &Username,
+    ) -> Result<Option<bool>, StoreError>;
+
+    /// Removes a user from a group, returning whether they were a member.
+    fn remove_group_member(
+        &self,
+        name: &GroupName,
+        username: &Username,
+    ) -> Result<bool, StoreError>;
+
+    /// Gets the names of the groups a user is a member of, ordered by name.
+    fn groups_of_user(&self, username: &Username) -> Result<Vec<GroupName>, StoreError>;
+}
+
+/// Everything the auth server keeps, which is kept in one store so that it can be changed in a
+/// single transaction.
+pub trait Store: UserStore + SessionStore + LockoutStore + GroupStore {}
+
+impl<T: UserStore + SessionStore + LockoutStore + GroupStore> Store for T {}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("SQLite error accessing database: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("serialization error accessing database: {0}")]
+    Json(#[from] serde_json::Error),
+}
@@ -0,0 +1,614 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use prae::Wrapper;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use server_common::revocation::TokenId;
+use server_common::user::{GroupName, Username};
+use time::OffsetDateTime;
+
+use super::{GroupStore, LockoutStore, SessionStore, StoreError, UserStore};
+use crate::group::Group;
+use crate::session::Session;
+use crate::throttle::Lockout;
+use crate::user::UserRecord;
+
+/// Keeps users and sessions in an SQLite database, with each record serialized as JSON so that
+/// new fields don't need a schema migration.
+pub struct SqliteStore {
+    path: PathBuf,
+    connection: Mutex<Connection>,
+}
+
+impl fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let connection = Connection::open(path)?;
+        // The write-ahead log keeps the database intact if the process dies mid-write, and
+        // syncing it on every commit makes committed changes survive power loss.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "FULL")?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS users (
+                name TEXT PRIMARY KEY NOT NULL,
+                record TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY NOT NULL,
+                username TEXT NOT NULL,
+                refreshed_at INTEGER NOT NULL,
+                record TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS sessions_by_user ON sessions (username, refreshed_at);
+            CREATE TABLE IF NOT EXISTS revoked_tokens (
+                id TEXT PRIMARY KEY NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS revoked_tokens_by_expiry ON revoked_tokens (expires_at);
+            CREATE TABLE IF NOT EXISTS lockouts (
+                id INTEGER PRIMARY KEY,
+                locked_at INTEGER NOT NULL,
+                record TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS lockouts_by_time ON lockouts (locked_at);
+            CREATE TABLE IF NOT EXISTS groups (
+                name TEXT PRIMARY KEY NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS group_members (
+                group_name TEXT NOT NULL,
+                username TEXT NOT NULL,
+                PRIMARY KEY (group_name, username)
+            );
+            CREATE INDEX IF NOT EXISTS group_members_by_user ON group_members (username);",
+        )?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("poisoned lock")
+    }
+}
+
+fn insert_user(connection: &Connection, user: &UserRecord) -> Result<bool, StoreError> {
+    let inserted = connection.execute(
+        "INSERT OR IGNORE INTO users (name, record) VALUES (?1, ?2)",
+        params![user.name().to_string(), serde_json::to_string(user)?],
+    )?;
+    Ok(inserted == 1)
+}
+
+/// Adds tokens to the revocation list, dropping the ones that expired since they can't be used
+/// anymore anyway.
+fn revoke_tokens(connection: &Connection, tokens: &[TokenId]) -> Result<(), StoreError> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    connection.execute("DELETE FROM revoked_tokens WHERE expires_at < ?1", [now])?;
+
+    let mut statement = connection
+        .prepare("INSERT OR IGNORE INTO revoked_tokens (id, expires_at) VALUES (?1, ?2)")?;
+    for token in tokens.iter().filter(|token| !token.is_expired()) {
+        statement.execute(params![token.id, token.expires])?;
+    }
+    Ok(())
+}
+
+impl UserStore for SqliteStore {
+    fn get(&self, username: &Username) -> Result<Option<UserRecord>, StoreError> {
+        self.connection()
+            .query_row(
+                "SELECT record FROM users WHERE name = ?1",
+                [username.to_string()],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .map(|record| serde_json::from_str(&record))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn list(&self) -> Result<Vec<UserRecord>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT record FROM users ORDER BY name")?;
+        let records = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        records
+            .iter()
+            .map(|record| serde_json::from_str(record).map_err(Into::into))
+            .collect()
+    }
+
+    fn list_page(&self, offset: usize, limit: usize) -> Result<Vec<UserRecord>, StoreError> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare("SELECT record FROM users ORDER BY name LIMIT ?1 OFFSET ?2")?;
+        let records = statement
+            .query_map(params![limit, offset], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        records
+            .iter()
+            .map(|record| serde_json::from_str(record).map_err(Into::into))
+            .collect()
+    }
+
+    fn count(&self) -> Result<usize, StoreError> {
+        Ok(self
+            .connection()
+            .query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?)
+    }
+
+    fn insert(&self, user: &UserRecord) -> Result<bool, StoreError> {
+        insert_user(&self.connection(), user)
+    }
+
+    fn import(&self, users: &[UserRecord]) -> Result<(), StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        for user in users {
+            insert_user(&transaction, user)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        username: &Username,
+        update: &mut dyn FnMut(&mut UserRecord) -> bool,
+    ) -> Result<Option<bool>, StoreError> {
+        let mut connection = self.connection();
+        // Taking the write lock up front keeps other connections from changing the record
+        // between reading and writing it.
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let record: Option<String> = transaction
+            .query_row(
+                "SELECT record FROM users WHERE name = ?1",
+                [username.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(record) = record else {
+            return Ok(None);
+        };
+
+        let mut user: UserRecord = serde_json::from_str(&record)?;
+        if !update(&mut user) {
+            return Ok(Some(false));
+        }
+
+        transaction.execute(
+            "UPDATE users SET record = ?2 WHERE name = ?1",
+            params![username.to_string(), serde_json::to_string(&user)?],
+        )?;
+        transaction.commit()?;
+        Ok(Some(true))
+    }
+
+    fn delete(&self, username: &Username) -> Result<Option<Vec<TokenId>>, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let deleted =
+            transaction.execute("DELETE FROM users WHERE name = ?1", [username.to_string()])?;
+        if deleted == 0 {
+            return Ok(None);
+        }
+
+        let records = transaction
+            .prepare("SELECT record FROM sessions WHERE username = ?1")?
+            .query_map([username.to_string()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut tokens = Vec::new();
+        for record in records {
+            let session: Session = serde_json::from_str(&record)?;
+            tokens.extend_from_slice(session.tokens());
+        }
+        revoke_tokens(&transaction, &tokens)?;
+        transaction.execute(
+            "DELETE FROM sessions WHERE username = ?1",
+            [username.to_string()],
+        )?;
+        transaction.execute(
+            "DELETE FROM group_members WHERE username = ?1",
+            [username.to_string()],
+        )?;
+
+        transaction.commit()?;
+        Ok(Some(tokens))
+    }
+}
+
+impl SessionStore for SqliteStore {
+    fn get_session(&self, id: &str) -> Result<Option<Session>, StoreError> {
+        self.connection()
+            .query_row("SELECT record FROM sessions WHERE id = ?1", [id], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?
+            .map(|record| serde_json::from_str(&record))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn list_sessions(&self, username: &Username) -> Result<Vec<Session>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT record FROM sessions WHERE username = ?1 ORDER BY refreshed_at DESC",
+        )?;
+        let records = statement
+            .query_map([username.to_string()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        records
+            .iter()
+            .map(|record| serde_json::from_str(record).map_err(Into::into))
+            .collect()
+    }
+
+    fn insert_session(&self, session: &Session) -> Result<(), StoreError> {
+        self.connection().execute(
+            "INSERT INTO sessions (id, username, refreshed_at, record) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                session.id(),
+                session.username().to_string(),
+                session.refreshed_at().unix_timestamp(),
+                serde_json::to_string(session)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update_session(
+        &self,
+        id: &str,
+        update: &mut dyn FnMut(&mut Session) -> bool,
+    ) -> Result<Option<bool>, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let record: Option<String> = transaction
+            .query_row("SELECT record FROM sessions WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(record) = record else {
+            return Ok(None);
+        };
+
+        let mut session: Session = serde_json::from_str(&record)?;
+        if !update(&mut session) {
+            return Ok(Some(false));
+        }
+
+        transaction.execute(
+            "UPDATE sessions SET refreshed_at = ?2, record = ?3 WHERE id = ?1",
+            params![
+                id,
+                session.refreshed_at().unix_timestamp(),
+                serde_json::to_string(&session)?,
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(Some(true))
+    }
+
+    fn delete_session(&self, id: &str) -> Result<bool, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let record: Option<String> = transaction
+            .query_row("SELECT record FROM sessions WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(record) = record else {
+            return Ok(false);
+        };
+
+        let session: Session = serde_json::from_str(&record)?;
+        revoke_tokens(&transaction, session.tokens())?;
+        transaction.execute("DELETE FROM sessions WHERE id = ?1", [id])?;
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    fn delete_idle_sessions(
+        &self,
+        username: &Username,
+        before: OffsetDateTime,
+    ) -> Result<usize, StoreError> {
+        Ok(self.connection().execute(
+            "DELETE FROM sessions WHERE username = ?1 AND refreshed_at < ?2",
+            params![username.to_string(), before.unix_timestamp()],
+        )?)
+    }
+
+    fn revoke_tokens(&self, tokens: &[TokenId]) -> Result<(), StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        revoke_tokens(&transaction, tokens)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn revoked_tokens(&self) -> Result<Vec<TokenId>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT id, expires_at FROM revoked_tokens WHERE expires_at >= ?1")?;
+        let tokens = statement
+            .query_map([OffsetDateTime::now_utc().unix_timestamp()], |row| {
+                Ok(TokenId {
+                    id: row.get(0)?,
+                    expires: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tokens)
+    }
+}
+
+impl LockoutStore for SqliteStore {
+    fn insert_lockout(
+        &self,
+        lockout: &Lockout,
+        retain_since: OffsetDateTime,
+    ) -> Result<(), StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "DELETE FROM lockouts WHERE locked_at < ?1",
+            [retain_since.unix_timestamp()],
+        )?;
+        transaction.execute(
+            "INSERT INTO lockouts (locked_at, record) VALUES (?1, ?2)",
+            params![
+                lockout.locked_at.unix_timestamp(),
+                serde_json::to_string(lockout)?
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn list_lockouts(&self, limit: usize) -> Result<Vec<Lockout>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT record FROM lockouts ORDER BY locked_at DESC, id DESC LIMIT ?1")?;
+        let records = statement
+            .query_map([limit], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        records
+            .iter()
+            .map(|record| serde_json::from_str(record).map_err(Into::into))
+            .collect()
+    }
+}
+
+impl GroupStore for SqliteStore {
+    fn insert_group(
+        &self,
+        name: &GroupName,
+        created_at: OffsetDateTime,
+    ) -> Result<bool, StoreError> {
+        let inserted = self.connection().execute(
+            "INSERT OR IGNORE INTO groups (name, created_at) VALUES (?1, ?2)",
+            params![name.to_string(), created_at.unix_timestamp()],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn delete_group(&self, name: &GroupName) -> Result<bool, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let deleted =
+            transaction.execute("DELETE FROM groups WHERE name = ?1", [name.to_string()])?;
+        transaction.execute(
+            "DELETE FROM group_members WHERE group_name = ?1",
+            [name.to_string()],
+        )?;
+        transaction.commit()?;
+        Ok(deleted == 1)
+    }
+
+    fn group_exists(&self, name: &GroupName) -> Result<bool, StoreError> {
+        Ok(self.connection().query_row(
+            "SELECT EXISTS (SELECT 1 FROM groups WHERE name = ?1)",
+            [name.to_string()],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn list_groups(&self) -> Result<Vec<Group>, StoreError> {
+        let connection = self.connection();
+        let mut groups = connection
+            .prepare("SELECT name, created_at FROM groups ORDER BY name")?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .map(|row| {
+                let (name, created_at) = row?;
+                Ok(Group {
+                    // Names were validated before they were stored
+                    name: GroupName::new_unprocessed(name),
+                    created_at: OffsetDateTime::from_unix_timestamp(created_at)
+                        .unwrap_or(OffsetDateTime::UNIX_EPOCH),
+                    members: Vec::new(),
+                })
+            })
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        let mut statement = connection.prepare(
+            "SELECT username FROM group_members WHERE group_name = ?1 ORDER BY username",
+        )?;
+        for group in &mut groups {
+            group.members = statement
+                .query_map([group.name.to_string()], |row| row.get::<_, String>(0))?
+                .map(|username| username.map(Username::new_unprocessed))
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(groups)
+    }
+
+    fn add_group_member(
+        &self,
+        name: &GroupName,
+        username: &Username,
+    ) -> Result<Option<bool>, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let group_exists = transaction
+            .query_row(
+                "SELECT 1 FROM groups WHERE name = ?1",
+                [name.to_string()],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        let user_exists = transaction
+            .query_row(
+                "SELECT 1 FROM users WHERE name = ?1",
+                [username.to_string()],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if !(group_exists && user_exists) {
+            return Ok(None);
+        }
+
+        let inserted = transaction.execute(
+            "INSERT OR IGNORE INTO group_members (group_name, username) VALUES (?1, ?2)",
+            params![name.to_string(), username.to_string()],
+        )?;
+        transaction.commit()?;
+        Ok(Some(inserted == 1))
+    }
+
+    fn remove_group_member(
+        &self,
+        name: &GroupName,
+        username: &Username,
+    ) -> Result<bool, StoreError> {
+        let deleted = self.connection().execute(
+            "DELETE FROM group_members WHERE group_name = ?1 AND username = ?2",
+            params![name.to_string(), username.to_string()],
+        )?;
+        Ok(deleted == 1)
+    }
+
+    fn groups_of_user(&self, username: &Username) -> Result<Vec<GroupName>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT group_name FROM group_members WHERE username = ?1 ORDER BY group_name",
+        )?;
+        let names = statement
+            .query_map([username.to_string()], |row| row.get::<_, String>(0))?
+            .map(|name| name.map(GroupName::new_unprocessed))
+            .collect::<Result<_, _>>()?;
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+    const BATCH_SIZE: usize = 50;
+    // Tells the child process where to write until it's killed
+    const CRASH_TEST_DB_VAR: &str = "AUTH_SERVER_CRASH_TEST_DB";
+
+    /// Users that only differ by name, since hashing a password for each of them would be slow.
+    fn batch_of_users(batch: usize, template: &serde_json::Value) -> Vec<UserRecord> {
+        (0..BATCH_SIZE)
+            .map(|i| {
+                let mut record = template.clone();
+                record["user"]["name"] = format!("user_{batch}_{i}").into();
+                serde_json::from_value(record).unwrap()
+            })
+            .collect()
+    }
+
+    /// Run in a child process by `killed_writer_leaves_database_consistent`, which kills it while
+    /// it's writing.
+    #[test]
+    #[ignore]
+    fn write_until_killed() {
+        let Ok(path) = std::env::var(CRASH_TEST_DB_VAR) else {
+            return;
+        };
+        let store = SqliteStore::open(Path::new(&path)).unwrap();
+        let template = UserRecord::new(
+            serde_json::from_str("\"template\"").unwrap(),
+            "correct horse battery staple".to_owned(),
+            HashSet::new(),
+        )
+        .unwrap();
+        let template = serde_json::to_value(template).unwrap();
+        for batch in 0.. {
+            store.import(&batch_of_users(batch, &template)).unwrap();
+        }
+    }
+
+    #[test]
+    fn killed_writer_leaves_database_consistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth-server.db");
+        let mut writer = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--ignored",
+                "--exact",
+                "store::sqlite::tests::write_until_killed",
+            ])
+            .env(CRASH_TEST_DB_VAR, &path)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        // Killed once it's well into writing, so most likely in the middle of a transaction
+        let started = Instant::now();
+        while SqliteStore::open(&path)
+            .and_then(|store| store.count())
+            .unwrap_or(0)
+            < 5 * BATCH_SIZE
+        {
+            assert!(
+                started.elapsed() < Duration::from_secs(120),
+                "writer made no progress"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        writer.kill().unwrap();
+        writer.wait().unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+        let integrity: String = store
+            .connection()
+            .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(integrity, "ok");
+        // Every batch was imported in a single transaction, so none can be partially there
+        let users = store.list().unwrap();
+        assert!(users.len() >= 5 * BATCH_SIZE);
+        assert_eq!(users.len() % BATCH_SIZE, 0);
+    }
+}
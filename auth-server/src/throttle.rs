@@ -0,0 +1,197 @@
+//! Throttling of logins, so that passwords and second factors can't be guessed at full speed.
+//!
+//! Failures are counted for each account and for each address logins come from. Once a few have
+//! failed in a row, every further attempt has to wait twice as long as the previous one. Accounts
+//! are also locked for a while once too many logins to them failed in a row. Unknown usernames are
+//! throttled the same way as existing ones, so that the responses don't tell them apart.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use server_common::user::Username;
+use time::OffsetDateTime;
+
+/// How many logins to an account can fail in a row before they are slowed down.
+const ACCOUNT_FREE_FAILURES: u32 = 3;
+/// How many logins from an address can fail in a row before they are slowed down, which is more
+/// than for accounts since many users can share an address.
+const ADDRESS_FREE_FAILURES: u32 = 10;
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// How long after the last one failures are forgotten.
+const FAILURE_MEMORY: Duration = Duration::from_secs(60 * 60);
+
+/// An account being locked after too many failed logins in a row, kept for admins to review.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Lockout {
+    pub username: Username,
+    /// Where the last failed login came from.
+    pub address: IpAddr,
+    pub failures: u32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub locked_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub locked_until: OffsetDateTime,
+}
+
+/// Consecutive failed logins to one account or from one address.
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+    /// Logins that were started but not finished yet. Once logins are slowed down only one can be
+    /// in progress at a time, so that sending them in parallel doesn't get around the backoff.
+    in_progress: u32,
+}
+
+impl Default for Failures {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            last_failure: Instant::now(),
+            locked_until: None,
+            in_progress: 0,
+        }
+    }
+}
+
+impl Failures {
+    /// How long until another login can be started, if it can't be now.
+    fn retry_after(&self, free_failures: u32) -> Option<Duration> {
+        if let Some(locked_until) = self.locked_until {
+            let remaining = locked_until.saturating_duration_since(Instant::now());
+            if !remaining.is_zero() {
+                return Some(remaining);
+            }
+        }
+        if self.count + self.in_progress < free_failures {
+            return None;
+        }
+
+        let excess = (self.count + 1).saturating_sub(free_failures);
+        let backoff = BASE_BACKOFF
+            .saturating_mul(2u32.saturating_pow(excess.saturating_sub(1)))
+            .min(MAX_BACKOFF);
+        let remaining = backoff.saturating_sub(self.last_failure.elapsed());
+        if self.in_progress > 0 {
+            // The login in progress will most likely fail too
+            Some(remaining.max(BASE_BACKOFF))
+        } else {
+            Some(remaining).filter(|remaining| !remaining.is_zero())
+        }
+    }
+
+    /// Forgets failures that are old enough, or that led to a lockout which ended.
+    fn expire(&mut self) {
+        let lockout_ended = self
+            .locked_until
+            .is_some_and(|locked_until| locked_until <= Instant::now());
+        if lockout_ended || self.last_failure.elapsed() >= FAILURE_MEMORY {
+            self.count = 0;
+            self.locked_until = None;
+        }
+    }
+
+    fn is_stale(&mut self) -> bool {
+        self.expire();
+        self.count == 0 && self.in_progress == 0
+    }
+}
+
+fn finish<'a, K: Eq + Hash>(
+    failures: &'a mut HashMap<K, Failures>,
+    key: &K,
+) -> Option<&'a mut Failures> {
+    let entry = failures.get_mut(key)?;
+    entry.in_progress = entry.in_progress.saturating_sub(1);
+    Some(entry)
+}
+
+/// Tracks failed logins for each account and address.
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    accounts: HashMap<Username, Failures>,
+    addresses: HashMap<IpAddr, Failures>,
+}
+
+impl LoginThrottle {
+    /// Starts a login to `username` from `address`, unless logins to it or from there have to
+    /// wait, in which case it returns how long.
+    ///
+    /// Every login started must be finished with `record_success`, `record_failure` or `abandon`.
+    pub fn start(&mut self, username: &Username, address: IpAddr) -> Result<(), Duration> {
+        let account = self.accounts.entry(username.clone()).or_default();
+        account.expire();
+        let address_failures = self.addresses.entry(address).or_default();
+        address_failures.expire();
+
+        let retry_after = account
+            .retry_after(ACCOUNT_FREE_FAILURES)
+            .max(address_failures.retry_after(ADDRESS_FREE_FAILURES));
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+
+        account.in_progress += 1;
+        address_failures.in_progress += 1;
+        Ok(())
+    }
+
+    /// Finishes a login that succeeded, forgetting the failed ones to the account.
+    ///
+    /// Failures from the address aren't forgotten, since otherwise logging into an account of
+    /// their own would let someone reset them.
+    pub fn record_success(&mut self, username: &Username, address: IpAddr) {
+        self.abandon(username, address);
+        self.accounts.remove(username);
+    }
+
+    /// Finishes a login that failed, locking the account if that made too many in a row.
+    pub fn record_failure(
+        &mut self,
+        username: &Username,
+        address: IpAddr,
+        max_failures: u32,
+        lockout_duration: Duration,
+    ) -> Option<Lockout> {
+        self.purge_stale();
+        let now = Instant::now();
+
+        if let Some(address_failures) = finish(&mut self.addresses, &address) {
+            address_failures.count += 1;
+            address_failures.last_failure = now;
+        }
+
+        let account = finish(&mut self.accounts, username)?;
+        account.count += 1;
+        account.last_failure = now;
+        if account.count < max_failures || account.locked_until.is_some() {
+            return None;
+        }
+
+        account.locked_until = Some(now + lockout_duration);
+        let locked_at = OffsetDateTime::now_utc();
+        Some(Lockout {
+            username: username.clone(),
+            address,
+            failures: account.count,
+            locked_at,
+            locked_until: locked_at + lockout_duration,
+        })
+    }
+
+    /// Finishes a login that neither succeeded nor failed, such as one that ran into an error.
+    pub fn abandon(&mut self, username: &Username, address: IpAddr) {
+        finish(&mut self.accounts, username);
+        finish(&mut self.addresses, &address);
+    }
+
+    fn purge_stale(&mut self) {
+        self.accounts.retain(|_, failures| !failures.is_stale());
+        self.addresses.retain(|_, failures| !failures.is_stale());
+    }
+}
@@ -0,0 +1,162 @@
+//! Time-based one-time passwords as defined by RFC 6238, for two-factor authentication.
+//!
+//! Codes are the 6 digit, HMAC-SHA1 based ones that every authenticator app supports, and change
+//! every 30 seconds.
+
+use std::fmt;
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use server_common::user::Username;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+const ISSUER: &str = "CipherShare";
+const SECRET_SIZE: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// How many steps away from the current one a code is accepted from, to allow for clock drift.
+const ALLOWED_DRIFT: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_SIZE: usize = 16;
+
+/// Generates a new base32 encoded secret, the encoding that authenticator apps expect.
+pub fn generate_secret() -> String {
+    let mut secret = [0; SECRET_SIZE];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// Creates the `otpauth://` URI that authenticator apps are set up with, usually as a QR code.
+pub fn provisioning_uri(username: &Username, secret: &str) -> String {
+    let label = percent_encode(&format!("{}:{}", ISSUER, username));
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        label, secret, ISSUER, DIGITS, STEP_SECONDS
+    )
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(secret).expect("HMAC should accept keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation, from RFC 4226
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Checks `code` against the steps around the current time, returning the step it's valid for.
+fn verify_code(secret: &str, code: &str) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    if code.len() != DIGITS as usize || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current_step = OffsetDateTime::now_utc().unix_timestamp() / STEP_SECONDS;
+    (current_step - ALLOWED_DRIFT..=current_step + ALLOWED_DRIFT)
+        .find(|&step| code_at(&secret, step) == code)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    format!("{:x}", Sha256::digest(code))
+}
+
+/// A user's second factor: the secret their authenticator app generates codes from, and the
+/// recovery codes they can use once each if they lose it.
+///
+/// Only hashes of the recovery codes are kept. They are random enough that a fast hash is enough.
+#[derive(Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct TwoFactor {
+    secret: String,
+    /// The step of the last code accepted, so that a code can't be used twice.
+    #[serde(default)]
+    last_used_step: i64,
+    recovery_code_hashes: Vec<String>,
+}
+
+impl TwoFactor {
+    /// Enrolls a secret once the user proved that their authenticator app is set up by giving a
+    /// code from it, returning the recovery codes to show them.
+    pub fn enroll(secret: String, code: &str) -> Option<(Self, Vec<String>)> {
+        // The secret is sent back by the client, so it might not be one that was generated here.
+        if !BASE32_NOPAD
+            .decode(secret.as_bytes())
+            .is_ok_and(|secret| secret.len() == SECRET_SIZE)
+        {
+            return None;
+        }
+        let step = verify_code(&secret, code)?;
+
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(RECOVERY_CODE_SIZE)
+                    .map(char::from)
+                    .collect()
+            })
+            .collect();
+        let two_factor = Self {
+            secret,
+            last_used_step: step,
+            recovery_code_hashes: recovery_codes
+                .iter()
+                .map(|code| hash_recovery_code(code))
+                .collect(),
+        };
+        Some((two_factor, recovery_codes))
+    }
+
+    /// Checks a code from the authenticator app or a recovery code, using it up.
+    pub fn verify(&mut self, code: &str) -> bool {
+        if let Some(step) = verify_code(&self.secret, code) {
+            if step <= self.last_used_step {
+                return false;
+            }
+            self.last_used_step = step;
+            return true;
+        }
+
+        let hash = hash_recovery_code(code.trim());
+        let used = self.recovery_code_hashes.len();
+        self.recovery_code_hashes
+            .retain(|recovery_code_hash| *recovery_code_hash != hash);
+        self.recovery_code_hashes.len() < used
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.recovery_code_hashes.len()
+    }
+}
+
+impl fmt::Debug for TwoFactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TwoFactor")
+            .field("last_used_step", &self.last_used_step)
+            .field("recovery_codes_left", &self.recovery_codes_left())
+            .finish_non_exhaustive()
+    }
+}
@@ -4,15 +4,79 @@ use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
     Argon2,
 };
+use once_cell::sync::Lazy;
 use password_hash::{PasswordHash, PasswordVerifier};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 use server_common::user::*;
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
 use tracing::error;
 
+use crate::totp::TwoFactor;
+
+const PASSWORD_RESET_TOKEN_SIZE: usize = 32;
+const PASSWORD_RESET_LIFETIME: Duration = Duration::days(1);
+
+/// Hash of a random password, which logins to unknown users are checked against.
+static DUMMY_PASSWORD_HASH: Lazy<String> = Lazy::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(salt.as_str().as_bytes(), &salt)
+        .expect("hashing a password with a generated salt shouldn't fail")
+        .to_string()
+});
+
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct UserRecord {
     user: User,
     password_hash: String,
+    #[serde(default)]
+    encryption_keys: Option<EncryptionKeys>,
+    #[serde(default)]
+    two_factor: Option<TwoFactor>,
+    #[serde(default)]
+    password_reset: Option<PasswordReset>,
+    /// Unknown for users created before it was recorded.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    created_at: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    last_login_at: Option<OffsetDateTime>,
+    /// Disabled users can't log in, but keep their files and links.
+    #[serde(default)]
+    disabled: bool,
+}
+
+/// A single-use token that an admin issued for a user to set a new password with. Only its hash
+/// is kept.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PasswordReset {
+    token_hash: String,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token))
+}
+
+pub fn hash_password(password: &str) -> Result<String, password_hash::errors::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// A user's long-term key pair for end-to-end encryption, which is generated by the client.
+///
+/// The private key is encrypted by the client with a key derived from the user's password, so it
+/// can only be recovered by the user.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct EncryptionKeys {
+    /// SPKI PEM encoded RSA public key, which file keys are wrapped with.
+    pub public_key: String,
+    /// Opaque to the server.
+    pub encrypted_private_key: String,
 }
 
 impl UserRecord {
@@ -21,15 +85,15 @@ impl UserRecord {
         password: String,
         roles: HashSet<Role>,
     ) -> Result<Self, password_hash::errors::Error> {
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let password_hash = argon2
-            .hash_password(password.as_bytes(), &salt)?
-            .to_string();
-
         Ok(Self {
             user: User::new(username, roles),
-            password_hash,
+            password_hash: hash_password(&password)?,
+            encryption_keys: None,
+            two_factor: None,
+            password_reset: None,
+            created_at: Some(OffsetDateTime::now_utc()),
+            last_login_at: None,
+            disabled: false,
         })
     }
 
@@ -45,6 +109,100 @@ impl UserRecord {
         self.user.roles_mut()
     }
 
+    pub fn created_at(&self) -> Option<OffsetDateTime> {
+        self.created_at
+    }
+
+    pub fn last_login_at(&self) -> Option<OffsetDateTime> {
+        self.last_login_at
+    }
+
+    pub fn record_login(&mut self) {
+        self.last_login_at = Some(OffsetDateTime::now_utc());
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+
+    pub fn encryption_keys(&self) -> Option<&EncryptionKeys> {
+        self.encryption_keys.as_ref()
+    }
+
+    pub fn set_encryption_keys(&mut self, keys: EncryptionKeys) {
+        self.encryption_keys = Some(keys);
+    }
+
+    /// Replaces the encrypted private key, which has to be encrypted again whenever the password
+    /// changes. Returns `false` if the user has no encryption keys.
+    pub fn set_encrypted_private_key(&mut self, encrypted_private_key: String) -> bool {
+        match &mut self.encryption_keys {
+            Some(keys) => {
+                keys.encrypted_private_key = encrypted_private_key;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear_encryption_keys(&mut self) {
+        self.encryption_keys = None;
+    }
+
+    /// Replaces the password with one hashed by `hash_password`, which makes any pending reset
+    /// token useless.
+    pub fn set_password_hash(&mut self, password_hash: String) {
+        self.password_hash = password_hash;
+        self.password_reset = None;
+    }
+
+    /// Issues a token to reset the password with, replacing any previous one. Returns it along
+    /// with when it expires.
+    pub fn start_password_reset(&mut self) -> (String, OffsetDateTime) {
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(PASSWORD_RESET_TOKEN_SIZE)
+            .map(char::from)
+            .collect();
+        let expires_at = OffsetDateTime::now_utc() + PASSWORD_RESET_LIFETIME;
+        self.password_reset = Some(PasswordReset {
+            token_hash: hash_token(&token),
+            expires_at,
+        });
+        (token, expires_at)
+    }
+
+    /// Checks a password reset token, using it up if it's valid.
+    pub fn take_password_reset(&mut self, token: &str) -> bool {
+        let valid = self.password_reset.as_ref().is_some_and(|reset| {
+            reset.expires_at > OffsetDateTime::now_utc() && reset.token_hash == hash_token(token)
+        });
+        if valid {
+            self.password_reset = None;
+        }
+        valid
+    }
+
+    pub fn two_factor_enabled(&self) -> bool {
+        self.two_factor.is_some()
+    }
+
+    pub fn two_factor(&self) -> Option<&TwoFactor> {
+        self.two_factor.as_ref()
+    }
+
+    pub fn two_factor_mut(&mut self) -> Option<&mut TwoFactor> {
+        self.two_factor.as_mut()
+    }
+
+    pub fn set_two_factor(&mut self, two_factor: Option<TwoFactor>) {
+        self.two_factor = two_factor;
+    }
+
     pub fn check_password(&self, password: &str) -> bool {
         let hash = match PasswordHash::new(&self.password_hash) {
             Ok(hash) => hash,
@@ -63,4 +221,10 @@ impl UserRecord {
             }
         }
     }
+
+    /// Checks a password against a hash that it never matches, taking as long as `check_password`.
+    pub fn check_dummy_password(password: &str) {
+        let hash = PasswordHash::new(&DUMMY_PASSWORD_HASH).expect("generated hash should parse");
+        let _ = Argon2::default().verify_password(password.as_bytes(), &hash);
+    }
 }
@@ -8,49 +8,51 @@ use axum::{
     response::{IntoResponse, Response},
     Json, RequestPartsExt, TypedHeader,
 };
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, EncodingKey, Header, Validation};
 use once_cell::sync::Lazy;
 use prae::Wrapper;
-use rsa::pkcs1::EncodeRsaPublicKey;
-use rsa::pkcs8::DecodePublicKey;
-use rsa::RsaPublicKey;
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use time::{Duration, OffsetDateTime};
 use tracing::error;
-use openssl::{
-    sign::Verifier, 
-    hash::MessageDigest, 
-    pkey::PKey, 
-    rsa::Rsa, 
-};
-use base64::{Engine, engine::general_purpose};
-
 
-use crate::user::{Role, Username};
-use crate::util::new_reqwest_client_from_certificates;
+use crate::jwks;
+use crate::revocation::{self, TokenId};
+use crate::signature::{self, verify_request, SignaturePolicy, SIGNATURE_HEADER};
+use crate::user::{GroupName, Permission, Principal, Role, Username};
+use crate::util::{new_reqwest_client_from_certificates, service_url};
 
+/// The role the first user is given, which must allow managing users.
 pub static ADMIN_ROLE: Lazy<Role> = Lazy::new(|| Role::new(String::from("admin")).unwrap());
-pub static VIEWER_ROLE: Lazy<Role> = Lazy::new(|| Role::new(String::from("viewer")).unwrap());
-pub static UPLOADER_ROLE: Lazy<Role> = Lazy::new(|| Role::new(String::from("uploader")).unwrap());
-pub static SHARER_ROLE: Lazy<Role> = Lazy::new(|| Role::new(String::from("sharer")).unwrap());
+
+pub static FILES_READ_PERMISSION: Lazy<Permission> = Lazy::new(|| permission("files.read"));
+pub static FILES_WRITE_PERMISSION: Lazy<Permission> = Lazy::new(|| permission("files.write"));
+pub static FILES_DELETE_PERMISSION: Lazy<Permission> = Lazy::new(|| permission("files.delete"));
+/// Allows giving other users and groups access to one's own files.
+pub static FILES_SHARE_PERMISSION: Lazy<Permission> = Lazy::new(|| permission("files.share"));
+/// Allows access to the files of every user.
+pub static FILES_ADMIN_PERMISSION: Lazy<Permission> = Lazy::new(|| permission("files.admin"));
+pub static LINKS_CREATE_PERMISSION: Lazy<Permission> = Lazy::new(|| permission("links.create"));
+/// Allows removing the links of every user.
+pub static LINKS_ADMIN_PERMISSION: Lazy<Permission> = Lazy::new(|| permission("links.admin"));
+pub static USERS_MANAGE_PERMISSION: Lazy<Permission> = Lazy::new(|| permission("users.manage"));
+
+fn permission(name: &str) -> Permission {
+    Permission::new(String::from(name)).unwrap()
+}
+
+/// The auth server that this service gets signing keys and revoked tokens from.
 pub static AUTH_CLIENT: OnceLock<AuthClient> = OnceLock::new();
 
 const JWT_ALGORITHM: Algorithm = Algorithm::RS384;
-
-const AUTH_SERVER_PUBLIC_KEY_PATH: &str = "cfg/auth-server.pem";
-static AUTH_SERVER_PUBLIC_KEY: Lazy<DecodingKey> = Lazy::new(|| {
-    DecodingKey::from_rsa_der(
-        RsaPublicKey::read_public_key_pem_file(AUTH_SERVER_PUBLIC_KEY_PATH)
-            .expect("Failed to load the public key for the authentication server from")
-            .to_pkcs1_der()
-            .expect("Failed to convert key to DER format")
-            .as_bytes(),
-    )
-});
+const TOKEN_ID_SIZE: usize = 24;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
+    #[serde(rename = "jti")]
+    id: String,
     username: Username,
     #[serde(rename = "nbf")]
     not_before: i64,
@@ -63,7 +65,13 @@ impl Claims {
     /// Creates a new `Claims` valid from the current instant and until `duration` has passed.
     pub fn create(username: Username, duration: Duration, public_key: String) -> Self {
         let now = OffsetDateTime::now_utc();
+        let id = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_ID_SIZE)
+            .map(char::from)
+            .collect();
         Self {
+            id,
             username,
             not_before: now.unix_timestamp(),
             expires: (now + duration).unix_timestamp(),
@@ -71,22 +79,64 @@ impl Claims {
         }
     }
 
+    /// Gets the ID of the token, which is unique to every token issued.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn token_id(&self) -> TokenId {
+        TokenId {
+            id: self.id.clone(),
+            expires: self.expires,
+        }
+    }
+
     pub fn get_public_key(&self) -> &str {
         &self.public_key
     }
 
-    /// Decodes and validates `Claims` from a JWT string.
-    pub fn from_encoded(encoded: &str) -> Result<Self, jsonwebtoken::errors::Error> {
+    /// Decodes and validates `Claims` from a JWT string, with the auth server key it names.
+    pub async fn from_encoded(encoded: &str) -> Result<Self, AuthError> {
+        let key_id = jsonwebtoken::decode_header(encoded)
+            .map_err(|err| {
+                error!(?err, "Failed to decode JWT header");
+                AuthError::InvalidToken
+            })?
+            .kid
+            .ok_or(AuthError::InvalidToken)?;
+        let key = match jwks::decoding_key(&key_id).await {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                error!(key_id, "JWT signed with unknown key");
+                return Err(AuthError::InvalidToken);
+            }
+            Err(err) => {
+                error!(?err, "Failed to get signing keys from auth server");
+                return Err(AuthError::KeysUnavailable);
+            }
+        };
+
         let mut validation = Validation::new(JWT_ALGORITHM);
         validation.set_required_spec_claims(&["exp", "nbf"]);
         validation.validate_nbf = true;
 
-        jsonwebtoken::decode(encoded, &AUTH_SERVER_PUBLIC_KEY, &validation).map(|jwt| jwt.claims)
+        jsonwebtoken::decode(encoded, &key, &validation)
+            .map(|jwt| jwt.claims)
+            .map_err(|err| {
+                error!(?err, "Failed to decode or validate JWT");
+                AuthError::InvalidToken
+            })
     }
 
-    /// Encodes the `Claims` into a JWT, signed by the provided key.
-    pub fn encode(&self, key: &EncodingKey) -> Result<String, jsonwebtoken::errors::Error> {
-        jsonwebtoken::encode(&Header::new(JWT_ALGORITHM), &self, key)
+    /// Encodes the `Claims` into a JWT, signed by the provided key and naming it by `key_id`.
+    pub fn encode(
+        &self,
+        key_id: &str,
+        key: &EncodingKey,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(JWT_ALGORITHM);
+        header.kid = Some(key_id.to_owned());
+        jsonwebtoken::encode(&header, &self, key)
     }
 
     pub fn username(&self) -> &Username {
@@ -109,38 +159,27 @@ where
             .await
             .map_err(|_| AuthError::MissingToken)?;
 
-        let claim = Claims::from_encoded(bearer.token()).map_err(|err| {
-            error!(?err, "Failed to decode or validate JWT");
-            AuthError::InvalidToken
-        })?;
+        let claim = Claims::from_encoded(bearer.token()).await?;
+        revocation::check(&claim).await?;
 
-        if !parts.headers.contains_key("Hash") {
-            return Ok(claim)
+        let config = signature::config();
+        match config.policy {
+            SignaturePolicy::Off => return Ok(claim),
+            SignaturePolicy::Optional if !parts.headers.contains_key(SIGNATURE_HEADER) => {
+                return Ok(claim)
+            }
+            SignaturePolicy::Optional | SignaturePolicy::Required => {}
         }
 
-        let uri = parts.uri.path_and_query().map(|pq| pq.to_string()).unwrap_or_default();
-        let uri = format!("https://localhost:8080{}", uri);
-
-        let hash_string = parts.headers.get("Hash").expect("Failed getting signature").to_str().expect("Failed passing signature to string").to_string();
-        let hash = general_purpose::STANDARD.decode(hash_string).expect("Failed decoding signature");
-        
-        let timestamp = parts.headers.get("Timestamp").expect("Failed getting timestamp").to_str().expect("Failed passing timestamp to string").to_string();
-        let data = format!("{}+{}", timestamp, uri);
-
-        let rsa = Rsa::public_key_from_pem(claim.get_public_key().as_bytes()).expect("Failed reading publ");
-        let pkey = PKey::from_rsa(rsa).expect("Failed to get public key");
-
-        let pkey_ref = pkey.as_ref();
+        let uri = parts
+            .uri
+            .path_and_query()
+            .map(|pq| pq.to_string())
+            .unwrap_or_default();
+        let uri = format!("{}{}", config.origin, uri);
 
-        let mut verifier = Verifier::new(MessageDigest::sha256(), pkey_ref).expect("Failed to get verifier");
-        verifier.update(data.as_bytes()).expect("Failed to update the verifier");
-
-        if verifier.verify(&hash).expect("Failed to verify the signature") {
-            Ok(claim)
-        }
-        else {
-            Err(AuthError::InvalidSignature)
-        }
+        verify_request(parts, &uri, claim.get_public_key())?;
+        Ok(claim)
     }
 }
 
@@ -148,7 +187,12 @@ where
 pub enum AuthError {
     InvalidToken,
     MissingToken,
-    InvalidSignature
+    InvalidSignature,
+    ExpiredSignature,
+    ReplayedRequest,
+    RevokedToken,
+    RevocationCheckFailed,
+    KeysUnavailable,
 }
 
 impl IntoResponse for AuthError {
@@ -156,7 +200,18 @@ impl IntoResponse for AuthError {
         let (status, error_message) = match self {
             AuthError::InvalidToken => (StatusCode::BAD_REQUEST, "Invalid token"),
             AuthError::MissingToken => (StatusCode::BAD_REQUEST, "Missing token"),
-            AuthError::InvalidSignature => (StatusCode::BAD_REQUEST, "Invalid signature")
+            AuthError::InvalidSignature => (StatusCode::BAD_REQUEST, "Invalid signature"),
+            AuthError::ExpiredSignature => (StatusCode::BAD_REQUEST, "Expired signature"),
+            AuthError::ReplayedRequest => (StatusCode::BAD_REQUEST, "Replayed request"),
+            AuthError::RevokedToken => (StatusCode::BAD_REQUEST, "Revoked token"),
+            AuthError::RevocationCheckFailed => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to check whether token was revoked",
+            ),
+            AuthError::KeysUnavailable => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to get token signing keys",
+            ),
         };
         let body = Json(json!({
             "error": error_message,
@@ -176,27 +231,98 @@ impl AuthClient {
         Ok(Self { client, authority })
     }
 
-    pub async fn user_has_role(
+    /// Creates a client that makes its requests with `client`, instead of one set up with the
+    /// certificates in `cfg/tls`.
+    pub fn with_client(client: reqwest::Client, authority: String) -> Self {
+        Self { client, authority }
+    }
+
+    /// Checks whether any of a user's roles allows them `permission`.
+    pub async fn user_has_permission(
         &self,
         user: &Username,
-        role: &Role,
+        permission: &Permission,
     ) -> Result<bool, reqwest::Error> {
-        let url = format!("https://{}/user/{}/is/{}", &self.authority, user, role);
-        self.client.get(url).send().await?.json().await
+        let url = service_url(
+            &self.authority,
+            &["user", user.as_ref(), &permission.to_string()],
+        );
+        self.client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Gets the names of the groups a user is a member of.
+    pub async fn user_groups(&self, user: &Username) -> Result<Vec<GroupName>, reqwest::Error> {
+        let url = service_url(&self.authority, &["user", user.as_ref(), "groups"]);
+        self.client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Checks whether a user or group exists, before access is given to it.
+    pub async fn principal_exists(&self, principal: &Principal) -> Result<bool, reqwest::Error> {
+        let url = match principal {
+            Principal::User(user) => {
+                service_url(&self.authority, &["user", user.as_ref(), "exists"])
+            }
+            Principal::Group(group) => {
+                service_url(&self.authority, &["groups", group.as_ref(), "exists"])
+            }
+        };
+        self.client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Gets the keys that tokens are signed with.
+    pub async fn key_set(&self) -> Result<JwkSet, reqwest::Error> {
+        let url = service_url(&self.authority, &[".well-known", "jwks.json"]);
+        self.client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Gets the tokens that were revoked and haven't expired yet.
+    pub async fn revoked_tokens(&self) -> Result<Vec<TokenId>, reqwest::Error> {
+        let url = service_url(&self.authority, &["revoked-tokens"]);
+        self.client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
     }
 
-    pub async fn user_has_role_into_response(
+    pub async fn user_has_permission_into_response(
         &self,
         user: &Username,
-        role: &Role,
+        permission: &Permission,
     ) -> Result<(), Response> {
-        match self.user_has_role(user, role).await {
+        match self.user_has_permission(user, permission).await {
             Ok(true) => Ok(()),
             Ok(false) => Err(StatusCode::FORBIDDEN.into_response()),
             Err(err) => {
                 error!(
                     ?err,
-                    "Failed to get role membership information from auth server"
+                    "Failed to get permission information from auth server"
                 );
                 Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
             }
@@ -19,6 +19,32 @@ macro_rules! server_args {
             config: std::path::PathBuf,
         }
 
+        impl ::server_common::ServerArgs for Args {
+            fn port(&self) -> Option<u16> {
+                self.port
+            }
+
+            fn config_path(&self) -> &std::path::Path {
+                &self.config
+            }
+        }
+    };
+    ($default_cfg_path: literal, $command: ty) => {
+        #[derive(Debug, ::server_common::prelude::clap::Parser)]
+        struct Args {
+            /// Port to listen on
+            #[arg(short, long)]
+            port: Option<u16>,
+
+            /// Path to config file
+            #[arg(short, long, default_value = $default_cfg_path)]
+            config: std::path::PathBuf,
+
+            /// Runs a maintenance command instead of the server
+            #[command(subcommand)]
+            command: Option<$command>,
+        }
+
         impl ::server_common::ServerArgs for Args {
             fn port(&self) -> Option<u16> {
                 self.port
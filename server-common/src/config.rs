@@ -1,13 +1,28 @@
 use serde::Deserialize;
 
+use crate::signature::SignaturePolicy;
+use crate::DEFAULT_ORIGIN;
+
 pub trait ServerConfig {
     fn name() -> &'static str;
     fn port(&self) -> u16;
+    fn general(&self) -> &GeneralConfig;
 }
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct GeneralConfig {
     pub port: u16,
+    /// Whether requests must be signed with the private key of the session.
+    #[serde(default)]
+    pub signatures: SignaturePolicy,
+    /// Origin that clients address their requests to, which signed URIs are relative to.
+    #[serde(default = "default_origin")]
+    pub origin: String,
+}
+
+fn default_origin() -> String {
+    String::from(DEFAULT_ORIGIN)
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -52,6 +67,10 @@ macro_rules! server_config {
             fn port(&self) -> u16 {
                 self.general.port
             }
+
+            fn general(&self) -> &::server_common::GeneralConfig {
+                &self.general
+            }
         }
     }
 }
@@ -0,0 +1,122 @@
+//! The keys JWTs are signed with, which the auth server publishes as a JWKS document.
+//!
+//! Services fetch the key set from the auth server and keep it for a while. A token signed with a
+//! key that isn't known yet makes them fetch it again, which is how they pick up a key as soon as
+//! the auth server rotates it in. While the auth server can't be reached, the keys fetched last
+//! keep being used for a while.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::DecodingKey;
+use once_cell::sync::Lazy;
+use tracing::error;
+
+use crate::auth::AUTH_CLIENT;
+
+/// How long a fetched key set is used before fetching it again.
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+/// How long after a fetch a token signed with an unknown key can cause another one, so that
+/// tokens with made up key IDs can't flood the auth server.
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a fetched key set is still used while fetching it again fails. The auth server keeps
+/// verifying tokens signed with a retired key for a day, so the keys can't be trusted much longer.
+const MAX_STALENESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+static KEYS: Lazy<RwLock<KeyCache>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct KeyCache {
+    keys: HashMap<String, DecodingKey>,
+    fetched_at: Option<Instant>,
+    /// When fetching the key set last failed, so that an unreachable auth server isn't asked again
+    /// on every request.
+    failed_at: Option<Instant>,
+}
+
+impl KeyCache {
+    fn is_usable(&self) -> bool {
+        self.fetched_at
+            .is_some_and(|fetched_at| fetched_at.elapsed() < MAX_STALENESS)
+    }
+
+    fn is_due(&self, id: &str) -> bool {
+        let Some(fetched_at) = self.fetched_at.filter(|_| self.is_usable()) else {
+            return true;
+        };
+        if self
+            .failed_at
+            .is_some_and(|failed_at| failed_at.elapsed() < MIN_REFETCH_INTERVAL)
+        {
+            return false;
+        }
+
+        fetched_at.elapsed() >= CACHE_TTL
+            || (!self.keys.contains_key(id) && fetched_at.elapsed() >= MIN_REFETCH_INTERVAL)
+    }
+
+    fn replace(&mut self, key_set: &JwkSet) {
+        // Keys without an ID or of a type that can't be used are skipped.
+        self.keys = key_set
+            .keys
+            .iter()
+            .filter_map(|jwk| {
+                let id = jwk.common.key_id.clone()?;
+                Some((id, DecodingKey::from_jwk(jwk).ok()?))
+            })
+            .collect();
+        self.fetched_at = Some(Instant::now());
+        self.failed_at = None;
+    }
+}
+
+/// Sets the keys of this service, which is how the auth server verifies its own tokens.
+pub fn set_key_set(key_set: &JwkSet) {
+    KEYS.write().expect("poisoned lock").replace(key_set);
+}
+
+/// Gets the key with the given ID, fetching the key set if it's out of date. Fails only if the
+/// key set can't be fetched and the one fetched last is too old to use.
+pub async fn decoding_key(id: &str) -> Result<Option<DecodingKey>, reqwest::Error> {
+    if let Some(client) = AUTH_CLIENT.get() {
+        let due = KEYS.read().expect("poisoned lock").is_due(id);
+        if due {
+            match client.key_set().await {
+                Ok(key_set) => KEYS.write().expect("poisoned lock").replace(&key_set),
+                Err(err) => {
+                    let mut keys = KEYS.write().expect("poisoned lock");
+                    if !keys.is_usable() {
+                        return Err(err);
+                    }
+                    error!(
+                        ?err,
+                        "Failed to refresh signing keys, using the ones fetched before"
+                    );
+                    keys.failed_at = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    Ok(KEYS.read().expect("poisoned lock").keys.get(id).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_fetches_keep_expired_keys_in_use() {
+        let mut cache = KeyCache {
+            fetched_at: Instant::now().checked_sub(CACHE_TTL),
+            ..Default::default()
+        };
+        assert!(cache.is_usable());
+        assert!(cache.is_due("key"));
+
+        cache.failed_at = Some(Instant::now());
+        assert!(!cache.is_due("key"));
+    }
+}
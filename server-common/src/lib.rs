@@ -1,26 +1,37 @@
 pub mod auth;
 mod cli;
 mod config;
+pub mod jwks;
+pub mod revocation;
+pub mod signature;
 pub mod user;
 pub mod util;
 
 use std::fs::File;
+use std::future::Future;
 use std::io::Read;
 use std::net::{Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 
 use anyhow::Context;
-use axum::http::HeaderValue;
 use axum::Router;
 use axum_server::tls_rustls::RustlsConfig;
 use serde::de::DeserializeOwned;
 use tokio::runtime::Runtime;
+use tower_http::cors::AllowOrigin;
 use tracing_subscriber::EnvFilter;
 
 pub use crate::cli::ServerArgs;
 pub use crate::config::{AuthClientConfig, GeneralConfig, ServerConfig};
 
-pub const ORIGIN: HeaderValue = HeaderValue::from_static("https://localhost:8080");
+/// Origin that clients address their requests to unless [`GeneralConfig::origin`] is set.
+pub const DEFAULT_ORIGIN: &str = "https://localhost:8080";
+
+/// Allows cross-origin requests from the configured origin. It's looked up as requests arrive, so
+/// that routers can be built before the config is loaded.
+pub fn allowed_origin() -> AllowOrigin {
+    AllowOrigin::predicate(|origin, _| origin.as_bytes() == signature::config().origin.as_bytes())
+}
 
 pub mod prelude {
     pub use crate::{server_args, server_config, ServerConfig};
@@ -76,10 +87,16 @@ pub fn server_main<C: ServerConfig + DeserializeOwned, S: Clone + Send + Sync +
 
     let config: C = load_config(args.config_path())?;
     let port = args.port().unwrap_or(config.port());
+    signature::configure(config.general());
     let addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
 
-    let state = get_state(config)?;
-    Runtime::new()?.block_on(async {
+    let runtime = Runtime::new()?;
+    // Entering the runtime lets `get_state` spawn background tasks.
+    let state = {
+        let _guard = runtime.enter();
+        get_state(config)?
+    };
+    runtime.block_on(async {
         axum_server::bind_rustls(
             addr,
             get_tls_config(C::name())
@@ -88,6 +105,7 @@ pub fn server_main<C: ServerConfig + DeserializeOwned, S: Clone + Send + Sync +
         )
         .serve(
             router
+                .layer(axum::middleware::from_fn(signature::verify_body_digest))
                 .with_state(state)
                 .into_make_service_with_connect_info::<SocketAddr>(),
         )
@@ -95,3 +113,14 @@ pub fn server_main<C: ServerConfig + DeserializeOwned, S: Clone + Send + Sync +
         .context("HTTP server error")
     })
 }
+
+/// Runs a one-off maintenance command against a server's config and data, instead of the server.
+pub fn command_main<C: ServerConfig + DeserializeOwned, F: Future<Output = anyhow::Result<()>>>(
+    args: &impl ServerArgs,
+    command: impl FnOnce(C) -> F,
+) -> anyhow::Result<()> {
+    tracing_setup()?;
+
+    let config: C = load_config(args.config_path())?;
+    Runtime::new()?.block_on(command(config))
+}
@@ -0,0 +1,226 @@
+//! Revocation of JWTs before they expire, such as when the session they were issued for ends.
+//!
+//! The auth server keeps the list of revoked tokens. Other services fetch it from the auth server
+//! set in [`AUTH_CLIENT`] and keep it for a few seconds, so that they don't ask for it on every
+//! request. While the auth server can't be reached, the list fetched last keeps being used for a
+//! few minutes.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::error;
+
+use crate::auth::{AuthError, Claims, AUTH_CLIENT};
+
+/// How long a fetched revocation list is used before fetching it again, and how long after a
+/// failed fetch the next one is made.
+const CACHE_TTL: Duration = Duration::from_secs(10);
+/// How long a fetched revocation list is still used while fetching it again fails. Tokens revoked
+/// in the meantime are accepted until then, so this is kept short.
+const MAX_STALENESS: Duration = Duration::from_secs(5 * 60);
+
+static REVOKED: Lazy<Revocations> = Lazy::new(Default::default);
+
+/// Identifies a JWT, along with when it expires and no longer needs to be revoked.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct TokenId {
+    pub id: String,
+    pub expires: i64,
+}
+
+impl TokenId {
+    pub fn is_expired(&self) -> bool {
+        self.expires < OffsetDateTime::now_utc().unix_timestamp()
+    }
+}
+
+#[derive(Debug, Default)]
+struct RevocationList {
+    /// Expiry of each revoked token, by ID.
+    tokens: HashMap<String, i64>,
+    fetched_at: Option<Instant>,
+    /// When fetching the list last failed, so that an unreachable auth server isn't asked again on
+    /// every request.
+    failed_at: Option<Instant>,
+}
+
+impl RevocationList {
+    fn is_usable(&self) -> bool {
+        self.fetched_at
+            .is_some_and(|fetched_at| fetched_at.elapsed() < MAX_STALENESS)
+    }
+
+    fn is_due(&self) -> bool {
+        if !self.is_usable() {
+            return true;
+        }
+        let recently = |at: Option<Instant>| at.is_some_and(|at| at.elapsed() < CACHE_TTL);
+        !recently(self.fetched_at) && !recently(self.failed_at)
+    }
+
+    fn replace(&mut self, tokens: Vec<TokenId>) {
+        self.tokens = tokens
+            .into_iter()
+            .map(|token| (token.id, token.expires))
+            .collect();
+        self.fetched_at = Some(Instant::now());
+        self.failed_at = None;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Revocations {
+    list: RwLock<RevocationList>,
+    /// Held while fetching the list, so that requests arriving meanwhile wait for that fetch
+    /// instead of each making their own.
+    refresh: tokio::sync::Mutex<()>,
+}
+
+impl Revocations {
+    /// Fetches the list if it's out of date. Fails only if fetching fails and the list fetched last
+    /// is too old to use.
+    async fn refresh<F, E>(&self, fetch: impl FnOnce() -> F) -> Result<(), E>
+    where
+        F: Future<Output = Result<Vec<TokenId>, E>>,
+        E: std::fmt::Debug,
+    {
+        if !self.list.read().expect("poisoned lock").is_due() {
+            return Ok(());
+        }
+        let _refresh = self.refresh.lock().await;
+        // The list may have been fetched while waiting for the lock.
+        if !self.list.read().expect("poisoned lock").is_due() {
+            return Ok(());
+        }
+
+        match fetch().await {
+            Ok(tokens) => self.list.write().expect("poisoned lock").replace(tokens),
+            Err(err) => {
+                let mut list = self.list.write().expect("poisoned lock");
+                if !list.is_usable() {
+                    return Err(err);
+                }
+                error!(
+                    ?err,
+                    "Failed to refresh revoked tokens, using the ones fetched before"
+                );
+                list.failed_at = Some(Instant::now());
+            }
+        }
+        Ok(())
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.list
+            .read()
+            .expect("poisoned lock")
+            .tokens
+            .contains_key(id)
+    }
+}
+
+/// Adds tokens to the revocation list of this service, which is how the auth server keeps its own.
+pub fn revoke(tokens: impl IntoIterator<Item = TokenId>) {
+    let mut list = REVOKED.list.write().expect("poisoned lock");
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    list.tokens.retain(|_, expires| *expires >= now);
+    list.tokens.extend(
+        tokens
+            .into_iter()
+            .filter(|token| !token.is_expired())
+            .map(|token| (token.id, token.expires)),
+    );
+}
+
+/// Checks whether a token was revoked, fetching the revocation list if it's out of date. Fails
+/// only if the list can't be fetched and the one fetched last is too old to use.
+pub async fn is_revoked(id: &str) -> Result<bool, reqwest::Error> {
+    if let Some(client) = AUTH_CLIENT.get() {
+        REVOKED.refresh(|| client.revoked_tokens()).await?;
+    }
+
+    Ok(REVOKED.contains(id))
+}
+
+/// Rejects `claims` if their token was revoked.
+pub async fn check(claims: &Claims) -> Result<(), AuthError> {
+    match is_revoked(claims.id()).await {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(AuthError::RevokedToken),
+        Err(err) => {
+            error!(?err, "Failed to get revoked tokens from auth server");
+            Err(AuthError::RevocationCheckFailed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures_util::future;
+
+    use super::*;
+
+    fn token(id: &str) -> TokenId {
+        TokenId {
+            id: id.to_owned(),
+            expires: OffsetDateTime::now_utc().unix_timestamp() + 3600,
+        }
+    }
+
+    fn age(revocations: &Revocations, by: Duration) {
+        let mut list = revocations.list.write().expect("poisoned lock");
+        list.fetched_at = list.fetched_at.and_then(|at| at.checked_sub(by));
+    }
+
+    #[tokio::test]
+    async fn auth_server_down_keeps_last_list_in_use() {
+        let revocations = Revocations::default();
+        let fetches = AtomicUsize::new(0);
+        let auth_server_down = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            Err::<Vec<TokenId>, _>("connection refused")
+        };
+
+        assert!(revocations.refresh(auth_server_down).await.is_err());
+
+        revocations
+            .refresh(|| async { Ok::<_, &str>(vec![token("revoked")]) })
+            .await
+            .unwrap();
+        age(&revocations, CACHE_TTL);
+        revocations.refresh(auth_server_down).await.unwrap();
+        assert!(revocations.contains("revoked"));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // The auth server isn't asked again on every request while it's down.
+        revocations.refresh(auth_server_down).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        age(&revocations, MAX_STALENESS);
+        assert!(revocations.refresh(auth_server_down).await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn concurrent_checks_fetch_once() {
+        let revocations = Revocations::default();
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok::<_, &str>(vec![token("revoked")])
+        };
+
+        let results = future::join_all((0..10).map(|_| revocations.refresh(fetch))).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(revocations.contains("revoked"));
+    }
+}
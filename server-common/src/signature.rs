@@ -0,0 +1,243 @@
+//! Request signatures, made by clients with the private key of their session.
+//!
+//! A signature covers the time the request was made, a nonce, the method, the URI and a digest
+//! of the body, joined with `+`. Requests are only accepted within a window around the time they
+//! were made, and each nonce is only accepted once within that window, so a captured request
+//! can't be replayed or turned into a different one.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::io;
+use std::sync::{Mutex, OnceLock};
+
+use axum::body::{Body, Bytes};
+use axum::http::request::Parts;
+use axum::http::{HeaderName, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::{engine::general_purpose, Engine};
+use futures_util::{stream, StreamExt};
+use once_cell::sync::Lazy;
+use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, sign::Verifier};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tracing::error;
+
+use crate::auth::AuthError;
+use crate::{GeneralConfig, DEFAULT_ORIGIN};
+
+pub const SIGNATURE_HEADER: HeaderName = HeaderName::from_static("hash");
+pub const TIMESTAMP_HEADER: HeaderName = HeaderName::from_static("timestamp");
+pub const NONCE_HEADER: HeaderName = HeaderName::from_static("nonce");
+pub const BODY_DIGEST_HEADER: HeaderName = HeaderName::from_static("body-digest");
+/// Headers that signed requests carry, which must be allowed for cross-origin requests.
+pub const SIGNATURE_HEADERS: [HeaderName; 4] = [
+    SIGNATURE_HEADER,
+    TIMESTAMP_HEADER,
+    NONCE_HEADER,
+    BODY_DIGEST_HEADER,
+];
+
+/// How far the time a request was made may be from the current time, in milliseconds.
+const MAX_CLOCK_SKEW: i64 = 5 * 60 * 1000;
+const MAX_NONCE_LENGTH: usize = 64;
+
+static NONCES: Lazy<Mutex<Nonces>> = Lazy::new(Default::default);
+static CONFIG: OnceLock<SignatureConfig> = OnceLock::new();
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignaturePolicy {
+    /// Signatures aren't checked.
+    Off,
+    /// Signatures are checked when given.
+    Optional,
+    /// Every authenticated request must be signed.
+    #[default]
+    Required,
+}
+
+#[derive(Clone, Debug)]
+pub struct SignatureConfig {
+    pub policy: SignaturePolicy,
+    pub origin: String,
+}
+
+/// Sets how this service checks signatures. Only the first call has any effect.
+pub fn configure(config: &GeneralConfig) {
+    let _ = CONFIG.set(SignatureConfig {
+        policy: config.signatures,
+        origin: config.origin.trim_end_matches('/').to_owned(),
+    });
+}
+
+/// Gets how this service checks signatures, which is to require them if it wasn't configured.
+pub fn config() -> &'static SignatureConfig {
+    CONFIG.get_or_init(|| SignatureConfig {
+        policy: SignaturePolicy::default(),
+        origin: String::from(DEFAULT_ORIGIN),
+    })
+}
+
+fn now_millis() -> i64 {
+    (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+fn header<'a>(parts: &'a Parts, name: &HeaderName) -> Result<&'a str, AuthError> {
+    parts
+        .headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(AuthError::InvalidSignature)
+}
+
+/// Nonces of the requests accepted by this service.
+#[derive(Debug, Default)]
+struct Nonces {
+    used: HashSet<String>,
+    /// The same nonces along with when they can be forgotten, soonest first, so that forgetting
+    /// them only goes over the ones that can be.
+    expiry: BinaryHeap<Reverse<(i64, String)>>,
+}
+
+impl Nonces {
+    /// Records `nonce` as used until `forget_at`, returning `false` if it already was.
+    fn insert(&mut self, nonce: &str, forget_at: i64, now: i64) -> bool {
+        while let Some(Reverse((forget_at, _))) = self.expiry.peek() {
+            if *forget_at > now {
+                break;
+            }
+            if let Some(Reverse((_, forgotten))) = self.expiry.pop() {
+                self.used.remove(&forgotten);
+            }
+        }
+
+        if !self.used.insert(nonce.to_owned()) {
+            return false;
+        }
+        self.expiry.push(Reverse((forget_at, nonce.to_owned())));
+        true
+    }
+}
+
+/// Records `nonce` as used, returning `false` if it already was.
+fn use_nonce(nonce: &str, timestamp: i64) -> bool {
+    // Once the request would be rejected for being too old, its nonce no longer needs to be
+    // remembered.
+    NONCES
+        .lock()
+        .expect("poisoned lock")
+        .insert(nonce, timestamp + MAX_CLOCK_SKEW, now_millis())
+}
+
+/// Verifies the signature of a request made to `uri` with the public key of the session it was
+/// made in.
+pub fn verify_request(parts: &Parts, uri: &str, public_key: &str) -> Result<(), AuthError> {
+    let signature = general_purpose::STANDARD
+        .decode(header(parts, &SIGNATURE_HEADER)?)
+        .map_err(|_| AuthError::InvalidSignature)?;
+    let timestamp = header(parts, &TIMESTAMP_HEADER)?;
+    let nonce = header(parts, &NONCE_HEADER)?;
+    let body_digest = header(parts, &BODY_DIGEST_HEADER)?;
+
+    let time = timestamp
+        .parse::<i64>()
+        .map_err(|_| AuthError::InvalidSignature)?;
+    if (now_millis() - time).abs() > MAX_CLOCK_SKEW {
+        return Err(AuthError::ExpiredSignature);
+    }
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LENGTH {
+        return Err(AuthError::InvalidSignature);
+    }
+
+    let data = format!(
+        "{}+{}+{}+{}+{}",
+        timestamp, nonce, parts.method, uri, body_digest
+    );
+    let verified = Rsa::public_key_from_pem(public_key.as_bytes())
+        .and_then(PKey::from_rsa)
+        .and_then(|key| {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+            verifier.update(data.as_bytes())?;
+            verifier.verify(&signature)
+        })
+        .map_err(|err| {
+            error!(?err, "Failed to verify signature");
+            AuthError::InvalidSignature
+        })?;
+    if !verified {
+        return Err(AuthError::InvalidSignature);
+    }
+
+    // Only checked once the signature is known to be valid, so that unsigned requests can't use
+    // up nonces.
+    if !use_nonce(nonce, time) {
+        return Err(AuthError::ReplayedRequest);
+    }
+    Ok(())
+}
+
+/// Makes the body of requests with a digest fail to be read if it doesn't match the digest.
+///
+/// Bodies are streamed, so a mismatch is only noticed at the end of the body, which makes the
+/// handler reading it fail rather than act on a body that wasn't signed.
+pub async fn verify_body_digest(request: Request<Body>, next: Next<Body>) -> Response {
+    let Some(digest) = request.headers().get(BODY_DIGEST_HEADER) else {
+        return next.run(request).await;
+    };
+    let Some(expected) = digest
+        .to_str()
+        .ok()
+        .and_then(|digest| general_purpose::STANDARD.decode(digest).ok())
+    else {
+        return (StatusCode::BAD_REQUEST, "Invalid body digest").into_response();
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = stream::try_unfold((body, Sha256::new()), move |(mut body, mut hasher)| {
+        let expected = expected.clone();
+        async move {
+            match body.next().await {
+                Some(chunk) => {
+                    let chunk: Bytes = chunk.map_err(io::Error::other)?;
+                    hasher.update(&chunk);
+                    Ok(Some((chunk, (body, hasher))))
+                }
+                None => {
+                    if hasher.finalize().as_slice() != expected {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "body doesn't match its digest",
+                        ));
+                    }
+                    Ok(None)
+                }
+            }
+        }
+    });
+
+    next.run(Request::from_parts(parts, Body::wrap_stream(body)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonces_are_forgotten_once_expired() {
+        let mut nonces = Nonces::default();
+        assert!(nonces.insert("late", 300, 0));
+        assert!(nonces.insert("early", 100, 0));
+        assert!(!nonces.insert("early", 100, 50));
+
+        assert!(nonces.insert("early", 400, 100));
+        assert!(!nonces.insert("late", 300, 100));
+        assert_eq!(nonces.used.len(), 2);
+        assert_eq!(nonces.expiry.len(), 2);
+
+        assert!(nonces.insert("late", 500, 300));
+        assert!(!nonces.insert("early", 400, 300));
+    }
+}
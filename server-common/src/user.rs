@@ -54,3 +54,51 @@ impl fmt::Display for Role {
         write!(f, "{}", self.0)
     }
 }
+
+prae::define! {
+    /// Something a role allows its users to do, such as `files.read`.
+    #[derive(Clone, Debug, Eq, PartialEq, Hash)]
+    pub Permission: String;
+    adjust |permission| *permission = permission.trim().to_lowercase();
+    ensure |permission| !permission.is_empty() && permission.chars().all(|ch| ch.is_alphanumeric() || ch == '.' || ch == '_');
+    plugins: [prae::impl_serde];
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+prae::define! {
+    /// The name of a group of users, which files can be shared with.
+    #[derive(Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+    pub GroupName: String;
+    adjust |name| *name = name.trim().to_lowercase();
+    ensure |name| !name.is_empty() && name.chars().all(|ch| ch.is_alphanumeric() || ch == '_' || ch == '-');
+    plugins: [prae::impl_serde];
+}
+
+impl fmt::Display for GroupName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A user or a group that access to a file is given to, written as `{"user": "alice"}` or
+/// `{"group": "sales"}`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Principal {
+    User(Username),
+    Group(GroupName),
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(name) => write!(f, "user:{name}"),
+            Self::Group(name) => write!(f, "group:{name}"),
+        }
+    }
+}
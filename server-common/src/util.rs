@@ -3,7 +3,7 @@ use std::path::PathBuf;
 
 use anyhow::Context;
 use reqwest::tls::{Certificate, Identity};
-use reqwest::Client;
+use reqwest::{Client, Url};
 
 pub fn new_reqwest_client_from_certificates(name: &str) -> anyhow::Result<Client> {
     let cert_dir = PathBuf::from("cfg/tls");
@@ -21,6 +21,19 @@ pub fn new_reqwest_client_from_certificates(name: &str) -> anyhow::Result<Client
         .context("failed to build reqwest client")
 }
 
+/// Builds the URL of an endpoint of the service at `authority`, with each of `segments` encoded as
+/// a single path segment, so that names can't change which endpoint is addressed.
+///
+/// Panics if `authority` isn't a valid host and port.
+pub fn service_url(authority: &str, segments: &[&str]) -> Url {
+    let mut url =
+        Url::parse(&format!("https://{}", authority)).expect("service authorities should be valid");
+    url.path_segments_mut()
+        .expect("HTTPS URLs have a path")
+        .extend(segments);
+    url
+}
+
 #[macro_export]
 macro_rules! unwrap_result_and_500_on_error {
     ($e: expr, $err_msg: expr) => {
@@ -34,3 +47,17 @@ macro_rules! unwrap_result_and_500_on_error {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_url_segments_are_encoded() {
+        let url = service_url("localhost:8000", &["file-acl", "alice", "../a/b?c#d"]);
+        assert_eq!(
+            url.as_str(),
+            "https://localhost:8000/file-acl/alice/..%2Fa%2Fb%3Fc%23d"
+        );
+    }
+}
@@ -1,6 +1,8 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+
 use serde::Deserialize;
 
-use server_common::user::Role;
 use server_common::{server_config, AuthClientConfig};
 
 server_config! {
@@ -17,7 +19,13 @@ server_config! {
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct FileShareConfig {
-    pub share_role: Role,
+    known_services: HashSet<IpAddr>,
+}
+
+impl FileShareConfig {
+    pub fn address_is_service(&self, address: &IpAddr) -> bool {
+        self.known_services.contains(address)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
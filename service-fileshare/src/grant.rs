@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use server_common::user::{Principal, Username};
+
+/// What a grant lets its grantee do with a file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    /// Replacing the contents, which includes reading them.
+    Write,
+}
+
+impl Access {
+    /// The rights of the filestore ACL entry that gives this access.
+    pub fn rights(self) -> &'static [&'static str] {
+        match self {
+            Self::Read => &["read"],
+            Self::Write => &["read", "write"],
+        }
+    }
+}
+
+/// Access to one of the owner's files, given to a user or a group who have to be logged in to use
+/// it, unlike links.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Grant {
+    owner: Username,
+    file_name: String,
+    grantee: Principal,
+    access: Access,
+    #[serde(with = "time::serde::rfc3339")]
+    granted_at: OffsetDateTime,
+}
+
+impl Grant {
+    pub fn new(owner: Username, file_name: String, grantee: Principal, access: Access) -> Self {
+        Self {
+            owner,
+            file_name,
+            grantee,
+            access,
+            granted_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    pub fn owner(&self) -> &Username {
+        &self.owner
+    }
+
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    pub fn grantee(&self) -> &Principal {
+        &self.grantee
+    }
+}
@@ -0,0 +1,6 @@
+pub mod config;
+mod grant;
+pub mod link;
+pub mod server;
+pub mod state;
+pub mod store;
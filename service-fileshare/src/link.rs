@@ -1,5 +1,12 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
+    Argon2,
+};
+use password_hash::{PasswordHash, PasswordVerifier};
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::error;
 
 use server_common::user::Username;
 
@@ -7,16 +14,100 @@ use server_common::user::Username;
 pub struct Link {
     username: Username,
     file_name: String,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    expires_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    max_downloads: Option<u32>,
+    #[serde(default)]
+    downloads: u32,
+    #[serde(default)]
+    password_hash: Option<String>,
 }
 
 impl Link {
-    pub fn new(username: Username, file_name: String) -> Self {
+    pub fn new(
+        username: Username,
+        file_name: String,
+        expires_at: Option<OffsetDateTime>,
+        max_downloads: Option<u32>,
+        password_hash: Option<String>,
+    ) -> Self {
         Self {
             username,
             file_name,
+            expires_at,
+            max_downloads,
+            downloads: 0,
+            password_hash,
         }
     }
 
+    /// Hashes a password to protect a link with, in the format expected by `Link::new`.
+    pub fn hash_password(password: &str) -> Result<String, password_hash::errors::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(Argon2::default()
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string())
+    }
+
+    pub fn is_password_protected(&self) -> bool {
+        self.password_hash.is_some()
+    }
+
+    /// Checks `password` against the link's password. Links without a password accept anything.
+    pub fn check_password(&self, password: &str) -> bool {
+        let Some(password_hash) = &self.password_hash else {
+            return true;
+        };
+
+        let hash = match PasswordHash::new(password_hash) {
+            Ok(hash) => hash,
+            Err(err) => {
+                error!(
+                    ?err,
+                    file_name = self.file_name,
+                    "Error parsing password hash"
+                );
+                return false;
+            }
+        };
+
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => true,
+            Err(password_hash::Error::Password) => false,
+            Err(err) => {
+                error!(
+                    ?err,
+                    file_name = self.file_name,
+                    "Error parsing password hash"
+                );
+                false
+            }
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= OffsetDateTime::now_utc())
+    }
+
+    /// The number of downloads left before the link stops working, if it is limited.
+    pub fn remaining_downloads(&self) -> Option<u32> {
+        self.max_downloads
+            .map(|max| max.saturating_sub(self.downloads))
+    }
+
+    /// Whether another download may start, given the downloads in progress, which count towards
+    /// `max_downloads` until they finish.
+    pub fn allows_download(&self, pending_downloads: u32) -> bool {
+        self.max_downloads
+            .is_none_or(|max| self.downloads + pending_downloads < max)
+    }
+
+    pub fn record_download(&mut self) {
+        self.downloads += 1;
+    }
+
     pub fn username(&self) -> &Username {
         &self.username
     }
@@ -24,6 +115,18 @@ impl Link {
     pub fn file_name(&self) -> &str {
         &self.file_name
     }
+
+    pub fn expires_at(&self) -> Option<OffsetDateTime> {
+        self.expires_at
+    }
+
+    pub fn max_downloads(&self) -> Option<u32> {
+        self.max_downloads
+    }
+
+    pub fn downloads(&self) -> u32 {
+        self.downloads
+    }
 }
 
 const LINK_CODE_SIZE: usize = 16;
@@ -42,3 +145,21 @@ impl LinkCode {
         )
     }
 }
+
+impl AsRef<str> for LinkCode {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for LinkCode {
+    fn from(code: String) -> Self {
+        Self(code)
+    }
+}
+
+impl Default for LinkCode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,13 +1,7 @@
-mod config;
-pub mod link;
-mod server;
-mod state;
-
 use server_common::prelude::*;
-use state::{get_state, AppState};
-
-use crate::config::Config;
-use crate::server::get_router;
+use service_fileshare::config::Config;
+use service_fileshare::server::get_router;
+use service_fileshare::state::{get_state, AppState};
 
 server_args!("cfg/service-fileshare.toml");
 
@@ -1,116 +1,371 @@
-use axum::extract::{Path, State};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use axum::body::StreamBody;
+use axum::extract::{ConnectInfo, Path, State};
 use axum::http::header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE};
-use axum::http::{Method, StatusCode};
-use axum::response::{IntoResponse, Response};
-use axum::routing::{delete, get, put};
-use axum::{Json, Router};
-use serde::Deserialize;
+use axum::http::{HeaderMap, HeaderName, Method, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Form, Json, Router};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use time::{Duration, OffsetDateTime};
 use tower_http::cors::CorsLayer;
-use tracing::error;
+use tracing::{error, info};
+
+use crate::grant::{Access, Grant};
+use crate::link::{Link, LinkCode};
+use crate::state::{AppState, DownloadError, CLIENT};
+use server_common::auth::{
+    Claims, AUTH_CLIENT, FILES_SHARE_PERMISSION, LINKS_ADMIN_PERMISSION, LINKS_CREATE_PERMISSION,
+};
+use server_common::user::{GroupName, Principal, Username};
+use server_common::util::service_url;
+use server_common::{allowed_origin, unwrap_result_and_500_on_error};
 
-use crate::link::LinkCode;
-use crate::state::{AppState, AUTH_CLIENT, CLIENT};
-use server_common::auth::Claims;
-use server_common::{unwrap_result_and_500_on_error, ORIGIN};
+const LINK_PASSWORD_HEADER: HeaderName = HeaderName::from_static("link-password");
+const PASSWORD_FORM: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Password required</title></head>
+<body>
+<form method="post">
+<p>This file is protected by a password.</p>
+<p>{error}</p>
+<input type="password" name="password" autofocus required>
+<button type="submit">Download</button>
+</form>
+</body>
+</html>
+"#;
 
 pub fn get_router() -> Router<AppState> {
     Router::new()
-        .route("/config", get(config))
-        .route("/db", get(db))
         .route("/links", get(user_links))
         .route("/link", put(add_link))
         .route("/link/:code", get(file_of_link))
+        .route("/link/:code", post(file_of_link_with_password))
         .route("/link/:code", delete(delete_link))
+        .route("/grants", get(user_grants))
+        .route("/grants/received", get(received_grants))
+        .route("/grant", put(add_grant))
+        .route("/grant", delete(revoke_grant))
+        .route("/file-links/:owner/:file", delete(delete_file_links))
+        .route("/user-links/:owner", delete(delete_user_links))
+        .route("/group-grants/:group", delete(delete_group_grants))
         .layer(
             CorsLayer::new()
-                .allow_methods([Method::GET, Method::PUT, Method::DELETE, Method::OPTIONS])
-                .allow_headers([ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE])
-                .allow_origin(ORIGIN),
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::PUT,
+                    Method::DELETE,
+                    Method::OPTIONS,
+                ])
+                .allow_headers([
+                    ACCEPT_ENCODING,
+                    AUTHORIZATION,
+                    CONTENT_TYPE,
+                    LINK_PASSWORD_HEADER,
+                ])
+                .allow_origin(allowed_origin()),
         )
 }
 
-async fn config(State(state): State<AppState>) -> String {
-    format!("{:#?}", state.read().expect("poisoned lock").config)
+/// A link as reported to its owner, including how much it has been used.
+#[derive(Serialize)]
+struct LinkSummary<'a> {
+    file_name: &'a str,
+    #[serde(with = "time::serde::rfc3339::option")]
+    expires_at: Option<OffsetDateTime>,
+    max_downloads: Option<u32>,
+    downloads: u32,
+    remaining_downloads: Option<u32>,
+    password_protected: bool,
 }
 
-async fn db(State(state): State<AppState>) -> String {
-    format!("{:#?}", state.read().expect("poisoned lock").db)
+impl<'a> From<&'a Link> for LinkSummary<'a> {
+    fn from(link: &'a Link) -> Self {
+        Self {
+            file_name: link.file_name(),
+            expires_at: link.expires_at(),
+            max_downloads: link.max_downloads(),
+            downloads: link.downloads(),
+            remaining_downloads: link.remaining_downloads(),
+            password_protected: link.is_password_protected(),
+        }
+    }
 }
 
 // Get a list of Links for a user (by Username string)
 #[tracing::instrument(skip(state), ret)]
 async fn user_links(State(state): State<AppState>, claims: Claims) -> Response {
     let username = claims.username();
-    let state = state.read().expect("poisoned lock");
-    let links = state.db.get_file_links_for_user(username);
+    let links = unwrap_result_and_500_on_error!(
+        state
+            .read()
+            .expect("poisoned lock")
+            .db
+            .get_file_links_for_user(username),
+        "error reading database"
+    );
+    let links: HashMap<_, _> = links
+        .iter()
+        .map(|(code, link)| (code, LinkSummary::from(link)))
+        .collect();
     Json(json!(links)).into_response()
 }
 
 // Get a file name from a link then return the file in the response (from filestore service)
-#[tracing::instrument(skip(state), ret)]
-async fn file_of_link(State(state): State<AppState>, Path(code): Path<LinkCode>) -> Response {
-    let (link, authority) = {
+#[tracing::instrument(skip(state, headers), ret)]
+async fn file_of_link(
+    State(state): State<AppState>,
+    Path(code): Path<LinkCode>,
+    headers: HeaderMap,
+) -> Response {
+    let password = headers
+        .get(LINK_PASSWORD_HEADER)
+        .and_then(|password| password.to_str().ok());
+    if let Some(response) = check_link_password(&state, &code, password, false).await {
+        return response;
+    }
+
+    download_link(state, code).await
+}
+
+#[derive(Deserialize)]
+struct LinkPasswordForm {
+    password: String,
+}
+
+// Same as `file_of_link`, but with the password of the link submitted from a form
+#[tracing::instrument(skip(state, form), ret)]
+async fn file_of_link_with_password(
+    State(state): State<AppState>,
+    Path(code): Path<LinkCode>,
+    Form(form): Form<LinkPasswordForm>,
+) -> Response {
+    if let Some(response) = check_link_password(&state, &code, Some(&form.password), true).await {
+        return response;
+    }
+
+    download_link(state, code).await
+}
+
+/// Checks the password given for the link with `code`, if it is password protected, returning
+/// the response to reject the request with if the check fails.
+///
+/// When the password is wrong, the response asks for it again with an HTML form if `from_form` is
+/// set, or describes the error in JSON otherwise.
+async fn check_link_password(
+    state: &AppState,
+    code: &LinkCode,
+    password: Option<&str>,
+    from_form: bool,
+) -> Option<Response> {
+    let link = {
         let state = state.read().expect("poisoned lock");
-        (state.db.get_link_by_code(&code).map(|v| v.to_owned()), state.config.filestore_server.authority())
+        match state.db.get_link_by_code(code) {
+            Ok(Some(link)) if link.is_password_protected() => link,
+            // Links that don't exist are reported as such when downloading
+            Ok(_) => return None,
+            Err(err) => {
+                error!(?err, "Error reading database");
+                return Some(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        }
     };
-    if let Some(link) = link {
-        let file_name = link.file_name();
-        match CLIENT
-            .get()
-            .unwrap()
-            .get(format!(
-                "https://{}/file-shared/{}",
-                authority, &file_name
-            ))
-            .send()
-            .await
-        {
-            Ok(response) => {
-                let status = response.status();
-                let headers = response.headers().to_owned();
-                match response.bytes().await {
-                    Ok(body) => (status, headers, body).into_response(),
-                    Err(err) => {
-                        error!(?err, "Error in response");
-                        StatusCode::INTERNAL_SERVER_ERROR.into_response()
-                    }
-                }
+
+    let Some(password) = password else {
+        // Browsers following the link are shown a form to enter the password
+        return Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                Html(PASSWORD_FORM.replace("{error}", "")),
+            )
+                .into_response(),
+        );
+    };
+
+    if !state
+        .write()
+        .expect("poisoned lock")
+        .password_failures
+        .reserve_attempt(code)
+    {
+        return Some(StatusCode::TOO_MANY_REQUESTS.into_response());
+    }
+
+    // Verifying the password is deliberately slow, so it mustn't block the runtime
+    let password = password.to_owned();
+    let correct = match tokio::task::spawn_blocking(move || link.check_password(&password)).await {
+        Ok(correct) => correct,
+        Err(err) => {
+            error!(?err, "Error checking link password");
+            return Some(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    if correct {
+        state
+            .write()
+            .expect("poisoned lock")
+            .password_failures
+            .release_attempt(code);
+        return None;
+    }
+
+    if from_form {
+        Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                Html(PASSWORD_FORM.replace("{error}", "Wrong password.")),
+            )
+                .into_response(),
+        )
+    } else {
+        Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "error": "invalid password" })),
+            )
+                .into_response(),
+        )
+    }
+}
+
+/// Streams the file of the link with `code` from the filestore service, counting the download.
+async fn download_link(state: AppState, code: LinkCode) -> Response {
+    let (link, authority) = {
+        let mut state = state.write().expect("poisoned lock");
+        let link = match state.db.start_download(&code) {
+            Ok(link) => link,
+            Err(DownloadError::NotFound) => return StatusCode::NOT_FOUND.into_response(),
+            Err(DownloadError::Expired | DownloadError::Exhausted) => {
+                return StatusCode::GONE.into_response()
             }
-            Err(err) => {
-                error!(?err, "Failed to get file");
+            Err(DownloadError::Store(err)) => {
+                error!(?err, "Error reading database");
                 return StatusCode::INTERNAL_SERVER_ERROR.into_response();
             }
+        };
+        (link, state.config.filestore_server.authority())
+    };
+
+    let file_name = link.file_name();
+    let result = CLIENT
+        .get()
+        .unwrap()
+        .get(service_url(
+            &authority,
+            &["file-shared", link.username().as_ref(), file_name],
+        ))
+        .send()
+        .await;
+
+    // Only downloads that the filestore service agreed to serve count towards the limit.
+    let succeeded = matches!(&result, Ok(response) if response.status().is_success());
+    if let Err(err) = state
+        .write()
+        .expect("poisoned lock")
+        .db
+        .finish_download(&code, succeeded)
+    {
+        error!(?err, "Error saving database");
+    }
+
+    match result {
+        Ok(response) => {
+            let status = response.status();
+            let headers = response.headers().to_owned();
+            (status, headers, StreamBody::new(response.bytes_stream())).into_response()
+        }
+        Err(err) => {
+            error!(?err, "Failed to get file");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
-    } else {
-        StatusCode::NOT_FOUND.into_response()
     }
 }
 
 #[derive(Debug, Deserialize)]
 struct AddLinkRequest {
     file_name: String,
+    /// Instant after which the link stops working.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    expires_at: Option<OffsetDateTime>,
+    /// Number of seconds after which the link stops working, as an alternative to `expires_at`.
+    expires_in: Option<u32>,
+    /// Number of downloads after which the link stops working.
+    max_downloads: Option<u32>,
+    /// Password that must be given to download the file through the link.
+    password: Option<String>,
+}
+
+impl AddLinkRequest {
+    /// Resolves the requested expiry into an instant, if one was requested.
+    fn expiry(&self) -> Result<Option<OffsetDateTime>, &'static str> {
+        let now = OffsetDateTime::now_utc();
+        let expires_at = match (self.expires_at, self.expires_in) {
+            (Some(_), Some(_)) => return Err("only one of expires_at and expires_in may be given"),
+            (Some(expires_at), None) => expires_at,
+            (None, Some(seconds)) => now + Duration::seconds(seconds.into()),
+            (None, None) => return Ok(None),
+        };
+
+        if expires_at <= now {
+            return Err("expiry must be in the future");
+        }
+        Ok(Some(expires_at))
+    }
 }
 
 // Post a new Link to the database
-#[tracing::instrument(skip(state), ret)]
+#[tracing::instrument(skip(state, request), fields(file_name = %request.file_name), ret)]
 async fn add_link(
     State(state): State<AppState>,
     claims: Claims,
     Json(request): Json<AddLinkRequest>,
 ) -> Response {
-    let (role, filestore_authority) = {
-        let state = state.read().expect("poisoned lock");
-        (
-            state.config.file_share.share_role.clone(),
-            state.config.filestore_server.authority(),
-        )
+    let expires_at = match request.expiry() {
+        Ok(expires_at) => expires_at,
+        Err(error) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response()
+        }
+    };
+
+    let password_hash = match request.password.as_deref() {
+        Some("") => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "blank password" })),
+            )
+                .into_response()
+        }
+        Some(password) => Some(unwrap_result_and_500_on_error!(
+            Link::hash_password(password),
+            "failed to hash password"
+        )),
+        None => None,
     };
 
+    if request.max_downloads == Some(0) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "max_downloads must be at least 1" })),
+        )
+            .into_response();
+    }
+
+    let filestore_authority = state
+        .read()
+        .expect("poisoned lock")
+        .config
+        .filestore_server
+        .authority();
+
     if let Err(response) = AUTH_CLIENT
         .get()
         .unwrap()
-        .user_has_role_into_response(claims.username(), &role)
+        .user_has_permission_into_response(claims.username(), &LINKS_CREATE_PERMISSION)
         .await
     {
         return response;
@@ -119,9 +374,13 @@ async fn add_link(
     match CLIENT
         .get()
         .unwrap()
-        .get(format!(
-            "https://{}/file-exists/{}",
-            filestore_authority, &request.file_name
+        .get(service_url(
+            &filestore_authority,
+            &[
+                "file-exists",
+                claims.username().as_ref(),
+                &request.file_name,
+            ],
         ))
         .send()
         .await
@@ -144,11 +403,13 @@ async fn add_link(
     }
 
     let code = unwrap_result_and_500_on_error!(
-        state
-            .write()
-            .expect("poisoned lock")
-            .db
-            .add_link(claims.username().to_owned(), request.file_name),
+        state.write().expect("poisoned lock").db.add_link(
+            claims.username().to_owned(),
+            request.file_name,
+            expires_at,
+            request.max_downloads,
+            password_hash,
+        ),
         "error saving database"
     );
 
@@ -168,23 +429,27 @@ async fn delete_link(
         .db
         .get_link_by_code(&code)
     {
-        Some(link) => link.username().to_owned(),
-        None => return StatusCode::NOT_FOUND.into_response(),
+        Ok(Some(link)) => link.username().to_owned(),
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "Error reading database");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
     };
 
     if claims.username() != &link_owner {
         match AUTH_CLIENT
             .get()
             .unwrap()
-            .user_has_role(claims.username(), &server_common::auth::ADMIN_ROLE)
+            .user_has_permission(claims.username(), &LINKS_ADMIN_PERMISSION)
             .await
         {
-            Ok(true) => {} // admin users can remove any link
+            Ok(true) => {} // links admins can remove any link
             Ok(false) => return StatusCode::FORBIDDEN.into_response(),
             Err(err) => {
                 error!(
                     ?err,
-                    "Failed to get role membership information from auth server"
+                    "Failed to get permission information from auth server"
                 );
                 return StatusCode::INTERNAL_SERVER_ERROR.into_response();
             }
@@ -201,3 +466,301 @@ async fn delete_link(
     }
     .into_response()
 }
+
+// Get the grants a user gave
+#[tracing::instrument(skip(state), ret)]
+async fn user_grants(State(state): State<AppState>, claims: Claims) -> Response {
+    Json(unwrap_result_and_500_on_error!(
+        state
+            .read()
+            .expect("poisoned lock")
+            .db
+            .grants_by_user(claims.username()),
+        "error reading database"
+    ))
+    .into_response()
+}
+
+/// Lists the files other users shared with the caller, directly or through one of their groups.
+#[tracing::instrument(skip(state), ret)]
+async fn received_grants(State(state): State<AppState>, claims: Claims) -> Response {
+    let groups = match AUTH_CLIENT
+        .get()
+        .unwrap()
+        .user_groups(claims.username())
+        .await
+    {
+        Ok(groups) => groups,
+        Err(err) => {
+            error!(?err, "Failed to get group information from auth server");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let grantees: Vec<_> = std::iter::once(Principal::User(claims.username().clone()))
+        .chain(groups.into_iter().map(Principal::Group))
+        .collect();
+    let mut grants = unwrap_result_and_500_on_error!(
+        state.read().expect("poisoned lock").db.grants_to(&grantees),
+        "error reading database"
+    );
+    // Sharing with one of your own groups doesn't share anything with you
+    grants.retain(|grant| grant.owner() != claims.username());
+    Json(grants).into_response()
+}
+
+/// Sets the rights of a user or group to one of `owner`'s files in the filestore service's ACL
+/// of the file, with no rights removing them. Returns `Ok(false)` if there's no such file.
+async fn set_file_rights(
+    state: &AppState,
+    owner: &Username,
+    file_name: &str,
+    grantee: &Principal,
+    rights: &[&str],
+) -> Result<bool, reqwest::Error> {
+    #[derive(Serialize)]
+    struct AclEntry<'a> {
+        #[serde(flatten)]
+        principal: &'a Principal,
+        rights: &'a [&'a str],
+    }
+
+    let authority = state
+        .read()
+        .expect("poisoned lock")
+        .config
+        .filestore_server
+        .authority();
+    let response = CLIENT
+        .get()
+        .unwrap()
+        .put(service_url(
+            &authority,
+            &["file-acl", owner.as_ref(), file_name],
+        ))
+        .json(&AclEntry {
+            principal: grantee,
+            rights,
+        })
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    response.error_for_status().map(|_| true)
+}
+
+#[derive(Debug, Deserialize)]
+struct AddGrantRequest {
+    file_name: String,
+    grantee: Principal,
+    access: Access,
+}
+
+/// Gives a user or group access to one of the caller's files, replacing any access they were
+/// given to it before.
+#[tracing::instrument(skip(state), ret)]
+async fn add_grant(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(request): Json<AddGrantRequest>,
+) -> Response {
+    if request.grantee == Principal::User(claims.username().clone()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "files can't be shared with their owner" })),
+        )
+            .into_response();
+    }
+
+    let auth_client = AUTH_CLIENT.get().unwrap();
+    if let Err(response) = auth_client
+        .user_has_permission_into_response(claims.username(), &FILES_SHARE_PERMISSION)
+        .await
+    {
+        return response;
+    }
+
+    // A grant to a user or group that doesn't exist would go to whoever takes the name later
+    match auth_client.principal_exists(&request.grantee).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "no such user or group" })),
+            )
+                .into_response()
+        }
+        Err(err) => {
+            error!(?err, "Failed to look up grantee on auth server");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    }
+
+    // The ACL is what the filestore service enforces, so it's changed first, and a grant is only
+    // recorded once it's in effect.
+    match set_file_rights(
+        &state,
+        claims.username(),
+        &request.file_name,
+        &request.grantee,
+        request.access.rights(),
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "Failed to change access to file");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    }
+
+    let grant = Grant::new(
+        claims.username().clone(),
+        request.file_name,
+        request.grantee,
+        request.access,
+    );
+    unwrap_result_and_500_on_error!(
+        state.write().expect("poisoned lock").db.add_grant(&grant),
+        "error saving database"
+    );
+    info!(
+        owner = %grant.owner(),
+        file = grant.file_name(),
+        grantee = %grant.grantee(),
+        "Granted access to file"
+    );
+    Json(grant).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct RevokeGrantRequest {
+    file_name: String,
+    grantee: Principal,
+}
+
+/// Takes back the access the caller gave a user or group to one of their files.
+#[tracing::instrument(skip(state), ret)]
+async fn revoke_grant(
+    State(state): State<AppState>,
+    claims: Claims,
+    Json(request): Json<RevokeGrantRequest>,
+) -> Response {
+    let owner = claims.username();
+    match state.read().expect("poisoned lock").db.get_grant(
+        owner,
+        &request.file_name,
+        &request.grantee,
+    ) {
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "Error reading database");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    // A file that no longer exists can't be accessed anyway
+    if let Err(err) =
+        set_file_rights(&state, owner, &request.file_name, &request.grantee, &[]).await
+    {
+        error!(?err, "Failed to change access to file");
+        return StatusCode::BAD_GATEWAY.into_response();
+    }
+
+    unwrap_result_and_500_on_error!(
+        state.write().expect("poisoned lock").db.delete_grant(
+            owner,
+            &request.file_name,
+            &request.grantee
+        ),
+        "error saving database"
+    );
+    info!(%owner, file = request.file_name, grantee = %request.grantee, "Revoked access to file");
+    StatusCode::OK.into_response()
+}
+
+// Delete every link and grant to a file, called by the filestore service when the file is deleted
+#[tracing::instrument(skip(state), ret)]
+async fn delete_file_links(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((owner, file)): Path<(Username, String)>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+
+    if !state.config.file_share.address_is_service(&addr.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let removed = state
+        .db
+        .delete_links_to_file(&owner, &file)
+        .and_then(|links| Ok((links, state.db.delete_grants_for_file(&owner, &file)?)));
+    match removed {
+        Ok((links, grants)) => {
+            info!(%owner, %file, links, grants, "Removed links and grants to deleted file");
+            StatusCode::OK.into_response()
+        }
+        Err(err) => {
+            error!(?err, "Error saving database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Delete every link and grant of a user, called by the auth server when the account is deleted
+#[tracing::instrument(skip(state), ret)]
+async fn delete_user_links(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(owner): Path<Username>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+
+    if !state.config.file_share.address_is_service(&addr.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let removed = state
+        .db
+        .delete_links_of_user(&owner)
+        .and_then(|links| Ok((links, state.db.delete_grants_of_user(&owner)?)));
+    match removed {
+        Ok((links, grants)) => {
+            info!(%owner, links, grants, "Removed links and grants of deleted user");
+            StatusCode::OK.into_response()
+        }
+        Err(err) => {
+            error!(?err, "Error saving database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Delete every grant to a group, called by the auth server when the group is deleted
+#[tracing::instrument(skip(state), ret)]
+async fn delete_group_grants(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(group): Path<GroupName>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+
+    if !state.config.file_share.address_is_service(&addr.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    match state.db.delete_grants_to_group(&group) {
+        Ok(grants) => {
+            info!(%group, grants, "Removed grants to deleted group");
+            StatusCode::OK.into_response()
+        }
+        Err(err) => {
+            error!(?err, "Error saving database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
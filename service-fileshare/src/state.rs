@@ -3,85 +3,278 @@ use std::fs::{self, File};
 use std::io;
 use std::path::Path;
 use std::sync::{Arc, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use thiserror::Error;
+use time::OffsetDateTime;
+use tracing::{error, info};
 
 use crate::config::Config;
+use crate::grant::Grant;
 use crate::link::{Link, LinkCode};
-use server_common::auth::AuthClient;
-use server_common::user::Username;
+use crate::store::{LinkStore, SqliteStore, Store, StoreError};
+use server_common::auth::{AuthClient, AUTH_CLIENT};
+use server_common::user::{GroupName, Principal, Username};
 use server_common::util::new_reqwest_client_from_certificates;
 use server_common::ServerConfig;
 
-const DB_PATH: &str = "data/service-fileshare/links/db.json";
+const DB_PATH: &str = "data/service-fileshare/links/db.sqlite";
+const LEGACY_DB_PATH: &str = "data/service-fileshare/links/db.json";
+const MIGRATED_DB_PATH: &str = "data/service-fileshare/links/db.json.migrated";
+const PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const MAX_PASSWORD_FAILURES: u32 = 5;
+const PASSWORD_FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
 
 #[derive(Debug)]
 pub struct State {
     pub config: Config,
     pub db: Database,
+    pub password_failures: PasswordFailures,
 }
 
 pub type AppState = Arc<RwLock<State>>;
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// Tracks wrong passwords given for each link, to limit how fast they can be guessed.
+#[derive(Debug, Default)]
+pub struct PasswordFailures {
+    failures: HashMap<LinkCode, (u32, Instant)>,
+}
+
+impl PasswordFailures {
+    /// Counts an attempt at the password of the link with `code` as a failure until it's
+    /// released, so that concurrent attempts can't exceed the limit. Returns `false` if too many
+    /// wrong passwords were recently given.
+    pub fn reserve_attempt(&mut self, code: &LinkCode) -> bool {
+        let (count, since) = self
+            .failures
+            .entry(code.clone())
+            .or_insert((0, Instant::now()));
+
+        if since.elapsed() >= PASSWORD_FAILURE_WINDOW {
+            *count = 0;
+            *since = Instant::now();
+        }
+        if *count >= MAX_PASSWORD_FAILURES {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Releases an attempt reserved with [`PasswordFailures::reserve_attempt`], once the password
+    /// turned out to be right.
+    pub fn release_attempt(&mut self, code: &LinkCode) {
+        if let Some((count, _)) = self.failures.get_mut(code) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    fn purge_stale(&mut self) {
+        self.failures
+            .retain(|_, (_, since)| since.elapsed() < PASSWORD_FAILURE_WINDOW);
+    }
+}
+
+#[derive(Debug)]
 pub struct Database {
-    links: HashMap<LinkCode, Link>,
+    store: Box<dyn Store>,
+    /// Downloads in progress through each link, which aren't persisted since they don't survive
+    /// a restart anyway.
+    pending_downloads: HashMap<LinkCode, u32>,
 }
 
 impl Database {
+    pub fn new(store: Box<dyn Store>) -> Self {
+        Self {
+            store,
+            pending_downloads: HashMap::new(),
+        }
+    }
+
     pub fn add_link(
         &mut self,
         username: Username,
         file_name: String,
-    ) -> Result<LinkCode, SaveError> {
-        let mut code;
+        expires_at: Option<OffsetDateTime>,
+        max_downloads: Option<u32>,
+        password_hash: Option<String>,
+    ) -> Result<LinkCode, StoreError> {
+        let link = Link::new(
+            username,
+            file_name,
+            expires_at,
+            max_downloads,
+            password_hash,
+        );
         loop {
-            code = LinkCode::new();
-            if !self.links.contains_key(&code) {
-                break;
+            let code = LinkCode::new();
+            if self.store.insert(&code, &link)? {
+                return Ok(code);
             }
         }
-        self.links
-            .insert(code.clone(), Link::new(username, file_name));
-        self.save()?;
-        Ok(code)
-    }
-
-    pub fn delete_link(&mut self, code: LinkCode) -> Result<bool, SaveError> {
-        if self.links.remove(&code).is_some() {
-            self.save()?;
-            Ok(true)
-        } else {
-            Ok(false)
+    }
+
+    pub fn delete_link(&mut self, code: LinkCode) -> Result<bool, StoreError> {
+        self.store.delete(&code)
+    }
+
+    /// Reserves a download through the link with `code`, returning the link if it can be used.
+    ///
+    /// Every successful call must be followed by a call to `finish_download`.
+    pub fn start_download(&mut self, code: &LinkCode) -> Result<Link, DownloadError> {
+        let link = self.store.get(code)?.ok_or(DownloadError::NotFound)?;
+
+        if link.is_expired() {
+            return Err(DownloadError::Expired);
         }
+        let pending = self.pending_downloads.entry(code.clone()).or_default();
+        if !link.allows_download(*pending) {
+            return Err(DownloadError::Exhausted);
+        }
+        *pending += 1;
+        Ok(link)
     }
 
-    pub fn get_file_links_for_user(&self, username: &Username) -> HashMap<LinkCode, &Link> {
-        let mut links = HashMap::new();
-        for (code, link) in self.links.iter() {
-            if link.username() == username {
-                links.insert(code.clone(), link);
+    /// Finishes a download reserved with `start_download`, counting it if it succeeded.
+    pub fn finish_download(&mut self, code: &LinkCode, succeeded: bool) -> Result<(), StoreError> {
+        if let Some(pending) = self.pending_downloads.get_mut(code) {
+            *pending -= 1;
+            if *pending == 0 {
+                self.pending_downloads.remove(code);
             }
         }
-        links
+
+        // The link may have been deleted while the download was in progress.
+        if succeeded {
+            self.store.update(code, &mut |link| {
+                link.record_download();
+                true
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Removes every expired link, returning how many were removed.
+    pub fn purge_expired_links(&mut self) -> Result<usize, StoreError> {
+        self.store.delete_expired(OffsetDateTime::now_utc())
     }
 
-    pub fn get_link_by_code(&self, code: &LinkCode) -> Option<&Link> {
-        self.links.get(&code)
+    /// Removes every link that points to `owner`'s `file_name`, returning how many were removed.
+    pub fn delete_links_to_file(
+        &mut self,
+        owner: &Username,
+        file_name: &str,
+    ) -> Result<usize, StoreError> {
+        self.store.delete_for_file(owner, file_name)
     }
 
-    pub fn save(&self) -> Result<(), SaveError> {
-        fs::create_dir_all(Path::new(DB_PATH).parent().unwrap())?;
+    /// Removes every link created by `owner`, returning how many were removed.
+    pub fn delete_links_of_user(&mut self, owner: &Username) -> Result<usize, StoreError> {
+        self.store.delete_for_owner(owner)
+    }
 
-        let mut file = File::create(DB_PATH)?;
-        serde_json::to_writer_pretty(&mut file, self)?;
-        Ok(())
+    pub fn get_file_links_for_user(
+        &self,
+        username: &Username,
+    ) -> Result<HashMap<LinkCode, Link>, StoreError> {
+        Ok(self.store.list_for_owner(username)?.into_iter().collect())
+    }
+
+    pub fn get_link_by_code(&self, code: &LinkCode) -> Result<Option<Link>, StoreError> {
+        self.store.get(code)
+    }
+
+    pub fn add_grant(&mut self, grant: &Grant) -> Result<(), StoreError> {
+        self.store.upsert_grant(grant)
+    }
+
+    pub fn get_grant(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        grantee: &Principal,
+    ) -> Result<Option<Grant>, StoreError> {
+        self.store.get_grant(owner, file_name, grantee)
+    }
+
+    pub fn delete_grant(
+        &mut self,
+        owner: &Username,
+        file_name: &str,
+        grantee: &Principal,
+    ) -> Result<bool, StoreError> {
+        self.store.delete_grant(owner, file_name, grantee)
+    }
+
+    /// Gets the grants that `owner` gave.
+    pub fn grants_by_user(&self, owner: &Username) -> Result<Vec<Grant>, StoreError> {
+        self.store.list_grants_by_owner(owner)
+    }
+
+    /// Gets the grants that were given to any of `grantees`.
+    pub fn grants_to(&self, grantees: &[Principal]) -> Result<Vec<Grant>, StoreError> {
+        self.store.list_grants_to(grantees)
+    }
+
+    /// Removes every grant to `owner`'s `file_name`, returning how many were removed.
+    pub fn delete_grants_for_file(
+        &mut self,
+        owner: &Username,
+        file_name: &str,
+    ) -> Result<usize, StoreError> {
+        self.store.delete_grants_for_file(owner, file_name)
+    }
+
+    /// Removes every grant given by or to `user`, returning how many were removed.
+    pub fn delete_grants_of_user(&mut self, user: &Username) -> Result<usize, StoreError> {
+        self.store.delete_grants_of_user(user)
+    }
+
+    /// Removes every grant to a group, returning how many were removed.
+    pub fn delete_grants_to_group(&mut self, group: &GroupName) -> Result<usize, StoreError> {
+        self.store
+            .delete_grants_to(&Principal::Group(group.clone()))
     }
 }
 
-pub static AUTH_CLIENT: OnceLock<AuthClient> = OnceLock::new();
+/// Imports the links of the JSON file the database used to be kept in, then moves the file aside
+/// so that it's only imported once.
+///
+/// Links that already exist in the store are skipped, so the import is safe to repeat if the
+/// server stops before the file is moved.
+fn migrate_legacy_db(
+    store: &dyn LinkStore,
+    legacy_path: &Path,
+    migrated_path: &Path,
+) -> anyhow::Result<()> {
+    #[derive(Deserialize)]
+    struct LegacyDatabase {
+        links: HashMap<LinkCode, Link>,
+    }
+
+    let file = match File::open(legacy_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("Failed to open legacy db file"),
+    };
+    let legacy: LegacyDatabase =
+        serde_json::from_reader(file).context("Failed to deserialize legacy db file")?;
+
+    info!(
+        links = legacy.links.len(),
+        "Importing links from '{}'",
+        legacy_path.display()
+    );
+    store
+        .import(&legacy.links.into_iter().collect::<Vec<_>>())
+        .context("Failed to import legacy db file")?;
+    fs::rename(legacy_path, migrated_path).context("Failed to move legacy db file")?;
+    Ok(())
+}
+
 pub static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
 
 pub fn get_state(config: Config) -> anyhow::Result<AppState> {
@@ -95,22 +288,107 @@ pub fn get_state(config: Config) -> anyhow::Result<AppState> {
 
     CLIENT
         .set(new_reqwest_client_from_certificates("service-fileshare")?)
-        .ok()
         .expect("this should only get called once");
 
-    let db = match File::open(DB_PATH) {
-        Ok(file) => serde_json::from_reader(file).context("Failed to deserialize db file")?,
-        Err(err) if err.kind() == io::ErrorKind::NotFound => Default::default(),
-        Err(err) => return Err(err).context("Failed to open db file"),
-    };
+    fs::create_dir_all(Path::new(DB_PATH).parent().unwrap())?;
+    let store = SqliteStore::open(Path::new(DB_PATH)).context("Failed to open db")?;
+    migrate_legacy_db(
+        &store,
+        Path::new(LEGACY_DB_PATH),
+        Path::new(MIGRATED_DB_PATH),
+    )?;
+    let db = Database::new(Box::new(store));
 
-    Ok(Arc::new(RwLock::new(State { config, db })))
+    let state = Arc::new(RwLock::new(State {
+        config,
+        db,
+        password_failures: Default::default(),
+    }));
+    spawn_expired_link_purger(Arc::clone(&state));
+    Ok(state)
+}
+
+/// Periodically removes expired links from the database, so that they don't pile up.
+fn spawn_expired_link_purger(state: AppState) {
+    thread::spawn(move || loop {
+        {
+            let mut state = state.write().expect("poisoned lock");
+            match state.db.purge_expired_links() {
+                Ok(0) => {}
+                Ok(removed) => info!(removed, "Purged expired links"),
+                Err(err) => error!(?err, "Error saving database"),
+            }
+            state.password_failures.purge_stale();
+        }
+        thread::sleep(PURGE_INTERVAL);
+    });
 }
 
 #[derive(Debug, Error)]
-pub enum SaveError {
-    #[error("IO error saving database: {0}")]
-    Io(#[from] io::Error),
-    #[error("serialization error saving database: {0}")]
-    Json(#[from] serde_json::Error),
+pub enum DownloadError {
+    #[error("link doesn't exist")]
+    NotFound,
+    #[error("link has expired")]
+    Expired,
+    #[error("link has reached its download limit")]
+    Exhausted,
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attempts_are_limited_until_released() {
+        let mut failures = PasswordFailures::default();
+        let code = LinkCode::new();
+        for _ in 0..MAX_PASSWORD_FAILURES {
+            assert!(failures.reserve_attempt(&code));
+        }
+        assert!(!failures.reserve_attempt(&code));
+
+        failures.release_attempt(&code);
+        assert!(failures.reserve_attempt(&code));
+        assert!(!failures.reserve_attempt(&code));
+    }
+
+    #[test]
+    fn legacy_db_is_imported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (legacy_path, migrated_path) = (
+            dir.path().join("db.json"),
+            dir.path().join("db.json.migrated"),
+        );
+        let alice: Username = serde_json::from_str("\"alice\"").unwrap();
+        let link =
+            |file_name: &str| Link::new(alice.clone(), file_name.to_owned(), None, Some(3), None);
+        let (report, notes) = (LinkCode::new(), LinkCode::new());
+        let legacy = serde_json::json!({
+            "links": {report.as_ref(): link("report.txt"), notes.as_ref(): link("notes.txt")},
+        });
+        fs::write(&legacy_path, legacy.to_string()).unwrap();
+
+        let store = SqliteStore::open(&dir.path().join("service-fileshare.db")).unwrap();
+        migrate_legacy_db(&store, &legacy_path, &migrated_path).unwrap();
+        assert!(!legacy_path.exists());
+        assert_eq!(store.get(&report).unwrap(), Some(link("report.txt")));
+
+        // As if the server stopped before the file was moved, with a link changed since
+        fs::copy(&migrated_path, &legacy_path).unwrap();
+        store
+            .update(&report, &mut |link| {
+                link.record_download();
+                true
+            })
+            .unwrap();
+        migrate_legacy_db(&store, &legacy_path, &migrated_path).unwrap();
+
+        assert!(!legacy_path.exists());
+        assert!(migrated_path.exists());
+        assert_eq!(store.get(&report).unwrap().unwrap().downloads(), 1);
+        assert_eq!(store.get(&notes).unwrap(), Some(link("notes.txt")));
+        assert_eq!(store.list_for_owner(&alice).unwrap().len(), 2);
+    }
 }
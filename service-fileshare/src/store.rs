@@ -0,0 +1,99 @@
+use std::fmt;
+
+use server_common::user::{Principal, Username};
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use crate::grant::Grant;
+use crate::link::{Link, LinkCode};
+
+mod sqlite;
+
+pub use sqlite::SqliteStore;
+
+/// Persistent storage of share links. Every change is applied atomically, so that a crash never
+/// leaves a link half written.
+pub trait LinkStore: fmt::Debug + Send + Sync {
+    fn get(&self, code: &LinkCode) -> Result<Option<Link>, StoreError>;
+
+    /// Gets every link created by `owner`.
+    fn list_for_owner(&self, owner: &Username) -> Result<Vec<(LinkCode, Link)>, StoreError>;
+
+    /// Adds a link, unless one with the same code already exists.
+    fn insert(&self, code: &LinkCode, link: &Link) -> Result<bool, StoreError>;
+
+    /// Adds all the links that don't exist yet in a single transaction.
+    fn import(&self, links: &[(LinkCode, Link)]) -> Result<(), StoreError>;
+
+    /// Applies `update` to a link within a transaction, saving it if `update` returns `true`.
+    ///
+    /// Returns `Ok(None)` if the link doesn't exist, or what `update` returned otherwise.
+    fn update(
+        &self,
+        code: &LinkCode,
+        update: &mut dyn FnMut(&mut Link) -> bool,
+    ) -> Result<Option<bool>, StoreError>;
+
+    fn delete(&self, code: &LinkCode) -> Result<bool, StoreError>;
+
+    /// Removes every link to `owner`'s `file_name`, returning how many were removed.
+    fn delete_for_file(&self, owner: &Username, file_name: &str) -> Result<usize, StoreError>;
+
+    /// Removes every link created by `owner`, returning how many were removed.
+    fn delete_for_owner(&self, owner: &Username) -> Result<usize, StoreError>;
+
+    /// Removes every link that expired before `now`, returning how many were removed.
+    fn delete_expired(&self, now: OffsetDateTime) -> Result<usize, StoreError>;
+}
+
+/// Persistent storage of the access to files that owners granted to users and groups.
+pub trait GrantStore: fmt::Debug + Send + Sync {
+    /// Adds a grant, replacing any previous one to the same grantee for the same file.
+    fn upsert_grant(&self, grant: &Grant) -> Result<(), StoreError>;
+
+    fn get_grant(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        grantee: &Principal,
+    ) -> Result<Option<Grant>, StoreError>;
+
+    /// Gets every grant that `owner` gave.
+    fn list_grants_by_owner(&self, owner: &Username) -> Result<Vec<Grant>, StoreError>;
+
+    /// Gets every grant given to any of `grantees`.
+    fn list_grants_to(&self, grantees: &[Principal]) -> Result<Vec<Grant>, StoreError>;
+
+    fn delete_grant(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        grantee: &Principal,
+    ) -> Result<bool, StoreError>;
+
+    /// Removes every grant to `owner`'s `file_name`, returning how many were removed.
+    fn delete_grants_for_file(
+        &self,
+        owner: &Username,
+        file_name: &str,
+    ) -> Result<usize, StoreError>;
+
+    /// Removes every grant given by or to `user`, returning how many were removed.
+    fn delete_grants_of_user(&self, user: &Username) -> Result<usize, StoreError>;
+
+    /// Removes every grant to `grantee`, returning how many were removed.
+    fn delete_grants_to(&self, grantee: &Principal) -> Result<usize, StoreError>;
+}
+
+/// Everything the fileshare service keeps, in a single store.
+pub trait Store: LinkStore + GrantStore {}
+
+impl<T: LinkStore + GrantStore> Store for T {}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("SQLite error accessing database: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("serialization error accessing database: {0}")]
+    Json(#[from] serde_json::Error),
+}
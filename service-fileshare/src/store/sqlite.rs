@@ -0,0 +1,520 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use server_common::user::{Principal, Username};
+use time::OffsetDateTime;
+
+use super::{GrantStore, LinkStore, StoreError};
+use crate::grant::Grant;
+use crate::link::{Link, LinkCode};
+
+/// Keeps links and grants in an SQLite database, with each serialized as JSON so that new fields
+/// don't need a schema migration. The fields they are looked up by are also kept in their own
+/// columns, so that they can be indexed.
+pub struct SqliteStore {
+    path: PathBuf,
+    connection: Mutex<Connection>,
+}
+
+impl fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let connection = Connection::open(path)?;
+        // The write-ahead log keeps the database intact if the process dies mid-write, and
+        // syncing it on every commit makes committed changes survive power loss.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "FULL")?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS links (
+                code TEXT PRIMARY KEY NOT NULL,
+                owner TEXT NOT NULL,
+                file_name TEXT NOT NULL,
+                expires_at INTEGER,
+                link TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS links_by_file ON links (owner, file_name);
+            CREATE INDEX IF NOT EXISTS links_by_expiry ON links (expires_at)
+                WHERE expires_at IS NOT NULL;
+            CREATE TABLE IF NOT EXISTS grants (
+                owner TEXT NOT NULL,
+                file_name TEXT NOT NULL,
+                grantee TEXT NOT NULL,
+                record TEXT NOT NULL,
+                PRIMARY KEY (owner, file_name, grantee)
+            );
+            CREATE INDEX IF NOT EXISTS grants_by_grantee ON grants (grantee);",
+        )?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("poisoned lock")
+    }
+}
+
+fn insert_link(connection: &Connection, code: &LinkCode, link: &Link) -> Result<bool, StoreError> {
+    let inserted = connection.execute(
+        "INSERT OR IGNORE INTO links (code, owner, file_name, expires_at, link)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            code.as_ref(),
+            link.username().to_string(),
+            link.file_name(),
+            link.expires_at().map(OffsetDateTime::unix_timestamp),
+            serde_json::to_string(link)?,
+        ],
+    )?;
+    Ok(inserted == 1)
+}
+
+impl LinkStore for SqliteStore {
+    fn get(&self, code: &LinkCode) -> Result<Option<Link>, StoreError> {
+        self.connection()
+            .query_row(
+                "SELECT link FROM links WHERE code = ?1",
+                [code.as_ref()],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .map(|link| serde_json::from_str(&link))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn list_for_owner(&self, owner: &Username) -> Result<Vec<(LinkCode, Link)>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT code, link FROM links WHERE owner = ?1")?;
+        let rows = statement
+            .query_map([owner.to_string()], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(code, link)| Ok((LinkCode::from(code), serde_json::from_str(&link)?)))
+            .collect()
+    }
+
+    fn insert(&self, code: &LinkCode, link: &Link) -> Result<bool, StoreError> {
+        insert_link(&self.connection(), code, link)
+    }
+
+    fn import(&self, links: &[(LinkCode, Link)]) -> Result<(), StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        for (code, link) in links {
+            insert_link(&transaction, code, link)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        code: &LinkCode,
+        update: &mut dyn FnMut(&mut Link) -> bool,
+    ) -> Result<Option<bool>, StoreError> {
+        let mut connection = self.connection();
+        // Taking the write lock up front keeps other connections from changing the link between
+        // reading and writing it.
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let link: Option<String> = transaction
+            .query_row(
+                "SELECT link FROM links WHERE code = ?1",
+                [code.as_ref()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(link) = link else {
+            return Ok(None);
+        };
+
+        let mut link: Link = serde_json::from_str(&link)?;
+        if !update(&mut link) {
+            return Ok(Some(false));
+        }
+
+        transaction.execute(
+            "UPDATE links SET expires_at = ?2, link = ?3 WHERE code = ?1",
+            params![
+                code.as_ref(),
+                link.expires_at().map(OffsetDateTime::unix_timestamp),
+                serde_json::to_string(&link)?,
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(Some(true))
+    }
+
+    fn delete(&self, code: &LinkCode) -> Result<bool, StoreError> {
+        let deleted = self
+            .connection()
+            .execute("DELETE FROM links WHERE code = ?1", [code.as_ref()])?;
+        Ok(deleted == 1)
+    }
+
+    fn delete_for_file(&self, owner: &Username, file_name: &str) -> Result<usize, StoreError> {
+        Ok(self.connection().execute(
+            "DELETE FROM links WHERE owner = ?1 AND file_name = ?2",
+            params![owner.to_string(), file_name],
+        )?)
+    }
+
+    fn delete_for_owner(&self, owner: &Username) -> Result<usize, StoreError> {
+        Ok(self
+            .connection()
+            .execute("DELETE FROM links WHERE owner = ?1", [owner.to_string()])?)
+    }
+
+    fn delete_expired(&self, now: OffsetDateTime) -> Result<usize, StoreError> {
+        // Expiry is kept to the second, so a link is only certain to have expired once the second
+        // it expires in is over. Links are checked for expiry when used, so this only delays
+        // removing them.
+        Ok(self.connection().execute(
+            "DELETE FROM links WHERE expires_at < ?1",
+            [now.unix_timestamp()],
+        )?)
+    }
+}
+
+impl GrantStore for SqliteStore {
+    fn upsert_grant(&self, grant: &Grant) -> Result<(), StoreError> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO grants (owner, file_name, grantee, record)
+                VALUES (?1, ?2, ?3, ?4)",
+            params![
+                grant.owner().to_string(),
+                grant.file_name(),
+                grant.grantee().to_string(),
+                serde_json::to_string(grant)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_grant(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        grantee: &Principal,
+    ) -> Result<Option<Grant>, StoreError> {
+        self.connection()
+            .query_row(
+                "SELECT record FROM grants WHERE owner = ?1 AND file_name = ?2 AND grantee = ?3",
+                params![owner.to_string(), file_name, grantee.to_string()],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .map(|grant| serde_json::from_str(&grant))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn list_grants_by_owner(&self, owner: &Username) -> Result<Vec<Grant>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT record FROM grants WHERE owner = ?1")?;
+        let rows = statement
+            .query_map([owner.to_string()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.iter()
+            .map(|grant| Ok(serde_json::from_str(grant)?))
+            .collect()
+    }
+
+    fn list_grants_to(&self, grantees: &[Principal]) -> Result<Vec<Grant>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT record FROM grants WHERE grantee = ?1")?;
+        let mut grants = Vec::new();
+        for grantee in grantees {
+            for grant in
+                statement.query_map([grantee.to_string()], |row| row.get::<_, String>(0))?
+            {
+                grants.push(serde_json::from_str(&grant?)?);
+            }
+        }
+        Ok(grants)
+    }
+
+    fn delete_grant(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        grantee: &Principal,
+    ) -> Result<bool, StoreError> {
+        let deleted = self.connection().execute(
+            "DELETE FROM grants WHERE owner = ?1 AND file_name = ?2 AND grantee = ?3",
+            params![owner.to_string(), file_name, grantee.to_string()],
+        )?;
+        Ok(deleted == 1)
+    }
+
+    fn delete_grants_for_file(
+        &self,
+        owner: &Username,
+        file_name: &str,
+    ) -> Result<usize, StoreError> {
+        Ok(self.connection().execute(
+            "DELETE FROM grants WHERE owner = ?1 AND file_name = ?2",
+            params![owner.to_string(), file_name],
+        )?)
+    }
+
+    fn delete_grants_of_user(&self, user: &Username) -> Result<usize, StoreError> {
+        Ok(self.connection().execute(
+            "DELETE FROM grants WHERE owner = ?1 OR grantee = ?2",
+            params![user.to_string(), Principal::User(user.clone()).to_string()],
+        )?)
+    }
+
+    fn delete_grants_to(&self, grantee: &Principal) -> Result<usize, StoreError> {
+        Ok(self.connection().execute(
+            "DELETE FROM grants WHERE grantee = ?1",
+            [grantee.to_string()],
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+    use std::thread;
+
+    use time::Duration as TimeDuration;
+
+    use super::*;
+    use crate::grant::Access;
+
+    fn username(name: &str) -> Username {
+        serde_json::from_str(&format!("\"{name}\"")).unwrap()
+    }
+
+    fn link(owner: &str, file_name: &str, expires_at: Option<OffsetDateTime>) -> Link {
+        Link::new(
+            username(owner),
+            file_name.to_owned(),
+            expires_at,
+            None,
+            None,
+        )
+    }
+
+    fn open() -> (tempfile::TempDir, SqliteStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(&dir.path().join("service-fileshare.db")).unwrap();
+        (dir, store)
+    }
+
+    fn codes(links: Vec<(LinkCode, Link)>) -> Vec<String> {
+        let mut codes: Vec<_> = links
+            .into_iter()
+            .map(|(code, _)| code.as_ref().to_owned())
+            .collect();
+        codes.sort();
+        codes
+    }
+
+    #[test]
+    fn links_are_found_by_owner_and_file() {
+        let (_dir, store) = open();
+        for (code, owner, file_name) in [
+            ("a", "alice", "report.txt"),
+            ("b", "alice", "report.txt"),
+            ("c", "alice", "notes.txt"),
+            ("d", "bob", "report.txt"),
+        ] {
+            let code = LinkCode::from(code.to_owned());
+            assert!(store.insert(&code, &link(owner, file_name, None)).unwrap());
+        }
+        assert!(!store
+            .insert(&LinkCode::from("a".to_owned()), &link("bob", "x", None))
+            .unwrap());
+
+        assert_eq!(
+            codes(store.list_for_owner(&username("alice")).unwrap()),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            store
+                .delete_for_file(&username("alice"), "report.txt")
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            codes(store.list_for_owner(&username("alice")).unwrap()),
+            ["c"]
+        );
+        // Another owner's file of the same name keeps its link
+        assert_eq!(
+            store.get(&LinkCode::from("d".to_owned())).unwrap(),
+            Some(link("bob", "report.txt", None))
+        );
+
+        assert_eq!(store.delete_for_owner(&username("alice")).unwrap(), 1);
+        assert!(store.list_for_owner(&username("alice")).unwrap().is_empty());
+        assert_eq!(
+            codes(store.list_for_owner(&username("bob")).unwrap()),
+            ["d"]
+        );
+    }
+
+    #[test]
+    fn grants_are_found_by_owner_file_and_grantee() {
+        let (_dir, store) = open();
+        let bob = Principal::User(username("bob"));
+        let team = Principal::Group(serde_json::from_str("\"team\"").unwrap());
+        for (owner, file_name, grantee) in [
+            ("alice", "report.txt", &bob),
+            ("alice", "report.txt", &team),
+            ("alice", "notes.txt", &bob),
+            ("carol", "report.txt", &team),
+        ] {
+            let grant = Grant::new(
+                username(owner),
+                file_name.to_owned(),
+                grantee.clone(),
+                Access::Read,
+            );
+            store.upsert_grant(&grant).unwrap();
+        }
+        // Granting again replaces the grant
+        let write = Grant::new(
+            username("alice"),
+            "notes.txt".to_owned(),
+            bob.clone(),
+            Access::Write,
+        );
+        store.upsert_grant(&write).unwrap();
+
+        assert_eq!(
+            store
+                .get_grant(&username("alice"), "notes.txt", &bob)
+                .unwrap(),
+            Some(write)
+        );
+        assert_eq!(
+            store
+                .list_grants_by_owner(&username("alice"))
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(
+            store.list_grants_to(slice::from_ref(&bob)).unwrap().len(),
+            2
+        );
+        assert_eq!(
+            store
+                .list_grants_to(&[bob.clone(), team.clone()])
+                .unwrap()
+                .len(),
+            4
+        );
+
+        assert_eq!(
+            store
+                .delete_grants_for_file(&username("alice"), "report.txt")
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            store.list_grants_to(slice::from_ref(&team)).unwrap().len(),
+            1
+        );
+        assert_eq!(store.delete_grants_of_user(&username("bob")).unwrap(), 1);
+        assert_eq!(store.delete_grants_to(&team).unwrap(), 1);
+        assert!(store
+            .list_grants_by_owner(&username("carol"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        const UPDATES: u32 = 50;
+        let (dir, store) = open();
+        let code = LinkCode::new();
+        store
+            .insert(&code, &link("alice", "report.txt", None))
+            .unwrap();
+
+        // Each thread has its own connection, as separate processes would.
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                let store = SqliteStore::open(&dir.path().join("service-fileshare.db")).unwrap();
+                let code = &code;
+                scope.spawn(move || {
+                    for _ in 0..UPDATES {
+                        let updated = store.update(code, &mut |link| {
+                            link.record_download();
+                            true
+                        });
+                        assert_eq!(updated.unwrap(), Some(true));
+                    }
+                });
+            }
+        });
+        assert_eq!(store.get(&code).unwrap().unwrap().downloads(), 2 * UPDATES);
+
+        // Declined and failed updates leave the link as it was
+        let declined = store.update(&code, &mut |link| {
+            link.record_download();
+            false
+        });
+        assert_eq!(declined.unwrap(), Some(false));
+        assert_eq!(store.get(&code).unwrap().unwrap().downloads(), 2 * UPDATES);
+        let missing = store.update(&LinkCode::new(), &mut |_| true).unwrap();
+        assert_eq!(missing, None);
+    }
+
+    #[test]
+    fn only_expired_links_are_deleted() {
+        let (_dir, store) = open();
+        let now = OffsetDateTime::now_utc();
+        for (code, expires_at) in [
+            ("expired", Some(now - TimeDuration::hours(1))),
+            ("expiring", Some(now)),
+            ("valid", Some(now + TimeDuration::hours(1))),
+            ("permanent", None),
+        ] {
+            let code = LinkCode::from(code.to_owned());
+            store
+                .insert(&code, &link("alice", code.as_ref(), expires_at))
+                .unwrap();
+        }
+
+        assert_eq!(store.delete_expired(now).unwrap(), 1);
+        assert_eq!(
+            codes(store.list_for_owner(&username("alice")).unwrap()),
+            ["expiring", "permanent", "valid"]
+        );
+        assert_eq!(
+            store
+                .delete_expired(now + TimeDuration::seconds(1))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            store.delete_expired(now + TimeDuration::days(1)).unwrap(),
+            1
+        );
+        assert_eq!(
+            codes(store.list_for_owner(&username("alice")).unwrap()),
+            ["permanent"]
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use server_common::user::{GroupName, Principal, Username};
+
+// Plenty for sharing with a few departments, but keeps the database from being filled.
+pub const MAX_ACL_ENTRIES: usize = 256;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Right {
+    /// Download the file and read its metadata.
+    Read,
+    /// Replace the contents of the file.
+    Write,
+    /// Give other users the key of an end-to-end encrypted file.
+    Share,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct AclEntry {
+    #[serde(flatten)]
+    pub principal: Principal,
+    pub rights: BTreeSet<Right>,
+}
+
+/// The users and groups that a file is shared with, besides its owner, who can always do
+/// anything with it. Rights don't imply each other.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Acl(Vec<AclEntry>);
+
+impl Acl {
+    /// Merges entries for the same principal and drops those without rights, so that each
+    /// principal appears at most once.
+    pub fn normalized(self) -> Self {
+        let mut entries: Vec<AclEntry> = Vec::with_capacity(self.0.len());
+        for entry in self.0 {
+            match entries
+                .iter_mut()
+                .find(|existing| existing.principal == entry.principal)
+            {
+                Some(existing) => existing.rights.extend(entry.rights),
+                None => entries.push(entry),
+            }
+        }
+        entries.retain(|entry| !entry.rights.is_empty());
+        Self(entries)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Checks whether a user is granted `right`, either directly or through one of their groups.
+    pub fn allows(&self, user: &Username, groups: &[GroupName], right: Right) -> bool {
+        self.0.iter().any(|entry| {
+            entry.rights.contains(&right)
+                && match &entry.principal {
+                    Principal::User(name) => name == user,
+                    Principal::Group(name) => groups.contains(name),
+                }
+        })
+    }
+
+    /// Replaces the rights of the entry's principal, removing their entry if it has none.
+    pub fn set_entry(&mut self, entry: AclEntry) {
+        self.0
+            .retain(|existing| existing.principal != entry.principal);
+        if !entry.rights.is_empty() {
+            self.0.push(entry);
+        }
+    }
+
+    /// Removes the entry of a principal, such as a user or group that was deleted, returning
+    /// whether there was one.
+    pub fn remove(&mut self, principal: &Principal) -> bool {
+        let len = self.0.len();
+        self.0.retain(|entry| &entry.principal != principal);
+        self.0.len() != len
+    }
+
+    /// The users and groups that have an entry.
+    pub fn principals(&self) -> impl Iterator<Item = &Principal> {
+        self.0.iter().map(|entry| &entry.principal)
+    }
+}
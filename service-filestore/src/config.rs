@@ -1,9 +1,11 @@
 use std::collections::HashSet;
+use std::fmt;
 use std::net::IpAddr;
+use std::path::PathBuf;
 
 use serde::Deserialize;
 
-use server_common::user::Role;
+use server_common::user::Username;
 use server_common::{server_config, AuthClientConfig};
 
 server_config! {
@@ -13,6 +15,7 @@ server_config! {
     pub struct Config {
         pub auth_server: AuthClientConfig,
         pub file_store: FileStoreConfig,
+        pub fileshare_server: FileShareClientConfig,
     }
 }
 
@@ -20,8 +23,10 @@ server_config! {
 #[serde(rename_all = "kebab-case")]
 pub struct FileStoreConfig {
     known_services: HashSet<IpAddr>,
-    pub read_role: Role,
-    pub write_role: Role,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
 }
 
 impl FileStoreConfig {
@@ -29,3 +34,96 @@ impl FileStoreConfig {
         self.known_services.contains(address)
     }
 }
+
+/// Which backend holds the contents of stored files.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum StorageConfig {
+    Local(LocalStorageConfig),
+    S3(S3StorageConfig),
+    /// Files are lost when the server stops, so this is only useful for tests.
+    Memory,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::Local(LocalStorageConfig {
+            path: PathBuf::from("data/service-filestore/files/"),
+            legacy_owner: None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LocalStorageConfig {
+    pub path: PathBuf,
+    /// Who gets the files stored before per-user namespaces were introduced, which are moved into
+    /// their namespace on startup and then recorded like any other file found without a record.
+    #[serde(default)]
+    pub legacy_owner: Option<Username>,
+}
+
+/// Settings for an S3-compatible object store. Those that are left out are taken from the usual
+/// `AWS_*` environment variables.
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3StorageConfig {
+    pub bucket: String,
+    pub region: Option<String>,
+    /// For stores other than AWS itself, such as MinIO.
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub allow_http: bool,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+}
+
+// The config may end up in logs, so the secret must not end up in its debug output.
+impl fmt::Debug for S3StorageConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3StorageConfig")
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .field("allow_http", &self.allow_http)
+            .field("access_key_id", &self.access_key_id)
+            .field(
+                "secret_access_key",
+                &self.secret_access_key.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+/// Where the master keys that wrap the data keys of stored files are kept.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EncryptionConfig {
+    /// New data keys are wrapped with this key, which is generated if it doesn't exist.
+    pub master_key: PathBuf,
+    /// Previous master keys, which are still needed to unwrap data keys until they're rotated.
+    #[serde(default)]
+    pub retired_master_keys: Vec<PathBuf>,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            master_key: PathBuf::from("cfg/service-filestore-master.key"),
+            retired_master_keys: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FileShareClientConfig {
+    host: String,
+    port: u16,
+}
+
+impl FileShareClientConfig {
+    pub fn authority(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
@@ -0,0 +1,554 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine};
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream, StreamExt};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::config::EncryptionConfig;
+use crate::storage::ByteStream;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+// The STREAM construction reserves 5 bytes of the nonce for the segment counter and last segment
+// flag.
+const NONCE_PREFIX_SIZE: usize = NONCE_SIZE - 5;
+// Plaintext is encrypted in segments of this size, so that files can be streamed without holding
+// them in memory and every segment is authenticated before it's served.
+const SEGMENT_SIZE: usize = 64 * 1024;
+/// Starts encrypted content, so that it can be told apart from files that were stored in
+/// plaintext before encryption at rest was introduced.
+const MAGIC: &[u8; 8] = b"\x89ENC\r\n\x1a\n";
+// The magic bytes are followed by the length of the wrapped data key, as a big-endian `u16`.
+const HEADER_PREFIX_SIZE: usize = MAGIC.len() + 2;
+
+/// A key that's only used to wrap the data keys of files.
+struct MasterKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    fn from_bytes(key: &Key<Aes256Gcm>) -> Self {
+        // Identifies the key without revealing anything about it, so that wrapped data keys can
+        // name the key that unwraps them.
+        let digest = Sha256::digest(key);
+        Self {
+            id: format!("{:x}", digest)[..16].to_owned(),
+            cipher: Aes256Gcm::new(key),
+        }
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let encoded = fs::read_to_string(path)
+            .with_context(|| format!("failed to read master key '{}'", path.display()))?;
+        let key = general_purpose::STANDARD
+            .decode(encoded.trim())
+            .with_context(|| format!("master key '{}' isn't valid base64", path.display()))?;
+        if key.len() != KEY_SIZE {
+            anyhow::bail!(
+                "master key '{}' isn't {} bytes long",
+                path.display(),
+                KEY_SIZE
+            );
+        }
+
+        Ok(Self::from_bytes(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+
+    /// Generates the key if it doesn't exist yet, which is only allowed if `may_generate` is set,
+    /// since files encrypted before would otherwise become unreadable.
+    fn read_or_generate(path: &Path, may_generate: bool) -> anyhow::Result<Self> {
+        if path.is_file() {
+            info!("Loading master key from '{}'", path.display());
+            return Self::read(path);
+        }
+        if !may_generate {
+            anyhow::bail!(
+                "master key '{}' doesn't exist, but the store holds encrypted files",
+                path.display()
+            );
+        }
+
+        info!("Generating new master key");
+        let key = Aes256Gcm::generate_key(rand::thread_rng());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, general_purpose::STANDARD.encode(key))
+            .with_context(|| format!("failed to write master key '{}'", path.display()))?;
+        Ok(Self::from_bytes(&key))
+    }
+}
+
+/// The master key that new data keys are wrapped with, along with retired ones that may still be
+/// needed to unwrap older data keys until they're rotated.
+pub struct MasterKeys {
+    current: MasterKey,
+    retired: Vec<MasterKey>,
+}
+
+impl fmt::Debug for MasterKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MasterKeys")
+            .field("current", &self.current.id)
+            .field(
+                "retired",
+                &self.retired.iter().map(|key| &key.id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl MasterKeys {
+    /// Reads the configured master keys. A missing current master key is only generated if
+    /// `has_encrypted_files` isn't set.
+    pub fn from_config(
+        config: &EncryptionConfig,
+        has_encrypted_files: bool,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            current: MasterKey::read_or_generate(&config.master_key, !has_encrypted_files)?,
+            retired: config
+                .retired_master_keys
+                .iter()
+                .map(|path| MasterKey::read(path))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    fn get(&self, id: &str) -> Option<&MasterKey> {
+        std::iter::once(&self.current)
+            .chain(&self.retired)
+            .find(|key| key.id == id)
+    }
+
+    fn wrap(&self, key: &DataKey) -> WrappedKey {
+        let mut nonce = Nonce::default();
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let wrapped = self
+            .current
+            .cipher
+            .encrypt(&nonce, key.0.as_slice())
+            .expect("encrypting a data key shouldn't fail");
+
+        WrappedKey {
+            master_key: self.current.id.clone(),
+            nonce: general_purpose::STANDARD.encode(nonce),
+            key: general_purpose::STANDARD.encode(wrapped),
+        }
+    }
+
+    pub fn data_key(&self, wrapped: &WrappedKey) -> Result<DataKey, io::Error> {
+        let master_key = self.get(&wrapped.master_key).ok_or_else(|| {
+            io::Error::other(format!("unknown master key '{}'", wrapped.master_key))
+        })?;
+
+        let nonce = general_purpose::STANDARD
+            .decode(&wrapped.nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == NONCE_SIZE)
+            .ok_or_else(|| invalid_data("malformed data key nonce"))?;
+        let key = general_purpose::STANDARD
+            .decode(&wrapped.key)
+            .map_err(|_| invalid_data("malformed wrapped data key"))?;
+        let key = master_key
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), key.as_slice())
+            .ok()
+            .filter(|key| key.len() == KEY_SIZE)
+            .ok_or_else(|| invalid_data("failed to unwrap data key"))?;
+
+        Ok(DataKey(*Key::<Aes256Gcm>::from_slice(&key)))
+    }
+
+    /// Encrypts `content` with a new data key as it's consumed, starting the output with a header
+    /// that holds the data key wrapped with the current master key.
+    ///
+    /// Keeping the key with the contents means that they're always written, replaced and deleted
+    /// together, so a crash can never leave contents without the key that decrypts them.
+    pub fn encrypt(&self, content: ByteStream) -> ByteStream {
+        let key = DataKey(Aes256Gcm::generate_key(rand::thread_rng()));
+        with_header(&self.wrap(&key), encrypt(content, &key))
+    }
+
+    /// Wraps a data key again with the current master key. Returns `Ok(None)` if it already is.
+    pub fn rewrap(&self, wrapped: &WrappedKey) -> Result<Option<WrappedKey>, io::Error> {
+        if wrapped.master_key == self.current.id {
+            return Ok(None);
+        }
+        Ok(Some(self.wrap(&self.data_key(wrapped)?)))
+    }
+}
+
+/// The key that the contents of a single file are encrypted with.
+pub struct DataKey(Key<Aes256Gcm>);
+
+/// A data key encrypted with a master key, as stored in the header of the contents it encrypts.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct WrappedKey {
+    master_key: String,
+    nonce: String,
+    key: String,
+}
+
+/// Starts `encrypted`, which was encrypted with the data key that `key` wraps, with a header that
+/// holds `key`.
+pub fn with_header(key: &WrappedKey, encrypted: ByteStream) -> ByteStream {
+    let key = serde_json::to_vec(key).expect("wrapped keys should serialize");
+    let mut header = BytesMut::with_capacity(HEADER_PREFIX_SIZE + key.len());
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(
+        &u16::try_from(key.len())
+            .expect("wrapped keys are short")
+            .to_be_bytes(),
+    );
+    header.extend_from_slice(&key);
+
+    stream::once(async move { Ok(header.freeze()) })
+        .chain(encrypted)
+        .boxed()
+}
+
+/// Stored content, split into its header and what follows it.
+pub struct Sealed {
+    /// The key that the content was encrypted with, or `None` if it was stored in plaintext.
+    pub key: Option<WrappedKey>,
+    pub header_size: u64,
+    pub body: ByteStream,
+}
+
+/// Reads the header of stored content, if it has one.
+pub async fn read_header(content: ByteStream) -> Result<Sealed, io::Error> {
+    let mut content = content.fuse().boxed();
+    let mut buffer = BytesMut::new();
+
+    if !fill(&mut content, &mut buffer, MAGIC.len()).await? || &buffer[..MAGIC.len()] != MAGIC {
+        return Ok(Sealed {
+            key: None,
+            header_size: 0,
+            body: prepend(buffer, content),
+        });
+    }
+    if !fill(&mut content, &mut buffer, HEADER_PREFIX_SIZE).await? {
+        return Err(invalid_data("encrypted file header is truncated"));
+    }
+    let key_size = u16::from_be_bytes([buffer[MAGIC.len()], buffer[MAGIC.len() + 1]]) as usize;
+    let header_size = HEADER_PREFIX_SIZE + key_size;
+    if !fill(&mut content, &mut buffer, header_size).await? {
+        return Err(invalid_data("encrypted file header is truncated"));
+    }
+
+    let header = buffer.split_to(header_size);
+    let key = serde_json::from_slice(&header[HEADER_PREFIX_SIZE..])
+        .map_err(|_| invalid_data("malformed encrypted file header"))?;
+    Ok(Sealed {
+        key: Some(key),
+        header_size: header_size as u64,
+        body: prepend(buffer, content),
+    })
+}
+
+/// Reads from `content` until `buffer` holds at least `size` bytes, returning whether it does.
+async fn fill(
+    content: &mut ByteStream,
+    buffer: &mut BytesMut,
+    size: usize,
+) -> Result<bool, io::Error> {
+    while buffer.len() < size {
+        match content.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk?),
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// Puts back what was read of `rest` before it.
+fn prepend(read: BytesMut, rest: ByteStream) -> ByteStream {
+    if read.is_empty() {
+        return rest;
+    }
+    stream::once(async move { Ok(read.freeze()) })
+        .chain(rest)
+        .boxed()
+}
+
+/// Encrypts `content` as it's consumed.
+///
+/// The output starts with a random nonce prefix, followed by the encrypted segments. The last
+/// segment is marked as such, so that truncated ciphertext fails to decrypt.
+fn encrypt(content: ByteStream, key: &DataKey) -> ByteStream {
+    let mut nonce = [0; NONCE_PREFIX_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let encryptor = EncryptorBE32::from_aead(Aes256Gcm::new(&key.0), &nonce.into());
+
+    let header = stream::once(async move { Ok(Bytes::copy_from_slice(&nonce)) });
+    let segments = stream::try_unfold(
+        Some((content, encryptor, BytesMut::new())),
+        |state| async move {
+            let Some((mut content, mut encryptor, mut buffer)) = state else {
+                return Ok(None);
+            };
+
+            // A segment is only encrypted once more data is known to follow it, since the last
+            // segment is encrypted differently.
+            loop {
+                if buffer.len() > SEGMENT_SIZE {
+                    let segment = buffer.split_to(SEGMENT_SIZE);
+                    let encrypted = encryptor
+                        .encrypt_next(segment.as_ref())
+                        .map_err(|_| io::Error::other("failed to encrypt segment"))?;
+                    return Ok(Some((
+                        Bytes::from(encrypted),
+                        Some((content, encryptor, buffer)),
+                    )));
+                }
+
+                match content.next().await {
+                    Some(chunk) => buffer.extend_from_slice(&chunk?),
+                    None => {
+                        let encrypted = encryptor
+                            .encrypt_last(buffer.as_ref())
+                            .map_err(|_| io::Error::other("failed to encrypt segment"))?;
+                        return Ok(Some((Bytes::from(encrypted), None)));
+                    }
+                }
+            }
+        },
+    );
+
+    header.chain(segments).boxed()
+}
+
+/// Decrypts content produced by [`encrypt`] as it's consumed, failing if it has been tampered
+/// with or truncated.
+pub fn decrypt(content: ByteStream, key: &DataKey) -> ByteStream {
+    let cipher = Aes256Gcm::new(&key.0);
+
+    stream::try_unfold(
+        Some((content, Decryption::Header(cipher), BytesMut::new())),
+        |state| async move {
+            let Some((mut content, mut decryption, mut buffer)) = state else {
+                return Ok(None);
+            };
+
+            loop {
+                decryption = match decryption {
+                    Decryption::Header(cipher) if buffer.len() >= NONCE_PREFIX_SIZE => {
+                        let nonce = buffer.split_to(NONCE_PREFIX_SIZE);
+                        Decryption::Segments(DecryptorBE32::from_aead(
+                            cipher,
+                            Nonce::<aes_gcm::aead::consts::U7>::from_slice(&nonce),
+                        ))
+                    }
+                    Decryption::Segments(mut decryptor)
+                        if buffer.len() > SEGMENT_SIZE + TAG_SIZE =>
+                    {
+                        let segment = buffer.split_to(SEGMENT_SIZE + TAG_SIZE);
+                        let decrypted = decryptor
+                            .decrypt_next(segment.as_ref())
+                            .map_err(|_| invalid_data("failed to decrypt segment"))?;
+                        return Ok(Some((
+                            Bytes::from(decrypted),
+                            Some((content, Decryption::Segments(decryptor), buffer)),
+                        )));
+                    }
+                    decryption => match content.next().await {
+                        Some(chunk) => {
+                            buffer.extend_from_slice(&chunk?);
+                            decryption
+                        }
+                        None => {
+                            let Decryption::Segments(decryptor) = decryption else {
+                                return Err(invalid_data("encrypted file is truncated"));
+                            };
+                            let decrypted = decryptor
+                                .decrypt_last(buffer.as_ref())
+                                .map_err(|_| invalid_data("failed to decrypt segment"))?;
+                            return Ok(Some((Bytes::from(decrypted), None)));
+                        }
+                    },
+                };
+            }
+        },
+    )
+    .boxed()
+}
+
+enum Decryption {
+    Header(Aes256Gcm),
+    Segments(DecryptorBE32<Aes256Gcm>),
+}
+
+/// Computes the size of the plaintext from the size of content produced by [`encrypt`].
+pub fn plaintext_size(encrypted_size: u64) -> u64 {
+    let body = encrypted_size.saturating_sub(NONCE_PREFIX_SIZE as u64);
+    let segments = body.div_ceil((SEGMENT_SIZE + TAG_SIZE) as u64);
+    body.saturating_sub(segments * TAG_SIZE as u64)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    fn data_key() -> DataKey {
+        DataKey(Aes256Gcm::generate_key(rand::thread_rng()))
+    }
+
+    fn plaintext() -> Vec<u8> {
+        (0..SEGMENT_SIZE * 5 / 2).map(|i| i as u8).collect()
+    }
+
+    fn stream_of(content: Vec<u8>) -> ByteStream {
+        // Chunks that don't line up with segments, like those of a request body
+        let chunks = content
+            .chunks(1000)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        stream::iter(chunks).boxed()
+    }
+
+    async fn collect(content: ByteStream) -> Result<Vec<u8>, io::Error> {
+        content
+            .try_fold(Vec::new(), |mut collected, chunk| async move {
+                collected.extend_from_slice(&chunk);
+                Ok(collected)
+            })
+            .await
+    }
+
+    async fn encrypted(key: &DataKey) -> Vec<u8> {
+        collect(encrypt(stream_of(plaintext()), key)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let key = data_key();
+        let encrypted = encrypted(&key).await;
+        assert_eq!(
+            plaintext_size(encrypted.len() as u64),
+            plaintext().len() as u64
+        );
+
+        let decrypted = collect(decrypt(stream_of(encrypted), &key)).await.unwrap();
+        assert_eq!(decrypted, plaintext());
+    }
+
+    #[tokio::test]
+    async fn empty_round_trip() {
+        let key = data_key();
+        let encrypted = collect(encrypt(stream_of(Vec::new()), &key)).await.unwrap();
+        assert_eq!(plaintext_size(encrypted.len() as u64), 0);
+
+        let decrypted = collect(decrypt(stream_of(encrypted), &key)).await.unwrap();
+        assert!(decrypted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tampering_is_rejected() {
+        let key = data_key();
+        for position in [0, NONCE_PREFIX_SIZE + 10, SEGMENT_SIZE * 2] {
+            let mut encrypted = encrypted(&key).await;
+            encrypted[position] ^= 1;
+
+            let err = collect(decrypt(stream_of(encrypted), &key))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncation_is_rejected() {
+        let key = data_key();
+        let encrypted = encrypted(&key).await;
+        let segment_boundary = NONCE_PREFIX_SIZE + 2 * (SEGMENT_SIZE + TAG_SIZE);
+        for length in [3, NONCE_PREFIX_SIZE, segment_boundary, encrypted.len() - 1] {
+            let err = collect(decrypt(stream_of(encrypted[..length].to_vec()), &key))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_key_is_rejected() {
+        let encrypted = encrypted(&data_key()).await;
+        let err = collect(decrypt(stream_of(encrypted), &data_key()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn header_holds_the_data_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = MasterKeys::from_config(
+            &EncryptionConfig {
+                master_key: dir.path().join("master.key"),
+                retired_master_keys: Vec::new(),
+            },
+            false,
+        )
+        .unwrap();
+        let encrypted = collect(keys.encrypt(stream_of(plaintext()))).await.unwrap();
+
+        let sealed = read_header(stream_of(encrypted.clone())).await.unwrap();
+        let key = keys.data_key(&sealed.key.unwrap()).unwrap();
+        assert_eq!(
+            plaintext_size(encrypted.len() as u64 - sealed.header_size),
+            plaintext().len() as u64
+        );
+        let decrypted = collect(decrypt(sealed.body, &key)).await.unwrap();
+        assert_eq!(decrypted, plaintext());
+
+        let truncated = encrypted[..HEADER_PREFIX_SIZE + 3].to_vec();
+        let err = read_header(stream_of(truncated)).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn plaintext_has_no_header() {
+        for content in [plaintext(), MAGIC[..4].to_vec(), Vec::new()] {
+            let sealed = read_header(stream_of(content.clone())).await.unwrap();
+            assert!(sealed.key.is_none());
+            assert_eq!(sealed.header_size, 0);
+            assert_eq!(collect(sealed.body).await.unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn missing_master_key_is_only_generated_for_new_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = EncryptionConfig {
+            master_key: dir.path().join("master.key"),
+            retired_master_keys: Vec::new(),
+        };
+
+        let err = MasterKeys::from_config(&config, true).unwrap_err();
+        assert!(err.to_string().contains("master.key"));
+        assert!(!config.master_key.exists());
+
+        let generated = MasterKeys::from_config(&config, false).unwrap();
+        let read = MasterKeys::from_config(&config, true).unwrap();
+        assert_eq!(read.current.id, generated.current.id);
+    }
+}
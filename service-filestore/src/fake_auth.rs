@@ -0,0 +1,116 @@
+//! A stand-in for the auth server that grants every permission and revokes nothing, along with
+//! tokens signed by a key it publishes, so that handlers can be tested without an auth server.
+
+use std::net::TcpListener;
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+
+use axum::routing::get;
+use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{engine::general_purpose, Engine};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::EncodingKey;
+use openssl::rsa::Rsa;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use serde_json::json;
+use time::Duration;
+
+use server_common::auth::{AuthClient, Claims, AUTH_CLIENT};
+use server_common::signature::{self, SignaturePolicy};
+use server_common::{jwks, GeneralConfig, DEFAULT_ORIGIN};
+
+const KEY_ID: &str = "fake-auth";
+
+static ISSUER: OnceLock<Issuer> = OnceLock::new();
+
+/// Issues tokens that the fake auth server accepts.
+pub struct Issuer {
+    key: EncodingKey,
+}
+
+impl Issuer {
+    pub fn token(&self, username: &str) -> String {
+        let username = serde_json::from_str(&format!("\"{username}\"")).unwrap();
+        Claims::create(username, Duration::hours(1), String::new())
+            .encode(KEY_ID, &self.key)
+            .unwrap()
+    }
+}
+
+/// Starts the fake auth server and makes this process use it, unless that was done already.
+/// Requests don't have to be signed.
+pub fn start() -> &'static Issuer {
+    ISSUER.get_or_init(|| {
+        let rsa = Rsa::generate(2048).unwrap();
+        let key_set: JwkSet = serde_json::from_value(json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS384",
+                "kid": KEY_ID,
+                "n": general_purpose::URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+                "e": general_purpose::URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+            }],
+        }))
+        .unwrap();
+        jwks::set_key_set(&key_set);
+        signature::configure(&GeneralConfig {
+            port: 0,
+            signatures: SignaturePolicy::Off,
+            origin: DEFAULT_ORIGIN.to_owned(),
+        });
+
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let cert =
+            Certificate::from_params(CertificateParams::new(vec!["localhost".to_owned()])).unwrap();
+        let (cert_pem, key_pem) = (
+            cert.serialize_pem_with_signer(&ca).unwrap(),
+            cert.serialize_private_key_pem(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Served from a runtime of its own, since each test's runtime ends with the test.
+        let router = Router::new()
+            .route("/user/:user/:permission", get(|| async { Json(true) }))
+            .route("/revoked-tokens", get(|| async { Json(json!([])) }))
+            .route(
+                "/.well-known/jwks.json",
+                get(move || async move { Json(key_set) }),
+            );
+        let (started, ready) = mpsc::channel();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let tls = RustlsConfig::from_pem(cert_pem.into_bytes(), key_pem.into_bytes())
+                    .await
+                    .unwrap();
+                let server =
+                    axum_server::from_tcp_rustls(listener, tls).serve(router.into_make_service());
+                started.send(()).unwrap();
+                server.await.unwrap();
+            });
+        });
+        ready.recv().unwrap();
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(ca.serialize_pem().unwrap().as_bytes()).unwrap(),
+            )
+            .build()
+            .unwrap();
+        AUTH_CLIENT
+            .set(AuthClient::with_client(client, format!("localhost:{port}")))
+            .ok()
+            .expect("the auth client should only be set up here");
+
+        let key = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+        Issuer { key }
+    })
+}
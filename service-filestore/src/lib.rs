@@ -0,0 +1,10 @@
+mod acl;
+pub mod config;
+mod encryption;
+#[cfg(test)]
+mod fake_auth;
+mod metadata;
+pub mod server;
+pub mod state;
+pub mod storage;
+pub mod store;
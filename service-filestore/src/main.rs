@@ -1,16 +1,21 @@
-mod config;
-mod server;
-mod state;
-
 use server_common::prelude::*;
-use state::{get_state, AppState};
+use service_filestore::config::Config;
+use service_filestore::server::get_router;
+use service_filestore::state::{get_state, rotate_keys, AppState};
 
-use crate::config::Config;
-use crate::server::get_router;
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Wrap every data key with the current master key and encrypt files still stored in
+    /// plaintext. The server must not be running.
+    RotateKeys,
+}
 
-server_args!("cfg/service-filestore.toml");
+server_args!("cfg/service-filestore.toml", Command);
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    server_common::server_main::<Config, AppState>(&args, get_router(), get_state)
+    match args.command {
+        Some(Command::RotateKeys) => server_common::command_main::<Config, _>(&args, rotate_keys),
+        None => server_common::server_main::<Config, AppState>(&args, get_router(), get_state),
+    }
 }
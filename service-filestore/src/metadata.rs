@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use server_common::user::Username;
+
+use crate::acl::Acl;
+
+// Enough for `infer` to recognize every format it knows about.
+const SNIFF_SIZE: usize = 8192;
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct FileMetadata {
+    owner: Username,
+    name: String,
+    uploader: Username,
+    #[serde(with = "time::serde::rfc3339")]
+    uploaded_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    modified_at: OffsetDateTime,
+    size: u64,
+    content_type: String,
+    sha256: String,
+    /// Whether the contents were encrypted by the client, in which case the server can't read
+    /// them and `sha256` is the hash of the ciphertext.
+    #[serde(default)]
+    end_to_end: bool,
+    /// Who else may access the file. Kept when the contents are replaced.
+    #[serde(default)]
+    acl: Acl,
+}
+
+impl FileMetadata {
+    pub fn new(owner: Username, name: String, uploader: Username, content: ContentInfo) -> Self {
+        let now = OffsetDateTime::now_utc();
+        let content_type = content.content_type(&name);
+        Self {
+            owner,
+            name,
+            uploader,
+            uploaded_at: now,
+            modified_at: now,
+            size: content.size,
+            content_type,
+            sha256: content.sha256,
+            end_to_end: content.end_to_end,
+            acl: Acl::default(),
+        }
+    }
+
+    /// Updates the record after the contents of the file have been replaced.
+    pub fn replace_content(&mut self, content: ContentInfo) {
+        self.modified_at = OffsetDateTime::now_utc();
+        self.size = content.size;
+        self.content_type = content.content_type(&self.name);
+        self.sha256 = content.sha256;
+        self.end_to_end = content.end_to_end;
+    }
+
+    /// Sets the upload time, for records created from files that were stored before metadata was
+    /// tracked.
+    pub fn with_uploaded_at(mut self, uploaded_at: OffsetDateTime) -> Self {
+        self.uploaded_at = uploaded_at;
+        self.modified_at = uploaded_at;
+        self
+    }
+
+    pub fn owner(&self) -> &Username {
+        &self.owner
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn is_end_to_end(&self) -> bool {
+        self.end_to_end
+    }
+
+    pub fn acl(&self) -> &Acl {
+        &self.acl
+    }
+
+    pub fn acl_mut(&mut self) -> &mut Acl {
+        &mut self.acl
+    }
+}
+
+/// Accumulates the properties of a file's contents as they are written.
+#[derive(Default)]
+pub struct ContentInspector {
+    hasher: Sha256,
+    size: u64,
+    prefix: Vec<u8>,
+}
+
+impl ContentInspector {
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+
+        if self.prefix.len() < SNIFF_SIZE {
+            let missing = SNIFF_SIZE - self.prefix.len();
+            self.prefix
+                .extend_from_slice(&chunk[..missing.min(chunk.len())]);
+        }
+    }
+
+    pub fn finish(self) -> ContentInfo {
+        ContentInfo {
+            size: self.size,
+            sha256: format!("{:x}", self.hasher.finalize()),
+            detected_type: infer::get(&self.prefix).map(|kind| kind.mime_type().to_owned()),
+            end_to_end: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ContentInfo {
+    size: u64,
+    sha256: String,
+    detected_type: Option<String>,
+    end_to_end: bool,
+}
+
+impl ContentInfo {
+    /// Marks the contents as encrypted by the client. Nothing can be detected from ciphertext, so
+    /// the content type is only guessed from the file name.
+    pub fn end_to_end(self) -> Self {
+        Self {
+            detected_type: None,
+            end_to_end: true,
+            ..self
+        }
+    }
+
+    /// The MIME type detected from the contents, falling back to a guess from the file name.
+    fn content_type(&self, file_name: &str) -> String {
+        self.detected_type.clone().unwrap_or_else(|| {
+            mime_guess::from_path(file_name)
+                .first_raw()
+                .unwrap_or(mime::APPLICATION_OCTET_STREAM.as_ref())
+                .to_owned()
+        })
+    }
+}
@@ -1,95 +1,264 @@
 use std::net::SocketAddr;
 
-use axum::body::Body;
-use axum::extract::{ConnectInfo, Path, State};
-use axum::http::header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE};
-use axum::http::{Method, StatusCode};
+use axum::body::StreamBody;
+use axum::extract::{BodyStream, ConnectInfo, Path, Query, State};
+use axum::headers::{HeaderMapExt, IfMatch};
+use axum::http::header::{
+    ACCEPT_ENCODING, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+    IF_MATCH,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::routing::{get, put};
-use axum::{Json, Router};
+use axum::routing::{delete, get, put};
+use axum::{Json, Router, TypedHeader};
+use base64::{engine::general_purpose, Engine};
+use futures_util::{StreamExt, TryStreamExt};
 use tower_http::cors::CorsLayer;
-use tracing::error;
+use tracing::{error, info};
 
-use crate::state::{exists_store, list_store, read_store, write_store, AppState, AUTH_CLIENT};
-use server_common::auth::Claims;
-use server_common::ORIGIN;
+use crate::acl::{Acl, AclEntry, Right, MAX_ACL_ENTRIES};
+use crate::metadata::{ContentInfo, FileMetadata};
+use crate::state::{AppState, CLIENT, STORAGE};
+use crate::storage::{FileContent, StoredFile};
+use serde::Deserialize;
+use server_common::allowed_origin;
+use server_common::auth::{
+    Claims, AUTH_CLIENT, FILES_ADMIN_PERMISSION, FILES_DELETE_PERMISSION, FILES_READ_PERMISSION,
+    FILES_SHARE_PERMISSION, FILES_WRITE_PERMISSION,
+};
+use server_common::user::{GroupName, Username};
+use server_common::util::service_url;
+
+/// Carries the key of an end-to-end encrypted upload, wrapped with the uploader's public key.
+const FILE_KEY_HEADER: HeaderName = HeaderName::from_static("file-key");
+// An RSA-OAEP wrapped key is the size of the modulus, so this allows for keys of up to 16384 bits.
+const MAX_FILE_KEY_SIZE: usize = 4096;
 
 pub fn get_router() -> Router<AppState> {
     Router::new()
-        .route("/config", get(config))
         .route("/files", get(list))
         .route("/files/:file", get(read))
         .route("/files/:file", put(write))
-        .route("/file-exists/:file", get(exists))
-        .route("/file-shared/:file", get(read_shared))
+        .route("/files/:file", delete(remove))
+        .route("/files/:file/meta", get(meta))
+        .route("/files/:file/key", get(file_key))
+        .route("/files/:file/keys/:user", put(add_file_key))
+        .route("/files/:file/acl", get(get_acl))
+        .route("/files/:file/acl", put(set_acl))
+        .route("/file-exists/:owner/:file", get(exists))
+        .route("/file-shared/:owner/:file", get(read_shared))
+        .route("/file-acl/:owner/:file", put(set_acl_entry))
+        .route("/user-files/:owner", delete(delete_user_files))
+        .route("/group-acl/:group", delete(delete_group_acl_entries))
         .layer(
             CorsLayer::new()
-                .allow_methods([Method::GET, Method::PUT, Method::OPTIONS])
-                .allow_headers([ACCEPT_ENCODING, AUTHORIZATION, CONTENT_TYPE])
-                .allow_origin(ORIGIN),
+                .allow_methods([Method::GET, Method::PUT, Method::DELETE, Method::OPTIONS])
+                .allow_headers([
+                    ACCEPT_ENCODING,
+                    AUTHORIZATION,
+                    CONTENT_TYPE,
+                    IF_MATCH,
+                    FILE_KEY_HEADER,
+                ])
+                .expose_headers([ETAG])
+                .allow_origin(allowed_origin()),
         )
 }
 
-#[tracing::instrument]
-async fn config(State(state): State<AppState>) -> String {
-    format!("{:#?}", state.read().expect("poisoned lock").config)
+#[derive(Debug, Deserialize)]
+struct OwnerQuery {
+    owner: Option<Username>,
 }
 
-#[tracing::instrument(skip(state), ret)]
-async fn list(State(state): State<AppState>, claims: Claims) -> Response {
-    let role = state
+/// Resolves the namespace a request operates on, which is the caller's own unless an admin asks
+/// for another user's.
+async fn resolve_owner(claims: &Claims, owner: Option<Username>) -> Result<Username, Response> {
+    match owner {
+        Some(owner) if &owner != claims.username() => {
+            AUTH_CLIENT
+                .get()
+                .unwrap()
+                .user_has_permission_into_response(claims.username(), &FILES_ADMIN_PERMISSION)
+                .await?;
+            Ok(owner)
+        }
+        _ => Ok(claims.username().clone()),
+    }
+}
+
+async fn is_files_admin(claims: &Claims) -> Result<bool, Response> {
+    AUTH_CLIENT
+        .get()
+        .unwrap()
+        .user_has_permission(claims.username(), &FILES_ADMIN_PERMISSION)
+        .await
+        .map_err(|err| {
+            error!(
+                ?err,
+                "Failed to get permission information from auth server"
+            );
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })
+}
+
+async fn groups_of_caller(claims: &Claims) -> Result<Vec<GroupName>, Response> {
+    AUTH_CLIENT
+        .get()
+        .unwrap()
+        .user_groups(claims.username())
+        .await
+        .map_err(|err| {
+            error!(?err, "Failed to get group information from auth server");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })
+}
+
+/// Resolves the owner of a file a request operates on, like `resolve_owner`, but also lets
+/// callers access another user's file if its ACL grants them `right`.
+async fn authorize(
+    state: &AppState,
+    claims: &Claims,
+    owner: Option<Username>,
+    file: &str,
+    right: Right,
+) -> Result<Username, Response> {
+    let owner = match owner {
+        Some(owner) if &owner != claims.username() => owner,
+        _ => return Ok(claims.username().clone()),
+    };
+    if is_files_admin(claims).await? {
+        return Ok(owner);
+    }
+
+    let groups = groups_of_caller(claims).await?;
+    let metadata = state
         .read()
         .expect("poisoned lock")
-        .config
-        .file_store
-        .read_role
-        .clone();
+        .db
+        .get_file(&owner, file)
+        .map_err(|err| {
+            error!(?err, "Error reading database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    if metadata.is_some_and(|metadata| metadata.acl().allows(claims.username(), &groups, right)) {
+        Ok(owner)
+    } else {
+        Err(StatusCode::FORBIDDEN.into_response())
+    }
+}
+
+/// Lists the caller's files and those shared with them, or every file in the store if the
+/// caller is an admin.
+#[tracing::instrument(skip(state), ret)]
+async fn list(State(state): State<AppState>, claims: Claims) -> Response {
     if let Err(response) = AUTH_CLIENT
         .get()
         .unwrap()
-        .user_has_role_into_response(claims.username(), &role)
+        .user_has_permission_into_response(claims.username(), &FILES_READ_PERMISSION)
         .await
     {
         return response;
     }
 
-    match list_store() {
+    let groups = match is_files_admin(&claims).await {
+        Ok(true) => None,
+        Ok(false) => match groups_of_caller(&claims).await {
+            Ok(groups) => Some(groups),
+            Err(response) => return response,
+        },
+        Err(response) => return response,
+    };
+
+    let state = state.read().expect("poisoned lock");
+    let files = match groups {
+        Some(groups) => state.db.list_accessible_files(claims.username(), &groups),
+        None => state.db.list_files(None),
+    };
+    match files {
         Ok(files) => (StatusCode::OK, Json(files)).into_response(),
         Err(err) => {
-            error!(?err, "Failed to get list of files");
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            error!(?err, "Error reading database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
 #[tracing::instrument(skip(state), ret)]
-async fn read(State(state): State<AppState>, claims: Claims, Path(file): Path<String>) -> Response {
-    let role = state
+async fn meta(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(file): Path<String>,
+    Query(query): Query<OwnerQuery>,
+) -> Response {
+    if let Err(response) = AUTH_CLIENT
+        .get()
+        .unwrap()
+        .user_has_permission_into_response(claims.username(), &FILES_READ_PERMISSION)
+        .await
+    {
+        return response;
+    }
+
+    let owner = match authorize(&state, &claims, query.owner, &file, Right::Read).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    match state
         .read()
         .expect("poisoned lock")
-        .config
-        .file_store
-        .read_role
-        .clone();
+        .db
+        .get_file(&owner, &file)
+    {
+        Ok(Some(metadata)) => Json(metadata).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "Error reading database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[tracing::instrument(skip(state), ret)]
+async fn read(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(file): Path<String>,
+    Query(query): Query<OwnerQuery>,
+) -> Response {
     if let Err(response) = AUTH_CLIENT
         .get()
         .unwrap()
-        .user_has_role_into_response(claims.username(), &role)
+        .user_has_permission_into_response(claims.username(), &FILES_READ_PERMISSION)
         .await
     {
         return response;
     }
 
-    match read_store(&file) {
-        Ok(content) => Response::builder()
-            .status(StatusCode::OK)
-            .header(
-                "Content-Disposition",
-                format!("attachment; filename=\"{}\"", &file),
-            )
-            .body(Body::from(content))
-            .unwrap()
-            .into_response(),
+    let owner = match authorize(&state, &claims, query.owner, &file, Right::Read).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    let file = match StoredFile::new(owner, file) {
+        Ok(file) => file,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+
+    let key = match state
+        .read()
+        .expect("poisoned lock")
+        .db
+        .legacy_key(file.owner(), file.name())
+    {
+        Ok(key) => key,
+        Err(err) => {
+            error!(?err, "Error reading database");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match STORAGE.get().unwrap().read(&file, key.as_ref()).await {
+        Ok(content) => file_response(&state, &file, content),
         Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
             StatusCode::NOT_FOUND.into_response()
         }
@@ -100,22 +269,70 @@ async fn read(State(state): State<AppState>, claims: Claims, Path(file): Path<St
     }
 }
 
+/// Builds a response that streams `content` to the client as an attachment named after `file`.
+fn file_response(state: &AppState, file: &StoredFile, content: FileContent) -> Response {
+    let content_type = state
+        .read()
+        .expect("poisoned lock")
+        .db
+        .get_file(file.owner(), file.name())
+        .unwrap_or_else(|err| {
+            error!(?err, "Error reading database");
+            None
+        })
+        .map(|metadata| metadata.content_type().to_owned())
+        .unwrap_or_else(|| mime::APPLICATION_OCTET_STREAM.to_string());
+
+    (
+        TypedHeader(content.etag),
+        [
+            (CONTENT_TYPE, content_type),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file.name()),
+            ),
+            (CONTENT_LENGTH, content.size.to_string()),
+        ],
+        StreamBody::new(content.body),
+    )
+        .into_response()
+}
+
 #[tracing::instrument(skip(state), ret)]
-async fn read_shared(State(state): State<AppState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, Path(file): Path<String>) -> Response {
-    if !state.read().expect("poisoned lock").config.file_store.address_is_service(&addr.ip()) {
+async fn read_shared(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((owner, file)): Path<(Username, String)>,
+) -> Response {
+    if !state
+        .read()
+        .expect("poisoned lock")
+        .config
+        .file_store
+        .address_is_service(&addr.ip())
+    {
         return StatusCode::FORBIDDEN.into_response();
     }
 
-    match read_store(&file) {
-        Ok(content) => Response::builder()
-            .status(StatusCode::OK)
-            .header(
-                "Content-Disposition",
-                format!("attachment; filename=\"{}\"", &file),
-            )
-            .body(Body::from(content))
-            .unwrap()
-            .into_response(),
+    let file = match StoredFile::new(owner, file) {
+        Ok(file) => file,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+
+    let key = match state
+        .read()
+        .expect("poisoned lock")
+        .db
+        .legacy_key(file.owner(), file.name())
+    {
+        Ok(key) => key,
+        Err(err) => {
+            error!(?err, "Error reading database");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match STORAGE.get().unwrap().read(&file, key.as_ref()).await {
+        Ok(content) => file_response(&state, &file, content),
         Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
             StatusCode::NOT_FOUND.into_response()
         }
@@ -126,35 +343,498 @@ async fn read_shared(State(state): State<AppState>, ConnectInfo(addr): ConnectIn
     }
 }
 
-#[tracing::instrument(skip(state), ret)]
+/// Checks that a wrapped file key is plausible, without being able to unwrap it.
+fn parse_file_key(value: &HeaderValue) -> Option<String> {
+    let key = value.to_str().ok()?;
+    (key.len() <= MAX_FILE_KEY_SIZE && general_purpose::STANDARD.decode(key).is_ok())
+        .then(|| key.to_owned())
+}
+
+/// Gets the `If-Match` header of a request. It's looked up here rather than with
+/// `Option<TypedHeader<IfMatch>>`, which takes a missing header for one that matches nothing.
+fn if_match(headers: &HeaderMap) -> Result<Option<IfMatch>, (StatusCode, &'static str)> {
+    headers
+        .typed_try_get()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid If-Match header"))
+}
+
+/// Uploads a new file, or replaces an existing one if an `If-Match` header is given. Files can
+/// only be created in another user's namespace by admins, but existing ones can be replaced by
+/// whoever their ACL grants write access.
+///
+/// If a `File-Key` header is given, the contents were encrypted by the client and are stored as
+/// they are, along with the wrapped key.
+#[tracing::instrument(skip(state, headers, contents), ret)]
 async fn write(
     State(state): State<AppState>,
     claims: Claims,
     Path(file): Path<String>,
-    contents: String,
+    Query(query): Query<OwnerQuery>,
+    headers: HeaderMap,
+    contents: BodyStream,
+) -> Response {
+    let if_match = match if_match(&headers) {
+        Ok(if_match) => if_match,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if let Err(response) = AUTH_CLIENT
+        .get()
+        .unwrap()
+        .user_has_permission_into_response(claims.username(), &FILES_WRITE_PERMISSION)
+        .await
+    {
+        return response;
+    }
+
+    let owner = match &if_match {
+        Some(_) => authorize(&state, &claims, query.owner, &file, Right::Write).await,
+        None => resolve_owner(&claims, query.owner).await,
+    };
+    let owner = match owner {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    let file = match StoredFile::new(owner, file) {
+        Ok(file) => file,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    let file_key = match headers.get(FILE_KEY_HEADER).map(parse_file_key) {
+        None => None,
+        Some(Some(file_key)) => Some(file_key),
+        Some(None) => return (StatusCode::BAD_REQUEST, "Invalid file key").into_response(),
+    };
+    let describe = |content: ContentInfo| match file_key {
+        Some(_) => content.end_to_end(),
+        None => content,
+    };
+    let contents = contents.map_err(std::io::Error::other).boxed();
+
+    // Held until the upload is recorded, so that the record of a file always describes the
+    // upload that ended up in storage.
+    let file = STORAGE.get().unwrap().lock(&file).await;
+    let saved = match if_match {
+        None => match STORAGE.get().unwrap().create(&file, contents).await {
+            Ok(Some(content)) => {
+                state
+                    .write()
+                    .expect("poisoned lock")
+                    .db
+                    .add_file(FileMetadata::new(
+                        file.owner().clone(),
+                        file.name().to_owned(),
+                        claims.username().clone(),
+                        describe(content),
+                    ))
+            }
+            Ok(None) => return (StatusCode::CONFLICT, "File already exists").into_response(),
+            Err(err) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+            }
+        },
+        Some(if_match) => {
+            match STORAGE
+                .get()
+                .unwrap()
+                .replace(&file, contents, &if_match)
+                .await
+            {
+                Ok(Some(content)) => {
+                    let content = describe(content);
+                    info!(
+                        owner = %file.owner(),
+                        file = %file.name(),
+                        username = %claims.username(),
+                        "Replaced file"
+                    );
+                    let db = &mut state.write().expect("poisoned lock").db;
+                    match db.replace_file_content(file.owner(), file.name(), content.clone()) {
+                        Ok(true) => Ok(()),
+                        Ok(false) => db.add_file(FileMetadata::new(
+                            file.owner().clone(),
+                            file.name().to_owned(),
+                            claims.username().clone(),
+                            content,
+                        )),
+                        Err(err) => Err(err),
+                    }
+                }
+                Ok(None) => return StatusCode::PRECONDITION_FAILED.into_response(),
+                Err(err) => {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+                }
+            }
+        }
+    };
+
+    let saved = saved.and_then(|()| match file_key {
+        Some(file_key) => state
+            .write()
+            .expect("poisoned lock")
+            .db
+            .set_file_key(file.owner(), file.name(), claims.username(), &file_key)
+            .map(|_| ()),
+        None => Ok(()),
+    });
+
+    match saved {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(err) => {
+            error!(?err, "Error saving database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Gets the key of an end-to-end encrypted file, as wrapped for the caller.
+#[tracing::instrument(skip(state), ret)]
+async fn file_key(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(file): Path<String>,
+    Query(query): Query<OwnerQuery>,
+) -> Response {
+    if let Err(response) = AUTH_CLIENT
+        .get()
+        .unwrap()
+        .user_has_permission_into_response(claims.username(), &FILES_READ_PERMISSION)
+        .await
+    {
+        return response;
+    }
+
+    let owner = match authorize(&state, &claims, query.owner, &file, Right::Read).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    match state
+        .read()
+        .expect("poisoned lock")
+        .db
+        .get_file_key(&owner, &file, claims.username())
+    {
+        Ok(Some(file_key)) => file_key.into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "Error reading database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Records the key of an end-to-end encrypted file as wrapped for another user, so that they can
+/// decrypt it. Besides the owner, whoever the file's ACL grants the share right can do this.
+#[tracing::instrument(skip(state, file_key), ret)]
+async fn add_file_key(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path((file, recipient)): Path<(String, Username)>,
+    Query(query): Query<OwnerQuery>,
+    file_key: String,
+) -> Response {
+    if let Err(response) = AUTH_CLIENT
+        .get()
+        .unwrap()
+        .user_has_permission_into_response(claims.username(), &FILES_WRITE_PERMISSION)
+        .await
+    {
+        return response;
+    }
+
+    let owner = match authorize(&state, &claims, query.owner, &file, Right::Share).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    let file_key = match HeaderValue::from_str(&file_key)
+        .ok()
+        .as_ref()
+        .and_then(parse_file_key)
+    {
+        Some(file_key) => file_key,
+        None => return (StatusCode::BAD_REQUEST, "Invalid file key").into_response(),
+    };
+
+    match state
+        .write()
+        .expect("poisoned lock")
+        .db
+        .set_file_key(&owner, &file, &recipient, &file_key)
+    {
+        Ok(true) => {
+            info!(%owner, %file, %recipient, "Added recipient of end-to-end encrypted file");
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "Error saving database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[tracing::instrument(skip(state), ret)]
+async fn get_acl(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(file): Path<String>,
+    Query(query): Query<OwnerQuery>,
 ) -> Response {
-    let role = state
+    let owner = match resolve_owner(&claims, query.owner).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    match state
+        .read()
+        .expect("poisoned lock")
+        .db
+        .get_file(&owner, &file)
+    {
+        Ok(Some(metadata)) => Json(metadata.acl()).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "Error reading database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Replaces the users and groups a file is shared with, which only its owner and admins can do.
+#[tracing::instrument(skip(state), ret)]
+async fn set_acl(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(file): Path<String>,
+    Query(query): Query<OwnerQuery>,
+    Json(acl): Json<Acl>,
+) -> Response {
+    if let Err(response) = AUTH_CLIENT
+        .get()
+        .unwrap()
+        .user_has_permission_into_response(claims.username(), &FILES_SHARE_PERMISSION)
+        .await
+    {
+        return response;
+    }
+
+    let owner = match resolve_owner(&claims, query.owner).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    let acl = acl.normalized();
+    if acl.len() > MAX_ACL_ENTRIES {
+        return (StatusCode::BAD_REQUEST, "Too many ACL entries").into_response();
+    }
+
+    match state
+        .write()
+        .expect("poisoned lock")
+        .db
+        .set_acl(&owner, &file, acl)
+    {
+        Ok(true) => {
+            info!(%owner, %file, username = %claims.username(), "Changed ACL of file");
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "Error saving database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Set the rights of one user or group to a file, called by the fileshare service when access is
+// granted or revoked
+#[tracing::instrument(skip(state), ret)]
+async fn set_acl_entry(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((owner, file)): Path<(Username, String)>,
+    Json(entry): Json<AclEntry>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+
+    if !state.config.file_store.address_is_service(&addr.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let principal = entry.principal.clone();
+    match state.db.set_acl_entry(&owner, &file, entry) {
+        Ok(true) => {
+            info!(%owner, %file, %principal, "Changed rights of principal to file");
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(?err, "Error saving database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, headers), ret)]
+async fn remove(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(file): Path<String>,
+    Query(query): Query<OwnerQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let if_match = match if_match(&headers) {
+        Ok(if_match) => if_match,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let fileshare_authority = state
         .read()
         .expect("poisoned lock")
         .config
-        .file_store
-        .write_role
-        .clone();
+        .fileshare_server
+        .authority();
     if let Err(response) = AUTH_CLIENT
         .get()
         .unwrap()
-        .user_has_role_into_response(claims.username(), &role)
+        .user_has_permission_into_response(claims.username(), &FILES_DELETE_PERMISSION)
         .await
     {
         return response;
     }
 
-    let body = contents.as_bytes().to_vec();
+    let owner = match resolve_owner(&claims, query.owner).await {
+        Ok(owner) => owner,
+        Err(response) => return response,
+    };
+
+    let stored = match StoredFile::new(owner.clone(), file.clone()) {
+        Ok(stored) => stored,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
 
-    match write_store(&file, &body) {
-        Ok(true) => StatusCode::OK.into_response(),
-        Ok(false) => (StatusCode::CONFLICT, "File already exists").into_response(),
-        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    let stored = STORAGE.get().unwrap().lock(&stored).await;
+    match STORAGE
+        .get()
+        .unwrap()
+        .delete(&stored, if_match.as_ref())
+        .await
+    {
+        Ok(true) => info!(%owner, %file, username = %claims.username(), "Deleted file"),
+        Ok(false) => return StatusCode::PRECONDITION_FAILED.into_response(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return StatusCode::NOT_FOUND.into_response()
+        }
+        Err(err) => {
+            error!(?err, "Failed to delete file from store");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    if let Err(err) = state
+        .write()
+        .expect("poisoned lock")
+        .db
+        .delete_file(&owner, &file)
+    {
+        error!(?err, "Error saving database");
+    }
+    drop(stored);
+
+    // The file is already gone at this point, so a failure here only leaves dangling links behind.
+    if let Err(err) = CLIENT
+        .get()
+        .unwrap()
+        .delete(service_url(
+            &fileshare_authority,
+            &["file-links", owner.as_ref(), &file],
+        ))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+    {
+        error!(?err, "Failed to invalidate links to deleted file");
+    }
+
+    StatusCode::OK.into_response()
+}
+
+// Delete every file owned by a user, called by the auth server when the account is deleted
+#[tracing::instrument(skip(state), ret)]
+async fn delete_user_files(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(owner): Path<Username>,
+) -> Response {
+    if !state
+        .read()
+        .expect("poisoned lock")
+        .config
+        .file_store
+        .address_is_service(&addr.ip())
+    {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let storage = STORAGE.get().unwrap();
+    let files = match storage.list(Some(&owner)).await {
+        Ok(files) => files,
+        Err(err) => {
+            error!(?err, "Failed to list files in store");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    for file in &files {
+        match storage.delete(&storage.lock(file).await, None).await {
+            Ok(_) => {}
+            // Deleted in the meantime
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                error!(?err, "Failed to delete file from store");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    }
+
+    match state
+        .write()
+        .expect("poisoned lock")
+        .db
+        .delete_files_of_user(&owner)
+    {
+        Ok(removed) => {
+            info!(%owner, stored = files.len(), removed, "Deleted files of deleted user");
+            StatusCode::OK.into_response()
+        }
+        Err(err) => {
+            error!(?err, "Error saving database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Remove a group from every ACL, called by the auth server when the group is deleted
+#[tracing::instrument(skip(state), ret)]
+async fn delete_group_acl_entries(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(group): Path<GroupName>,
+) -> Response {
+    let mut state = state.write().expect("poisoned lock");
+
+    if !state.config.file_store.address_is_service(&addr.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    match state.db.delete_group_acl_entries(&group) {
+        Ok(files) => {
+            info!(%group, files, "Removed deleted group from ACLs");
+            StatusCode::OK.into_response()
+        }
+        Err(err) => {
+            error!(?err, "Error saving database");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
     }
 }
 
@@ -162,7 +842,7 @@ async fn write(
 async fn exists(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Path(file): Path<String>,
+    Path((owner, file)): Path<(Username, String)>,
 ) -> Response {
     if !state
         .read()
@@ -174,7 +854,12 @@ async fn exists(
         return StatusCode::FORBIDDEN.into_response();
     }
 
-    match exists_store(&file) {
+    let file = match StoredFile::new(owner, file) {
+        Ok(file) => file,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+
+    match STORAGE.get().unwrap().exists(&file).await {
         Ok(res) => Json(res).into_response(),
         Err(err) => {
             error!(?err, "Failed to check if file exists");
@@ -182,3 +867,127 @@ async fn exists(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use serde_json::json;
+    use server_common::signature;
+
+    use super::*;
+    use crate::config::{EncryptionConfig, StorageConfig};
+    use crate::fake_auth;
+    use crate::state::{Database, State};
+    use crate::storage::Storage;
+    use crate::store::SqliteStore;
+
+    /// Serves the filestore with files kept in memory, returning its address.
+    async fn start_filestore(dir: &std::path::Path) -> SocketAddr {
+        let encryption = EncryptionConfig {
+            master_key: dir.join("master.key"),
+            retired_master_keys: Vec::new(),
+        };
+        assert!(STORAGE
+            .set(Storage::from_config(&StorageConfig::Memory, &encryption, false).unwrap())
+            .is_ok());
+
+        let config = serde_json::from_value(json!({
+            "general": {"port": 0},
+            "auth-server": {"host": "localhost", "port": 1},
+            "file-store": {"known-services": []},
+            "fileshare-server": {"host": "localhost", "port": 1},
+        }))
+        .unwrap();
+        let store = SqliteStore::open(&dir.join("service-filestore.db")).unwrap();
+        let state = Arc::new(RwLock::new(State {
+            config,
+            db: Database::new(Box::new(store)),
+        }));
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            get_router()
+                .layer(axum::middleware::from_fn(signature::verify_body_digest))
+                .with_state(state)
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        );
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    #[tokio::test]
+    async fn binary_files_round_trip() {
+        let token = fake_auth::start().token("alice");
+        let dir = tempfile::tempdir().unwrap();
+        let filestore = start_filestore(dir.path()).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{filestore}/files/image.png");
+        // Every byte value, including sequences that aren't valid UTF-8, over enough bytes to be
+        // streamed and encrypted in several chunks
+        let content: Vec<u8> = (0..=255)
+            .chain([0xc3, 0x28, 0xff, 0xfe, 0x00])
+            .cycle()
+            .take(1024 * 1024 + 7)
+            .collect();
+
+        let response = client
+            .put(&url)
+            .bearer_auth(&token)
+            .body(content.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = client
+            .put(&url)
+            .bearer_auth(&token)
+            .body(content.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = client.get(&url).bearer_auth(&token).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[ETAG].clone();
+        assert_eq!(response.bytes().await.unwrap(), content);
+
+        // Replacing the contents needs the ETag they were read with
+        let replacement: Vec<u8> = content.iter().rev().copied().collect();
+        let response = client
+            .put(&url)
+            .bearer_auth(&token)
+            .header(IF_MATCH, &etag)
+            .body(replacement.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = client
+            .put(&url)
+            .bearer_auth(&token)
+            .header(IF_MATCH, &etag)
+            .body(content.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let response = client.get(&url).bearer_auth(&token).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), replacement);
+        assert!(STORAGE
+            .get()
+            .unwrap()
+            .is_encrypted(
+                &StoredFile::new(
+                    serde_json::from_str("\"alice\"").unwrap(),
+                    "image.png".to_owned()
+                )
+                .unwrap()
+            )
+            .await
+            .unwrap());
+    }
+}
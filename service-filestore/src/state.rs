@@ -1,73 +1,396 @@
+use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::io;
+use std::path::Path;
 use std::sync::{Arc, OnceLock, RwLock};
 
+use anyhow::Context;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+
+use crate::acl::{Acl, AclEntry, Right};
 use crate::config::Config;
-use server_common::auth::AuthClient;
+use crate::encryption::WrappedKey;
+use crate::metadata::{ContentInfo, ContentInspector, FileMetadata};
+use crate::storage::{Rotation, Storage, StoredFile};
+use crate::store::{FileStore, ImportedFile, SqliteStore, StoreError};
+use server_common::auth::{AuthClient, AUTH_CLIENT};
+use server_common::user::{GroupName, Principal, Username};
+use server_common::util::new_reqwest_client_from_certificates;
 use server_common::ServerConfig;
 
-const FILESTORE_PATH: &str = "data/service-filestore/files/";
+const DB_PATH: &str = "data/service-filestore/db.sqlite";
+const LEGACY_DB_PATH: &str = "data/service-filestore/db.json";
+const MIGRATED_DB_PATH: &str = "data/service-filestore/db.json.migrated";
 
 #[derive(Debug)]
 pub struct State {
     pub config: Config,
+    pub db: Database,
 }
 
 pub type AppState = Arc<RwLock<State>>;
 
-fn check_filename(file: &str) -> Result<PathBuf, io::Error> {
-    let file_name = PathBuf::from(file);
+/// Metadata records of the files in the store, by owner and file name.
+#[derive(Debug)]
+pub struct Database {
+    store: Box<dyn FileStore>,
+}
 
-    if file_name.is_absolute() || file_name.components().count() != 1 {
-        return Err(io::Error::other(
-            "Path isn't relative or has multiple components",
-        ));
+impl Database {
+    pub fn new(store: Box<dyn FileStore>) -> Self {
+        Self { store }
     }
 
-    Ok(PathBuf::from(FILESTORE_PATH).join(file_name))
-}
+    pub fn get_file(
+        &self,
+        owner: &Username,
+        file_name: &str,
+    ) -> Result<Option<FileMetadata>, StoreError> {
+        self.store.get(owner, file_name)
+    }
 
-pub fn list_store() -> Result<Vec<String>, io::Error> {
-    Ok(fs::read_dir(FILESTORE_PATH)?
-        .filter_map(|file| file.ok())
-        .filter_map(|file| file.file_name().into_string().ok())
-        .collect())
-}
+    /// Gets the data key of a file that was encrypted before data keys were stored along with the
+    /// contents, until `rotate-keys` moves it there.
+    pub fn legacy_key(
+        &self,
+        owner: &Username,
+        file_name: &str,
+    ) -> Result<Option<WrappedKey>, StoreError> {
+        self.store.legacy_key(owner, file_name)
+    }
+
+    /// Gets the key of an end-to-end encrypted file that was wrapped for `recipient`.
+    pub fn get_file_key(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        recipient: &Username,
+    ) -> Result<Option<String>, StoreError> {
+        self.store.get_file_key(owner, file_name, recipient)
+    }
+
+    /// Records the key of an end-to-end encrypted file, wrapped for `recipient`. Returns
+    /// `Ok(false)` if there's no such file, or it isn't end-to-end encrypted.
+    pub fn set_file_key(
+        &mut self,
+        owner: &Username,
+        file_name: &str,
+        recipient: &Username,
+        key: &str,
+    ) -> Result<bool, StoreError> {
+        if !self
+            .get_file(owner, file_name)?
+            .is_some_and(|metadata| metadata.is_end_to_end())
+        {
+            return Ok(false);
+        }
+        self.store.set_file_key(owner, file_name, recipient, key)
+    }
+
+    pub fn has_encrypted_files(&self) -> Result<bool, StoreError> {
+        self.store.has_encrypted_files()
+    }
+
+    /// Lists the records of files owned by `owner`, or of every file if `owner` is `None`.
+    pub fn list_files(&self, owner: Option<&Username>) -> Result<Vec<FileMetadata>, StoreError> {
+        self.store.list(owner)
+    }
+
+    /// Lists the records of the files a user owns or that were shared with them, directly or
+    /// through one of their groups.
+    pub fn list_accessible_files(
+        &self,
+        user: &Username,
+        groups: &[GroupName],
+    ) -> Result<Vec<FileMetadata>, StoreError> {
+        let principals = std::iter::once(Principal::User(user.clone()))
+            .chain(groups.iter().cloned().map(Principal::Group))
+            .collect::<Vec<_>>();
+        let mut files = self.store.list(Some(user))?;
+        files.extend(
+            self.store
+                .list_by_principal(&principals)?
+                .into_iter()
+                .filter(|metadata| {
+                    metadata.owner() != user && metadata.acl().allows(user, groups, Right::Read)
+                }),
+        );
+        Ok(files)
+    }
+
+    /// Replaces the ACL of a file, returning `Ok(false)` if there's no such file.
+    pub fn set_acl(
+        &mut self,
+        owner: &Username,
+        file_name: &str,
+        acl: Acl,
+    ) -> Result<bool, StoreError> {
+        let mut acl = Some(acl);
+        let updated = self.store.update(owner, file_name, &mut |metadata| {
+            *metadata.acl_mut() = acl.take().unwrap_or_default();
+            true
+        })?;
+        Ok(updated.is_some())
+    }
+
+    /// Replaces the rights of a single principal in the ACL of a file, returning `Ok(false)` if
+    /// there's no such file.
+    pub fn set_acl_entry(
+        &mut self,
+        owner: &Username,
+        file_name: &str,
+        entry: AclEntry,
+    ) -> Result<bool, StoreError> {
+        let updated = self.store.update(owner, file_name, &mut |metadata| {
+            metadata.acl_mut().set_entry(entry.clone());
+            true
+        })?;
+        Ok(updated.is_some())
+    }
+
+    /// Records a new file, whose contents were stored encrypted.
+    pub fn add_file(&mut self, metadata: FileMetadata) -> Result<(), StoreError> {
+        self.store.upsert(&metadata, true)
+    }
+
+    /// Records a file that was found in storage without a record, unless one was added in the
+    /// meantime. Returns `Ok(false)` if there already is one.
+    pub fn add_missing_file(
+        &mut self,
+        metadata: FileMetadata,
+        encrypted: bool,
+    ) -> Result<bool, StoreError> {
+        self.store.insert(&metadata, encrypted)
+    }
 
-pub fn read_store(file_name: &str) -> Result<Vec<u8>, io::Error> {
-    let file_path = check_filename(file_name)?;
+    pub fn replace_file_content(
+        &mut self,
+        owner: &Username,
+        file_name: &str,
+        content: ContentInfo,
+    ) -> Result<bool, StoreError> {
+        self.store.replace_content(owner, file_name, content)
+    }
+
+    /// Records that a file is now stored encrypted with its data key in its header, so that any
+    /// key recorded for it is no longer needed.
+    pub fn set_encrypted(&mut self, owner: &Username, file_name: &str) -> Result<(), StoreError> {
+        self.store.set_encrypted(owner, file_name)
+    }
+
+    pub fn delete_file(&mut self, owner: &Username, file_name: &str) -> Result<bool, StoreError> {
+        self.store.delete(owner, file_name)
+    }
 
-    let mut buffer = Vec::new();
-    let mut file = File::open(file_path)?;
-    file.read_to_end(&mut buffer)?;
+    /// Removes the records of every file owned by `owner`, along with the file keys wrapped for
+    /// them and the ACL entries naming them, returning how many files were removed.
+    pub fn delete_files_of_user(&mut self, owner: &Username) -> Result<usize, StoreError> {
+        self.store.delete_of_user(owner)
+    }
 
-    Ok(buffer)
+    /// Removes the ACL entries naming a deleted group, returning from how many files.
+    pub fn delete_group_acl_entries(&mut self, group: &GroupName) -> Result<usize, StoreError> {
+        self.store
+            .delete_acl_entries(&Principal::Group(group.clone()))
+    }
 }
 
-pub fn write_store(file_name: &str, file_content: &[u8]) -> Result<bool, io::Error> {
-    let file_path = check_filename(file_name)?;
+/// Imports the records of the JSON file the database used to be kept in, then moves the file
+/// aside so that it's only imported once.
+///
+/// Files that already have a record in the store are skipped, so the import is safe to repeat if
+/// the server stops before the file is moved.
+fn migrate_legacy_db(
+    store: &dyn FileStore,
+    legacy_path: &Path,
+    migrated_path: &Path,
+) -> anyhow::Result<()> {
+    #[derive(Deserialize)]
+    struct LegacyDatabase {
+        files: HashMap<Username, HashMap<String, FileMetadata>>,
+        #[serde(default)]
+        keys: HashMap<Username, HashMap<String, WrappedKey>>,
+        #[serde(default)]
+        file_keys: HashMap<Username, HashMap<String, HashMap<Username, String>>>,
+        #[serde(default)]
+        encrypted_files: bool,
+    }
 
-    if file_path.exists() {
-        return Ok(false);
+    let file = match File::open(legacy_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("Failed to open legacy db file"),
+    };
+    let mut legacy: LegacyDatabase =
+        serde_json::from_reader(file).context("Failed to deserialize legacy db file")?;
+
+    let mut files = Vec::new();
+    for (owner, records) in legacy.files {
+        for (file_name, metadata) in records {
+            let legacy_key = legacy
+                .keys
+                .get_mut(owner.as_ref())
+                .and_then(|keys| keys.remove(&file_name));
+            let file_keys = legacy
+                .file_keys
+                .get_mut(owner.as_ref())
+                .and_then(|files| files.remove(&file_name))
+                .unwrap_or_default();
+            files.push(ImportedFile {
+                metadata,
+                // Which files were stored encrypted wasn't tracked, so every file is assumed to
+                // be, to keep the master key from being lost.
+                encrypted: legacy_key.is_some() || legacy.encrypted_files,
+                legacy_key,
+                file_keys: file_keys.into_iter().collect(),
+            });
+        }
     }
 
-    let mut file = File::create(file_path)?;
-    file.write_all(file_content)?;
+    info!(
+        files = files.len(),
+        "Importing file records from '{}'",
+        legacy_path.display()
+    );
+    store
+        .import(&files)
+        .context("Failed to import legacy db file")?;
+    fs::rename(legacy_path, migrated_path).context("Failed to move legacy db file")?;
+    Ok(())
+}
+
+/// Opens the database, importing the legacy JSON database if there is one.
+fn open_db() -> anyhow::Result<Database> {
+    fs::create_dir_all(Path::new(DB_PATH).parent().unwrap())?;
+    let store = SqliteStore::open(Path::new(DB_PATH)).context("Failed to open db")?;
+    migrate_legacy_db(
+        &store,
+        Path::new(LEGACY_DB_PATH),
+        Path::new(MIGRATED_DB_PATH),
+    )?;
+    Ok(Database::new(Box::new(store)))
+}
+
+/// Creates metadata records for stored files that don't have one, such as those uploaded before
+/// metadata was tracked. Their owner is recorded as the uploader.
+async fn backfill_metadata(state: AppState) -> anyhow::Result<()> {
+    let storage = STORAGE.get().unwrap();
+    for stored in storage.list(None).await? {
+        // Keeps uploads from recording the file while it's being inspected
+        let stored = storage.lock(&stored).await;
+        if state
+            .read()
+            .expect("poisoned lock")
+            .db
+            .get_file(stored.owner(), stored.name())?
+            .is_some()
+        {
+            continue;
+        }
 
-    Ok(true)
+        let (metadata, encrypted) = inspect_stored_file(storage, &stored).await?;
+        if state
+            .write()
+            .expect("poisoned lock")
+            .db
+            .add_missing_file(metadata, encrypted)?
+        {
+            info!(owner = %stored.owner(), file = %stored.name(), "Recorded metadata of stored file");
+        }
+    }
+    Ok(())
 }
 
-pub fn exists_store(file_name: &str) -> Result<bool, io::Error> {
-    let file_path = check_filename(file_name)?;
-    Ok(file_path.is_file())
+/// Describes a stored file that has no record, which is either a file uploaded before metadata was
+/// tracked or one whose upload was interrupted before it could be recorded. Returns whether it's
+/// stored encrypted along with its metadata.
+async fn inspect_stored_file(
+    storage: &Storage,
+    stored: &StoredFile,
+) -> Result<(FileMetadata, bool), io::Error> {
+    let encrypted = storage.is_encrypted(stored).await?;
+    let mut content = storage.read(stored, None).await?;
+    let mut inspector = ContentInspector::default();
+    while let Some(chunk) = content.body.next().await {
+        inspector.update(&chunk?);
+    }
+
+    let metadata = FileMetadata::new(
+        stored.owner().clone(),
+        stored.name().to_owned(),
+        stored.owner().clone(),
+        inspector.finish(),
+    )
+    .with_uploaded_at(OffsetDateTime::from(content.modified));
+    Ok((metadata, encrypted))
 }
 
-pub static AUTH_CLIENT: OnceLock<AuthClient> = OnceLock::new();
+pub static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+pub static STORAGE: OnceLock<Storage> = OnceLock::new();
+
+/// Stores every file encrypted, with its data key wrapped with the current master key in its
+/// header. This works on the database directly, so the server must not be running.
+pub async fn rotate_keys(config: Config) -> anyhow::Result<()> {
+    let mut db = open_db()?;
+    let storage = Storage::from_config(
+        &config.file_store.storage,
+        &config.file_store.encryption,
+        db.has_encrypted_files()?,
+    )
+    .context("Failed to set up storage")?;
+
+    let (mut rewrapped, mut encrypted) = (0, 0);
+    for file in storage.list(None).await? {
+        let file = storage.lock(&file).await;
+        let legacy_key = db.legacy_key(file.owner(), file.name())?;
+        let rotation = storage
+            .rotate(&file, legacy_key.as_ref())
+            .await
+            .with_context(|| format!("Failed to rotate '{}/{}'", file.owner(), file.name()))?;
+        match rotation {
+            Rotation::Current if legacy_key.is_none() => continue,
+            Rotation::Current => {}
+            Rotation::Rewrapped => rewrapped += 1,
+            Rotation::Encrypted => {
+                info!(owner = %file.owner(), file = %file.name(), "Encrypted stored file");
+                encrypted += 1;
+            }
+            Rotation::Changed => {
+                warn!(
+                    owner = %file.owner(),
+                    file = %file.name(),
+                    "File changed while it was being rotated; run this again"
+                );
+                continue;
+            }
+        }
+        // Only once the key is stored with the file, which then no longer depends on it
+        db.set_encrypted(file.owner(), file.name())?;
+    }
+    info!(
+        rewrapped,
+        encrypted, "Stored data keys with the current master key"
+    );
+
+    Ok(())
+}
 
 pub fn get_state(config: Config) -> anyhow::Result<AppState> {
-    // Ensure that the file store directory exists
-    fs::create_dir_all(FILESTORE_PATH)?;
+    let db = open_db()?;
+
+    STORAGE
+        .set(
+            Storage::from_config(
+                &config.file_store.storage,
+                &config.file_store.encryption,
+                db.has_encrypted_files()?,
+            )
+            .context("Failed to set up storage")?,
+        )
+        .expect("this should only get called once");
 
     AUTH_CLIENT
         .set(AuthClient::new(
@@ -77,5 +400,253 @@ pub fn get_state(config: Config) -> anyhow::Result<AppState> {
         .ok()
         .expect("this should only get called once");
 
-    Ok(Arc::new(RwLock::new(State { config })))
+    CLIENT
+        .set(new_reqwest_client_from_certificates(Config::name())?)
+        .expect("this should only get called once");
+
+    let state = Arc::new(RwLock::new(State { config, db }));
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            if let Err(err) = backfill_metadata(state).await {
+                error!(?err, "Failed to record metadata of stored files");
+            }
+        }
+    });
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use axum::body::Bytes;
+    use axum::headers::IfMatch;
+    use futures_util::{stream, TryStreamExt};
+
+    use super::*;
+    use crate::config::{EncryptionConfig, LocalStorageConfig, StorageConfig};
+
+    const FIRST: &[u8] = b"First draft of the report.";
+    const SECOND: &[u8] = b"Second draft of the report, which was never recorded.";
+    // Tells the child process where to store files until it's killed
+    const CRASH_TEST_DIR_VAR: &str = "FILESTORE_CRASH_TEST_DIR";
+
+    fn storage(dir: &Path) -> Storage {
+        Storage::from_config(
+            &StorageConfig::Local(LocalStorageConfig {
+                path: dir.join("files"),
+                legacy_owner: None,
+            }),
+            &EncryptionConfig {
+                master_key: dir.join("master.key"),
+                retired_master_keys: Vec::new(),
+            },
+            false,
+        )
+        .unwrap()
+    }
+
+    fn stored_file(name: &str) -> StoredFile {
+        StoredFile::new(serde_json::from_str("\"alice\"").unwrap(), name.to_owned()).unwrap()
+    }
+
+    fn stream_of(content: &'static [u8]) -> crate::storage::ByteStream {
+        stream::once(async move { Ok(Bytes::from_static(content)) }).boxed()
+    }
+
+    async fn read(storage: &Storage, file: &StoredFile) -> Vec<u8> {
+        storage
+            .read(file, None)
+            .await
+            .unwrap()
+            .body
+            .try_fold(Vec::new(), |mut collected, chunk| async move {
+                collected.extend_from_slice(&chunk);
+                Ok(collected)
+            })
+            .await
+            .unwrap()
+    }
+
+    /// Run in a child process by `killed_upload_leaves_files_readable`, which kills it after it
+    /// stored files but before it could record them.
+    #[tokio::test]
+    #[ignore]
+    async fn store_until_killed() {
+        let Ok(dir) = std::env::var(CRASH_TEST_DIR_VAR) else {
+            return;
+        };
+        let dir = Path::new(&dir);
+        let storage = storage(dir);
+        storage
+            .create(
+                &storage.lock(&stored_file("new.txt")).await,
+                stream_of(SECOND),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        storage
+            .replace(
+                &storage.lock(&stored_file("existing.txt")).await,
+                stream_of(SECOND),
+                &IfMatch::any(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        // This is where the uploads would be recorded in the database
+        fs::write(dir.join("stored"), "").unwrap();
+        loop {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn killed_upload_leaves_files_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = stored_file("existing.txt");
+        let storage = storage(dir.path());
+        storage
+            .create(&storage.lock(&existing).await, stream_of(FIRST))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut uploader = Command::new(std::env::current_exe().unwrap())
+            .args(["--ignored", "--exact", "state::tests::store_until_killed"])
+            .env(CRASH_TEST_DIR_VAR, dir.path())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        while !dir.path().join("stored").exists() {
+            assert!(
+                started.elapsed() < Duration::from_secs(120),
+                "uploader made no progress"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        uploader.kill().unwrap();
+        uploader.wait().unwrap();
+
+        // Neither upload was recorded, but both can be read with the key stored alongside them.
+        let new = stored_file("new.txt");
+        assert_eq!(read(&storage, &existing).await, SECOND);
+        assert_eq!(read(&storage, &new).await, SECOND);
+
+        let (metadata, encrypted) = inspect_stored_file(&storage, &new).await.unwrap();
+        assert!(encrypted);
+        let mut inspector = ContentInspector::default();
+        inspector.update(SECOND);
+        let expected = FileMetadata::new(
+            new.owner().clone(),
+            new.name().to_owned(),
+            new.owner().clone(),
+            inspector.finish(),
+        );
+        let (metadata, expected) = (
+            serde_json::to_value(metadata).unwrap(),
+            serde_json::to_value(expected).unwrap(),
+        );
+        assert_eq!(metadata["size"], expected["size"]);
+        assert_eq!(metadata["sha256"], expected["sha256"]);
+
+        // Rotating keys leaves them as they are, rather than encrypting them again
+        for file in [&existing, &new] {
+            let lock = storage.lock(file).await;
+            assert_eq!(
+                storage.rotate(&lock, None).await.unwrap(),
+                Rotation::Current
+            );
+            assert_eq!(read(&storage, file).await, SECOND);
+        }
+    }
+
+    #[test]
+    fn legacy_db_is_imported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (legacy_path, migrated_path) = (
+            dir.path().join("db.json"),
+            dir.path().join("db.json.migrated"),
+        );
+        let alice: Username = serde_json::from_str("\"alice\"").unwrap();
+        let mut inspector = ContentInspector::default();
+        inspector.update(FIRST);
+        let metadata = FileMetadata::new(
+            alice.clone(),
+            "report.txt".to_owned(),
+            alice.clone(),
+            inspector.finish().end_to_end(),
+        );
+        let key = serde_json::json!({"master_key": "0123456789abcdef", "nonce": "n", "key": "k"});
+        let legacy = serde_json::json!({
+            "files": {"alice": {"report.txt": metadata}},
+            "keys": {"alice": {"report.txt": key}},
+            "file_keys": {"alice": {"report.txt": {"bob": "wrapped for bob"}}},
+        });
+        fs::write(&legacy_path, legacy.to_string()).unwrap();
+
+        let store = SqliteStore::open(&dir.path().join("service-filestore.db")).unwrap();
+        migrate_legacy_db(&store, &legacy_path, &migrated_path).unwrap();
+        assert!(!legacy_path.exists());
+        // As if the server stopped before the file was moved, after the record was changed
+        fs::copy(&migrated_path, &legacy_path).unwrap();
+        store
+            .replace_content(&alice, "report.txt", ContentInspector::default().finish())
+            .unwrap();
+        migrate_legacy_db(&store, &legacy_path, &migrated_path).unwrap();
+
+        assert!(!legacy_path.exists());
+        assert!(migrated_path.exists());
+        let files = store.list(None).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_ne!(files[0], metadata);
+        assert!(store.legacy_key(&alice, "report.txt").unwrap().is_none());
+        assert!(store.has_encrypted_files().unwrap());
+        // A repeated import doesn't bring back keys of the old contents
+        let bob = serde_json::from_str("\"bob\"").unwrap();
+        assert!(store
+            .get_file_key(&alice, "report.txt", &bob)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn legacy_keys_are_imported() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy_path = dir.path().join("db.json");
+        let alice: Username = serde_json::from_str("\"alice\"").unwrap();
+        let metadata = |name: &str| {
+            FileMetadata::new(
+                alice.clone(),
+                name.to_owned(),
+                alice.clone(),
+                ContentInspector::default().finish(),
+            )
+        };
+        let key = serde_json::json!({"master_key": "0123456789abcdef", "nonce": "n", "key": "k"});
+        let legacy = serde_json::json!({
+            "files": {"alice": {
+                "report.txt": metadata("report.txt"),
+                "plain.txt": metadata("plain.txt"),
+            }},
+            "keys": {"alice": {"report.txt": key}},
+        });
+        fs::write(&legacy_path, legacy.to_string()).unwrap();
+
+        let store = SqliteStore::open(&dir.path().join("service-filestore.db")).unwrap();
+        migrate_legacy_db(&store, &legacy_path, &dir.path().join("migrated")).unwrap();
+        assert_eq!(
+            store.legacy_key(&alice, "report.txt").unwrap(),
+            Some(serde_json::from_value(key).unwrap())
+        );
+        assert!(store.legacy_key(&alice, "plain.txt").unwrap().is_none());
+        assert!(store.has_encrypted_files().unwrap());
+    }
 }
@@ -0,0 +1,645 @@
+use std::collections::HashMap;
+use std::io;
+use std::ops::Deref;
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::SystemTime;
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::headers::{ETag, IfMatch};
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::config::{EncryptionConfig, StorageConfig};
+use crate::encryption::{self, MasterKeys, WrappedKey};
+use crate::metadata::{ContentInfo, ContentInspector};
+use server_common::user::Username;
+
+#[cfg(test)]
+mod fake_s3;
+mod local;
+mod memory;
+mod s3;
+
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use s3::S3Storage;
+
+pub type ByteStream = BoxStream<'static, Result<Bytes, io::Error>>;
+
+/// A file in the store, identified by its owner and its name within the owner's namespace.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct StoredFile {
+    owner: Username,
+    name: String,
+}
+
+impl StoredFile {
+    /// Fails if `name` isn't a single plain path component, so that backends can safely use it as
+    /// part of a path or key.
+    pub fn new(owner: Username, name: String) -> Result<Self, io::Error> {
+        let mut components = Path::new(&name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Path isn't relative or has multiple components",
+            ));
+        }
+
+        Ok(Self { owner, name })
+    }
+
+    pub fn owner(&self) -> &Username {
+        &self.owner
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// The contents of a stored file, along with what's needed to serve them.
+pub struct FileContent {
+    pub size: u64,
+    pub etag: ETag,
+    pub modified: SystemTime,
+    pub body: ByteStream,
+}
+
+/// Where the contents of stored files are kept.
+///
+/// Reading a missing file, or deleting one, fails with [`io::ErrorKind::NotFound`]. [`Storage`]
+/// only writes a file while holding its [`FileLock`], so a backend's checks before writing aren't
+/// raced by other writes through the same store.
+#[async_trait]
+pub trait StorageBackend: std::fmt::Debug + Send + Sync {
+    /// Lists the files owned by `owner`, or every file in the store if `owner` is `None`.
+    async fn list(&self, owner: Option<&Username>) -> Result<Vec<StoredFile>, io::Error>;
+
+    async fn read(&self, file: &StoredFile) -> Result<FileContent, io::Error>;
+
+    async fn exists(&self, file: &StoredFile) -> Result<bool, io::Error>;
+
+    /// Writes `content` to a new file. Returns `Ok(false)` if the file already exists.
+    ///
+    /// If the stream fails midway, no file is left behind.
+    async fn create(&self, file: &StoredFile, content: ByteStream) -> Result<bool, io::Error>;
+
+    /// Replaces the contents of an existing file, if its current ETag satisfies `if_match`.
+    /// Returns `Ok(false)` if the precondition fails, which includes the case where the file
+    /// doesn't exist.
+    ///
+    /// Readers never observe a partially replaced file.
+    async fn replace(
+        &self,
+        file: &StoredFile,
+        content: ByteStream,
+        if_match: &IfMatch,
+    ) -> Result<bool, io::Error>;
+
+    /// Removes a file, if its current ETag satisfies `if_match` (when present). Returns
+    /// `Ok(false)` if the precondition fails.
+    async fn delete(
+        &self,
+        file: &StoredFile,
+        if_match: Option<&IfMatch>,
+    ) -> Result<bool, io::Error>;
+}
+
+/// The configured storage backend, which encrypts file contents and records their properties as
+/// they're written.
+///
+/// Every file is encrypted with its own data key, which is wrapped with the master key and stored
+/// in a header in front of the file's encrypted contents.
+///
+/// Writing a file requires holding its [`FileLock`], so that backends can check preconditions
+/// before writing without another upload getting in between.
+#[derive(Debug)]
+pub struct Storage {
+    backend: Box<dyn StorageBackend>,
+    keys: MasterKeys,
+    /// The lock of every file that's being written or waited for.
+    locks: Mutex<HashMap<StoredFile, Weak<tokio::sync::Mutex<()>>>>,
+}
+
+/// Exclusive access to write a file, taken with [`Storage::lock`].
+///
+/// It should be held until the outcome of the write is recorded in the database, so that the
+/// record always describes the upload that ended up in storage.
+#[derive(Debug)]
+pub struct FileLock<'a> {
+    file: StoredFile,
+    storage: &'a Storage,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Deref for FileLock<'_> {
+    type Target = StoredFile;
+
+    fn deref(&self) -> &StoredFile {
+        &self.file
+    }
+}
+
+impl Drop for FileLock<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let mut locks = self.storage.locks.lock().expect("poisoned lock");
+        // Kept while anyone else is waiting for it
+        if locks
+            .get(&self.file)
+            .is_some_and(|lock| lock.strong_count() == 0)
+        {
+            locks.remove(&self.file);
+        }
+    }
+}
+
+/// What [`Storage::rotate`] did to a file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rotation {
+    /// The file's key was already wrapped with the current master key and stored with it.
+    Current,
+    /// The file's key was wrapped again with the current master key, or moved into the file.
+    Rewrapped,
+    /// The file was still stored in plaintext and is now encrypted.
+    Encrypted,
+    /// The file was replaced while it was being rotated, and was left as it was.
+    Changed,
+}
+
+impl Storage {
+    /// `has_encrypted_files` tells whether any file was stored encrypted before, in which case the
+    /// master key must already exist.
+    pub fn from_config(
+        storage: &StorageConfig,
+        encryption: &EncryptionConfig,
+        has_encrypted_files: bool,
+    ) -> anyhow::Result<Self> {
+        let backend: Box<dyn StorageBackend> = match storage {
+            StorageConfig::Local(config) => Box::new(LocalStorage::new(
+                &config.path,
+                config.legacy_owner.as_ref(),
+            )?),
+            StorageConfig::S3(config) => Box::new(S3Storage::new(config)?),
+            StorageConfig::Memory => Box::<MemoryStorage>::default(),
+        };
+        Ok(Self {
+            backend,
+            keys: MasterKeys::from_config(encryption, has_encrypted_files)?,
+            locks: Mutex::default(),
+        })
+    }
+
+    /// Waits until no one else is writing `file`, and keeps others from writing it until the
+    /// returned lock is dropped.
+    pub async fn lock(&self, file: &StoredFile) -> FileLock<'_> {
+        let lock = {
+            let mut locks = self.locks.lock().expect("poisoned lock");
+            match locks.get(file).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    locks.insert(file.clone(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+
+        FileLock {
+            file: file.clone(),
+            storage: self,
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+
+    pub async fn list(&self, owner: Option<&Username>) -> Result<Vec<StoredFile>, io::Error> {
+        self.backend.list(owner).await
+    }
+
+    /// Reads a file, decrypting it if it was stored encrypted.
+    ///
+    /// Files stored before encryption at rest was introduced are served as they are, unless
+    /// `legacy_key` is given: files encrypted before data keys were stored with them have their
+    /// key recorded in the database instead.
+    pub async fn read(
+        &self,
+        file: &StoredFile,
+        legacy_key: Option<&WrappedKey>,
+    ) -> Result<FileContent, io::Error> {
+        let content = self.backend.read(file).await?;
+        let sealed = encryption::read_header(content.body).await?;
+        let Some(key) = sealed.key.as_ref().or(legacy_key) else {
+            return Ok(FileContent {
+                body: sealed.body,
+                ..content
+            });
+        };
+
+        let key = self.keys.data_key(key)?;
+        Ok(FileContent {
+            size: encryption::plaintext_size(content.size - sealed.header_size),
+            body: encryption::decrypt(sealed.body, &key),
+            ..content
+        })
+    }
+
+    /// Tells whether a file is stored encrypted, with its data key in its header.
+    pub async fn is_encrypted(&self, file: &StoredFile) -> Result<bool, io::Error> {
+        let content = self.backend.read(file).await?;
+        Ok(encryption::read_header(content.body).await?.key.is_some())
+    }
+
+    pub async fn exists(&self, file: &StoredFile) -> Result<bool, io::Error> {
+        self.backend.exists(file).await
+    }
+
+    /// Returns `Ok(None)` if the file already exists.
+    pub async fn create(
+        &self,
+        file: &FileLock<'_>,
+        content: ByteStream,
+    ) -> Result<Option<ContentInfo>, io::Error> {
+        let (content, inspector) = inspect(content);
+        let created = self
+            .backend
+            .create(file, self.keys.encrypt(content))
+            .await?;
+        Ok(created.then(|| finish(&inspector)))
+    }
+
+    /// Returns `Ok(None)` if the precondition fails.
+    pub async fn replace(
+        &self,
+        file: &FileLock<'_>,
+        content: ByteStream,
+        if_match: &IfMatch,
+    ) -> Result<Option<ContentInfo>, io::Error> {
+        let (content, inspector) = inspect(content);
+        let replaced = self
+            .backend
+            .replace(file, self.keys.encrypt(content), if_match)
+            .await?;
+        Ok(replaced.then(|| finish(&inspector)))
+    }
+
+    pub async fn delete(
+        &self,
+        file: &FileLock<'_>,
+        if_match: Option<&IfMatch>,
+    ) -> Result<bool, io::Error> {
+        self.backend.delete(file, if_match).await
+    }
+
+    /// Stores a file encrypted, with its data key wrapped with the current master key in its
+    /// header. `legacy_key` is the key recorded for a file that was encrypted before data keys
+    /// were stored with it.
+    pub async fn rotate(
+        &self,
+        file: &FileLock<'_>,
+        legacy_key: Option<&WrappedKey>,
+    ) -> Result<Rotation, io::Error> {
+        let content = self.backend.read(file).await?;
+        let if_match = IfMatch::from(content.etag);
+        let sealed = encryption::read_header(content.body).await?;
+
+        let (rotation, body) = match (&sealed.key, legacy_key) {
+            (Some(key), _) => match self.keys.rewrap(key)? {
+                Some(key) => (
+                    Rotation::Rewrapped,
+                    encryption::with_header(&key, sealed.body),
+                ),
+                None => return Ok(Rotation::Current),
+            },
+            (None, Some(key)) => {
+                let key = self.keys.rewrap(key)?.unwrap_or_else(|| key.clone());
+                (
+                    Rotation::Rewrapped,
+                    encryption::with_header(&key, sealed.body),
+                )
+            }
+            (None, None) => (Rotation::Encrypted, self.keys.encrypt(sealed.body)),
+        };
+
+        let replaced = self.backend.replace(file, body, &if_match).await?;
+        Ok(if replaced {
+            rotation
+        } else {
+            Rotation::Changed
+        })
+    }
+}
+
+/// Feeds the chunks of `content` to an inspector as the backend consumes them.
+fn inspect(content: ByteStream) -> (ByteStream, Arc<Mutex<ContentInspector>>) {
+    let inspector = Arc::new(Mutex::new(ContentInspector::default()));
+    let content = {
+        let inspector = inspector.clone();
+        content
+            .inspect_ok(move |chunk| inspector.lock().expect("poisoned lock").update(chunk))
+            .boxed()
+    };
+    (content, inspector)
+}
+
+fn finish(inspector: &Mutex<ContentInspector>) -> ContentInfo {
+    std::mem::take(&mut *inspector.lock().expect("poisoned lock")).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures_util::stream;
+
+    use super::*;
+
+    const CONTENT: &[u8] = b"Quarterly figures, not to be shared outside the company.";
+
+    fn stream_of(content: &'static [u8]) -> ByteStream {
+        stream::once(async move { Ok(Bytes::from_static(content)) }).boxed()
+    }
+
+    fn failing_stream() -> ByteStream {
+        stream::iter([
+            Ok(Bytes::from_static(CONTENT)),
+            Err(io::Error::other("connection reset")),
+        ])
+        .boxed()
+    }
+
+    fn stored_file(owner: &str, name: &str) -> StoredFile {
+        let owner = serde_json::from_str(&format!("\"{owner}\"")).unwrap();
+        StoredFile::new(owner, name.to_owned()).unwrap()
+    }
+
+    async fn collect(content: ByteStream) -> Vec<u8> {
+        content
+            .try_fold(Vec::new(), |mut collected, chunk| async move {
+                collected.extend_from_slice(&chunk);
+                Ok(collected)
+            })
+            .await
+            .unwrap()
+    }
+
+    fn local_storage(dir: &Path) -> Storage {
+        let encryption = EncryptionConfig {
+            master_key: dir.join("keys/master.key"),
+            retired_master_keys: Vec::new(),
+        };
+        Storage {
+            backend: Box::new(LocalStorage::new(&dir.join("files"), None).unwrap()),
+            keys: MasterKeys::from_config(&encryption, false).unwrap(),
+            locks: Mutex::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn files_are_stored_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = local_storage(dir.path());
+        let file = stored_file("alice", "figures.txt");
+
+        storage
+            .create(&storage.lock(&file).await, stream_of(CONTENT))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let on_disk = fs::read(dir.path().join("files/alice/figures.txt")).unwrap();
+        assert!(on_disk.len() > CONTENT.len());
+        assert!(!on_disk
+            .windows(CONTENT.len())
+            .any(|window| window == CONTENT));
+        assert!(storage.is_encrypted(&file).await.unwrap());
+
+        let content = storage.read(&file, None).await.unwrap();
+        assert_eq!(content.size, CONTENT.len() as u64);
+        assert_eq!(collect(content.body).await, CONTENT);
+    }
+
+    #[tokio::test]
+    async fn rotation_stores_keys_with_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = local_storage(dir.path());
+
+        let plaintext = stored_file("alice", "plaintext.txt");
+        storage
+            .backend
+            .create(&plaintext, stream_of(CONTENT))
+            .await
+            .unwrap();
+        // Encrypted before data keys were stored with the files
+        let legacy = stored_file("alice", "legacy.txt");
+        let sealed = encryption::read_header(storage.keys.encrypt(stream_of(CONTENT)))
+            .await
+            .unwrap();
+        let legacy_key = sealed.key.unwrap();
+        storage.backend.create(&legacy, sealed.body).await.unwrap();
+        let current = stored_file("alice", "current.txt");
+        storage
+            .create(&storage.lock(&current).await, stream_of(CONTENT))
+            .await
+            .unwrap()
+            .unwrap();
+
+        for (file, legacy_key, rotation) in [
+            (&plaintext, None, Rotation::Encrypted),
+            (&legacy, Some(&legacy_key), Rotation::Rewrapped),
+            (&current, None, Rotation::Current),
+        ] {
+            let lock = storage.lock(file).await;
+            assert_eq!(storage.rotate(&lock, legacy_key).await.unwrap(), rotation);
+            assert!(storage.is_encrypted(file).await.unwrap());
+            let content = storage.read(file, None).await.unwrap();
+            assert_eq!(content.size, CONTENT.len() as u64);
+            assert_eq!(collect(content.body).await, CONTENT);
+        }
+    }
+
+    #[tokio::test]
+    async fn locks_are_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = local_storage(dir.path());
+        let file = stored_file("alice", "notes.txt");
+
+        let lock = storage.lock(&file).await;
+        drop(storage.lock(&stored_file("alice", "other.txt")).await);
+        let waiting = storage.lock(&file);
+        tokio::pin!(waiting);
+        assert!(futures_util::poll!(&mut waiting).is_pending());
+
+        drop(lock);
+        drop(waiting.await);
+        assert!(storage.locks.lock().unwrap().is_empty());
+    }
+
+    /// Checks that only one of several concurrent uploads of a file wins, which a backend on its
+    /// own can't guarantee.
+    async fn check_concurrent_writes(storage: &Storage) {
+        let file = &stored_file("alice", "notes.txt");
+        let create = |content: &'static [u8]| async move {
+            let lock = storage.lock(file).await;
+            storage.create(&lock, stream_of(content)).await.unwrap()
+        };
+        let created = tokio::join!(create(b"first"), create(b"second"));
+        assert!(created.0.is_some() != created.1.is_some());
+
+        let etag = &IfMatch::from(storage.backend.read(file).await.unwrap().etag);
+        let replace = |content: &'static [u8]| async move {
+            let lock = storage.lock(file).await;
+            let replaced = storage
+                .replace(&lock, stream_of(content), etag)
+                .await
+                .unwrap();
+            replaced.map(|_| content)
+        };
+        let replaced = tokio::join!(replace(b"third"), replace(b"fourth"));
+        let winner = match replaced {
+            (Some(winner), None) | (None, Some(winner)) => winner,
+            replaced => panic!("expected exactly one replacement, got {replaced:?}"),
+        };
+        let content = storage.read(file, None).await.unwrap();
+        assert_eq!(collect(content.body).await, winner);
+    }
+
+    #[tokio::test]
+    async fn concurrent_local_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        check_concurrent_writes(&local_storage(dir.path())).await;
+    }
+
+    /// Checks that a backend behaves as [`StorageBackend`] describes.
+    async fn check_backend(backend: &dyn StorageBackend) {
+        let file = stored_file("alice", "notes.txt");
+        let other = stored_file("bob", "notes.txt");
+        assert!(backend.list(None).await.unwrap().is_empty());
+        assert!(!backend.exists(&file).await.unwrap());
+        assert_eq!(
+            backend.read(&file).await.err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        assert!(backend.create(&file, stream_of(b"first")).await.unwrap());
+        assert!(!backend.create(&file, stream_of(b"again")).await.unwrap());
+        assert!(backend.create(&other, stream_of(b"other")).await.unwrap());
+        assert!(backend.exists(&file).await.unwrap());
+        assert_eq!(
+            backend.list(Some(file.owner())).await.unwrap(),
+            std::slice::from_ref(&file)
+        );
+        assert_eq!(backend.list(None).await.unwrap().len(), 2);
+
+        let content = backend.read(&file).await.unwrap();
+        assert_eq!(content.size, 5);
+        let first_etag = IfMatch::from(content.etag);
+        assert_eq!(collect(content.body).await, b"first");
+
+        assert!(backend
+            .replace(&file, stream_of(b"second"), &first_etag)
+            .await
+            .unwrap());
+        assert!(!backend
+            .replace(&file, stream_of(b"third"), &first_etag)
+            .await
+            .unwrap());
+        let content = backend.read(&file).await.unwrap();
+        assert_eq!(collect(content.body).await, b"second");
+        let missing = stored_file("alice", "missing.txt");
+        assert!(!backend
+            .replace(&missing, stream_of(b"new"), &IfMatch::any())
+            .await
+            .unwrap());
+
+        // Failed uploads leave nothing behind, and don't replace what's there
+        let failed = stored_file("alice", "failed.txt");
+        assert!(backend.create(&failed, failing_stream()).await.is_err());
+        assert!(!backend.exists(&failed).await.unwrap());
+        let current_etag = IfMatch::from(backend.read(&file).await.unwrap().etag);
+        assert!(backend
+            .replace(&file, failing_stream(), &current_etag)
+            .await
+            .is_err());
+        let content = backend.read(&file).await.unwrap();
+        assert_eq!(collect(content.body).await, b"second");
+
+        assert!(!backend.delete(&file, Some(&first_etag)).await.unwrap());
+        assert!(backend.delete(&file, Some(&current_etag)).await.unwrap());
+        assert!(!backend.exists(&file).await.unwrap());
+        assert_eq!(
+            backend.delete(&file, None).await.unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(backend.delete(&other, None).await.unwrap());
+        assert!(backend.list(None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_backend() {
+        check_backend(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn local_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        check_backend(&LocalStorage::new(dir.path(), None).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn legacy_files_are_moved_to_their_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("report.txt"), CONTENT).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), CONTENT).unwrap();
+        std::fs::create_dir(dir.path().join("admin")).unwrap();
+        std::fs::write(dir.path().join("admin/notes.txt"), b"newer").unwrap();
+
+        let error = LocalStorage::new(dir.path(), None).unwrap_err();
+        assert!(error.to_string().contains("legacy-owner"));
+
+        // The owner's own file is kept, and the legacy file that would replace it stays in place.
+        let admin = stored_file("admin", "report.txt").owner().clone();
+        assert!(LocalStorage::new(dir.path(), Some(&admin)).is_err());
+        assert!(dir.path().join("notes.txt").exists());
+        std::fs::remove_file(dir.path().join("notes.txt")).unwrap();
+
+        let backend = LocalStorage::new(dir.path(), Some(&admin)).unwrap();
+        let mut files = backend.list(Some(&admin)).await.unwrap();
+        files.sort_by(|a, b| a.name().cmp(b.name()));
+        assert_eq!(
+            files,
+            [
+                stored_file("admin", "notes.txt"),
+                stored_file("admin", "report.txt")
+            ]
+        );
+        let file = backend.read(&stored_file("admin", "report.txt")).await;
+        assert_eq!(collect(file.unwrap().body).await, CONTENT);
+        assert!(!dir.path().join("report.txt").exists());
+
+        // Nothing is left to move on the next start.
+        LocalStorage::new(dir.path(), None).unwrap();
+    }
+
+    #[tokio::test]
+    async fn s3_backend() {
+        let config = fake_s3::start().await;
+        check_backend(&S3Storage::new(&config).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_s3_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fake_s3::start().await;
+        let storage = Storage {
+            backend: Box::new(S3Storage::new(&config).unwrap()),
+            ..local_storage(dir.path())
+        };
+        check_concurrent_writes(&storage).await;
+    }
+}
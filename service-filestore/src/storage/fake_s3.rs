@@ -0,0 +1,177 @@
+//! A stand-in for MinIO that serves the part of the S3 API that [`super::S3Storage`] uses, so that
+//! it can be tested without an object store.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::{header, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+
+use crate::config::S3StorageConfig;
+
+const BUCKET: &str = "files";
+
+#[derive(Debug, Default)]
+struct Bucket {
+    objects: BTreeMap<String, Object>,
+    // Parts of unfinished multipart uploads by upload ID, then by part number.
+    uploads: HashMap<String, BTreeMap<u32, Bytes>>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Object {
+    data: Bytes,
+    etag: String,
+}
+
+impl Bucket {
+    fn next_etag(&mut self) -> String {
+        self.next_id += 1;
+        format!("\"{:x}\"", self.next_id)
+    }
+}
+
+type FakeState = Arc<Mutex<Bucket>>;
+
+/// Serves an empty bucket on a local port until the test's runtime stops, returning the config
+/// to reach it with.
+pub async fn start() -> S3StorageConfig {
+    let app = Router::new()
+        .fallback(handle)
+        .with_state(FakeState::default());
+    let server =
+        axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+    let endpoint = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    S3StorageConfig {
+        bucket: BUCKET.to_owned(),
+        region: Some("us-east-1".to_owned()),
+        endpoint: Some(endpoint),
+        allow_http: true,
+        access_key_id: Some("minioadmin".to_owned()),
+        secret_access_key: Some("minioadmin".to_owned()),
+    }
+}
+
+async fn handle(
+    State(state): State<FakeState>,
+    method: Method,
+    uri: Uri,
+    Query(query): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> Response {
+    let path = uri.path().trim_start_matches('/');
+    let key = match path.split_once('/') {
+        Some((BUCKET, key)) => key,
+        None if path == BUCKET => "",
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let mut bucket = state.lock().expect("poisoned lock");
+
+    match (method, key, query.get("uploadId")) {
+        (Method::GET, "", _) => list(&bucket, query.get("prefix").map_or("", String::as_str)),
+        (Method::HEAD, _, _) => match bucket.objects.get(key) {
+            Some(object) => (
+                [
+                    (header::ETAG, object.etag.clone()),
+                    (header::CONTENT_LENGTH, object.data.len().to_string()),
+                ],
+                (),
+            )
+                .into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::GET, _, _) => match bucket.objects.get(key) {
+            Some(object) => {
+                ([(header::ETAG, object.etag.clone())], object.data.clone()).into_response()
+            }
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::POST, _, None) if query.contains_key("uploads") => {
+            let id = bucket.next_etag().trim_matches('"').to_owned();
+            bucket.uploads.insert(id.clone(), BTreeMap::new());
+            xml(format!(
+                "<InitiateMultipartUploadResult><UploadId>{id}</UploadId>\
+                 </InitiateMultipartUploadResult>"
+            ))
+        }
+        (Method::PUT, _, Some(id)) => {
+            let Some(number) = query.get("partNumber").and_then(|n| n.parse().ok()) else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            let etag = bucket.next_etag();
+            match bucket.uploads.get_mut(id) {
+                Some(parts) => {
+                    parts.insert(number, body);
+                    [(header::ETAG, etag)].into_response()
+                }
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+        (Method::POST, _, Some(id)) => {
+            let Some(parts) = bucket.uploads.remove(id) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let data = parts.into_values().collect::<Vec<_>>().concat();
+            let etag = bucket.next_etag();
+            bucket.objects.insert(
+                key.to_owned(),
+                Object {
+                    data: data.into(),
+                    etag: etag.clone(),
+                },
+            );
+            xml(format!(
+                "<CompleteMultipartUploadResult><ETag>{}</ETag></CompleteMultipartUploadResult>",
+                escape(&etag)
+            ))
+        }
+        (Method::DELETE, _, Some(id)) => match bucket.uploads.remove(id) {
+            Some(_) => StatusCode::NO_CONTENT.into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::DELETE, _, None) => {
+            bucket.objects.remove(key);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        _ => StatusCode::NOT_IMPLEMENTED.into_response(),
+    }
+}
+
+fn list(bucket: &Bucket, prefix: &str) -> Response {
+    let contents: String = bucket
+        .objects
+        .iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .map(|(key, object)| {
+            format!(
+                "<Contents><Key>{}</Key><Size>{}</Size>\
+                 <LastModified>2024-01-01T00:00:00.000Z</LastModified><ETag>{}</ETag></Contents>",
+                escape(key),
+                object.data.len(),
+                escape(&object.etag)
+            )
+        })
+        .collect();
+    xml(format!(
+        "<ListBucketResult><Name>{BUCKET}</Name><Prefix>{}</Prefix>{contents}</ListBucketResult>",
+        escape(prefix)
+    ))
+}
+
+fn xml(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,275 @@
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::async_trait;
+use axum::headers::{ETag, IfMatch};
+use futures_util::StreamExt;
+use prae::Wrapper;
+use rand::Rng;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tracing::info;
+
+use super::{ByteStream, FileContent, StorageBackend, StoredFile};
+use server_common::user::Username;
+
+/// Keeps files in a directory per owner under a root directory.
+#[derive(Debug)]
+pub struct LocalStorage {
+    root: PathBuf,
+    // Replacement uploads are written here first and then renamed over the original, so this must
+    // be on the same filesystem as `root`. Usernames can't start with a dot, so it can't collide
+    // with an owner's directory.
+    uploads: PathBuf,
+}
+
+impl LocalStorage {
+    /// Moves files from before per-user namespaces were introduced, which are kept directly in
+    /// `root`, into the namespace of `legacy_owner`. Fails if there are any and no owner is given,
+    /// since they couldn't be served.
+    pub fn new(root: &Path, legacy_owner: Option<&Username>) -> Result<Self, io::Error> {
+        let uploads = root.join(".uploads");
+        fs::create_dir_all(root)?;
+        fs::create_dir_all(&uploads)?;
+
+        let storage = Self {
+            root: root.to_owned(),
+            uploads,
+        };
+        storage.migrate_legacy_files(legacy_owner)?;
+        Ok(storage)
+    }
+
+    fn migrate_legacy_files(&self, legacy_owner: Option<&Username>) -> Result<(), io::Error> {
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let Some(owner) = legacy_owner else {
+                return Err(io::Error::other(format!(
+                    "'{}' is from before files were kept per user; set `legacy-owner` in the \
+                     storage config to move such files into that user's namespace",
+                    entry.path().display()
+                )));
+            };
+            let file = entry
+                .file_name()
+                .into_string()
+                .ok()
+                .and_then(|name| StoredFile::new(owner.clone(), name).ok())
+                .ok_or_else(|| {
+                    io::Error::other(format!(
+                        "'{}' doesn't have a valid file name",
+                        entry.path().display()
+                    ))
+                })?;
+
+            // Renaming never replaces an existing file of the owner, so that nothing is lost.
+            fs::create_dir_all(self.owner_path(owner))?;
+            let file_path = self.file_path(&file);
+            if file_path.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "can't move '{}' to '{}', which already exists",
+                        entry.path().display(),
+                        file_path.display()
+                    ),
+                ));
+            }
+            fs::rename(entry.path(), &file_path)?;
+            info!(%owner, file = %file.name(), "Moved file into the namespace of its legacy owner");
+        }
+        Ok(())
+    }
+
+    fn owner_path(&self, owner: &Username) -> PathBuf {
+        // Usernames are restricted to alphanumeric characters and underscores, so they are always
+        // safe to use as a single path component.
+        self.root.join(owner.as_ref())
+    }
+
+    fn file_path(&self, file: &StoredFile) -> PathBuf {
+        self.owner_path(file.owner()).join(file.name())
+    }
+
+    async fn list_owner(&self, owner: Username) -> Result<Vec<StoredFile>, io::Error> {
+        let mut entries = match tokio::fs::read_dir(self.owner_path(&owner)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if let Ok(name) = entry.file_name().into_string() {
+                files.push(StoredFile::new(owner.clone(), name)?);
+            }
+        }
+        Ok(files)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn list(&self, owner: Option<&Username>) -> Result<Vec<StoredFile>, io::Error> {
+        if let Some(owner) = owner {
+            return self.list_owner(owner.clone()).await;
+        }
+
+        let mut files = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+
+            let owner = entry.file_name().into_string().ok();
+            if let Some(owner) = owner.and_then(|owner| Username::new(owner).ok()) {
+                files.extend(self.list_owner(owner).await?);
+            }
+        }
+        Ok(files)
+    }
+
+    async fn read(&self, file: &StoredFile) -> Result<FileContent, io::Error> {
+        let content = tokio::fs::File::open(self.file_path(file)).await?;
+        let metadata = content.metadata().await?;
+
+        Ok(FileContent {
+            size: metadata.len(),
+            etag: etag(&metadata),
+            modified: metadata.modified().unwrap_or(SystemTime::now()),
+            body: ReaderStream::new(content).boxed(),
+        })
+    }
+
+    async fn exists(&self, file: &StoredFile) -> Result<bool, io::Error> {
+        match tokio::fs::metadata(self.file_path(file)).await {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn create(&self, file: &StoredFile, content: ByteStream) -> Result<bool, io::Error> {
+        let file_path = self.file_path(file);
+        tokio::fs::create_dir_all(self.owner_path(file.owner())).await?;
+
+        let mut output = match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file_path)
+            .await
+        {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        if let Err(err) = write_chunks(&mut output, content).await {
+            drop(output);
+            tokio::fs::remove_file(&file_path).await?;
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    async fn replace(
+        &self,
+        file: &StoredFile,
+        content: ByteStream,
+        if_match: &IfMatch,
+    ) -> Result<bool, io::Error> {
+        let file_path = self.file_path(file);
+        if !precondition_passes(&file_path, if_match).await? {
+            return Ok(false);
+        }
+
+        let upload_path = self.uploads.join(format!(
+            "{}.{}.{:016x}",
+            file.owner(),
+            file.name(),
+            rand::thread_rng().gen::<u64>()
+        ));
+        let mut output = tokio::fs::File::create(&upload_path).await?;
+        if let Err(err) = write_chunks(&mut output, content).await {
+            drop(output);
+            tokio::fs::remove_file(&upload_path).await?;
+            return Err(err);
+        }
+        drop(output);
+
+        // The file may have changed while the upload was in progress.
+        if !precondition_passes(&file_path, if_match).await? {
+            tokio::fs::remove_file(&upload_path).await?;
+            return Ok(false);
+        }
+
+        tokio::fs::rename(&upload_path, &file_path).await?;
+        Ok(true)
+    }
+
+    async fn delete(
+        &self,
+        file: &StoredFile,
+        if_match: Option<&IfMatch>,
+    ) -> Result<bool, io::Error> {
+        let file_path = self.file_path(file);
+
+        if let Some(if_match) = if_match {
+            if !precondition_passes(&file_path, if_match).await? {
+                return Ok(false);
+            }
+        }
+
+        tokio::fs::remove_file(&file_path).await?;
+        Ok(true)
+    }
+}
+
+/// Computes the entity tag of a stored file from its size and modification time, along with its
+/// inode where there is one. Modification times come from a coarse clock, so replacements in quick
+/// succession can share one, but every replacement is a new file renamed over the old one.
+fn etag(metadata: &Metadata) -> ETag {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+
+    format!(
+        "\"{:x}-{:x}-{:x}\"",
+        inode,
+        modified.as_nanos(),
+        metadata.len()
+    )
+    .parse()
+    .expect("hex digits should form a valid ETag")
+}
+
+async fn precondition_passes(file_path: &Path, if_match: &IfMatch) -> Result<bool, io::Error> {
+    match tokio::fs::metadata(file_path).await {
+        Ok(metadata) => Ok(if_match.precondition_passes(&etag(&metadata))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+async fn write_chunks(
+    output: &mut tokio::fs::File,
+    mut chunks: ByteStream,
+) -> Result<(), io::Error> {
+    while let Some(chunk) = chunks.next().await {
+        output.write_all(&chunk?).await?;
+    }
+    output.flush().await
+}
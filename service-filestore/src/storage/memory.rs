@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::headers::{ETag, IfMatch};
+use futures_util::{stream, StreamExt, TryStreamExt};
+
+use super::{ByteStream, FileContent, StorageBackend, StoredFile};
+use server_common::user::Username;
+
+/// Keeps files in memory, so that they're lost when the server stops. Meant for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    files: HashMap<Username, HashMap<String, MemoryFile>>,
+    next_version: u64,
+}
+
+#[derive(Clone, Debug)]
+struct MemoryFile {
+    data: Bytes,
+    version: u64,
+    modified: SystemTime,
+}
+
+impl MemoryFile {
+    fn etag(&self) -> ETag {
+        format!("\"{:x}\"", self.version)
+            .parse()
+            .expect("hex digits should form a valid ETag")
+    }
+}
+
+impl Inner {
+    fn get(&self, file: &StoredFile) -> Option<&MemoryFile> {
+        self.files
+            .get(file.owner())
+            .and_then(|files| files.get(file.name()))
+    }
+
+    fn insert(&mut self, file: &StoredFile, data: Bytes) {
+        self.next_version += 1;
+        self.files.entry(file.owner().clone()).or_default().insert(
+            file.name().to_owned(),
+            MemoryFile {
+                data,
+                version: self.next_version,
+                modified: SystemTime::now(),
+            },
+        );
+    }
+
+    fn precondition_passes(&self, file: &StoredFile, if_match: &IfMatch) -> bool {
+        self.get(file)
+            .is_some_and(|stored| if_match.precondition_passes(&stored.etag()))
+    }
+}
+
+impl MemoryStorage {
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("poisoned lock")
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn list(&self, owner: Option<&Username>) -> Result<Vec<StoredFile>, io::Error> {
+        let inner = self.inner();
+        Ok(inner
+            .files
+            .iter()
+            .filter(|(files_owner, _)| owner.is_none_or(|owner| owner == *files_owner))
+            .flat_map(|(owner, files)| {
+                files.keys().map(|name| StoredFile {
+                    owner: owner.clone(),
+                    name: name.clone(),
+                })
+            })
+            .collect())
+    }
+
+    async fn read(&self, file: &StoredFile) -> Result<FileContent, io::Error> {
+        let stored = self
+            .inner()
+            .get(file)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+        Ok(FileContent {
+            size: stored.data.len() as u64,
+            etag: stored.etag(),
+            modified: stored.modified,
+            body: stream::once(async move { Ok(stored.data) }).boxed(),
+        })
+    }
+
+    async fn exists(&self, file: &StoredFile) -> Result<bool, io::Error> {
+        Ok(self.inner().get(file).is_some())
+    }
+
+    async fn create(&self, file: &StoredFile, content: ByteStream) -> Result<bool, io::Error> {
+        if self.inner().get(file).is_some() {
+            return Ok(false);
+        }
+        let data = collect(content).await?;
+
+        let mut inner = self.inner();
+        if inner.get(file).is_some() {
+            return Ok(false);
+        }
+        inner.insert(file, data);
+        Ok(true)
+    }
+
+    async fn replace(
+        &self,
+        file: &StoredFile,
+        content: ByteStream,
+        if_match: &IfMatch,
+    ) -> Result<bool, io::Error> {
+        if !self.inner().precondition_passes(file, if_match) {
+            return Ok(false);
+        }
+        let data = collect(content).await?;
+
+        let mut inner = self.inner();
+        if !inner.precondition_passes(file, if_match) {
+            return Ok(false);
+        }
+        inner.insert(file, data);
+        Ok(true)
+    }
+
+    async fn delete(
+        &self,
+        file: &StoredFile,
+        if_match: Option<&IfMatch>,
+    ) -> Result<bool, io::Error> {
+        let mut inner = self.inner();
+        if inner.get(file).is_none() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if let Some(if_match) = if_match {
+            if !inner.precondition_passes(file, if_match) {
+                return Ok(false);
+            }
+        }
+
+        if let Some(files) = inner.files.get_mut(file.owner()) {
+            files.remove(file.name());
+        }
+        Ok(true)
+    }
+}
+
+async fn collect(content: ByteStream) -> Result<Bytes, io::Error> {
+    let chunks: Vec<Bytes> = content.try_collect().await?;
+    Ok(chunks.concat().into())
+}
@@ -0,0 +1,233 @@
+use std::io;
+use std::time::SystemTime;
+
+use axum::async_trait;
+use axum::headers::{ETag, IfMatch};
+use futures_util::{StreamExt, TryStreamExt};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path;
+use object_store::{ObjectMeta, ObjectStore};
+use prae::Wrapper;
+use tokio::io::AsyncWriteExt;
+
+use super::{ByteStream, FileContent, StorageBackend, StoredFile};
+use crate::config::S3StorageConfig;
+use server_common::user::Username;
+
+/// Keeps files in a bucket of an S3-compatible object store, under `<owner>/<file name>` keys.
+#[derive(Debug)]
+pub struct S3Storage {
+    store: AmazonS3,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3StorageConfig) -> anyhow::Result<Self> {
+        // Credentials and other settings that aren't configured are taken from the usual `AWS_*`
+        // environment variables.
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&config.bucket)
+            .with_allow_http(config.allow_http);
+        if let Some(region) = &config.region {
+            builder = builder.with_region(region);
+        }
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(access_key_id) = &config.access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+
+        Ok(Self {
+            store: builder.build()?,
+        })
+    }
+
+    async fn head(&self, location: &Path) -> Result<Option<ObjectMeta>, io::Error> {
+        match self.store.head(location).await {
+            Ok(meta) => Ok(Some(meta)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(io_error(err)),
+        }
+    }
+
+    async fn precondition_passes(
+        &self,
+        location: &Path,
+        if_match: &IfMatch,
+    ) -> Result<bool, io::Error> {
+        Ok(self
+            .head(location)
+            .await?
+            .is_some_and(|meta| if_match.precondition_passes(&etag(&meta))))
+    }
+
+    /// Streams `content` to `location` in a multipart upload, which only becomes visible once
+    /// it's completed. The upload is abandoned if `before_completing` returns `false`.
+    async fn upload(
+        &self,
+        location: &Path,
+        mut content: ByteStream,
+        before_completing: impl std::future::Future<Output = Result<bool, io::Error>>,
+    ) -> Result<bool, io::Error> {
+        let (id, mut writer) = self.store.put_multipart(location).await.map_err(io_error)?;
+
+        let written = async {
+            while let Some(chunk) = content.next().await {
+                writer.write_all(&chunk?).await?;
+            }
+            writer.flush().await?;
+            before_completing.await
+        }
+        .await;
+
+        match written {
+            Ok(true) => {
+                writer.shutdown().await?;
+                Ok(true)
+            }
+            result => {
+                self.store
+                    .abort_multipart(location, &id)
+                    .await
+                    .map_err(io_error)?;
+                result
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn list(&self, owner: Option<&Username>) -> Result<Vec<StoredFile>, io::Error> {
+        let prefix = owner.map(|owner| Path::from(owner.to_string()));
+        let objects: Vec<ObjectMeta> = self
+            .store
+            .list(prefix.as_ref())
+            .try_collect()
+            .await
+            .map_err(io_error)?;
+
+        Ok(objects
+            .into_iter()
+            .filter_map(|meta| {
+                let mut parts = meta.location.parts();
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(owner), Some(name), None) => StoredFile::new(
+                        Username::new(owner.as_ref()).ok()?,
+                        name.as_ref().to_owned(),
+                    )
+                    .ok(),
+                    _ => None,
+                }
+            })
+            .collect())
+    }
+
+    async fn read(&self, file: &StoredFile) -> Result<FileContent, io::Error> {
+        let result = self.store.get(&location(file)?).await.map_err(io_error)?;
+
+        Ok(FileContent {
+            size: result.meta.size as u64,
+            etag: etag(&result.meta),
+            modified: result.meta.last_modified.into(),
+            body: result.into_stream().map_err(io_error).boxed(),
+        })
+    }
+
+    async fn exists(&self, file: &StoredFile) -> Result<bool, io::Error> {
+        Ok(self.head(&location(file)?).await?.is_some())
+    }
+
+    async fn create(&self, file: &StoredFile, content: ByteStream) -> Result<bool, io::Error> {
+        let location = location(file)?;
+        // S3 has no portable way to make a write conditional on the object not existing, so this
+        // relies on `Storage` not writing the file concurrently. That only holds as long as a
+        // single filestore uses the bucket.
+        if self.head(&location).await?.is_some() {
+            return Ok(false);
+        }
+
+        self.upload(&location, content, async { Ok(true) }).await
+    }
+
+    async fn replace(
+        &self,
+        file: &StoredFile,
+        content: ByteStream,
+        if_match: &IfMatch,
+    ) -> Result<bool, io::Error> {
+        let location = location(file)?;
+        if !self.precondition_passes(&location, if_match).await? {
+            return Ok(false);
+        }
+
+        // The object may have changed while the upload was in progress.
+        self.upload(
+            &location,
+            content,
+            self.precondition_passes(&location, if_match),
+        )
+        .await
+    }
+
+    async fn delete(
+        &self,
+        file: &StoredFile,
+        if_match: Option<&IfMatch>,
+    ) -> Result<bool, io::Error> {
+        let location = location(file)?;
+        let meta = self
+            .head(&location)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+        if let Some(if_match) = if_match {
+            if !if_match.precondition_passes(&etag(&meta)) {
+                return Ok(false);
+            }
+        }
+
+        self.store.delete(&location).await.map_err(io_error)?;
+        Ok(true)
+    }
+}
+
+fn location(file: &StoredFile) -> Result<Path, io::Error> {
+    // Parsing rather than building the path from parts keeps file names from being percent-encoded
+    // into keys that don't list back as the same name.
+    Path::parse(format!("{}/{}", file.owner(), file.name()))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Uses the object store's entity tag, falling back to one computed from the size and
+/// modification time for stores that don't provide one.
+fn etag(meta: &ObjectMeta) -> ETag {
+    let etag = match &meta.e_tag {
+        Some(etag) if etag.starts_with('"') || etag.starts_with("W/") => etag.clone(),
+        Some(etag) => format!("\"{}\"", etag),
+        None => format!(
+            "\"{:x}-{:x}\"",
+            SystemTime::from(meta.last_modified)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            meta.size
+        ),
+    };
+
+    etag.parse().unwrap_or_else(|_| {
+        format!("\"{:x}\"", meta.size)
+            .parse()
+            .expect("hex digits should form a valid ETag")
+    })
+}
+
+fn io_error(err: object_store::Error) -> io::Error {
+    match err {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, err),
+        err => io::Error::other(err),
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+
+use server_common::user::{Principal, Username};
+use thiserror::Error;
+
+use crate::encryption::WrappedKey;
+use crate::metadata::{ContentInfo, FileMetadata};
+
+mod sqlite;
+
+pub use sqlite::SqliteStore;
+
+/// Everything kept about a file, as imported from the legacy database.
+#[derive(Debug)]
+pub struct ImportedFile {
+    pub metadata: FileMetadata,
+    pub encrypted: bool,
+    pub legacy_key: Option<WrappedKey>,
+    /// The keys of an end-to-end encrypted file, by recipient.
+    pub file_keys: Vec<(Username, String)>,
+}
+
+/// Persistent storage of the records of stored files, keyed by owner and file name. Every change
+/// is applied atomically, so that a crash never leaves a record half written.
+pub trait FileStore: fmt::Debug + Send + Sync {
+    fn get(&self, owner: &Username, file_name: &str) -> Result<Option<FileMetadata>, StoreError>;
+
+    /// Gets the records of files owned by `owner`, or of every file if `owner` is `None`.
+    fn list(&self, owner: Option<&Username>) -> Result<Vec<FileMetadata>, StoreError>;
+
+    /// Gets the records of files whose ACL has an entry for any of `principals`.
+    fn list_by_principal(&self, principals: &[Principal]) -> Result<Vec<FileMetadata>, StoreError>;
+
+    /// Adds the record of a file, replacing any previous one along with the keys kept for it.
+    /// `encrypted` tells whether the file is stored encrypted.
+    fn upsert(&self, metadata: &FileMetadata, encrypted: bool) -> Result<(), StoreError>;
+
+    /// Adds the record of a file, unless it already has one.
+    fn insert(&self, metadata: &FileMetadata, encrypted: bool) -> Result<bool, StoreError>;
+
+    /// Adds all the files that don't have a record yet in a single transaction.
+    fn import(&self, files: &[ImportedFile]) -> Result<(), StoreError>;
+
+    /// Applies `update` to a record within a transaction, saving it if `update` returns `true`.
+    ///
+    /// Returns `Ok(None)` if the file has no record, or what `update` returned otherwise.
+    fn update(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        update: &mut dyn FnMut(&mut FileMetadata) -> bool,
+    ) -> Result<Option<bool>, StoreError>;
+
+    /// Records new encrypted contents of a file, dropping the keys kept for the old ones. Returns
+    /// `Ok(false)` if the file has no record.
+    fn replace_content(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        content: ContentInfo,
+    ) -> Result<bool, StoreError>;
+
+    fn delete(&self, owner: &Username, file_name: &str) -> Result<bool, StoreError>;
+
+    /// Removes the records of every file owned by `user`, along with the file keys wrapped for
+    /// them and their entries in other files' ACLs, returning how many files were removed.
+    fn delete_of_user(&self, user: &Username) -> Result<usize, StoreError>;
+
+    /// Removes the ACL entries of `principal`, returning from how many files.
+    fn delete_acl_entries(&self, principal: &Principal) -> Result<usize, StoreError>;
+
+    /// Gets the data key recorded for a file that was encrypted before data keys were stored
+    /// along with the contents.
+    fn legacy_key(
+        &self,
+        owner: &Username,
+        file_name: &str,
+    ) -> Result<Option<WrappedKey>, StoreError>;
+
+    /// Records that a file is stored encrypted with its data key in its header, dropping any
+    /// legacy key.
+    fn set_encrypted(&self, owner: &Username, file_name: &str) -> Result<(), StoreError>;
+
+    /// Whether any file is stored encrypted, in which case the master key must exist.
+    fn has_encrypted_files(&self) -> Result<bool, StoreError>;
+
+    /// Gets the key of an end-to-end encrypted file that was wrapped for `recipient`.
+    fn get_file_key(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        recipient: &Username,
+    ) -> Result<Option<String>, StoreError>;
+
+    /// Records the key of a file wrapped for `recipient`, replacing any previous one. Returns
+    /// `Ok(false)` if the file has no record.
+    fn set_file_key(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        recipient: &Username,
+        key: &str,
+    ) -> Result<bool, StoreError>;
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("SQLite error accessing database: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("serialization error accessing database: {0}")]
+    Json(#[from] serde_json::Error),
+}
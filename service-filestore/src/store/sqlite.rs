@@ -0,0 +1,592 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, TransactionBehavior};
+use server_common::user::{Principal, Username};
+
+use super::{FileStore, ImportedFile, StoreError};
+use crate::encryption::WrappedKey;
+use crate::metadata::{ContentInfo, FileMetadata};
+
+/// Keeps file records in an SQLite database, with each serialized as JSON so that new fields
+/// don't need a schema migration. The principals named in each file's ACL are also kept in their
+/// own table, so that the files shared with a user can be looked up without reading every record.
+pub struct SqliteStore {
+    path: PathBuf,
+    connection: Mutex<Connection>,
+}
+
+impl fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let connection = Connection::open(path)?;
+        // The write-ahead log keeps the database intact if the process dies mid-write, and
+        // syncing it on every commit makes committed changes survive power loss.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "FULL")?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS files (
+                owner TEXT NOT NULL,
+                file_name TEXT NOT NULL,
+                encrypted INTEGER NOT NULL,
+                legacy_key TEXT,
+                record TEXT NOT NULL,
+                PRIMARY KEY (owner, file_name)
+            );
+            CREATE TABLE IF NOT EXISTS file_access (
+                owner TEXT NOT NULL,
+                file_name TEXT NOT NULL,
+                principal TEXT NOT NULL,
+                PRIMARY KEY (owner, file_name, principal)
+            );
+            CREATE INDEX IF NOT EXISTS file_access_by_principal ON file_access (principal);
+            CREATE TABLE IF NOT EXISTS file_keys (
+                owner TEXT NOT NULL,
+                file_name TEXT NOT NULL,
+                recipient TEXT NOT NULL,
+                key TEXT NOT NULL,
+                PRIMARY KEY (owner, file_name, recipient)
+            );
+            CREATE INDEX IF NOT EXISTS file_keys_by_recipient ON file_keys (recipient);",
+        )?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("poisoned lock")
+    }
+}
+
+fn get_record(
+    connection: &Connection,
+    owner: &Username,
+    file_name: &str,
+) -> Result<Option<FileMetadata>, StoreError> {
+    connection
+        .query_row(
+            "SELECT record FROM files WHERE owner = ?1 AND file_name = ?2",
+            params![owner.to_string(), file_name],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?
+        .map(|record| serde_json::from_str(&record))
+        .transpose()
+        .map_err(Into::into)
+}
+
+fn query_records(
+    connection: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<FileMetadata>, StoreError> {
+    let mut statement = connection.prepare(sql)?;
+    let rows = statement
+        .query_map(params, |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    rows.iter()
+        .map(|record| Ok(serde_json::from_str(record)?))
+        .collect()
+}
+
+/// Saves a changed record, along with the principals named in its ACL.
+fn save_record(connection: &Connection, metadata: &FileMetadata) -> Result<(), StoreError> {
+    let (owner, file_name) = (metadata.owner().to_string(), metadata.name());
+    connection.execute(
+        "UPDATE files SET record = ?3 WHERE owner = ?1 AND file_name = ?2",
+        params![owner, file_name, serde_json::to_string(metadata)?],
+    )?;
+    save_access(connection, metadata)
+}
+
+fn save_access(connection: &Connection, metadata: &FileMetadata) -> Result<(), StoreError> {
+    let (owner, file_name) = (metadata.owner().to_string(), metadata.name());
+    connection.execute(
+        "DELETE FROM file_access WHERE owner = ?1 AND file_name = ?2",
+        params![owner, file_name],
+    )?;
+    let mut statement = connection.prepare(
+        "INSERT OR IGNORE INTO file_access (owner, file_name, principal) VALUES (?1, ?2, ?3)",
+    )?;
+    for principal in metadata.acl().principals() {
+        statement.execute(params![owner, file_name, principal.to_string()])?;
+    }
+    Ok(())
+}
+
+/// Adds a record, replacing any previous one along with the keys kept for it if `replace` is set.
+fn insert_record(
+    connection: &Connection,
+    metadata: &FileMetadata,
+    encrypted: bool,
+    legacy_key: Option<&WrappedKey>,
+    replace: bool,
+) -> Result<bool, StoreError> {
+    let (owner, file_name) = (metadata.owner().to_string(), metadata.name());
+    let verb = if replace {
+        "INSERT OR REPLACE"
+    } else {
+        "INSERT OR IGNORE"
+    };
+    let inserted = connection.execute(
+        &format!(
+            "{verb} INTO files (owner, file_name, encrypted, legacy_key, record)
+                VALUES (?1, ?2, ?3, ?4, ?5)"
+        ),
+        params![
+            owner,
+            file_name,
+            encrypted,
+            legacy_key.map(serde_json::to_string).transpose()?,
+            serde_json::to_string(metadata)?,
+        ],
+    )?;
+    if inserted == 0 {
+        return Ok(false);
+    }
+
+    delete_file_keys(connection, metadata.owner(), file_name)?;
+    save_access(connection, metadata)?;
+    Ok(true)
+}
+
+// Wrapped file keys belong to the contents they were uploaded with.
+fn delete_file_keys(
+    connection: &Connection,
+    owner: &Username,
+    file_name: &str,
+) -> Result<(), StoreError> {
+    connection.execute(
+        "DELETE FROM file_keys WHERE owner = ?1 AND file_name = ?2",
+        params![owner.to_string(), file_name],
+    )?;
+    Ok(())
+}
+
+fn remove_acl_entries(connection: &Connection, principal: &Principal) -> Result<usize, StoreError> {
+    let mut statement =
+        connection.prepare("SELECT owner, file_name FROM file_access WHERE principal = ?1")?;
+    let files = statement
+        .query_map([principal.to_string()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut removed = 0;
+    for (owner, file_name) in files {
+        let record: String = connection.query_row(
+            "SELECT record FROM files WHERE owner = ?1 AND file_name = ?2",
+            params![owner, file_name],
+            |row| row.get(0),
+        )?;
+        let mut metadata: FileMetadata = serde_json::from_str(&record)?;
+        if metadata.acl_mut().remove(principal) {
+            save_record(connection, &metadata)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+impl FileStore for SqliteStore {
+    fn get(&self, owner: &Username, file_name: &str) -> Result<Option<FileMetadata>, StoreError> {
+        get_record(&self.connection(), owner, file_name)
+    }
+
+    fn list(&self, owner: Option<&Username>) -> Result<Vec<FileMetadata>, StoreError> {
+        let connection = self.connection();
+        match owner {
+            Some(owner) => query_records(
+                &connection,
+                "SELECT record FROM files WHERE owner = ?1",
+                [owner.to_string()],
+            ),
+            None => query_records(&connection, "SELECT record FROM files", []),
+        }
+    }
+
+    fn list_by_principal(&self, principals: &[Principal]) -> Result<Vec<FileMetadata>, StoreError> {
+        if principals.is_empty() {
+            return Ok(Vec::new());
+        }
+        let placeholders = vec!["?"; principals.len()].join(", ");
+        query_records(
+            &self.connection(),
+            &format!(
+                "SELECT record FROM files WHERE (owner, file_name) IN (
+                    SELECT owner, file_name FROM file_access WHERE principal IN ({placeholders})
+                )"
+            ),
+            params_from_iter(principals.iter().map(Principal::to_string)),
+        )
+    }
+
+    fn upsert(&self, metadata: &FileMetadata, encrypted: bool) -> Result<(), StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        insert_record(&transaction, metadata, encrypted, None, true)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn insert(&self, metadata: &FileMetadata, encrypted: bool) -> Result<bool, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let inserted = insert_record(&transaction, metadata, encrypted, None, false)?;
+        transaction.commit()?;
+        Ok(inserted)
+    }
+
+    fn import(&self, files: &[ImportedFile]) -> Result<(), StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        for file in files {
+            let inserted = insert_record(
+                &transaction,
+                &file.metadata,
+                file.encrypted,
+                file.legacy_key.as_ref(),
+                false,
+            )?;
+            if !inserted {
+                continue;
+            }
+            for (recipient, key) in &file.file_keys {
+                transaction.execute(
+                    "INSERT INTO file_keys (owner, file_name, recipient, key)
+                        VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        file.metadata.owner().to_string(),
+                        file.metadata.name(),
+                        recipient.to_string(),
+                        key,
+                    ],
+                )?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        update: &mut dyn FnMut(&mut FileMetadata) -> bool,
+    ) -> Result<Option<bool>, StoreError> {
+        let mut connection = self.connection();
+        // Taking the write lock up front keeps other connections from changing the record
+        // between reading and writing it.
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let Some(mut metadata) = get_record(&transaction, owner, file_name)? else {
+            return Ok(None);
+        };
+        if !update(&mut metadata) {
+            return Ok(Some(false));
+        }
+
+        save_record(&transaction, &metadata)?;
+        transaction.commit()?;
+        Ok(Some(true))
+    }
+
+    fn replace_content(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        content: ContentInfo,
+    ) -> Result<bool, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let Some(mut metadata) = get_record(&transaction, owner, file_name)? else {
+            return Ok(false);
+        };
+        metadata.replace_content(content);
+        transaction.execute(
+            "UPDATE files SET encrypted = 1, legacy_key = NULL, record = ?3
+                WHERE owner = ?1 AND file_name = ?2",
+            params![
+                owner.to_string(),
+                file_name,
+                serde_json::to_string(&metadata)?
+            ],
+        )?;
+        delete_file_keys(&transaction, owner, file_name)?;
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    fn delete(&self, owner: &Username, file_name: &str) -> Result<bool, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let deleted = transaction.execute(
+            "DELETE FROM files WHERE owner = ?1 AND file_name = ?2",
+            params![owner.to_string(), file_name],
+        )?;
+        transaction.execute(
+            "DELETE FROM file_access WHERE owner = ?1 AND file_name = ?2",
+            params![owner.to_string(), file_name],
+        )?;
+        delete_file_keys(&transaction, owner, file_name)?;
+        transaction.commit()?;
+        Ok(deleted == 1)
+    }
+
+    fn delete_of_user(&self, user: &Username) -> Result<usize, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let deleted =
+            transaction.execute("DELETE FROM files WHERE owner = ?1", [user.to_string()])?;
+        transaction.execute(
+            "DELETE FROM file_access WHERE owner = ?1",
+            [user.to_string()],
+        )?;
+        transaction.execute(
+            "DELETE FROM file_keys WHERE owner = ?1 OR recipient = ?1",
+            [user.to_string()],
+        )?;
+        remove_acl_entries(&transaction, &Principal::User(user.clone()))?;
+        transaction.commit()?;
+        Ok(deleted)
+    }
+
+    fn delete_acl_entries(&self, principal: &Principal) -> Result<usize, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let removed = remove_acl_entries(&transaction, principal)?;
+        transaction.commit()?;
+        Ok(removed)
+    }
+
+    fn legacy_key(
+        &self,
+        owner: &Username,
+        file_name: &str,
+    ) -> Result<Option<WrappedKey>, StoreError> {
+        self.connection()
+            .query_row(
+                "SELECT legacy_key FROM files WHERE owner = ?1 AND file_name = ?2",
+                params![owner.to_string(), file_name],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()?
+            .flatten()
+            .map(|key| serde_json::from_str(&key))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn set_encrypted(&self, owner: &Username, file_name: &str) -> Result<(), StoreError> {
+        self.connection().execute(
+            "UPDATE files SET encrypted = 1, legacy_key = NULL WHERE owner = ?1 AND file_name = ?2",
+            params![owner.to_string(), file_name],
+        )?;
+        Ok(())
+    }
+
+    fn has_encrypted_files(&self) -> Result<bool, StoreError> {
+        Ok(self.connection().query_row(
+            "SELECT EXISTS (SELECT 1 FROM files WHERE encrypted)",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn get_file_key(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        recipient: &Username,
+    ) -> Result<Option<String>, StoreError> {
+        Ok(self
+            .connection()
+            .query_row(
+                "SELECT key FROM file_keys WHERE owner = ?1 AND file_name = ?2 AND recipient = ?3",
+                params![owner.to_string(), file_name, recipient.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_file_key(
+        &self,
+        owner: &Username,
+        file_name: &str,
+        recipient: &Username,
+        key: &str,
+    ) -> Result<bool, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if get_record(&transaction, owner, file_name)?.is_none() {
+            return Ok(false);
+        }
+        transaction.execute(
+            "INSERT OR REPLACE INTO file_keys (owner, file_name, recipient, key)
+                VALUES (?1, ?2, ?3, ?4)",
+            params![owner.to_string(), file_name, recipient.to_string(), key],
+        )?;
+        transaction.commit()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::acl::{AclEntry, Right};
+    use crate::metadata::ContentInspector;
+
+    fn username(name: &str) -> Username {
+        serde_json::from_str(&format!("\"{name}\"")).unwrap()
+    }
+
+    fn content(data: &[u8]) -> ContentInfo {
+        let mut inspector = ContentInspector::default();
+        inspector.update(data);
+        inspector.finish()
+    }
+
+    fn metadata(owner: &str, name: &str) -> FileMetadata {
+        FileMetadata::new(
+            username(owner),
+            name.to_owned(),
+            username(owner),
+            content(b"contents"),
+        )
+    }
+
+    fn open() -> (tempfile::TempDir, SqliteStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(&dir.path().join("service-filestore.db")).unwrap();
+        (dir, store)
+    }
+
+    fn share(store: &SqliteStore, owner: &str, name: &str, principal: Principal) {
+        store
+            .update(&username(owner), name, &mut |metadata| {
+                metadata.acl_mut().set_entry(AclEntry {
+                    principal: principal.clone(),
+                    rights: BTreeSet::from([Right::Read]),
+                });
+                true
+            })
+            .unwrap()
+            .unwrap();
+    }
+
+    fn names(files: Vec<FileMetadata>) -> Vec<String> {
+        let mut names = files
+            .iter()
+            .map(|metadata| format!("{}/{}", metadata.owner(), metadata.name()))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn files_are_found_through_their_acl() {
+        let (_dir, store) = open();
+        let sales = Principal::Group(serde_json::from_str("\"sales\"").unwrap());
+        let bob = Principal::User(username("bob"));
+        for (owner, name) in [("alice", "a.txt"), ("alice", "b.txt"), ("carol", "c.txt")] {
+            store.upsert(&metadata(owner, name), true).unwrap();
+        }
+        share(&store, "alice", "a.txt", bob.clone());
+        share(&store, "carol", "c.txt", sales.clone());
+        share(&store, "carol", "c.txt", bob.clone());
+
+        assert_eq!(
+            names(store.list(Some(&username("alice"))).unwrap()),
+            ["alice/a.txt", "alice/b.txt"]
+        );
+        assert_eq!(
+            names(
+                store
+                    .list_by_principal(&[bob.clone(), sales.clone()])
+                    .unwrap()
+            ),
+            ["alice/a.txt", "carol/c.txt"]
+        );
+        assert!(store.list_by_principal(&[]).unwrap().is_empty());
+
+        assert_eq!(store.delete_acl_entries(&sales).unwrap(), 1);
+        assert_eq!(
+            names(store.list_by_principal(&[sales]).unwrap()),
+            Vec::<String>::new()
+        );
+        // Other entries of the file are kept
+        assert_eq!(
+            names(store.list_by_principal(&[bob]).unwrap()),
+            ["alice/a.txt", "carol/c.txt"]
+        );
+    }
+
+    #[test]
+    fn new_contents_drop_old_keys() {
+        let (_dir, store) = open();
+        let (alice, bob) = (username("alice"), username("bob"));
+        store.upsert(&metadata("alice", "a.txt"), true).unwrap();
+        assert!(store.set_file_key(&alice, "a.txt", &bob, "key").unwrap());
+        assert!(!store
+            .set_file_key(&alice, "missing.txt", &bob, "key")
+            .unwrap());
+        assert_eq!(
+            store
+                .get_file_key(&alice, "a.txt", &bob)
+                .unwrap()
+                .as_deref(),
+            Some("key")
+        );
+
+        assert!(store
+            .replace_content(&alice, "a.txt", content(b"new contents"))
+            .unwrap());
+        assert!(store.get_file_key(&alice, "a.txt", &bob).unwrap().is_none());
+        assert!(!store
+            .replace_content(&alice, "missing.txt", content(b"new contents"))
+            .unwrap());
+
+        // Backfilled records don't replace the record of an upload
+        assert!(!store.insert(&metadata("alice", "a.txt"), false).unwrap());
+        let record = serde_json::to_value(store.get(&alice, "a.txt").unwrap()).unwrap();
+        assert_eq!(record["size"], b"new contents".len());
+    }
+
+    #[test]
+    fn deleted_users_leave_nothing_behind() {
+        let (_dir, store) = open();
+        let (alice, bob) = (username("alice"), username("bob"));
+        store.upsert(&metadata("alice", "a.txt"), false).unwrap();
+        store.upsert(&metadata("bob", "b.txt"), true).unwrap();
+        share(&store, "bob", "b.txt", Principal::User(alice.clone()));
+        store.set_file_key(&bob, "b.txt", &alice, "key").unwrap();
+        assert!(store.has_encrypted_files().unwrap());
+
+        assert_eq!(store.delete_of_user(&alice).unwrap(), 1);
+        assert!(store.get(&alice, "a.txt").unwrap().is_none());
+        assert!(store.get_file_key(&bob, "b.txt", &alice).unwrap().is_none());
+        let shared = store.get(&bob, "b.txt").unwrap().unwrap();
+        assert_eq!(shared.acl().principals().count(), 0);
+
+        assert!(store.delete(&bob, "b.txt").unwrap());
+        assert!(!store.delete(&bob, "b.txt").unwrap());
+        assert!(!store.has_encrypted_files().unwrap());
+    }
+}
@@ -1,7 +1,11 @@
 document.addEventListener("DOMContentLoaded", function () {
     const token = localStorage.getItem("jwtToken");
+    const refreshToken = localStorage.getItem("refreshToken");
 
-    if (token) {
+    if (refreshToken) {
+        // The dashboard refreshes the JWT if it has expired
+        window.location.href = "/dashboard.html";
+    } else if (token) {
         // Use the token to authenticate
         authenticateWithToken(token);
     } else {
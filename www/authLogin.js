@@ -3,6 +3,10 @@ document.addEventListener("DOMContentLoaded", function () {
     const errorMessageContainer = document.getElementById("error-message");
     const infoMessageContainer = document.getElementById("info-message");
     const submitButton = document.getElementById('submitButton');
+    const twoFactorContainer = document.getElementById("two-factor");
+    const codeInput = document.getElementById("code");
+    // Identifies a login that was given the right password and waits for a second factor
+    let challenge = null;
 
     function handleLoginError(error) {
         console.log(error);
@@ -12,15 +16,49 @@ document.addEventListener("DOMContentLoaded", function () {
         submitButton.disabled = false;
     }
 
+    // Asks for a code from the user's authenticator app to finish logging in
+    function askForCode(data) {
+        challenge = data.challenge;
+        document.getElementById("username").readOnly = true;
+        document.getElementById("password").readOnly = true;
+        twoFactorContainer.style.display = "block";
+        codeInput.required = true;
+        codeInput.focus();
+        infoMessageContainer.style.display = "none";
+        submitButton.disabled = false;
+    }
+
+    // Goes back to asking for the password, once the login waiting for a code has ended
+    function startOver() {
+        challenge = null;
+        document.getElementById("username").readOnly = false;
+        document.getElementById("password").readOnly = false;
+        twoFactorContainer.style.display = "none";
+        codeInput.required = false;
+        codeInput.value = "";
+    }
+
     // Function to handle user login
     function loginUser(formData) {
-        fetch("https://localhost:27464/user/login", {
-            method: "POST",
-            headers: {
-                "Content-Type": "application/json",
-            },
-            body: JSON.stringify(formData),
-        })
+        const request = challenge
+            ? fetch(`${AUTH_SERVER_URL}/user/login/two-factor`, {
+                  method: "POST",
+                  headers: {
+                      "Content-Type": "application/json",
+                  },
+                  body: JSON.stringify({ challenge, code: formData.code.trim() }),
+              })
+            : fetch(`${AUTH_SERVER_URL}/user/login`, {
+                  method: "POST",
+                  headers: {
+                      "Content-Type": "application/json",
+                  },
+                  body: JSON.stringify({
+                      username: formData.username,
+                      password: formData.password,
+                  }),
+              });
+        request
             .then((response) => {
                 // Check if the response is ok
                 if (response.ok) {
@@ -28,17 +66,39 @@ document.addEventListener("DOMContentLoaded", function () {
                 } else {
                     if (response.status === 400) {
                         return response.json().then(errorMsg => {
+                            if (challenge && errorMsg.error === "invalid or expired login") {
+                                startOver();
+                            }
                             const customError = new Error(errorMsg.error ? ("Problem logging in: "+errorMsg.error) : "An error occurred. Please try again later.");
                             throw customError;
                         });
                     }
+                    if (response.status === 403) {
+                        if (challenge) {
+                            startOver();
+                        }
+                        throw new Error("Problem logging in: your account has been disabled, please contact an admin.");
+                    }
+                    if (response.status === 429) {
+                        return response.json().then(errorMsg => {
+                            throw new Error("Problem logging in: " + errorMsg.error);
+                        });
+                    }
                     throw new Error("An error occurred. Please try again later.");
                 }
             })
             .then((data) => {
-                localStorage.setItem("privateKey", data.private_key);
-                localStorage.setItem("jwtToken", data.token);
-                window.location.href = `dashboard.html`;
+                if (data.two_factor_required) {
+                    askForCode(data);
+                    return;
+                }
+                storeSession(data);
+                return E2E.setUp(formData.password).then(() => {
+                    if (data.two_factor_enrollment_required) {
+                        alert("Admins must set up two-factor authentication. You can do so from the dashboard.");
+                    }
+                    window.location.href = `dashboard.html`;
+                });
             })
             .catch((error) => handleLoginError(error));
     }
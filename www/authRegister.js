@@ -99,8 +99,10 @@ document.addEventListener("DOMContentLoaded", function () {
                 }
             })
             .then((data) => {
-                localStorage.setItem("privateKey", data.private_key);
-                localStorage.setItem("jwtToken", data.token);
+                storeSession(data);
+                return E2E.setUp(formData.password);
+            })
+            .then(() => {
                 const username = document.getElementById("username").value;
                 window.location.href = `dashboard.html?username=${username}`;
             })
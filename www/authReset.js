@@ -0,0 +1,52 @@
+document.addEventListener("DOMContentLoaded", function () {
+    const resetForm = document.getElementById("reset-form");
+    const passwordError = document.getElementById("password-error");
+    const errorMessageContainer = document.getElementById("error-message");
+    const submitButton = document.getElementById("submitButton");
+
+    function showError(message) {
+        errorMessageContainer.textContent = message;
+        errorMessageContainer.style.display = "block";
+        submitButton.disabled = false;
+    }
+
+    resetForm.addEventListener("submit", function (event) {
+        event.preventDefault();
+
+        const password = document.getElementById("new-password").value;
+        if (password !== document.getElementById("confirm-password").value) {
+            passwordError.style.display = "block";
+            return;
+        }
+        passwordError.style.display = "none";
+        errorMessageContainer.style.display = "none";
+        submitButton.disabled = true;
+
+        fetch("https://localhost:27464/user/password/reset", {
+            method: "POST",
+            headers: {
+                "Content-Type": "application/json",
+            },
+            body: JSON.stringify({
+                username: document.getElementById("username").value,
+                reset_token: document.getElementById("reset-token").value.trim(),
+                new_password: password,
+            }),
+        })
+            .then((response) => {
+                if (response.ok) {
+                    alert("Password reset, you can log in now.");
+                    window.location.href = "/login.html";
+                } else if (response.status === 429) {
+                    throw new Error("Too many attempts, please try again later.");
+                } else if (response.status === 400) {
+                    return response.json().then((error) => {
+                        throw new Error(error.error || "Unable to reset password.");
+                    });
+                } else {
+                    throw new Error("An error occurred. Please try again later.");
+                }
+            })
+            .catch((error) => showError(error.message));
+    });
+});
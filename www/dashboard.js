@@ -15,43 +15,105 @@ document.addEventListener("DOMContentLoaded", () => {
     }
 
     const listUpload = document.getElementById("fileList");
-    const token = localStorage.getItem("jwtToken");
-    const private_key = localStorage.getItem("privateKey");
+    const sharedList = document.getElementById("sharedList");
 
     function canViewFileContent(fileName) {
         const fileExtension = fileName.split(".").pop().toLowerCase();
         return fileExtension === "txt";
     }
 
+    function formatSize(bytes) {
+        const units = ["B", "KiB", "MiB", "GiB"];
+        let unit = 0;
+        while (bytes >= 1024 && unit < units.length - 1) {
+            bytes /= 1024;
+            unit++;
+        }
+        return `${unit === 0 ? bytes : bytes.toFixed(1)} ${units[unit]}`;
+    }
+
+    // URL of a file in its owner's namespace
+    function filePath(file) {
+        return `https://localhost:8080/files/${encodeURIComponent(
+            file.name
+        )}?owner=${encodeURIComponent(file.owner)}`;
+    }
+
+    // Path of the key of an end-to-end encrypted file, wrapped for the current user
+    function fileKeyPath(file) {
+        return `https://localhost:8080/files/${encodeURIComponent(
+            file.name
+        )}/key?owner=${encodeURIComponent(file.owner)}`;
+    }
+
+    // Fetches and decrypts the contents of an end-to-end encrypted file
+    async function fetchDecrypted(file) {
+        const keyPath = fileKeyPath(file);
+        const keyResponse = await fetch(keyPath, {
+            headers: signedHeaders("GET", keyPath),
+        });
+        if (!keyResponse.ok) {
+            throw new Error("You don't have the key of this file");
+        }
+
+        const path = filePath(file);
+        const response = await fetch(path, { headers: signedHeaders("GET", path) });
+        if (!response.ok) {
+            throw new Error(`Failed to fetch file: ${response.statusText}`);
+        }
+
+        return E2E.decryptFile(await response.arrayBuffer(), await keyResponse.text());
+    }
+
     // Function to create a list item with buttons for each file
-    function createListItem(fileName) {
+    function createListItem(file) {
+        const fileName = file.name;
+        const ownFile = file.owner === sessionClaims().username;
         const listItem = document.createElement("li");
-        listItem.textContent = fileName;
+        listItem.textContent = ownFile
+            ? `${fileName} (${formatSize(file.size)})`
+            : `${fileName} (${formatSize(file.size)}, owned by ${file.owner})`;
+        listItem.title = `Owner: ${file.owner}\nType: ${file.content_type}\nSHA-256: ${file.sha256}`;
+        if (file.end_to_end) {
+            listItem.title += "\nEnd-to-end encrypted";
+        }
         listItem.style.margin = 10;
 
-        const downloadBtn = createDownloadButton(fileName);
-        const shareBtn = createShareButton(fileName);
-        const shareLinksBtn = createViewShareLinksButton(fileName);
+        const downloadBtn = createDownloadButton(file);
+        const deleteFileBtn = createDeleteFileButton(file);
 
         if (canViewFileContent(fileName)) {
-            const readBtn = createReadButton(fileName);
+            const readBtn = createReadButton(file);
             listItem.appendChild(readBtn);
         }
         listItem.appendChild(downloadBtn);
-        listItem.appendChild(shareBtn);
-        listItem.appendChild(shareLinksBtn);
+        // Share links would only hand out ciphertext, so access is given to users instead
+        if (file.end_to_end) {
+            listItem.appendChild(createGrantAccessButton(file));
+        }
+        // Files shared by others can only be managed by their owner
+        if (ownFile) {
+            if (!file.end_to_end) {
+                listItem.appendChild(createShareButton(fileName));
+                listItem.appendChild(createViewShareLinksButton(fileName));
+            }
+            listItem.appendChild(createGrantButton(fileName));
+            listItem.appendChild(createRevokeButton(fileName));
+            listItem.appendChild(createAccessButton(file));
+            listItem.appendChild(deleteFileBtn);
+        }
 
         return listItem;
     }
 
-    function createReadButton(fileName) {
+    function createReadButton(file) {
         const readBtn = document.createElement("button");
         readBtn.classList = "readBtn";
         readBtn.textContent = "View Content";
         readBtn.style.marginRight = 10;
 
         readBtn.addEventListener("click", () => {
-            readFile(fileName);
+            readFile(file);
         });
 
         return readBtn;
@@ -78,23 +140,333 @@ document.addEventListener("DOMContentLoaded", () => {
         return shareLinksBtn;
     }
 
-    function createDownloadButton(fileName) {
+    function createGrantAccessButton(file) {
+        const grantBtn = document.createElement("button");
+        grantBtn.classList = "shareBtn";
+        grantBtn.textContent = "Give Access";
+
+        grantBtn.addEventListener("click", () => {
+            const username = prompt("Give which user access to this file?");
+            if (username) {
+                grantAccess(file, username.trim());
+            }
+        });
+
+        return grantBtn;
+    }
+
+    async function grantAccess(file, username) {
+        try {
+            const keyPath = fileKeyPath(file);
+            const keyResponse = await fetch(keyPath, {
+                headers: signedHeaders("GET", keyPath),
+            });
+            if (!keyResponse.ok) {
+                throw new Error("You don't have the key of this file");
+            }
+            const wrapped = await E2E.wrapFileKeyFor(
+                await keyResponse.text(),
+                username
+            );
+
+            const path = `https://localhost:8080/files/${encodeURIComponent(
+                file.name
+            )}/keys/${encodeURIComponent(username)}?owner=${encodeURIComponent(
+                file.owner
+            )}`;
+            const response = await fetch(path, {
+                method: "PUT",
+                headers: signedHeaders("PUT", path, await bodyDigest(wrapped)),
+                body: wrapped,
+            });
+            if (response.status === 403) {
+                throw new Error("You don't have permission to give access to this file.");
+            } else if (!response.ok) {
+                throw new Error(`Failed to give access: ${response.statusText}`);
+            }
+            alert(`${username} can now decrypt ${file.name}`);
+        } catch (error) {
+            alert(error.message);
+        }
+    }
+
+    // Grants are made to "bob" for a user, or "group:sales" for a group
+    function parseGrantee(text) {
+        const match = text.trim().match(/^(?:(user|group):)?(.+)$/);
+        return { [match[1] || "user"]: match[2].trim() };
+    }
+
+    function formatGrantee(grantee) {
+        return grantee.user ? grantee.user : `group:${grantee.group}`;
+    }
+
+    async function grantRequest(method, body) {
+        const path = "https://localhost:8080/grant";
+        const json = JSON.stringify(body);
+        const response = await fetch(path, {
+            method,
+            headers: {
+                ...signedHeaders(method, path, await bodyDigest(json)),
+                "Content-Type": "application/json",
+            },
+            body: json,
+        });
+        if (response.status === 403) {
+            throw new Error("You don't have permission to share files.");
+        } else if (!response.ok) {
+            const error = await response.json().catch(() => ({}));
+            throw new Error(error.error || response.statusText);
+        }
+    }
+
+    function createGrantButton(fileName) {
+        const grantBtn = document.createElement("button");
+        grantBtn.classList = "shareBtn";
+        grantBtn.textContent = "Share With User";
+
+        grantBtn.addEventListener("click", async () => {
+            const grantee = prompt(
+                'Share with which user? Use "group:name" to share with a group.'
+            );
+            if (!grantee || !grantee.trim()) {
+                return;
+            }
+            const access = prompt('Give "read" or "write" access?', "read");
+            if (access === null) {
+                return;
+            }
+
+            try {
+                await grantRequest("PUT", {
+                    file_name: fileName,
+                    grantee: parseGrantee(grantee),
+                    access: access.trim().toLowerCase(),
+                });
+                alert(`Shared ${fileName} with ${grantee.trim()}`);
+            } catch (error) {
+                alert(`Unable to share ${fileName}: ${error.message}`);
+            }
+        });
+
+        return grantBtn;
+    }
+
+    function createRevokeButton(fileName) {
+        const revokeBtn = document.createElement("button");
+        revokeBtn.classList = "shareBtn";
+        revokeBtn.textContent = "Revoke Access";
+
+        revokeBtn.addEventListener("click", async () => {
+            try {
+                const path = "https://localhost:8080/grants";
+                const response = await fetch(path, { headers: signedHeaders("GET", path) });
+                if (!response.ok) {
+                    throw new Error(response.statusText);
+                }
+                const grants = (await response.json()).filter(
+                    (grant) => grant.file_name === fileName
+                );
+                if (grants.length === 0) {
+                    alert(`${fileName} isn't shared with anyone.`);
+                    return;
+                }
+
+                const grantees = grants
+                    .map((grant) => `${formatGrantee(grant.grantee)} (${grant.access})`)
+                    .join(", ");
+                const grantee = prompt(
+                    `${fileName} is shared with ${grantees}. Revoke the access of which one?`
+                );
+                if (!grantee || !grantee.trim()) {
+                    return;
+                }
+                await grantRequest("DELETE", {
+                    file_name: fileName,
+                    grantee: parseGrantee(grantee),
+                });
+                fetchFiles();
+            } catch (error) {
+                alert(`Unable to revoke access: ${error.message}`);
+            }
+        });
+
+        return revokeBtn;
+    }
+
+    async function fetchReceivedGrants() {
+        const path = "https://localhost:8080/grants/received";
+        const response = await fetch(path, { headers: signedHeaders("GET", path) });
+        if (!response.ok) {
+            throw new Error(response.statusText);
+        }
+        return response.json();
+    }
+
+    // ACLs are edited as text, like "user:alice=read,write; group:sales=read"
+    function formatAcl(acl) {
+        return acl
+            .map((entry) => {
+                const principal = entry.user ? `user:${entry.user}` : `group:${entry.group}`;
+                return `${principal}=${entry.rights.join(",")}`;
+            })
+            .join("; ");
+    }
+
+    function parseAcl(text) {
+        return text
+            .split(";")
+            .map((entry) => entry.trim())
+            .filter((entry) => entry)
+            .map((entry) => {
+                const match = entry.match(/^(user|group):([^=]+)=(.*)$/);
+                if (!match) {
+                    throw new Error(`Invalid entry "${entry}"`);
+                }
+                const rights = match[3]
+                    .split(",")
+                    .map((right) => right.trim())
+                    .filter((right) => right);
+                return { [match[1]]: match[2].trim(), rights };
+            });
+    }
+
+    function createAccessButton(file) {
+        const accessBtn = document.createElement("button");
+        accessBtn.classList = "shareBtn";
+        accessBtn.textContent = "Access";
+
+        accessBtn.addEventListener("click", () => {
+            editAccess(file);
+        });
+
+        return accessBtn;
+    }
+
+    async function editAccess(file) {
+        const path = `https://localhost:8080/files/${encodeURIComponent(
+            file.name
+        )}/acl?owner=${encodeURIComponent(file.owner)}`;
+        try {
+            const response = await fetch(path, { headers: signedHeaders("GET", path) });
+            if (!response.ok) {
+                throw new Error(`Failed to get access list: ${response.statusText}`);
+            }
+            const text = prompt(
+                "Who else may access this file? Separate entries with ';', like " +
+                    '"user:alice=read,write; group:sales=read". Rights are read, write and share.',
+                formatAcl(await response.json())
+            );
+            if (text === null) {
+                return;
+            }
+
+            const body = JSON.stringify(parseAcl(text));
+            const saveResponse = await fetch(path, {
+                method: "PUT",
+                headers: {
+                    ...signedHeaders("PUT", path, await bodyDigest(body)),
+                    "Content-Type": "application/json",
+                },
+                body,
+            });
+            if (!saveResponse.ok) {
+                throw new Error(
+                    `Failed to save access list: ${
+                        (await saveResponse.text()) || saveResponse.statusText
+                    }`
+                );
+            }
+            fetchFiles();
+        } catch (error) {
+            alert(error.message);
+        }
+    }
+
+    function createDownloadButton(file) {
         const downloadBtn = document.createElement("button");
         downloadBtn.classList = "downloadBtn";
         downloadBtn.textContent = "Download";
 
         downloadBtn.addEventListener("click", () => {
-            downloadFile(fileName);
+            downloadFile(file);
         });
 
         return downloadBtn;
     }
 
-    function shareFile(fileName) {
+    function createDeleteFileButton(file) {
+        const deleteFileBtn = document.createElement("button");
+        deleteFileBtn.classList = "deleteFileBtn";
+        deleteFileBtn.textContent = "Delete";
+        deleteFileBtn.style.backgroundColor = "#a80000";
+        deleteFileBtn.style.color = "white";
+
+        deleteFileBtn.addEventListener("click", () => {
+            if (confirm(`Delete ${file.name}? Its share links will stop working.`)) {
+                deleteFile(file);
+            }
+        });
+
+        return deleteFileBtn;
+    }
+
+    function deleteFile(file) {
+        const xhr = new XMLHttpRequest();
+
+        xhr.onreadystatechange = function () {
+            if (xhr.readyState === XMLHttpRequest.DONE) {
+                if (xhr.status === 200) {
+                    fetchFiles();
+                } else if (xhr.status === 403) {
+                    alert("You don't have permission to delete files.");
+                } else {
+                    console.error("Failed to delete file:", xhr.statusText);
+                }
+            }
+        };
+
+        const path = filePath(file);
+
+        xhr.open("DELETE", path);
+        setSignedHeaders(xhr, "DELETE", path);
+        xhr.send();
+    }
+
+    async function shareFile(fileName) {
         const xhr = new XMLHttpRequest();
 
         const data = { file_name: fileName }; // Prepare the data in JSON format
 
+        const hours = prompt(
+            "Expire the link after how many hours? Leave blank for a link that never expires."
+        );
+        if (hours === null) {
+            return;
+        }
+        if (hours.trim() !== "") {
+            data.expires_in = Math.round(parseFloat(hours) * 3600);
+        }
+
+        const downloads = prompt(
+            "Stop the link working after how many downloads? Leave blank for no limit."
+        );
+        if (downloads === null) {
+            return;
+        }
+        if (downloads.trim() !== "") {
+            data.max_downloads = parseInt(downloads, 10);
+        }
+
+        const password = prompt(
+            "Password required to download through the link? Leave blank for none."
+        );
+        if (password === null) {
+            return;
+        }
+        if (password !== "") {
+            data.password = password;
+        }
+
         xhr.onreadystatechange = function () {
             if (xhr.readyState === XMLHttpRequest.DONE) {
                 if (xhr.status === 200) {
@@ -112,36 +484,36 @@ document.addEventListener("DOMContentLoaded", () => {
             }
         };
 
-        timestamp = getCurrentTimestamp();
-        path = "https://localhost:8080/link";
-        //message = timestamp + "+" + path + "+" + data;
-        message = timestamp + "+" + path;
-        hash = signWithPrivateKey(message);
+        const path = "https://localhost:8080/link";
+        const json = JSON.stringify(data);
+        const digest = await bodyDigest(json);
 
         xhr.open("PUT", path);
-        if (token) {
-            xhr.setRequestHeader("Authorization", `Bearer ${token}`);
-        }
-
-        xhr.setRequestHeader("Hash", hash);
-        xhr.setRequestHeader("Timestamp", timestamp);
+        setSignedHeaders(xhr, "PUT", path, digest);
 
         // Set the content type to application/json
         xhr.setRequestHeader("Content-Type", "application/json");
 
-        xhr.send(JSON.stringify(data)); // Send the JSON data
+        xhr.send(json); // Send the JSON data
 
         fetchAllLinks();
     }
 
-    function readFile(fileName) {
-        if (!canViewFileContent(fileName)) {
+    function readFile(file) {
+        if (!canViewFileContent(file.name)) {
             displayFileContentInModal(
                 "Unexpected error. File extension is not supported for viewing. Please download the file."
             );
             return;
         }
 
+        if (file.end_to_end) {
+            fetchDecrypted(file)
+                .then((data) => displayFileContentInModal(new TextDecoder().decode(data)))
+                .catch((error) => alert(error.message));
+            return;
+        }
+
         const xhr = new XMLHttpRequest();
 
         xhr.onreadystatechange = function () {
@@ -155,23 +527,36 @@ document.addEventListener("DOMContentLoaded", () => {
             }
         };
 
-        timestamp = getCurrentTimestamp();
-        path = `https://localhost:8080/files/${encodeURIComponent(fileName)}`;
-        //message = path.concat("+", timestamp);
-        message = timestamp + "+" + path;
-        hash = signWithPrivateKey(message);
+        const path = filePath(file);
 
         xhr.open("GET", path);
-        if (token) {
-            xhr.setRequestHeader("Authorization", `Bearer ${token}`);
-        }
-
-        xhr.setRequestHeader("Hash", hash);
-        xhr.setRequestHeader("Timestamp", timestamp);
+        setSignedHeaders(xhr, "GET", path);
         xhr.send();
     }
 
-    function downloadFile(fileName) {
+    function saveBlob(blob, fileName) {
+        const url = URL.createObjectURL(blob);
+
+        const a = document.createElement("a");
+        a.style.display = "none";
+        a.href = url;
+        a.download = fileName;
+
+        document.body.appendChild(a);
+        a.click();
+
+        window.URL.revokeObjectURL(url);
+        document.body.removeChild(a);
+    }
+
+    function downloadFile(file) {
+        if (file.end_to_end) {
+            fetchDecrypted(file)
+                .then((data) => saveBlob(new Blob([data]), file.name))
+                .catch((error) => alert(error.message));
+            return;
+        }
+
         const xhr = new XMLHttpRequest();
 
         xhr.onreadystatechange = function () {
@@ -180,35 +565,17 @@ document.addEventListener("DOMContentLoaded", () => {
                     const blob = new Blob([xhr.response], {
                         type: xhr.getResponseHeader("Content-Type"),
                     });
-                    const url = URL.createObjectURL(blob);
-
-                    const a = document.createElement("a");
-                    a.style.display = "none";
-                    a.href = url;
-                    a.download = fileName;
-
-                    document.body.appendChild(a);
-                    a.click();
-
-                    window.URL.revokeObjectURL(url);
-                    document.body.removeChild(a);
+                    saveBlob(blob, file.name);
                 } else {
                     console.error("Failed to fetch file:", xhr.statusText);
                 }
             }
         };
 
-        timestamp = getCurrentTimestamp();
-        path = `https://localhost:8080/files/${encodeURIComponent(fileName)}`;
-        message = timestamp + "+" + path;
-        hash = signWithPrivateKey(message);
+        const path = filePath(file);
 
         xhr.open("GET", path);
-        if (token) {
-            xhr.setRequestHeader("Authorization", `Bearer ${token}`);
-        }
-        xhr.setRequestHeader("Hash", hash);
-        xhr.setRequestHeader("Timestamp", timestamp);
+        setSignedHeaders(xhr, "GET", path);
         xhr.responseType = "blob";
         xhr.send();
     }
@@ -223,15 +590,34 @@ document.addEventListener("DOMContentLoaded", () => {
 
                     const fileList = JSON.parse(files);
 
-                    listUpload.innerHTML = "";
-
-                    fileList.forEach((fileName) => {
-                        const listItem = createListItem(fileName);
-                        listUpload.appendChild(listItem);
-                    });
+                    // Files that were shared through a grant are listed apart
+                    fetchReceivedGrants()
+                        .catch((error) => {
+                            console.error("Error fetching shared files:", error);
+                            return [];
+                        })
+                        .then((grants) => {
+                            const username = sessionClaims().username;
+                            const isGranted = (file) =>
+                                file.owner !== username &&
+                                grants.some(
+                                    (grant) =>
+                                        grant.owner === file.owner &&
+                                        grant.file_name === file.name
+                                );
+
+                            listUpload.innerHTML = "";
+                            sharedList.innerHTML = "";
+                            fileList.forEach((file) => {
+                                const listItem = createListItem(file);
+                                (isGranted(file) ? sharedList : listUpload).appendChild(
+                                    listItem
+                                );
+                            });
 
-                    //fetch of links for files
-                    fetchAllLinks();
+                            //fetch of links for files
+                            fetchAllLinks();
+                        });
                 } else {
                     const error = xhr.responseText;
                     console.error("Error fetching files:", error);
@@ -239,18 +625,10 @@ document.addEventListener("DOMContentLoaded", () => {
             }
         };
 
-        const timestamp = getCurrentTimestamp();
         const path = "https://localhost:8080/files";
 
-        message = timestamp + "+" + path;
-        hash = signWithPrivateKey(message);
         xhr.open("GET", path);
-        if (token) {
-            xhr.setRequestHeader("Authorization", `Bearer ${token}`);
-        }
-
-        xhr.setRequestHeader("Hash", hash);
-        xhr.setRequestHeader("Timestamp", timestamp);
+        setSignedHeaders(xhr, "GET", path);
 
         xhr.send();
     }
@@ -284,19 +662,10 @@ document.addEventListener("DOMContentLoaded", () => {
             }
         };
 
-        const timestamp = getCurrentTimestamp();
         const path = "https://localhost:8080/links";
 
-        message = timestamp + "+" + path;
-        hash = signWithPrivateKey(message);
-
         xhr.open("GET", path);
-        if (token) {
-            xhr.setRequestHeader("Authorization", `Bearer ${token}`);
-        }
-
-        xhr.setRequestHeader("Hash", hash);
-        xhr.setRequestHeader("Timestamp", timestamp);
+        setSignedHeaders(xhr, "GET", path);
         xhr.send();
     }
 
@@ -345,6 +714,19 @@ document.addEventListener("DOMContentLoaded", () => {
             linkInput.id = "link-" + link[0];
             linkInput.style.width = "40%";
             linkInput.value = window.location.origin + "/link/" + link[0];
+            const details = [];
+            if (link[1].expires_at) {
+                details.push("Expires at " + new Date(link[1].expires_at).toLocaleString());
+            }
+            if (link[1].max_downloads !== null) {
+                details.push(
+                    `Downloaded ${link[1].downloads} times, ${link[1].remaining_downloads} remaining`
+                );
+            }
+            if (link[1].password_protected) {
+                details.push("Password protected");
+            }
+            linkInput.title = details.join("\n");
             linkInput.style.marginRight = 10;
             linkInput.readOnly = true;
             linkInput.onclick = function () {
@@ -421,22 +803,12 @@ document.addEventListener("DOMContentLoaded", () => {
             }
         };
 
-        const timestamp = getCurrentTimestamp();
-        const path = `https://localhost:27401/link/${encodeURIComponent(
+        const path = `https://localhost:8080/link/${encodeURIComponent(
             linkText
         )}`;
 
-        message = timestamp + "+" + path;
-        console.log(message);
-        hash = signWithPrivateKey(message);
-
         xhr.open("DELETE", path);
-        if (token) {
-            xhr.setRequestHeader("Authorization", `Bearer ${token}`);
-        }
-
-        xhr.setRequestHeader("Hash", hash);
-        xhr.setRequestHeader("Timestamp", timestamp);
+        setSignedHeaders(xhr, "DELETE", path);
         xhr.send();
     }
 
@@ -453,76 +825,236 @@ document.addEventListener("DOMContentLoaded", () => {
         });
     }
 
-    function getCurrentTimestamp() {
-        return new Date().getTime().toString();
+    // Initial fetch of files, once the JWT is valid
+    keepSessionAlive((error) => {
+        alert(error.message);
+        clearSession();
+        E2E.forget();
+        window.location.href = "/login.html";
+    }).then(() => {
+        fetchFiles();
+        fetchTwoFactorStatus();
+    });
+
+    // Two-factor authentication, set up by confirming a secret with a code from an authenticator
+    // app
+    const twoFactorPath = `${AUTH_SERVER_URL}/user/two-factor`;
+    const twoFactorStatus = document.getElementById("twoFactorStatus");
+    const twoFactorSetupBtn = document.getElementById("twoFactorSetupBtn");
+    const twoFactorDisableBtn = document.getElementById("twoFactorDisableBtn");
+    const twoFactorSetup = document.getElementById("twoFactorSetup");
+    let twoFactorSecret = null;
+
+    async function twoFactorRequest(method, body) {
+        const json = body ? JSON.stringify(body) : "";
+        const headers = signedHeaders(
+            method,
+            twoFactorPath,
+            body ? await bodyDigest(json) : EMPTY_BODY_DIGEST
+        );
+        if (body) {
+            headers["Content-Type"] = "application/json";
+        }
+        const response = await fetch(twoFactorPath, {
+            method,
+            headers,
+            body: body ? json : undefined,
+        });
+        if (!response.ok) {
+            const error = await response.json().catch(() => ({}));
+            throw new Error(error.error || response.statusText);
+        }
+        return method === "DELETE" ? null : response.json();
     }
 
-    function signWithPrivateKey(inputValue) {
-        var sign = new JSEncrypt();
-        sign.setPrivateKey(private_key);
+    async function fetchTwoFactorStatus() {
+        try {
+            const status = await twoFactorRequest("GET");
+            if (status.enabled) {
+                twoFactorStatus.textContent = `Enabled, with ${status.recovery_codes_left} recovery codes left.`;
+            } else if (status.required) {
+                twoFactorStatus.textContent =
+                    "Admins must set up two-factor authentication before they can use admin features.";
+            } else {
+                twoFactorStatus.textContent = "Not enabled.";
+            }
+            twoFactorSetupBtn.style.display = status.enabled ? "none" : "inline";
+            twoFactorDisableBtn.style.display =
+                status.enabled && !status.required ? "inline" : "none";
+        } catch (error) {
+            console.error("Failed to fetch two-factor status:", error);
+        }
+    }
+
+    twoFactorSetupBtn.addEventListener("click", async () => {
+        try {
+            const enrollment = await twoFactorRequest("POST");
+            twoFactorSecret = enrollment.secret;
+            document.getElementById("twoFactorUri").value = enrollment.provisioning_uri;
+            document.getElementById("twoFactorSecret").value = enrollment.secret;
+            twoFactorSetup.style.display = "block";
+        } catch (error) {
+            alert(`Unable to set up two-factor authentication: ${error.message}`);
+        }
+    });
+
+    document.getElementById("twoFactorConfirmBtn").addEventListener("click", async () => {
+        const code = document.getElementById("twoFactorCode").value.trim();
+        try {
+            const { recovery_codes } = await twoFactorRequest("PUT", {
+                secret: twoFactorSecret,
+                code,
+            });
+            twoFactorSetup.style.display = "none";
+            document.getElementById("recoveryCodeList").textContent = recovery_codes.join("\n");
+            document.getElementById("recoveryCodes").style.display = "block";
+            fetchTwoFactorStatus();
+        } catch (error) {
+            alert(`Unable to enable two-factor authentication: ${error.message}`);
+        }
+    });
 
-        var signature = sign.sign(inputValue, CryptoJS.SHA256, "sha256");
-        return signature;
+    twoFactorDisableBtn.addEventListener("click", async () => {
+        const code = prompt("Enter a code from your authenticator app, or a recovery code:");
+        if (!code) {
+            return;
+        }
+        try {
+            await twoFactorRequest("DELETE", { code: code.trim() });
+            document.getElementById("recoveryCodes").style.display = "none";
+            fetchTwoFactorStatus();
+        } catch (error) {
+            alert(`Unable to disable two-factor authentication: ${error.message}`);
+        }
+    });
+
+    // Account management, which needs the user's password again
+    async function accountRequest(method, path, body) {
+        const url = `${AUTH_SERVER_URL}${path}`;
+        const json = JSON.stringify(body);
+        const response = await fetch(url, {
+            method,
+            headers: {
+                ...signedHeaders(method, url, await bodyDigest(json)),
+                "Content-Type": "application/json",
+            },
+            body: json,
+        });
+        if (!response.ok) {
+            const error = await response.json().catch(() => ({}));
+            throw new Error(error.error || response.statusText);
+        }
     }
 
-    // Initial fetch of files
-    fetchFiles();
+    const changePasswordForm = document.getElementById("changePasswordForm");
+    changePasswordForm.addEventListener("submit", async (event) => {
+        event.preventDefault();
+        const currentPassword = document.getElementById("currentPassword").value;
+        const newPassword = document.getElementById("newPassword").value;
+        if (newPassword !== document.getElementById("confirmNewPassword").value) {
+            alert("Passwords do not match");
+            return;
+        }
+
+        try {
+            // The private key is encrypted with the password, so it has to be encrypted again
+            let encryptedPrivateKey;
+            try {
+                encryptedPrivateKey = await E2E.rewrapPrivateKey(currentPassword, newPassword);
+            } catch (error) {
+                throw new Error("invalid credentials");
+            }
+            const body = { current_password: currentPassword, new_password: newPassword };
+            if (encryptedPrivateKey) {
+                body.encrypted_private_key = encryptedPrivateKey;
+            }
+            await accountRequest("POST", "/user/password", body);
+            changePasswordForm.reset();
+            alert("Password changed. Your other sessions have been logged out.");
+        } catch (error) {
+            alert(`Unable to change password: ${error.message}`);
+        }
+    });
+
+    document.getElementById("deleteAccountBtn").addEventListener("click", async () => {
+        const password = prompt(
+            "Deleting your account also deletes all of your files and share links. Enter your password to confirm:"
+        );
+        if (!password) {
+            return;
+        }
+        try {
+            const username = encodeURIComponent(sessionClaims().username);
+            await accountRequest("DELETE", `/user/${username}`, { password });
+            clearSession();
+            E2E.forget();
+            window.location.href = "/login.html";
+        } catch (error) {
+            alert(`Unable to delete account: ${error.message}`);
+        }
+    });
 
     // Handle file upload
     const uploadBtn = document.getElementById("uploadBtn");
-    uploadBtn.addEventListener("click", () => {
+    uploadBtn.addEventListener("click", async () => {
         const fileInput = document.getElementById("fileUpload");
         const file = fileInput.files[0];
+        const endToEnd = document.getElementById("endToEnd").checked;
 
         if (file) {
-            const reader = new FileReader();
-            reader.onload = () => {
-                const fileContent = reader.result;
-                const formData = new FormData();
-                formData.append("contents", fileContent);
-
-                const xhr = new XMLHttpRequest();
-                xhr.onreadystatechange = () => {
-                    if (xhr.readyState === XMLHttpRequest.DONE) {
-                        if (xhr.status === 200) {
-                            alert("File uploaded!");
-                            fetchFiles();
-                        } else if (xhr.status === 403) {
-                            alert("You don't have permission to upload files.");
-                        } else {
-                            const error = xhr.responseText;
-                            alert(`File upload failed: ${error}`);
-                        }
-                    }
-                };
-
-                const timestamp = getCurrentTimestamp();
-                const path = `https://localhost:8080/files/${encodeURIComponent(
-                    file.name
-                )}`;
-
-                message = timestamp + "+" + path;
-                hash = signWithPrivateKey(message);
-
-                xhr.open("PUT", path);
-                if (token) {
-                    xhr.setRequestHeader("Authorization", `Bearer ${token}`);
+            let body = file;
+            let fileKey = null;
+            if (endToEnd) {
+                if (!E2E.isUnlocked()) {
+                    alert("Please log in again to unlock your encryption keys.");
+                    return;
                 }
-                xhr.setRequestHeader("Hash", hash);
-                xhr.setRequestHeader("Timestamp", timestamp);
+                ({ body, fileKey } = await E2E.encryptFile(file));
+            }
 
-                xhr.send(formData);
+            const xhr = new XMLHttpRequest();
+            xhr.onreadystatechange = () => {
+                if (xhr.readyState === XMLHttpRequest.DONE) {
+                    if (xhr.status === 200) {
+                        alert("File uploaded!");
+                        fetchFiles();
+                    } else if (xhr.status === 403) {
+                        alert("You don't have permission to upload files.");
+                    } else {
+                        const error = xhr.responseText;
+                        alert(`File upload failed: ${error}`);
+                    }
+                }
             };
-            reader.readAsText(file);
+
+            const path = `https://localhost:8080/files/${encodeURIComponent(
+                file.name
+            )}`;
+            const digest = await bodyDigest(body);
+
+            xhr.open("PUT", path);
+            setSignedHeaders(xhr, "PUT", path, digest);
+            if (fileKey) {
+                xhr.setRequestHeader("File-Key", fileKey);
+                xhr.setRequestHeader("Content-Type", "application/octet-stream");
+            } else {
+                xhr.setRequestHeader(
+                    "Content-Type",
+                    file.type || "application/octet-stream"
+                );
+            }
+
+            // Send the raw file so that binary contents arrive unchanged
+            xhr.send(body);
         } else {
             alert("Please select a file to upload");
         }
     });
 
     const logoutBtn = document.getElementById("logoutBtn");
-    logoutBtn.addEventListener("click", () => {
-        localStorage.removeItem("jwtToken");
-        localStorage.removeItem("privateKey");
+    logoutBtn.addEventListener("click", async () => {
+        await endSession();
+        E2E.forget();
         window.location.href = "/login.html";
     });
 });
@@ -0,0 +1,232 @@
+// End-to-end encryption of files, so that neither server can read their contents.
+//
+// Every user has a long-term RSA-OAEP key pair. The private key is kept by the auth server
+// encrypted with a key derived from the user's password. Each file is encrypted with its own
+// AES-GCM key, which is wrapped with the public key of every user allowed to read the file.
+const E2E = (() => {
+    const AUTH_SERVER = "https://localhost:27464";
+    const PBKDF2_ITERATIONS = 600000;
+    const SALT_SIZE = 16;
+    const IV_SIZE = 12;
+    const RSA_PARAMS = {
+        name: "RSA-OAEP",
+        modulusLength: 3072,
+        publicExponent: new Uint8Array([1, 0, 1]),
+        hash: "SHA-256",
+    };
+    const PUBLIC_KEY_ITEM = "e2ePublicKey";
+    const PRIVATE_KEY_ITEM = "e2ePrivateKey";
+
+    function toBase64(buffer) {
+        return btoa(String.fromCharCode(...new Uint8Array(buffer)));
+    }
+
+    function fromBase64(text) {
+        return Uint8Array.from(atob(text), (c) => c.charCodeAt(0));
+    }
+
+    function toPem(buffer) {
+        const lines = toBase64(buffer).match(/.{1,64}/g).join("\n");
+        return `-----BEGIN PUBLIC KEY-----\n${lines}\n-----END PUBLIC KEY-----\n`;
+    }
+
+    function fromPem(pem) {
+        return fromBase64(pem.replace(/-----[^-]+-----|\s/g, ""));
+    }
+
+    async function passwordKey(password, salt) {
+        const material = await crypto.subtle.importKey(
+            "raw",
+            new TextEncoder().encode(password),
+            "PBKDF2",
+            false,
+            ["deriveKey"]
+        );
+        return crypto.subtle.deriveKey(
+            { name: "PBKDF2", salt, iterations: PBKDF2_ITERATIONS, hash: "SHA-256" },
+            material,
+            { name: "AES-GCM", length: 256 },
+            false,
+            ["encrypt", "decrypt"]
+        );
+    }
+
+    // Encrypted private keys are stored as base64 of salt || IV || ciphertext
+    async function encryptPrivateKey(pkcs8, password) {
+        const salt = crypto.getRandomValues(new Uint8Array(SALT_SIZE));
+        const iv = crypto.getRandomValues(new Uint8Array(IV_SIZE));
+        const key = await passwordKey(password, salt);
+        const encrypted = await crypto.subtle.encrypt({ name: "AES-GCM", iv }, key, pkcs8);
+        return toBase64(new Uint8Array([...salt, ...iv, ...new Uint8Array(encrypted)]));
+    }
+
+    async function decryptPrivateKey(encryptedPrivateKey, password) {
+        const data = fromBase64(encryptedPrivateKey);
+        const salt = data.slice(0, SALT_SIZE);
+        const iv = data.slice(SALT_SIZE, SALT_SIZE + IV_SIZE);
+        const key = await passwordKey(password, salt);
+        return crypto.subtle.decrypt(
+            { name: "AES-GCM", iv },
+            key,
+            data.slice(SALT_SIZE + IV_SIZE)
+        );
+    }
+
+    async function createKeys(password) {
+        const keyPair = await crypto.subtle.generateKey(RSA_PARAMS, true, [
+            "encrypt",
+            "decrypt",
+        ]);
+        const publicKey = await crypto.subtle.exportKey("spki", keyPair.publicKey);
+        const privateKey = await crypto.subtle.exportKey("pkcs8", keyPair.privateKey);
+        return {
+            public_key: toPem(publicKey),
+            encrypted_private_key: await encryptPrivateKey(privateKey, password),
+        };
+    }
+
+    // Fetches the user's key pair, creating it on their first login, and unlocks the private key
+    // for the rest of the session
+    async function setUp(password) {
+        const url = `${AUTH_SERVER}/user/keys`;
+        let response = await fetch(url, { headers: signedHeaders("GET", url) });
+        if (response.status === 404) {
+            const body = JSON.stringify(await createKeys(password));
+            const created = await fetch(url, {
+                method: "PUT",
+                headers: {
+                    ...signedHeaders("PUT", url, await bodyDigest(body)),
+                    "Content-Type": "application/json",
+                },
+                body,
+            });
+            // Another session may have created the keys in the meantime
+            if (!created.ok && created.status !== 409) {
+                throw new Error("Failed to store encryption keys");
+            }
+            response = await fetch(url, { headers: signedHeaders("GET", url) });
+        }
+        if (!response.ok) {
+            throw new Error("Failed to fetch encryption keys");
+        }
+
+        const keys = await response.json();
+        const privateKey = await decryptPrivateKey(keys.encrypted_private_key, password);
+        sessionStorage.setItem(PUBLIC_KEY_ITEM, keys.public_key);
+        sessionStorage.setItem(PRIVATE_KEY_ITEM, toBase64(privateKey));
+    }
+
+    // Encrypts the user's private key with a new password, returning null if they have no keys
+    async function rewrapPrivateKey(currentPassword, newPassword) {
+        const url = `${AUTH_SERVER}/user/keys`;
+        const response = await fetch(url, { headers: signedHeaders("GET", url) });
+        if (response.status === 404) {
+            return null;
+        } else if (!response.ok) {
+            throw new Error("Failed to fetch encryption keys");
+        }
+
+        const keys = await response.json();
+        const privateKey = await decryptPrivateKey(keys.encrypted_private_key, currentPassword);
+        return encryptPrivateKey(privateKey, newPassword);
+    }
+
+    function forget() {
+        sessionStorage.removeItem(PUBLIC_KEY_ITEM);
+        sessionStorage.removeItem(PRIVATE_KEY_ITEM);
+    }
+
+    function isUnlocked() {
+        return sessionStorage.getItem(PRIVATE_KEY_ITEM) !== null;
+    }
+
+    async function importPublicKey(pem) {
+        return crypto.subtle.importKey("spki", fromPem(pem), RSA_PARAMS, false, ["encrypt"]);
+    }
+
+    async function importPrivateKey() {
+        const pkcs8 = sessionStorage.getItem(PRIVATE_KEY_ITEM);
+        if (pkcs8 === null) {
+            throw new Error("Encryption keys are locked, please log in again");
+        }
+        return crypto.subtle.importKey("pkcs8", fromBase64(pkcs8), RSA_PARAMS, false, [
+            "decrypt",
+        ]);
+    }
+
+    async function unwrapFileKey(wrappedFileKey) {
+        return crypto.subtle.decrypt(
+            { name: "RSA-OAEP" },
+            await importPrivateKey(),
+            fromBase64(wrappedFileKey)
+        );
+    }
+
+    // Encrypts a file for upload, returning the contents as IV || ciphertext along with the file
+    // key wrapped for the current user
+    async function encryptFile(file) {
+        const key = await crypto.subtle.generateKey({ name: "AES-GCM", length: 256 }, true, [
+            "encrypt",
+        ]);
+        const iv = crypto.getRandomValues(new Uint8Array(IV_SIZE));
+        const encrypted = await crypto.subtle.encrypt(
+            { name: "AES-GCM", iv },
+            key,
+            await file.arrayBuffer()
+        );
+
+        const publicKey = await importPublicKey(sessionStorage.getItem(PUBLIC_KEY_ITEM));
+        const wrapped = await crypto.subtle.encrypt(
+            { name: "RSA-OAEP" },
+            publicKey,
+            await crypto.subtle.exportKey("raw", key)
+        );
+
+        return { body: new Blob([iv, encrypted]), fileKey: toBase64(wrapped) };
+    }
+
+    async function decryptFile(data, wrappedFileKey) {
+        const key = await crypto.subtle.importKey(
+            "raw",
+            await unwrapFileKey(wrappedFileKey),
+            "AES-GCM",
+            false,
+            ["decrypt"]
+        );
+        const bytes = new Uint8Array(data);
+        return crypto.subtle.decrypt(
+            { name: "AES-GCM", iv: bytes.slice(0, IV_SIZE) },
+            key,
+            bytes.slice(IV_SIZE)
+        );
+    }
+
+    // Wraps the key of a file the current user can decrypt for another user
+    async function wrapFileKeyFor(wrappedFileKey, username) {
+        const url = `${AUTH_SERVER}/user/${encodeURIComponent(username)}/public-key`;
+        const response = await fetch(url, { headers: signedHeaders("GET", url) });
+        if (response.status === 404) {
+            throw new Error(`${username} has no encryption keys yet`);
+        } else if (!response.ok) {
+            throw new Error("Failed to fetch public key");
+        }
+
+        const publicKey = await importPublicKey((await response.json()).public_key);
+        const wrapped = await crypto.subtle.encrypt(
+            { name: "RSA-OAEP" },
+            publicKey,
+            await unwrapFileKey(wrappedFileKey)
+        );
+        return toBase64(wrapped);
+    }
+
+    return {
+        setUp,
+        rewrapPrivateKey,
+        forget,
+        isUnlocked,
+        encryptFile,
+        decryptFile,
+        wrapFileKeyFor,
+    };
+})();
@@ -1,10 +1,224 @@
 // admin.js
 
 document.addEventListener("DOMContentLoaded", () => {
+    const USERS_PER_PAGE = 50;
+    const userList = document.getElementById("userList");
+    const groupList = document.getElementById("groupList");
+    const pageInfo = document.getElementById("pageInfo");
+    const previousPageBtn = document.getElementById("previousPageBtn");
+    const nextPageBtn = document.getElementById("nextPageBtn");
+    let page = 1;
 
     const backToMainBtn = document.getElementById("backToMainBtn");
     backToMainBtn.addEventListener("click", () => {
         window.location.href = "/dashboard.html";
     });
 
+    async function adminRequest(method, path, body) {
+        const url = `${AUTH_SERVER_URL}${path}`;
+        const options = { method, headers: signedHeaders(method, url) };
+        if (body !== undefined) {
+            options.body = JSON.stringify(body);
+            options.headers = {
+                ...signedHeaders(method, url, await bodyDigest(options.body)),
+                "Content-Type": "application/json",
+            };
+        }
+        const response = await fetch(url, options);
+        if (!response.ok) {
+            const error = await response.json().catch(() => ({}));
+            throw new Error(error.error || response.statusText);
+        }
+        return response;
+    }
+
+    function formatTime(time) {
+        return time ? new Date(time).toLocaleString() : "Unknown";
+    }
+
+    function createActionButton(text, action, refresh = fetchUsers) {
+        const button = document.createElement("button");
+        button.textContent = text;
+        button.addEventListener("click", async () => {
+            try {
+                await action();
+                refresh();
+            } catch (error) {
+                alert(`${text} failed: ${error.message}`);
+            }
+        });
+        return button;
+    }
+
+    function createUserRow(user) {
+        const path = `/user/${encodeURIComponent(user.username)}`;
+        const row = document.createElement("tr");
+        [
+            user.username,
+            user.roles.sort().join(", "),
+            formatTime(user.created_at),
+            user.last_login_at ? formatTime(user.last_login_at) : "Never",
+            user.two_factor_enabled ? "Enabled" : "Not enabled",
+            user.disabled ? "Disabled" : "Active",
+        ].forEach((text) => {
+            const cell = document.createElement("td");
+            cell.textContent = text;
+            row.appendChild(cell);
+        });
+
+        const actions = document.createElement("td");
+        if (user.disabled) {
+            actions.appendChild(
+                createActionButton("Enable", () => adminRequest("DELETE", `${path}/disabled`))
+            );
+        } else {
+            actions.appendChild(
+                createActionButton("Disable", () => adminRequest("PUT", `${path}/disabled`))
+            );
+        }
+        actions.appendChild(
+            createActionButton("Log Out", () => adminRequest("DELETE", `${path}/sessions`))
+        );
+        actions.appendChild(
+            createActionButton("Reset Password", async () => {
+                const response = await adminRequest("POST", `${path}/password-reset`);
+                const reset = await response.json();
+                prompt(
+                    `Give ${user.username} this reset token, which works once until ${formatTime(
+                        reset.expires_at
+                    )}:`,
+                    reset.reset_token
+                );
+            })
+        );
+        actions.appendChild(
+            createActionButton("Delete", async () => {
+                if (confirm(`Delete ${user.username} along with their files and share links?`)) {
+                    await adminRequest("DELETE", path);
+                }
+            })
+        );
+        row.appendChild(actions);
+
+        return row;
+    }
+
+    async function fetchUsers() {
+        try {
+            const response = await adminRequest(
+                "GET",
+                `/users?page=${page}&per_page=${USERS_PER_PAGE}`
+            );
+            const { users, total } = await response.json();
+            const pages = Math.max(1, Math.ceil(total / USERS_PER_PAGE));
+
+            userList.innerHTML = "";
+            users.forEach((user) => userList.appendChild(createUserRow(user)));
+            pageInfo.textContent = `Page ${page} of ${pages}`;
+            previousPageBtn.disabled = page <= 1;
+            nextPageBtn.disabled = page >= pages;
+            document.getElementById("loadingText").style.display = "none";
+            document.getElementById("userTable").style.display = "table";
+        } catch (error) {
+            document.getElementById("loadingText").textContent =
+                `Unable to list users: ${error.message}`;
+        }
+    }
+
+    function createGroupRow(group) {
+        const path = `/groups/${encodeURIComponent(group.name)}`;
+        const row = document.createElement("tr");
+        [group.name, group.members.join(", "), formatTime(group.created_at)].forEach((text) => {
+            const cell = document.createElement("td");
+            cell.textContent = text;
+            row.appendChild(cell);
+        });
+
+        const actions = document.createElement("td");
+        actions.appendChild(
+            createActionButton(
+                "Add Member",
+                async () => {
+                    const username = prompt(`Add which user to ${group.name}?`);
+                    if (username) {
+                        await adminRequest(
+                            "PUT",
+                            `${path}/members/${encodeURIComponent(username.trim())}`
+                        );
+                    }
+                },
+                fetchGroups
+            )
+        );
+        actions.appendChild(
+            createActionButton(
+                "Remove Member",
+                async () => {
+                    const username = prompt(`Remove which user from ${group.name}?`);
+                    if (username) {
+                        await adminRequest(
+                            "DELETE",
+                            `${path}/members/${encodeURIComponent(username.trim())}`
+                        );
+                    }
+                },
+                fetchGroups
+            )
+        );
+        actions.appendChild(
+            createActionButton(
+                "Delete",
+                async () => {
+                    if (confirm(`Delete the group ${group.name}?`)) {
+                        await adminRequest("DELETE", path);
+                    }
+                },
+                fetchGroups
+            )
+        );
+        row.appendChild(actions);
+
+        return row;
+    }
+
+    async function fetchGroups() {
+        try {
+            const response = await adminRequest("GET", "/groups");
+            const groups = await response.json();
+            groupList.innerHTML = "";
+            groups.forEach((group) => groupList.appendChild(createGroupRow(group)));
+        } catch (error) {
+            alert(`Unable to list groups: ${error.message}`);
+        }
+    }
+
+    document.getElementById("createGroupForm").addEventListener("submit", async (event) => {
+        event.preventDefault();
+        const nameInput = document.getElementById("groupName");
+        try {
+            await adminRequest("POST", "/groups", { name: nameInput.value.trim() });
+            nameInput.value = "";
+            fetchGroups();
+        } catch (error) {
+            alert(`Unable to create group: ${error.message}`);
+        }
+    });
+
+    previousPageBtn.addEventListener("click", () => {
+        page--;
+        fetchUsers();
+    });
+    nextPageBtn.addEventListener("click", () => {
+        page++;
+        fetchUsers();
+    });
+
+    keepSessionAlive((error) => {
+        alert(error.message);
+        clearSession();
+        window.location.href = "/login.html";
+    }).then(() => {
+        fetchUsers();
+        fetchGroups();
+    });
 });
@@ -0,0 +1,131 @@
+// Request signatures, made with the private key of the session that the auth server hands out on
+// login. A signature covers the time, a fresh nonce, the method, the URL and a digest of the body,
+// so that a request can't be replayed or reused for a different one.
+
+// Digest of the empty body of GET and DELETE requests
+const EMPTY_BODY_DIGEST = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+
+function getCurrentTimestamp() {
+    return new Date().getTime().toString();
+}
+
+function signWithPrivateKey(inputValue) {
+    var sign = new JSEncrypt();
+    sign.setPrivateKey(localStorage.getItem("privateKey"));
+
+    var signature = sign.sign(inputValue, CryptoJS.SHA256, "sha256");
+    return signature;
+}
+
+// Base64 SHA-256 digest of a request body
+async function bodyDigest(body) {
+    const data =
+        body instanceof Blob
+            ? await body.arrayBuffer()
+            : new TextEncoder().encode(body);
+    const digest = await crypto.subtle.digest("SHA-256", data);
+    return btoa(String.fromCharCode(...new Uint8Array(digest)));
+}
+
+// Headers authenticating a request to `url`
+function signedHeaders(method, url, digest = EMPTY_BODY_DIGEST) {
+    const timestamp = getCurrentTimestamp();
+    const nonce = crypto.randomUUID();
+    return {
+        Authorization: `Bearer ${localStorage.getItem("jwtToken")}`,
+        Hash: signWithPrivateKey([timestamp, nonce, method, url, digest].join("+")),
+        Timestamp: timestamp,
+        Nonce: nonce,
+        "Body-Digest": digest,
+    };
+}
+
+function setSignedHeaders(xhr, method, url, digest) {
+    const headers = signedHeaders(method, url, digest);
+    for (const [name, value] of Object.entries(headers)) {
+        xhr.setRequestHeader(name, value);
+    }
+}
+
+// Sessions, which hand out short-lived JWTs that are swapped for new ones with a refresh token.
+// Every refresh token can only be used once, so only the page that refreshes first gets a new one.
+
+const AUTH_SERVER_URL = "https://localhost:27464";
+// How long before its expiry a JWT is refreshed, in milliseconds
+const REFRESH_MARGIN = 60 * 1000;
+
+function storeSession(data) {
+    localStorage.setItem("jwtToken", data.token);
+    localStorage.setItem("refreshToken", data.refresh_token);
+    localStorage.setItem("sessionId", data.session_id);
+    if (data.private_key) {
+        localStorage.setItem("privateKey", data.private_key);
+    }
+}
+
+function clearSession() {
+    localStorage.removeItem("jwtToken");
+    localStorage.removeItem("refreshToken");
+    localStorage.removeItem("sessionId");
+    localStorage.removeItem("privateKey");
+}
+
+// Claims of the stored JWT, or null if there's none
+function sessionClaims() {
+    const token = localStorage.getItem("jwtToken");
+    if (!token) {
+        return null;
+    }
+    const payload = token.split(".")[1].replace(/-/g, "+").replace(/_/g, "/");
+    return JSON.parse(atob(payload));
+}
+
+// Milliseconds until the stored JWT needs to be refreshed
+function timeUntilRefresh() {
+    const claims = sessionClaims();
+    if (!claims) {
+        return 0;
+    }
+    return claims.exp * 1000 - Date.now() - REFRESH_MARGIN;
+}
+
+async function refreshSession() {
+    const response = await fetch(`${AUTH_SERVER_URL}/user/refresh`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ refresh_token: localStorage.getItem("refreshToken") }),
+    });
+    if (!response.ok) {
+        throw new Error("Session ended, please log in again");
+    }
+    storeSession(await response.json());
+}
+
+// Keeps the JWT valid for as long as the page is open, calling `onEnded` once the session can no
+// longer be refreshed. Resolves once the JWT is valid.
+async function keepSessionAlive(onEnded) {
+    // Another page of the same session may have refreshed the JWT in the meantime
+    async function refreshIfDue() {
+        if (timeUntilRefresh() <= 0) {
+            await refreshSession();
+        }
+        setTimeout(() => refreshIfDue().catch(onEnded), timeUntilRefresh());
+    }
+    try {
+        await refreshIfDue();
+    } catch (error) {
+        onEnded(error);
+    }
+}
+
+// Ends the current session on the auth server, so that neither its refresh token nor its JWTs can
+// be used anymore
+async function endSession() {
+    if (localStorage.getItem("jwtToken")) {
+        const url = `${AUTH_SERVER_URL}/user/logout`;
+        await fetch(url, { method: "POST", headers: signedHeaders("POST", url) }).catch((error) =>
+            console.error("Unable to end session:", error)
+        );
+    }
+    clearSession();
+}